@@ -2,8 +2,19 @@ use anyhow::{Context, Result};
 use clap::Parser;
 use tokio_util::sync::CancellationToken;
 
-use pheidippides_auth::{AuthServiceUsingArgon2, AuthStorage};
+use pheidippides_auth::{
+    AuditLogStorage, AuthServiceUsingArgon2, AuthStorage, LoginThrottlingConfig,
+    PasswordHashingConfig, Pepper, SecretKey,
+};
+use pheidippides_blob::{FsBlobStorage, S3BlobStorage};
+use pheidippides_mail::{FileMailer, SmtpMailer};
+use pheidippides_messenger::attachments::AttachmentLimits;
+use pheidippides_messenger::authorization::AuthService;
+use pheidippides_messenger::blob_storage::BlobStorage;
 use pheidippides_messenger::data_access::DataAccess;
+use pheidippides_messenger::mailer::{Mail, Mailer};
+use pheidippides_messenger::UserId;
+use pheidippides_oidc::{OidcAuthService, OidcClient, OidcConfig};
 use pheidippides_web::request_handler;
 
 #[derive(Parser, Debug)]
@@ -20,6 +31,210 @@ struct Args {
     db: Option<String>,
     #[arg(long)]
     mock: bool,
+    #[arg(
+        long,
+        id = "SMTP URL",
+        help = "SMTP relay url. Format: smtp[s]://[user[:password]@]host[:port]",
+        requires = "mail_from"
+    )]
+    smtp: Option<String>,
+    #[arg(long, help = "Sender address of outgoing mail")]
+    mail_from: Option<String>,
+    #[arg(
+        long,
+        default_value = "mail",
+        help = "Directory where outgoing mail is saved when no SMTP relay is specified"
+    )]
+    mail_dir: String,
+    #[arg(
+        long,
+        default_value = "pheidippides.key",
+        help = "File with the key used to encrypt stored secrets. Generated if it doesn't exist"
+    )]
+    secret_key_file: String,
+    #[arg(long, help = "Argon2 memory cost in KiB used for new password hashes")]
+    argon2_memory_cost: Option<u32>,
+    #[arg(
+        long,
+        help = "Argon2 number of iterations used for new password hashes"
+    )]
+    argon2_time_cost: Option<u32>,
+    #[arg(
+        long,
+        help = "Argon2 degree of parallelism used for new password hashes"
+    )]
+    argon2_parallelism: Option<u32>,
+    #[arg(
+        long,
+        help = "File with a secret mixed into password hashes. Existing hashes are upgraded on login"
+    )]
+    pepper_file: Option<String>,
+    #[arg(
+        long,
+        default_value = "1",
+        help = "Identifier of the pepper stored in password hashes, at most 8 bytes. Change it along with the pepper"
+    )]
+    pepper_key_id: String,
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "Failed login attempts after which an account is temporarily locked"
+    )]
+    login_lockout_threshold: u32,
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "Failed login attempts after which a client address is temporarily locked"
+    )]
+    client_lockout_threshold: u32,
+    #[arg(
+        long,
+        default_value_t = 15,
+        help = "Duration of a login lockout in minutes"
+    )]
+    lockout_minutes: i64,
+    #[arg(
+        long,
+        id = "ISSUER URL",
+        help = "OpenID Connect provider to log in through instead of passwords",
+        requires_all = ["oidc_client_id", "oidc_redirect_url"]
+    )]
+    oidc_issuer: Option<String>,
+    #[arg(long, help = "Client id registered with the OpenID Connect provider")]
+    oidc_client_id: Option<String>,
+    #[arg(
+        long,
+        help = "Client secret registered with the OpenID Connect provider, if any"
+    )]
+    oidc_client_secret: Option<String>,
+    #[arg(
+        long,
+        help = "Externally visible url of /login/oidc/callback registered with the OpenID Connect provider"
+    )]
+    oidc_redirect_url: Option<String>,
+    #[arg(
+        long = "admin",
+        id = "ADMIN USER ID",
+        help = "Id of a user allowed to query the audit log of all users. Can be repeated"
+    )]
+    admins: Vec<UserId>,
+    #[arg(
+        long,
+        default_value_t = 48 * 60,
+        help = "How long after sending a message its author may edit it, in minutes"
+    )]
+    message_edit_window_minutes: i64,
+    #[arg(
+        long,
+        default_value = "blobs",
+        help = "Directory where attachments are saved when no S3 endpoint is specified"
+    )]
+    blob_dir: String,
+    #[arg(
+        long,
+        id = "S3 ENDPOINT URL",
+        help = "S3-compatible service to store attachments in, addressed path-style",
+        requires_all = ["s3_bucket", "s3_access_key_id", "s3_secret_access_key"]
+    )]
+    s3_endpoint: Option<String>,
+    #[arg(long, help = "Bucket to store attachments in")]
+    s3_bucket: Option<String>,
+    #[arg(long, default_value = "us-east-1", help = "Region of the S3 bucket")]
+    s3_region: String,
+    #[arg(long, help = "Access key id of the S3 service")]
+    s3_access_key_id: Option<String>,
+    #[arg(long, help = "Secret access key of the S3 service")]
+    s3_secret_access_key: Option<String>,
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "Largest attachment accepted for upload, in MiB"
+    )]
+    max_attachment_size_mib: u64,
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Messages older than this many days are deleted from all conversations"
+    )]
+    message_retention_days: Option<u32>,
+    #[arg(
+        long,
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "How often expired messages are deleted, in seconds"
+    )]
+    purge_interval_seconds: u64,
+    #[arg(
+        long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "How often due scheduled messages are sent, in seconds"
+    )]
+    scheduler_interval_seconds: u64,
+}
+
+struct AuthConfig {
+    secret_key: SecretKey,
+    password_hashing_config: PasswordHashingConfig,
+    login_throttling_config: LoginThrottlingConfig,
+    oidc_config: Option<OidcConfig>,
+}
+
+struct MessengerConfig {
+    admins: Vec<UserId>,
+    message_edit_window: chrono::TimeDelta,
+    attachment_limits: AttachmentLimits,
+    message_retention: Option<chrono::TimeDelta>,
+    purge_interval: std::time::Duration,
+    scheduler_interval: std::time::Duration,
+}
+
+#[derive(Clone)]
+enum ServerMailer {
+    Smtp(SmtpMailer),
+    File(FileMailer),
+}
+
+impl Mailer for ServerMailer {
+    type Error = pheidippides_mail::Error;
+
+    async fn send_mail(&self, mail: Mail) -> Result<(), Self::Error> {
+        match self {
+            ServerMailer::Smtp(mailer) => mailer.send_mail(mail).await,
+            ServerMailer::File(mailer) => mailer.send_mail(mail).await,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum ServerBlobStorage {
+    Fs(FsBlobStorage),
+    S3(S3BlobStorage),
+}
+
+impl BlobStorage for ServerBlobStorage {
+    type Error = pheidippides_blob::Error;
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), Self::Error> {
+        match self {
+            ServerBlobStorage::Fs(storage) => storage.put(key, content).await,
+            ServerBlobStorage::S3(storage) => storage.put(key, content).await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self {
+            ServerBlobStorage::Fs(storage) => storage.get(key).await,
+            ServerBlobStorage::S3(storage) => storage.get(key).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Self::Error> {
+        match self {
+            ServerBlobStorage::Fs(storage) => storage.delete(key).await,
+            ServerBlobStorage::S3(storage) => storage.delete(key).await,
+        }
+    }
 }
 
 #[tokio::main]
@@ -31,11 +246,123 @@ async fn main() -> Result<()> {
 
     let cancellation_token = make_cancellation_token();
 
+    let mailer = match (args.smtp, args.mail_from) {
+        (Some(smtp), Some(mail_from)) => ServerMailer::Smtp(
+            SmtpMailer::new(&smtp, &mail_from).context("Couldn't set up SMTP mailer")?,
+        ),
+        _ => {
+            eprintln!(
+                "SMTP relay is not specified, outgoing mail will be saved to {}",
+                args.mail_dir
+            );
+            ServerMailer::File(FileMailer::new(args.mail_dir))
+        }
+    };
+
+    let blob_storage = match (
+        args.s3_endpoint,
+        args.s3_bucket,
+        args.s3_access_key_id,
+        args.s3_secret_access_key,
+    ) {
+        (Some(endpoint), Some(bucket), Some(access_key_id), Some(secret_access_key)) => {
+            ServerBlobStorage::S3(
+                S3BlobStorage::new(
+                    &endpoint,
+                    &bucket,
+                    &args.s3_region,
+                    &access_key_id,
+                    &secret_access_key,
+                )
+                .context("Couldn't set up S3 blob storage")?,
+            )
+        }
+        _ => ServerBlobStorage::Fs(FsBlobStorage::new(args.blob_dir)),
+    };
+
+    let secret_key = load_or_create_secret_key(&args.secret_key_file).await?;
+
+    let default_config = PasswordHashingConfig::default();
+    let pepper = match args.pepper_file {
+        Some(path) => Some(load_pepper(&args.pepper_key_id, &path).await?),
+        None => None,
+    };
+    let password_hashing_config = PasswordHashingConfig {
+        memory_cost: args
+            .argon2_memory_cost
+            .unwrap_or(default_config.memory_cost),
+        time_cost: args.argon2_time_cost.unwrap_or(default_config.time_cost),
+        parallelism: args
+            .argon2_parallelism
+            .unwrap_or(default_config.parallelism),
+        pepper,
+    };
+    password_hashing_config.validate()?;
+
+    let login_throttling_config = LoginThrottlingConfig {
+        user_lockout_threshold: args.login_lockout_threshold,
+        client_lockout_threshold: args.client_lockout_threshold,
+        lockout_duration: chrono::TimeDelta::try_minutes(args.lockout_minutes)
+            .context("Lockout duration is too long")?,
+        ..LoginThrottlingConfig::default()
+    };
+
+    let oidc_config = match (
+        args.oidc_issuer,
+        args.oidc_client_id,
+        args.oidc_redirect_url,
+    ) {
+        (Some(issuer_url), Some(client_id), Some(redirect_url)) => Some(OidcConfig {
+            issuer_url,
+            client_id,
+            client_secret: args.oidc_client_secret,
+            redirect_url,
+        }),
+        _ => None,
+    };
+
+    let auth_config = AuthConfig {
+        secret_key,
+        password_hashing_config,
+        login_throttling_config,
+        oidc_config,
+    };
+
+    let messenger_config = MessengerConfig {
+        admins: args.admins,
+        message_edit_window: chrono::TimeDelta::try_minutes(args.message_edit_window_minutes)
+            .context("Message edit window is too long")?,
+        attachment_limits: AttachmentLimits {
+            max_size: args
+                .max_attachment_size_mib
+                .checked_mul(1024 * 1024)
+                .context("Attachment size limit is too large")?,
+            ..AttachmentLimits::default()
+        },
+        message_retention: args
+            .message_retention_days
+            .map(|days| {
+                chrono::TimeDelta::try_days(days.into()).context("Message retention is too long")
+            })
+            .transpose()?,
+        purge_interval: std::time::Duration::from_secs(args.purge_interval_seconds),
+        scheduler_interval: std::time::Duration::from_secs(args.scheduler_interval_seconds),
+    };
+
     let use_mock = args.mock;
 
     if use_mock {
         let db_access = mock_db::Db::new().await;
-        run_server(db_access, &addr, cancellation_token).await?;
+        run_server(
+            db_access,
+            auth_config,
+            messenger_config,
+            mailer,
+            blob_storage,
+            &addr,
+            cancellation_token,
+        )
+        .await?;
     } else {
         let db_connection = args
             .db
@@ -44,7 +371,16 @@ async fn main() -> Result<()> {
         db_access.check_migrations().await?;
         let db_graceful_shutdown = db_access.graceful_shutdown(cancellation_token.clone());
 
-        run_server(db_access, &addr, cancellation_token).await?;
+        run_server(
+            db_access,
+            auth_config,
+            messenger_config,
+            mailer,
+            blob_storage,
+            &addr,
+            cancellation_token,
+        )
+        .await?;
 
         db_graceful_shutdown
             .await
@@ -54,17 +390,99 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn run_server<T: DataAccess + AuthStorage>(
+async fn run_server<T: DataAccess + AuthStorage + AuditLogStorage>(
     data_access: T,
+    auth_config: AuthConfig,
+    messenger_config: MessengerConfig,
+    mailer: impl Mailer,
+    blob_storage: impl BlobStorage,
+    addr: &str,
+    cancellation_token: CancellationToken,
+) -> Result<()> {
+    let auth_service = AuthServiceUsingArgon2::new(data_access.clone(), auth_config.secret_key)
+        .with_password_hashing_config(auth_config.password_hashing_config)
+        .with_login_throttling_config(auth_config.login_throttling_config);
+
+    match auth_config.oidc_config {
+        Some(oidc_config) => {
+            let issuer_url = oidc_config.issuer_url.clone();
+            let oidc_client = OidcClient::discover(oidc_config)
+                .await
+                .with_context(|| format!("Couldn't set up OpenID Connect provider {issuer_url}"))?;
+            let auth_service = OidcAuthService::new(auth_service, oidc_client);
+            serve(
+                data_access,
+                auth_service,
+                messenger_config,
+                mailer,
+                blob_storage,
+                addr,
+                cancellation_token,
+            )
+            .await
+        }
+        None => {
+            serve(
+                data_access,
+                auth_service,
+                messenger_config,
+                mailer,
+                blob_storage,
+                addr,
+                cancellation_token,
+            )
+            .await
+        }
+    }
+}
+
+async fn serve(
+    data_access: impl DataAccess,
+    auth_service: impl AuthService,
+    messenger_config: MessengerConfig,
+    mailer: impl Mailer,
+    blob_storage: impl BlobStorage,
     addr: &str,
     cancellation_token: CancellationToken,
 ) -> Result<()> {
-    let auth_service = AuthServiceUsingArgon2::new(data_access.clone());
-    let request_handler = request_handler::RequestHandler::new(data_access, auth_service);
-    http_server::server::run_server(addr, request_handler, cancellation_token.clone())
+    let request_handler =
+        request_handler::RequestHandler::new(data_access, auth_service, mailer, blob_storage)
+            .with_admins(messenger_config.admins)
+            .with_message_edit_window(messenger_config.message_edit_window)
+            .with_attachment_limits(messenger_config.attachment_limits)
+            .with_message_retention(messenger_config.message_retention);
+    let purge_job = request_handler.spawn_purge_job(messenger_config.purge_interval);
+    let scheduler = request_handler.spawn_scheduler(messenger_config.scheduler_interval);
+    let res = http_server::server::run_server(addr, request_handler, cancellation_token.clone())
         .await
-        .with_context(|| format!("Unable to start server at {}", addr))?;
-    Ok(())
+        .with_context(|| format!("Unable to start server at {}", addr));
+    purge_job.abort();
+    scheduler.abort();
+    res
+}
+
+async fn load_or_create_secret_key(path: &str) -> Result<SecretKey> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(hex) => SecretKey::from_hex(&hex)
+            .with_context(|| format!("Couldn't parse secret key from {path}")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("Secret key file {path} not found, generating a new key");
+            let secret_key = SecretKey::generate();
+            tokio::fs::write(path, secret_key.to_hex())
+                .await
+                .with_context(|| format!("Couldn't save secret key to {path}"))?;
+            Ok(secret_key)
+        }
+        Err(e) => Err(e).with_context(|| format!("Couldn't read secret key from {path}")),
+    }
+}
+
+async fn load_pepper(key_id: &str, path: &str) -> Result<Pepper> {
+    let pepper = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Couldn't read pepper from {path}"))?;
+    Pepper::new(key_id, pepper.trim().as_bytes())
+        .with_context(|| format!("Incorrect pepper in {path}"))
 }
 
 fn make_cancellation_token() -> CancellationToken {
@@ -12,7 +12,8 @@ const KEEP_ALIVE_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
 #[derive(Debug)]
 pub struct EventSourceEvent {
     pub data: String,
-    pub id: String,
+    /// Events without an id leave the client's last event id as is
+    pub id: Option<String>,
     pub event: Option<String>,
 }
 
@@ -77,7 +78,9 @@ async fn send_event_to_event_source_stream<T: AsyncWriteExt + Unpin>(
     for line in event.data.lines() {
         response_str.push_str(&format!("data: {line}\n"))
     }
-    response_str.push_str(&format!("id: {}\n", event.id));
+    if let Some(id) = event.id {
+        response_str.push_str(&format!("id: {id}\n"));
+    }
     response_str.push('\n');
 
     writer.write_all(response_str.as_bytes()).await?;
@@ -14,7 +14,7 @@ pub struct HttpResponseBuilder<'a> {
     version: HttpVersion,
     status: HttpStatusCode,
     headers: HashMap<CaseInsensitiveString, String>,
-    body: Option<&'a str>,
+    body: Option<&'a [u8]>,
 }
 
 impl<'a> HttpResponseBuilder<'a> {
@@ -49,7 +49,7 @@ impl<'a> HttpResponseBuilder<'a> {
 
         // body
         if let Some(body) = self.body {
-            lines.push(body.as_bytes().to_owned());
+            lines.push(body.to_owned());
         };
 
         let res: Vec<u8> = lines.concat();
@@ -67,6 +67,11 @@ impl<'a> HttpResponseBuilder<'a> {
     }
 
     pub fn body(&mut self, body: &'a str) -> &mut Self {
+        self.body = Some(body.as_bytes());
+        self
+    }
+
+    pub fn body_bytes(&mut self, body: &'a [u8]) -> &mut Self {
         self.body = Some(body);
         self
     }
@@ -107,6 +112,7 @@ impl<'a> HttpResponseBuilder<'a> {
 pub enum HttpStatusCode {
     OK,
     BadRequest,
+    NotFound,
     SeeOther,
     InternalServerError,
 }
@@ -116,6 +122,7 @@ impl std::fmt::Display for HttpStatusCode {
         let str_repr = match self {
             Self::OK => "200 OK",
             Self::BadRequest => "400 Bad Request",
+            Self::NotFound => "404 Not Found",
             Self::SeeOther => "303 See Other",
             Self::InternalServerError => "500 Internal Server Error",
         };
@@ -4,6 +4,7 @@ use crate::response::Response;
 use anyhow::Context;
 use pheidippides_utils::utils::{log_internal_error, CaseInsensitiveString};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::TcpStream;
 
@@ -12,6 +13,7 @@ pub struct Request<T> {
     method: Method,
     url: String,
     headers: HashMap<CaseInsensitiveString, String>,
+    remote_addr: Option<SocketAddr>,
 }
 
 impl Request<TcpStream> {
@@ -45,6 +47,19 @@ impl Request<TcpStream> {
                 }
                 builder.build()
             }
+            Response::Bytes {
+                content,
+                content_type,
+                headers,
+            } => {
+                let mut builder = HttpResponseBuilder::new();
+                builder.body_bytes(&content);
+                builder.header((CaseInsensitiveString::from("Content-Type"), content_type));
+                for header in headers {
+                    builder.header(header);
+                }
+                builder.build()
+            }
             Response::Redirect { location, headers } => {
                 let mut builder = HttpResponseBuilder::new();
                 builder.status(HttpStatusCode::SeeOther);
@@ -76,6 +91,10 @@ impl Request<TcpStream> {
                 .status(HttpStatusCode::BadRequest)
                 .body("Bad request")
                 .build(),
+            Response::NotFound => HttpResponseBuilder::new()
+                .status(HttpStatusCode::NotFound)
+                .body("Not found")
+                .build(),
             Response::InternalServerError => HttpResponseBuilder::new()
                 .status(HttpStatusCode::InternalServerError)
                 .body("Internal Server Error")
@@ -124,9 +143,17 @@ impl<T: AsyncRead + Unpin> Request<T> {
             method,
             url,
             headers,
+            remote_addr: None,
         })
     }
 
+    pub fn with_remote_addr(self, remote_addr: SocketAddr) -> Self {
+        Request {
+            remote_addr: Some(remote_addr),
+            ..self
+        }
+    }
+
     pub fn url(&self) -> &str {
         &self.url
     }
@@ -139,21 +166,34 @@ impl<T: AsyncRead + Unpin> Request<T> {
         &self.headers
     }
 
-    pub async fn content(&mut self) -> anyhow::Result<String> {
+    /// Address of the connected peer, None if the request wasn't read from a TCP connection
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// Declared length of the request body, None if the Content-Length header is missing
+    pub fn content_length(&self) -> anyhow::Result<Option<usize>> {
         let header_name: CaseInsensitiveString = "content-length".into();
-        let content_length: usize = self
-            .headers()
-            .get(&header_name)
-            .context("Content-Length header is missing")?
+        let Some(value) = self.headers().get(&header_name) else {
+            return Ok(None);
+        };
+        let content_length = value
             .parse()
-            .with_context(|| {
-                format!(
-                    "Couldn't parse content-length as a number: {:?}",
-                    self.headers().get(&header_name)
-                )
-            })?;
+            .with_context(|| format!("Couldn't parse content-length as a number: {value:?}"))?;
+        Ok(Some(content_length))
+    }
+
+    pub async fn content_bytes(&mut self) -> anyhow::Result<Vec<u8>> {
+        let content_length = self
+            .content_length()?
+            .context("Content-Length header is missing")?;
         let mut buf = vec![0u8; content_length];
         self.reader.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    pub async fn content(&mut self) -> anyhow::Result<String> {
+        let buf = self.content_bytes().await?;
         let res = String::from_utf8(buf)?;
         Ok(res)
     }
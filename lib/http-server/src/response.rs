@@ -16,6 +16,11 @@ pub enum Response {
         content: String,
         headers: Vec<Header>,
     },
+    Bytes {
+        content: Vec<u8>,
+        content_type: String,
+        headers: Vec<Header>,
+    },
     Redirect {
         location: String,
         headers: Vec<Header>,
@@ -25,6 +30,7 @@ pub enum Response {
         stream: UnboundedReceiver<EventSourceEvent>,
     },
     BadRequest,
+    NotFound,
     InternalServerError,
     Empty,
 }
@@ -39,6 +45,9 @@ impl Response {
     pub fn is_json(self) -> bool {
         matches!(self, Response::Json { .. })
     }
+    pub fn is_bytes(self) -> bool {
+        matches!(self, Response::Bytes { .. })
+    }
     pub fn is_redirect(self) -> bool {
         matches!(self, Response::Redirect { .. })
     }
@@ -48,6 +57,9 @@ impl Response {
     pub fn is_bad_request(self) -> bool {
         matches!(self, Response::BadRequest)
     }
+    pub fn is_not_found(self) -> bool {
+        matches!(self, Response::NotFound)
+    }
     pub fn is_internal_server_error(self) -> bool {
         matches!(self, Response::InternalServerError)
     }
@@ -21,7 +21,7 @@ pub async fn run_server(
     eprintln!("Started a server at {addr}");
 
     loop {
-        let (stream, _) = tokio::select! {
+        let (stream, remote_addr) = tokio::select! {
             _ = cancellation_token.cancelled() => {
                 eprintln!("Shutting down server...");
                 break;
@@ -39,7 +39,7 @@ pub async fn run_server(
 
         tokio::spawn(async move {
             let mut request = match Request::try_from_stream(stream).await {
-                Ok(req) => req,
+                Ok(req) => req.with_remote_addr(remote_addr),
                 Err(_) => {
                     // silently ignore all incorrect TCP connections
                     return;
@@ -1,20 +1,47 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    net::IpAddr,
     sync::{Arc, Mutex, PoisonError},
 };
 
-use pheidippides_messenger::authorization::AuthService;
+use chrono::{DateTime, TimeDelta, Utc};
+
+use pheidippides_messenger::attachments::{Attachment, AttachmentId};
+use pheidippides_messenger::audit_log::{
+    AuditEvent, AuditEventId, AuditEventKind, AuditEventQuery,
+};
+use pheidippides_messenger::authorization::{ApiToken, ApiTokenId, AuthService};
+use pheidippides_messenger::chats::{Chat, ChatMember, ChatRole, Conversation};
 use pheidippides_messenger::data_access::*;
-use pheidippides_messenger::{Message, MessageId, User, UserId};
+use pheidippides_messenger::notifications::{Notification, NotificationCursor, NotificationId};
+use pheidippides_messenger::pins::PinnedMessage;
+use pheidippides_messenger::profiles::Profile;
+use pheidippides_messenger::reactions::group_reactions;
+use pheidippides_messenger::scheduled::{ScheduledMessage, ScheduledMessageId};
+use pheidippides_messenger::search::{search_terms, MessageSearchQuery, MessageSearchResult};
+use pheidippides_messenger::stars::{StarCursor, StarredMessage};
+use pheidippides_messenger::{
+    ChatId, ConversationId, ForwardedFrom, Message, MessageId, MessageRevision, User, UserId,
+};
 
-use pheidippides_auth::{AuthServiceUsingArgon2, AuthStorage, AuthenticationInfo};
+use pheidippides_auth::{
+    AuditLogStorage, AuthServiceUsingArgon2, AuthStorage, AuthenticationInfo, LoginEvent,
+    LoginFailures, LoginThrottleKey, PasswordResetToken, SecretKey, TotpInfo,
+};
 
 struct MessageRecord {
     id: MessageId,
     from: UserId,
-    to: UserId,
+    to: ConversationId,
     message: String,
     timestamp: chrono::DateTime<chrono::Utc>,
+    edited_at: Option<chrono::DateTime<chrono::Utc>>,
+    deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    reply_to: Option<MessageId>,
+    forwarded_from: Option<ForwardedFrom>,
+    /// (emoji, user) in the order of reacting
+    reactions: Vec<(String, UserId)>,
 }
 
 impl MessageRecord {
@@ -25,9 +52,41 @@ impl MessageRecord {
         MessageRecord {
             id,
             from,
-            to,
+            to: ConversationId::Direct(to),
             message,
             timestamp,
+            edited_at: None,
+            deleted_at: None,
+            expires_at: None,
+            reply_to: None,
+            forwarded_from: None,
+            reactions: vec![],
+        }
+    }
+
+    fn to_message(&self, attachments: &[Attachment]) -> Message {
+        let attachments = attachments
+            .iter()
+            .filter(|attachment| attachment.message_id == Some(self.id))
+            .cloned()
+            .collect();
+        Message {
+            id: self.id,
+            from: self.from,
+            to: self.to,
+            message: self.message.clone(),
+            timestamp: self.timestamp,
+            edited_at: self.edited_at,
+            deleted_at: self.deleted_at,
+            expires_at: self.expires_at,
+            attachments,
+            reply_to: self.reply_to,
+            forwarded_from: self.forwarded_from,
+            reactions: group_reactions(
+                self.reactions
+                    .iter()
+                    .map(|(emoji, user_id)| (emoji.as_str(), *user_id)),
+            ),
         }
     }
 }
@@ -35,12 +94,15 @@ impl MessageRecord {
 #[derive(Debug)]
 pub enum Error {
     ThreadPoisonError,
+    /// Injected with [Db::fail_message_creations]
+    Unavailable,
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::ThreadPoisonError => write!(f, "Thread poisoning error"),
+            Self::Unavailable => write!(f, "Database unavailable"),
         }
     }
 }
@@ -58,21 +120,184 @@ struct AuthRecord {
     phc_string: password_hash::PasswordHashString,
 }
 
+struct PasswordResetTokenRecord {
+    token: PasswordResetToken,
+    used: bool,
+}
+
+struct RecoveryCodeRecord {
+    user_id: UserId,
+    code_hash: String,
+    used: bool,
+}
+
+/// Inverted index of message texts: word -> messages containing it
+#[derive(Default)]
+struct SearchIndex(HashMap<String, HashSet<MessageId>>);
+
+impl SearchIndex {
+    fn add(&mut self, message_id: &MessageId, text: &str) {
+        for term in search_terms(text) {
+            self.0.entry(term).or_default().insert(*message_id);
+        }
+    }
+
+    fn remove(&mut self, message_id: &MessageId, text: &str) {
+        for term in search_terms(text) {
+            if let Some(message_ids) = self.0.get_mut(&term) {
+                message_ids.remove(message_id);
+                if message_ids.is_empty() {
+                    self.0.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// Messages containing every one of the terms
+    fn find(&self, terms: &[String]) -> HashSet<MessageId> {
+        let mut res: Option<HashSet<MessageId>> = None;
+        for term in terms {
+            let message_ids = self.0.get(term).cloned().unwrap_or_default();
+            res = Some(match res {
+                Some(res) => res.intersection(&message_ids).copied().collect(),
+                None => message_ids,
+            });
+        }
+        res.unwrap_or_default()
+    }
+}
+
+struct MessageRevisionRecord {
+    message_id: MessageId,
+    revision: MessageRevision,
+}
+
+struct ChatMemberRecord {
+    chat_id: ChatId,
+    member: ChatMember,
+    joined_at: DateTime<Utc>,
+}
+
+/// Direct conversations share a timer between both participants
+#[derive(PartialEq, Eq, Hash)]
+enum MessageTimerKey {
+    /// The participants in the order of their ids
+    Direct(UserId, UserId),
+    Group(ChatId),
+}
+
+impl MessageTimerKey {
+    fn new(user_id: &UserId, conversation: &ConversationId) -> Self {
+        match conversation {
+            ConversationId::Direct(other_user) => {
+                MessageTimerKey::Direct(*user_id.min(other_user), *user_id.max(other_user))
+            }
+            ConversationId::Group(chat_id) => MessageTimerKey::Group(*chat_id),
+        }
+    }
+}
+
+struct PinRecord {
+    message_id: MessageId,
+    pinned_by: UserId,
+    pinned_at: DateTime<Utc>,
+}
+
+struct StarRecord {
+    user_id: UserId,
+    message_id: MessageId,
+    starred_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct ProfileRecord {
+    display_name: Option<String>,
+    bio: String,
+    avatar: Option<AttachmentId>,
+    deleted: bool,
+}
+
+struct ApiTokenRecord {
+    token: ApiToken,
+    token_hash: String,
+}
+
 #[derive(Clone)]
 pub struct Db {
     users: Arc<Mutex<Vec<(UserId, String)>>>,
+    emails: Arc<Mutex<HashMap<UserId, String>>>,
+    profiles: Arc<Mutex<HashMap<UserId, ProfileRecord>>>,
     messages: Arc<Mutex<Vec<MessageRecord>>>,
+    search_index: Arc<Mutex<SearchIndex>>,
+    message_revisions: Arc<Mutex<Vec<MessageRevisionRecord>>>,
+    hidden_messages: Arc<Mutex<HashSet<(UserId, MessageId)>>>,
+    pinned_messages: Arc<Mutex<Vec<PinRecord>>>,
+    starred_messages: Arc<Mutex<Vec<StarRecord>>>,
+    read_markers: Arc<Mutex<HashMap<(UserId, ConversationId), MessageId>>>,
+    muted_conversations: Arc<Mutex<HashSet<(UserId, ConversationId)>>>,
+    message_ttls: Arc<Mutex<HashMap<MessageTimerKey, TimeDelta>>>,
+    scheduled_messages: Arc<Mutex<Vec<ScheduledMessage>>>,
+    /// (message, mentioned user)
+    mentions: Arc<Mutex<Vec<(MessageId, UserId)>>>,
+    notifications: Arc<Mutex<Vec<Notification>>>,
+    /// (user, blocked user) in the order of blocking
+    user_blocks: Arc<Mutex<Vec<(UserId, UserId)>>>,
+    attachments: Arc<Mutex<Vec<Attachment>>>,
+    chats: Arc<Mutex<HashMap<ChatId, Chat>>>,
+    chat_members: Arc<Mutex<Vec<ChatMemberRecord>>>,
     auth: Arc<Mutex<Vec<AuthRecord>>>,
+    password_reset_tokens: Arc<Mutex<Vec<PasswordResetTokenRecord>>>,
+    totp: Arc<Mutex<HashMap<UserId, TotpInfo>>>,
+    recovery_codes: Arc<Mutex<Vec<RecoveryCodeRecord>>>,
+    login_failures: Arc<Mutex<HashMap<LoginThrottleKey, LoginFailures>>>,
+    login_events: Arc<Mutex<Vec<LoginEvent>>>,
+    api_tokens: Arc<Mutex<Vec<ApiTokenRecord>>>,
+    external_identities: Arc<Mutex<HashMap<(String, String), UserId>>>,
+    audit_events: Arc<Mutex<Vec<AuditEvent>>>,
+    /// Message creations left to fail
+    failing_message_creations: Arc<Mutex<usize>>,
 }
 
 impl Db {
     pub fn empty() -> Self {
         Self {
             users: Arc::new(Mutex::new(vec![])),
+            emails: Arc::new(Mutex::new(HashMap::new())),
+            profiles: Arc::new(Mutex::new(HashMap::new())),
             messages: Arc::new(Mutex::new(vec![])),
+            search_index: Arc::new(Mutex::new(SearchIndex::default())),
+            message_revisions: Arc::new(Mutex::new(vec![])),
+            hidden_messages: Arc::new(Mutex::new(HashSet::new())),
+            pinned_messages: Arc::new(Mutex::new(vec![])),
+            starred_messages: Arc::new(Mutex::new(vec![])),
+            read_markers: Arc::new(Mutex::new(HashMap::new())),
+            muted_conversations: Arc::new(Mutex::new(HashSet::new())),
+            message_ttls: Arc::new(Mutex::new(HashMap::new())),
+            scheduled_messages: Arc::new(Mutex::new(vec![])),
+            mentions: Arc::new(Mutex::new(vec![])),
+            notifications: Arc::new(Mutex::new(vec![])),
+            user_blocks: Arc::new(Mutex::new(vec![])),
+            attachments: Arc::new(Mutex::new(vec![])),
+            chats: Arc::new(Mutex::new(HashMap::new())),
+            chat_members: Arc::new(Mutex::new(vec![])),
             auth: Arc::new(Mutex::new(vec![])),
+            password_reset_tokens: Arc::new(Mutex::new(vec![])),
+            totp: Arc::new(Mutex::new(HashMap::new())),
+            recovery_codes: Arc::new(Mutex::new(vec![])),
+            login_failures: Arc::new(Mutex::new(HashMap::new())),
+            login_events: Arc::new(Mutex::new(vec![])),
+            api_tokens: Arc::new(Mutex::new(vec![])),
+            external_identities: Arc::new(Mutex::new(HashMap::new())),
+            audit_events: Arc::new(Mutex::new(vec![])),
+            failing_message_creations: Arc::new(Mutex::new(0)),
         }
     }
+
+    /// Makes the next `count` message creations fail, as if the database were unavailable
+    pub fn fail_message_creations(&self, count: usize) -> Result<(), Error> {
+        *self.failing_message_creations.lock()? = count;
+        Ok(())
+    }
     pub async fn new() -> Self {
         let mut users_vec = vec![
             (uuid::Uuid::new_v4(), "User1".into()),
@@ -131,17 +356,25 @@ impl Db {
             ));
         }
 
+        let mut search_index = SearchIndex::default();
+        for message_record in messages_vec.iter() {
+            search_index.add(&message_record.id, &message_record.message);
+        }
+
         let users = Arc::new(Mutex::new(users_vec));
         let messages = Arc::new(Mutex::new(messages_vec));
+        let search_index = Arc::new(Mutex::new(search_index));
         let auth = Arc::new(Mutex::new(vec![]));
 
         let res = Db {
             users,
             messages,
+            search_index,
             auth,
+            ..Db::empty()
         };
 
-        let auth_service = AuthServiceUsingArgon2::new(res.clone());
+        let auth_service = AuthServiceUsingArgon2::new(res.clone(), SecretKey::generate());
 
         let credentials = [
             (username_id_map["User1"], "User1"),
@@ -165,6 +398,22 @@ impl DataAccess for Db {
         Ok(self.users.lock()?.iter().cloned().collect())
     }
 
+    async fn find_users_by_substring(&self, substring: &str) -> Result<Vec<User>, Error> {
+        let search_query = substring.to_lowercase();
+        let profiles = self.profiles.lock()?;
+        let res = self
+            .users
+            .lock()?
+            .iter()
+            .filter(|(user_id, username)| {
+                username.to_lowercase().contains(&search_query)
+                    && !profiles.get(user_id).is_some_and(|record| record.deleted)
+            })
+            .map(|(user_id, username)| User::new::<Self>(*user_id, username.clone()))
+            .collect();
+        Ok(res)
+    }
+
     async fn create_user(&self, username: &str) -> Result<Option<UserId>, Self::Error> {
         let mut table_locked = self.users.lock()?;
 
@@ -181,56 +430,363 @@ impl DataAccess for Db {
         Ok(Some(user_id))
     }
 
-    async fn find_users_chats(&self, user_id: &UserId) -> Result<Vec<User>, Error> {
-        let users = self.fetch_users().await?;
-        let users = {
-            let mut res = HashMap::new();
-            for (user_id, username) in users {
-                res.insert(user_id, username);
-            }
-            res
+    async fn fetch_user_email(&self, user_id: &UserId) -> Result<Option<String>, Self::Error> {
+        Ok(self.emails.lock()?.get(user_id).cloned())
+    }
+
+    async fn update_user_email(
+        &self,
+        user_id: &UserId,
+        email: Option<&str>,
+    ) -> Result<(), Self::Error> {
+        let mut emails_lock = self.emails.lock()?;
+        match email {
+            Some(email) => emails_lock.insert(*user_id, email.to_owned()),
+            None => emails_lock.remove(user_id),
         };
-        let res = self
-            .messages
+        Ok(())
+    }
+
+    async fn fetch_profile(&self, user_id: &UserId) -> Result<Option<Profile>, Self::Error> {
+        let Some(username) = self
+            .users
             .lock()?
             .iter()
-            .rev()
-            .filter_map(|msg_record| {
-                if &msg_record.from == user_id {
-                    Some(User::new::<Db>(
-                        msg_record.to,
-                        users
-                            .get(&msg_record.to)
-                            .unwrap_or(&"<unknown user id>".to_owned())
-                            .clone(),
-                    ))
-                } else if &msg_record.to == user_id {
-                    Some(User::new::<Db>(
-                        msg_record.from,
-                        users
-                            .get(&msg_record.from)
-                            .unwrap_or(&"<unknown user id>".to_owned())
-                            .clone(),
-                    ))
+            .find(|(id, _)| id == user_id)
+            .map(|(_, username)| username.clone())
+        else {
+            return Ok(None);
+        };
+        let profiles = self.profiles.lock()?;
+        let record = profiles.get(user_id);
+        Ok(Some(Profile {
+            user_id: *user_id,
+            username,
+            display_name: record.and_then(|record| record.display_name.clone()),
+            bio: record.map(|record| record.bio.clone()).unwrap_or_default(),
+            avatar: record.and_then(|record| record.avatar),
+            deleted: record.is_some_and(|record| record.deleted),
+        }))
+    }
+
+    async fn update_profile(
+        &self,
+        user_id: &UserId,
+        display_name: Option<&str>,
+        bio: &str,
+    ) -> Result<(), Self::Error> {
+        let mut profiles = self.profiles.lock()?;
+        let record = profiles.entry(*user_id).or_default();
+        record.display_name = display_name.map(str::to_owned);
+        record.bio = bio.to_owned();
+        Ok(())
+    }
+
+    async fn update_avatar(
+        &self,
+        user_id: &UserId,
+        avatar: Option<&AttachmentId>,
+    ) -> Result<(), Self::Error> {
+        self.profiles.lock()?.entry(*user_id).or_default().avatar = avatar.copied();
+        Ok(())
+    }
+
+    async fn update_username(&self, user_id: &UserId, username: &str) -> Result<bool, Self::Error> {
+        let mut table_locked = self.users.lock()?;
+
+        if table_locked.iter().any(|record| {
+            &record.0 != user_id && record.1.to_lowercase() == username.to_lowercase()
+        }) {
+            return Ok(false);
+        };
+
+        if let Some(record) = table_locked.iter_mut().find(|record| &record.0 == user_id) {
+            record.1 = username.to_owned();
+        }
+        Ok(true)
+    }
+
+    async fn delete_user(
+        &self,
+        user_id: &UserId,
+        replacement: &Profile,
+    ) -> Result<Vec<Attachment>, Self::Error> {
+        let replacement_id = replacement.user_id;
+        let was_user = |id: &UserId| id == user_id;
+
+        let mut users = self.users.lock()?;
+        users.retain(|(id, _)| !was_user(id));
+        users.push((replacement_id, replacement.username.clone()));
+
+        let mut profiles = self.profiles.lock()?;
+        profiles.remove(user_id);
+        profiles.insert(
+            replacement_id,
+            ProfileRecord {
+                display_name: replacement.display_name.clone(),
+                bio: replacement.bio.clone(),
+                avatar: None,
+                deleted: replacement.deleted,
+            },
+        );
+        self.emails.lock()?.remove(user_id);
+
+        for record in self.messages.lock()?.iter_mut() {
+            if was_user(&record.from) {
+                record.from = replacement_id;
+            }
+            if record.to == ConversationId::Direct(*user_id) {
+                record.to = ConversationId::Direct(replacement_id);
+            }
+            if let Some(forwarded_from) = &mut record.forwarded_from {
+                if was_user(&forwarded_from.from) {
+                    forwarded_from.from = replacement_id;
+                }
+            }
+            record.reactions.retain(|(_, id)| !was_user(id));
+        }
+        self.hidden_messages.lock()?.retain(|(id, _)| !was_user(id));
+        for record in self.pinned_messages.lock()?.iter_mut() {
+            if was_user(&record.pinned_by) {
+                record.pinned_by = replacement_id;
+            }
+        }
+        self.starred_messages
+            .lock()?
+            .retain(|record| !was_user(&record.user_id));
+
+        let moved = |(id, conversation): (UserId, ConversationId)| {
+            (!was_user(&id)).then(|| {
+                if conversation == ConversationId::Direct(*user_id) {
+                    (id, ConversationId::Direct(replacement_id))
                 } else {
-                    None
+                    (id, conversation)
                 }
             })
-            .fold(Vec::new(), |mut state, chat_info| {
-                if !state.contains(&chat_info) {
-                    state.push(chat_info)
+        };
+        let mut read_markers = self.read_markers.lock()?;
+        *read_markers = read_markers
+            .drain()
+            .filter_map(|(key, message_id)| moved(key).map(|key| (key, message_id)))
+            .collect();
+        let mut muted_conversations = self.muted_conversations.lock()?;
+        *muted_conversations = muted_conversations.drain().filter_map(moved).collect();
+        self.message_ttls.lock()?.retain(|key, _| match key {
+            MessageTimerKey::Direct(user_id_1, user_id_2) => {
+                !was_user(user_id_1) && !was_user(user_id_2)
+            }
+            MessageTimerKey::Group(_) => true,
+        });
+        self.scheduled_messages.lock()?.retain(|scheduled| {
+            !was_user(&scheduled.from) && scheduled.to != ConversationId::Direct(*user_id)
+        });
+        self.mentions.lock()?.retain(|(_, id)| !was_user(id));
+        let mut notifications = self.notifications.lock()?;
+        notifications.retain(|notification| !was_user(&notification.user_id));
+        for notification in notifications.iter_mut() {
+            if was_user(&notification.actor) {
+                notification.actor = replacement_id;
+            }
+        }
+        self.user_blocks
+            .lock()?
+            .retain(|(id, blocked)| !was_user(id) && !was_user(blocked));
+
+        let mut chat_members = self.chat_members.lock()?;
+        let owned_chats: Vec<ChatId> = chat_members
+            .iter()
+            .filter(|record| {
+                was_user(&record.member.user_id) && record.member.role == ChatRole::Owner
+            })
+            .map(|record| record.chat_id)
+            .collect();
+        chat_members.retain(|record| !was_user(&record.member.user_id));
+        for chat_id in owned_chats {
+            if let Some(successor) = chat_members
+                .iter_mut()
+                .filter(|record| record.chat_id == chat_id)
+                .min_by_key(|record| (record.member.role != ChatRole::Admin, record.joined_at))
+            {
+                successor.member.role = ChatRole::Owner;
+            }
+        }
+
+        let mut attachments = self.attachments.lock()?;
+        let mut unsent = vec![];
+        attachments.retain_mut(|attachment| {
+            if !was_user(&attachment.uploader) {
+                true
+            } else if attachment.message_id.is_some() {
+                attachment.uploader = replacement_id;
+                true
+            } else {
+                unsent.push(attachment.clone());
+                false
+            }
+        });
+
+        self.auth
+            .lock()?
+            .retain(|record| !was_user(&record.user_id));
+        self.password_reset_tokens
+            .lock()?
+            .retain(|record| !was_user(&record.token.user_id));
+        self.totp.lock()?.remove(user_id);
+        self.recovery_codes
+            .lock()?
+            .retain(|record| !was_user(&record.user_id));
+        self.login_events
+            .lock()?
+            .retain(|event| event.user_id.as_ref() != Some(user_id));
+        self.api_tokens
+            .lock()?
+            .retain(|record| !was_user(&record.token.user_id));
+        self.external_identities
+            .lock()?
+            .retain(|_, id| !was_user(id));
+
+        Ok(unsent)
+    }
+
+    async fn block_user(
+        &self,
+        user_id: &UserId,
+        blocked_user_id: &UserId,
+        _created_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        let mut user_blocks = self.user_blocks.lock()?;
+        let block = (*user_id, *blocked_user_id);
+        if !user_blocks.contains(&block) {
+            user_blocks.push(block);
+        }
+        Ok(())
+    }
+
+    async fn unblock_user(
+        &self,
+        user_id: &UserId,
+        blocked_user_id: &UserId,
+    ) -> Result<(), Self::Error> {
+        self.user_blocks
+            .lock()?
+            .retain(|block| block != &(*user_id, *blocked_user_id));
+        Ok(())
+    }
+
+    async fn fetch_blocked_users(&self, user_id: &UserId) -> Result<Vec<UserId>, Self::Error> {
+        let res = self
+            .user_blocks
+            .lock()?
+            .iter()
+            .filter(|(blocker, _)| blocker == user_id)
+            .map(|(_, blocked)| *blocked)
+            .collect();
+        Ok(res)
+    }
+
+    async fn fetch_users_blocking(&self, user_id: &UserId) -> Result<Vec<UserId>, Self::Error> {
+        let res = self
+            .user_blocks
+            .lock()?
+            .iter()
+            .filter(|(_, blocked)| blocked == user_id)
+            .map(|(blocker, _)| *blocker)
+            .collect();
+        Ok(res)
+    }
+
+    async fn find_users_chats(&self, user_id: &UserId) -> Result<Vec<Conversation>, Error> {
+        let users: HashMap<UserId, String> = self.fetch_users().await?.into_iter().collect();
+        let memberships: Vec<(ChatId, DateTime<Utc>)> = self
+            .chat_members
+            .lock()?
+            .iter()
+            .filter(|record| &record.member.user_id == user_id)
+            .map(|record| (record.chat_id, record.joined_at))
+            .collect();
+
+        let mut last_activity: HashMap<ConversationId, DateTime<Utc>> = memberships
+            .iter()
+            .map(|(chat_id, joined_at)| (ConversationId::Group(*chat_id), *joined_at))
+            .collect();
+        let mut unread_counts: HashMap<ConversationId, u64> = HashMap::new();
+        let read_markers = self.read_markers.lock()?;
+        let hidden_messages = self.hidden_messages.lock()?;
+        for msg_record in self.messages.lock()?.iter() {
+            let conversation = match msg_record.to {
+                ConversationId::Direct(to) if &msg_record.from == user_id => {
+                    ConversationId::Direct(to)
                 }
-                state
-            });
+                ConversationId::Direct(to) if &to == user_id => {
+                    ConversationId::Direct(msg_record.from)
+                }
+                ConversationId::Direct(_) => continue,
+                ConversationId::Group(chat_id) => {
+                    if !memberships.iter().any(|(id, _)| id == &chat_id) {
+                        continue;
+                    }
+                    ConversationId::Group(chat_id)
+                }
+            };
+            let timestamp = last_activity
+                .entry(conversation)
+                .or_insert(msg_record.timestamp);
+            *timestamp = msg_record.timestamp.max(*timestamp);
+
+            let unread_count = unread_counts.entry(conversation).or_default();
+            if read_markers.get(&(*user_id, conversation)) == Some(&msg_record.id) {
+                *unread_count = 0;
+            } else if &msg_record.from != user_id
+                && msg_record.deleted_at.is_none()
+                && !hidden_messages.contains(&(*user_id, msg_record.id))
+            {
+                *unread_count += 1;
+            }
+        }
+
+        let mut last_activity: Vec<_> = last_activity.into_iter().collect();
+        last_activity.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+        let chats = self.chats.lock()?;
+        let muted_conversations = self.muted_conversations.lock()?;
+        let profiles = self.profiles.lock()?;
+        let res = last_activity
+            .into_iter()
+            .map(|(id, _)| {
+                let profile = match &id {
+                    ConversationId::Direct(user_id) => profiles.get(user_id),
+                    ConversationId::Group(_) => None,
+                };
+                let title = match &id {
+                    ConversationId::Direct(user_id) => profile
+                        .and_then(|profile| profile.display_name.as_ref())
+                        .or_else(|| users.get(user_id)),
+                    ConversationId::Group(chat_id) => chats.get(chat_id).map(|chat| &chat.title),
+                };
+                let title = title
+                    .cloned()
+                    .unwrap_or_else(|| "<unknown chat id>".to_owned());
+                let unread_count = unread_counts.get(&id).copied().unwrap_or_default();
+                Conversation {
+                    id,
+                    title,
+                    unread_count,
+                    muted: muted_conversations.contains(&(*user_id, id)),
+                    avatar: profile.and_then(|profile| profile.avatar),
+                }
+            })
+            .collect();
         Ok(res)
     }
 
     async fn fetch_last_messages_in_chat(
         &self,
-        user_id_1: &UserId,
-        user_id_2: &UserId,
+        user_id: &UserId,
+        conversation: &ConversationId,
         starting_point: Option<&MessageId>,
     ) -> Result<Vec<Message>, Error> {
+        let hidden_messages = self.hidden_messages.lock()?;
+        let attachments = self.attachments.lock()?;
         let res = self
             .messages
             .lock()?
@@ -241,21 +797,17 @@ impl DataAccess for Db {
                 None => false,
             })
             .skip(if starting_point.is_some() { 1 } else { 0 })
-            .filter_map(|msg_record| {
-                if (&msg_record.from == user_id_1 && &msg_record.to == user_id_2)
-                    || (&msg_record.from == user_id_2 && &msg_record.to == user_id_1)
-                {
-                    Some(Message {
-                        id: msg_record.id,
-                        from: msg_record.from,
-                        to: msg_record.to,
-                        message: msg_record.message.to_owned(),
-                        timestamp: msg_record.timestamp,
-                    })
-                } else {
-                    None
+            .filter(|msg_record| match conversation {
+                ConversationId::Direct(other_user) => {
+                    (&msg_record.from == user_id
+                        && msg_record.to == ConversationId::Direct(*other_user))
+                        || (&msg_record.from == other_user
+                            && msg_record.to == ConversationId::Direct(*user_id))
                 }
+                ConversationId::Group(_) => &msg_record.to == conversation,
             })
+            .filter(|msg_record| !hidden_messages.contains(&(*user_id, msg_record.id)))
+            .map(|msg_record| msg_record.to_message(&attachments))
             .take(MESSAGE_LOAD_BUF_SIZE as usize)
             .collect();
 
@@ -267,35 +819,40 @@ impl DataAccess for Db {
         user_id: &UserId,
         starting_point: &MessageId,
     ) -> Result<Vec<Message>, Self::Error> {
+        let chat_ids: Vec<ChatId> = self
+            .chat_members
+            .lock()?
+            .iter()
+            .filter(|record| &record.member.user_id == user_id)
+            .map(|record| record.chat_id)
+            .collect();
+
+        let hidden_messages = self.hidden_messages.lock()?;
+        let attachments = self.attachments.lock()?;
         let res = self
             .messages
             .lock()?
             .iter()
             .skip_while(|message_record| message_record.id != *starting_point)
             .skip(1)
-            .filter_map(|message_record| {
-                let id = message_record.id;
-                let from = message_record.from;
-                let to = message_record.to;
-                let message = message_record.message.clone();
-                let timestamp = message_record.timestamp;
-                if from == *user_id || to == *user_id {
-                    Some(Message {
-                        id,
-                        from,
-                        to,
-                        message,
-                        timestamp,
-                    })
-                } else {
-                    None
-                }
+            .filter(|message_record| match message_record.to {
+                ConversationId::Direct(to) => message_record.from == *user_id || to == *user_id,
+                ConversationId::Group(chat_id) => chat_ids.contains(&chat_id),
             })
+            .filter(|message_record| !hidden_messages.contains(&(*user_id, message_record.id)))
+            .map(|message_record| message_record.to_message(&attachments))
             .collect();
         Ok(res)
     }
 
     async fn create_message(&self, message: &Message) -> Result<(), Error> {
+        {
+            let mut failing = self.failing_message_creations.lock()?;
+            if *failing > 0 {
+                *failing -= 1;
+                return Err(Error::Unavailable);
+            }
+        }
         let mut messages_lock = self.messages.lock()?;
         let new_message = MessageRecord {
             id: message.id,
@@ -303,51 +860,1076 @@ impl DataAccess for Db {
             to: message.to,
             message: message.message.to_owned(),
             timestamp: message.timestamp,
+            edited_at: message.edited_at,
+            deleted_at: message.deleted_at,
+            expires_at: message.expires_at,
+            reply_to: message.reply_to,
+            forwarded_from: message.forwarded_from,
+            reactions: message
+                .reactions
+                .iter()
+                .flat_map(|reaction| {
+                    reaction
+                        .users
+                        .iter()
+                        .map(|user_id| (reaction.emoji.clone(), *user_id))
+                })
+                .collect(),
         };
+        self.search_index
+            .lock()?
+            .add(&new_message.id, &new_message.message);
         messages_lock.push(new_message);
+        for attachment in self.attachments.lock()?.iter_mut() {
+            if message.attachments.iter().any(|a| a.id == attachment.id) {
+                attachment.message_id = Some(message.id);
+            }
+        }
         Ok(())
     }
-}
 
-impl AuthStorage for Db {
-    type Error = Error;
+    async fn fetch_message(&self, message_id: &MessageId) -> Result<Option<Message>, Self::Error> {
+        let attachments = self.attachments.lock()?;
+        let res = self
+            .messages
+            .lock()?
+            .iter()
+            .find(|message_record| &message_record.id == message_id)
+            .map(|message_record| message_record.to_message(&attachments));
+        Ok(res)
+    }
 
-    async fn fetch_authentication(
+    async fn edit_message(
         &self,
-        user_id: &UserId,
-    ) -> Result<Option<AuthenticationInfo>, Error> {
+        message_id: &MessageId,
+        message: &str,
+        edited_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        let mut messages_lock = self.messages.lock()?;
+        let message_record = match messages_lock
+            .iter_mut()
+            .find(|message_record| &message_record.id == message_id)
+        {
+            Some(message_record) => message_record,
+            None => return Ok(()),
+        };
+
+        let mut search_index = self.search_index.lock()?;
+        search_index.remove(message_id, &message_record.message);
+        search_index.add(message_id, message);
+        self.message_revisions.lock()?.push(MessageRevisionRecord {
+            message_id: *message_id,
+            revision: MessageRevision {
+                message: std::mem::replace(&mut message_record.message, message.to_owned()),
+                created_at: message_record.edited_at.unwrap_or(message_record.timestamp),
+            },
+        });
+        message_record.edited_at = Some(edited_at);
+        Ok(())
+    }
+
+    async fn fetch_message_revisions(
+        &self,
+        message_id: &MessageId,
+    ) -> Result<Vec<MessageRevision>, Self::Error> {
         let res = self
-            .auth
+            .message_revisions
             .lock()?
             .iter()
-            .filter_map(|record| {
-                if record.user_id == *user_id {
-                    Some(AuthenticationInfo::from(record.phc_string.clone()))
-                } else {
-                    None
-                }
-            })
-            .next();
+            .filter(|record| &record.message_id == message_id)
+            .map(|record| record.revision.clone())
+            .collect();
         Ok(res)
     }
 
-    async fn update_authentication(
+    async fn delete_message(
+        &self,
+        message_id: &MessageId,
+        deleted_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        if let Some(message_record) = self
+            .messages
+            .lock()?
+            .iter_mut()
+            .find(|message_record| &message_record.id == message_id)
+        {
+            self.search_index
+                .lock()?
+                .remove(message_id, &message_record.message);
+            message_record.message = String::new();
+            message_record.deleted_at = Some(deleted_at);
+            message_record.reactions.clear();
+        }
+        self.message_revisions
+            .lock()?
+            .retain(|record| &record.message_id != message_id);
+        self.attachments
+            .lock()?
+            .retain(|attachment| attachment.message_id.as_ref() != Some(message_id));
+        self.pinned_messages
+            .lock()?
+            .retain(|record| &record.message_id != message_id);
+        self.starred_messages
+            .lock()?
+            .retain(|record| &record.message_id != message_id);
+        Ok(())
+    }
+
+    async fn add_reaction(
         &self,
+        message_id: &MessageId,
         user_id: &UserId,
-        auth_info: AuthenticationInfo,
-    ) -> Result<Option<AuthenticationInfo>, Self::Error> {
-        let mut table_locked = self.auth.lock()?;
-        for record in table_locked.iter_mut() {
-            if record.user_id == *user_id {
-                let old_auth = record.phc_string.clone();
-                record.phc_string = auth_info.phc_string().clone();
-                return Ok(Some(old_auth.into()));
-            };
+        emoji: &str,
+        _created_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        if let Some(message_record) = self
+            .messages
+            .lock()?
+            .iter_mut()
+            .find(|message_record| &message_record.id == message_id)
+        {
+            let reaction = (emoji.to_owned(), *user_id);
+            if !message_record.reactions.contains(&reaction) {
+                message_record.reactions.push(reaction);
+            }
         }
-        table_locked.push(AuthRecord {
+        Ok(())
+    }
+
+    async fn remove_reaction(
+        &self,
+        message_id: &MessageId,
+        user_id: &UserId,
+        emoji: &str,
+    ) -> Result<(), Self::Error> {
+        if let Some(message_record) = self
+            .messages
+            .lock()?
+            .iter_mut()
+            .find(|message_record| &message_record.id == message_id)
+        {
+            message_record
+                .reactions
+                .retain(|(reaction_emoji, reaction_user)| {
+                    !(reaction_emoji == emoji && reaction_user == user_id)
+                });
+        }
+        Ok(())
+    }
+
+    async fn pin_message(
+        &self,
+        message_id: &MessageId,
+        pinned_by: &UserId,
+        pinned_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        let mut pinned_messages = self.pinned_messages.lock()?;
+        if !pinned_messages
+            .iter()
+            .any(|record| &record.message_id == message_id)
+        {
+            pinned_messages.push(PinRecord {
+                message_id: *message_id,
+                pinned_by: *pinned_by,
+                pinned_at,
+            });
+        }
+        Ok(())
+    }
+
+    async fn unpin_message(&self, message_id: &MessageId) -> Result<(), Self::Error> {
+        self.pinned_messages
+            .lock()?
+            .retain(|record| &record.message_id != message_id);
+        Ok(())
+    }
+
+    async fn fetch_pinned_messages(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<Vec<PinnedMessage>, Self::Error> {
+        let hidden_messages = self.hidden_messages.lock()?;
+        let attachments = self.attachments.lock()?;
+        let messages = self.messages.lock()?;
+        let mut res: Vec<PinnedMessage> = self
+            .pinned_messages
+            .lock()?
+            .iter()
+            .filter(|record| !hidden_messages.contains(&(*user_id, record.message_id)))
+            .filter_map(|record| {
+                let message_record = messages.iter().find(|m| m.id == record.message_id)?;
+                let participant = match conversation {
+                    ConversationId::Direct(_) => {
+                        &message_record.from == user_id
+                            || message_record.to == ConversationId::Direct(*user_id)
+                    }
+                    ConversationId::Group(_) => true,
+                };
+                let message = message_record.to_message(&attachments);
+                (participant && &message.conversation_for(user_id) == conversation).then_some(
+                    PinnedMessage {
+                        message,
+                        pinned_by: record.pinned_by,
+                        pinned_at: record.pinned_at,
+                    },
+                )
+            })
+            .collect();
+        res.sort_by_key(|pin| std::cmp::Reverse((pin.pinned_at, pin.message.id)));
+        Ok(res)
+    }
+
+    async fn star_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+        starred_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        let mut starred_messages = self.starred_messages.lock()?;
+        if !starred_messages
+            .iter()
+            .any(|record| &record.user_id == user_id && &record.message_id == message_id)
+        {
+            starred_messages.push(StarRecord {
+                user_id: *user_id,
+                message_id: *message_id,
+                starred_at,
+            });
+        }
+        Ok(())
+    }
+
+    async fn unstar_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+    ) -> Result<(), Self::Error> {
+        self.starred_messages
+            .lock()?
+            .retain(|record| !(&record.user_id == user_id && &record.message_id == message_id));
+        Ok(())
+    }
+
+    async fn fetch_starred_messages(
+        &self,
+        user_id: &UserId,
+        cursor: Option<&StarCursor>,
+        limit: u32,
+    ) -> Result<Vec<StarredMessage>, Self::Error> {
+        let chat_ids: Vec<ChatId> = self
+            .chat_members
+            .lock()?
+            .iter()
+            .filter(|record| &record.member.user_id == user_id)
+            .map(|record| record.chat_id)
+            .collect();
+
+        let hidden_messages = self.hidden_messages.lock()?;
+        let attachments = self.attachments.lock()?;
+        let messages = self.messages.lock()?;
+        let mut res: Vec<StarredMessage> = self
+            .starred_messages
+            .lock()?
+            .iter()
+            .filter(|record| &record.user_id == user_id)
+            .filter(|record| !hidden_messages.contains(&(*user_id, record.message_id)))
+            .filter(|record| {
+                cursor.is_none_or(|cursor| cursor.precedes(&record.starred_at, &record.message_id))
+            })
+            .filter_map(|record| {
+                let message_record = messages.iter().find(|m| m.id == record.message_id)?;
+                let participant = match message_record.to {
+                    ConversationId::Direct(to) => message_record.from == *user_id || to == *user_id,
+                    ConversationId::Group(chat_id) => chat_ids.contains(&chat_id),
+                };
+                participant.then(|| StarredMessage {
+                    message: message_record.to_message(&attachments),
+                    starred_at: record.starred_at,
+                })
+            })
+            .collect();
+        res.sort_by_key(|star| std::cmp::Reverse((star.starred_at, star.message.id)));
+        res.truncate(limit as usize);
+        Ok(res)
+    }
+
+    async fn hide_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+    ) -> Result<(), Self::Error> {
+        self.hidden_messages.lock()?.insert((*user_id, *message_id));
+        Ok(())
+    }
+
+    async fn search_messages(
+        &self,
+        user_id: &UserId,
+        query: &MessageSearchQuery,
+    ) -> Result<Vec<MessageSearchResult>, Self::Error> {
+        let terms = search_terms(&query.text);
+        let found = self.search_index.lock()?.find(&terms);
+        let chat_ids: Vec<ChatId> = self
+            .chat_members
+            .lock()?
+            .iter()
+            .filter(|record| &record.member.user_id == user_id)
+            .map(|record| record.chat_id)
+            .collect();
+
+        let hidden_messages = self.hidden_messages.lock()?;
+        let attachments = self.attachments.lock()?;
+        let messages = self.messages.lock()?;
+        let mut res: Vec<MessageSearchResult> = messages
+            .iter()
+            .filter(|message_record| found.contains(&message_record.id))
+            .filter(|message_record| match message_record.to {
+                ConversationId::Direct(to) => message_record.from == *user_id || to == *user_id,
+                ConversationId::Group(chat_id) => chat_ids.contains(&chat_id),
+            })
+            .filter(|message_record| message_record.deleted_at.is_none())
+            .filter(|message_record| !hidden_messages.contains(&(*user_id, message_record.id)))
+            .map(|message_record| message_record.to_message(&attachments))
+            .filter(|message| match &query.conversation {
+                Some(conversation) => &message.conversation_for(user_id) == conversation,
+                None => true,
+            })
+            .filter(|message| query.sender.is_none_or(|sender| message.from == sender))
+            .filter(|message| query.since.is_none_or(|since| message.timestamp >= since))
+            .filter(|message| query.until.is_none_or(|until| message.timestamp < until))
+            .map(|message| {
+                // The number of occurrences of the query's words
+                let rank = search_terms(&message.message)
+                    .iter()
+                    .filter(|term| terms.contains(term))
+                    .count() as f32;
+                MessageSearchResult { message, rank }
+            })
+            .filter(|result| match &query.cursor {
+                Some(cursor) => {
+                    cursor.precedes(result.rank, &result.message.timestamp, &result.message.id)
+                }
+                None => true,
+            })
+            .collect();
+
+        res.sort_by(|a, b| {
+            (b.rank, b.message.timestamp, b.message.id)
+                .partial_cmp(&(a.rank, a.message.timestamp, a.message.id))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        res.truncate(query.limit as usize);
+        Ok(res)
+    }
+
+    async fn fetch_read_marker(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<Option<MessageId>, Self::Error> {
+        let res = self
+            .read_markers
+            .lock()?
+            .get(&(*user_id, *conversation))
+            .copied();
+        Ok(res)
+    }
+
+    async fn update_read_marker(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        message_id: &MessageId,
+    ) -> Result<(), Self::Error> {
+        self.read_markers
+            .lock()?
+            .insert((*user_id, *conversation), *message_id);
+        Ok(())
+    }
+
+    async fn update_conversation_muted(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        muted: bool,
+    ) -> Result<(), Self::Error> {
+        let mut muted_conversations = self.muted_conversations.lock()?;
+        if muted {
+            muted_conversations.insert((*user_id, *conversation));
+        } else {
+            muted_conversations.remove(&(*user_id, *conversation));
+        }
+        Ok(())
+    }
+
+    async fn is_conversation_muted(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<bool, Self::Error> {
+        let muted_conversations = self.muted_conversations.lock()?;
+        Ok(muted_conversations.contains(&(*user_id, *conversation)))
+    }
+
+    async fn fetch_message_ttl(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<Option<TimeDelta>, Self::Error> {
+        let res = self
+            .message_ttls
+            .lock()?
+            .get(&MessageTimerKey::new(user_id, conversation))
+            .copied();
+        Ok(res)
+    }
+
+    async fn update_message_ttl(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        ttl: Option<TimeDelta>,
+    ) -> Result<(), Self::Error> {
+        let key = MessageTimerKey::new(user_id, conversation);
+        let mut message_ttls = self.message_ttls.lock()?;
+        match ttl {
+            Some(ttl) => message_ttls.insert(key, ttl),
+            None => message_ttls.remove(&key),
+        };
+        Ok(())
+    }
+
+    async fn create_scheduled_message(
+        &self,
+        scheduled: &ScheduledMessage,
+    ) -> Result<(), Self::Error> {
+        self.scheduled_messages.lock()?.push(scheduled.clone());
+        Ok(())
+    }
+
+    async fn fetch_scheduled_messages(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<ScheduledMessage>, Self::Error> {
+        let mut res: Vec<ScheduledMessage> = self
+            .scheduled_messages
+            .lock()?
+            .iter()
+            .filter(|scheduled| &scheduled.from == user_id)
+            .cloned()
+            .collect();
+        res.sort_by_key(|scheduled| scheduled.send_at);
+        Ok(res)
+    }
+
+    async fn fetch_due_scheduled_messages(
+        &self,
+        now: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<ScheduledMessage>, Self::Error> {
+        let mut res: Vec<ScheduledMessage> = self
+            .scheduled_messages
+            .lock()?
+            .iter()
+            .filter(|scheduled| scheduled.retry_at.unwrap_or(scheduled.send_at) <= now)
+            .cloned()
+            .collect();
+        res.sort_by_key(|scheduled| scheduled.retry_at.unwrap_or(scheduled.send_at));
+        res.truncate(limit as usize);
+        Ok(res)
+    }
+
+    async fn postpone_scheduled_message(
+        &self,
+        scheduled_message_id: &ScheduledMessageId,
+        retry_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        for scheduled in self.scheduled_messages.lock()?.iter_mut() {
+            if &scheduled.id == scheduled_message_id {
+                scheduled.failed_attempts += 1;
+                scheduled.retry_at = Some(retry_at);
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_scheduled_message(
+        &self,
+        user_id: &UserId,
+        scheduled_message_id: &ScheduledMessageId,
+    ) -> Result<bool, Self::Error> {
+        let mut scheduled_messages = self.scheduled_messages.lock()?;
+        let len = scheduled_messages.len();
+        scheduled_messages.retain(|scheduled| {
+            !(&scheduled.id == scheduled_message_id && &scheduled.from == user_id)
+        });
+        Ok(scheduled_messages.len() < len)
+    }
+
+    async fn fetch_mentions(&self, message_id: &MessageId) -> Result<Vec<UserId>, Self::Error> {
+        let res = self
+            .mentions
+            .lock()?
+            .iter()
+            .filter(|(id, _)| id == message_id)
+            .map(|(_, user_id)| *user_id)
+            .collect();
+        Ok(res)
+    }
+
+    async fn create_mentions(
+        &self,
+        message_id: &MessageId,
+        user_ids: &[UserId],
+    ) -> Result<(), Self::Error> {
+        let mut mentions = self.mentions.lock()?;
+        for user_id in user_ids {
+            let mention = (*message_id, *user_id);
+            if !mentions.contains(&mention) {
+                mentions.push(mention);
+            }
+        }
+        Ok(())
+    }
+
+    async fn create_notification(&self, notification: &Notification) -> Result<(), Self::Error> {
+        self.notifications.lock()?.push(notification.clone());
+        Ok(())
+    }
+
+    async fn fetch_notifications(
+        &self,
+        user_id: &UserId,
+        cursor: Option<&NotificationCursor>,
+        limit: u32,
+    ) -> Result<Vec<Notification>, Self::Error> {
+        let mut res: Vec<Notification> = self
+            .notifications
+            .lock()?
+            .iter()
+            .filter(|notification| &notification.user_id == user_id)
+            .filter(|notification| {
+                cursor.is_none_or(|cursor| {
+                    cursor.precedes(&notification.created_at, &notification.id)
+                })
+            })
+            .cloned()
+            .collect();
+        res.sort_by_key(|notification| {
+            std::cmp::Reverse((notification.created_at, notification.id))
+        });
+        res.truncate(limit as usize);
+        Ok(res)
+    }
+
+    async fn count_unread_notifications(&self, user_id: &UserId) -> Result<u32, Self::Error> {
+        let count = self
+            .notifications
+            .lock()?
+            .iter()
+            .filter(|notification| {
+                &notification.user_id == user_id && notification.read_at.is_none()
+            })
+            .count();
+        Ok(count as u32)
+    }
+
+    async fn mark_notifications_read(
+        &self,
+        user_id: &UserId,
+        notification_id: Option<&NotificationId>,
+        read_at: DateTime<Utc>,
+    ) -> Result<u64, Self::Error> {
+        let mut marked = 0;
+        for notification in self.notifications.lock()?.iter_mut() {
+            if &notification.user_id == user_id
+                && notification.read_at.is_none()
+                && notification_id.is_none_or(|id| id == &notification.id)
+            {
+                notification.read_at = Some(read_at);
+                marked += 1;
+            }
+        }
+        Ok(marked)
+    }
+
+    async fn purge_expired_messages(
+        &self,
+        now: DateTime<Utc>,
+        sent_before: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> Result<Vec<Message>, Self::Error> {
+        let is_expired = |record: &MessageRecord| {
+            record
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now)
+                || sent_before.is_some_and(|sent_before| record.timestamp < sent_before)
+        };
+
+        let mut messages = self.messages.lock()?;
+        let mut attachments = self.attachments.lock()?;
+        let mut search_index = self.search_index.lock()?;
+        let mut purged = vec![];
+        messages.retain(|record| {
+            if purged.len() >= limit as usize || !is_expired(record) {
+                return true;
+            }
+            search_index.remove(&record.id, &record.message);
+            purged.push(record.to_message(&attachments));
+            false
+        });
+
+        let was_purged =
+            |message_id: &MessageId| purged.iter().any(|message| &message.id == message_id);
+        for record in messages.iter_mut() {
+            if record.reply_to.as_ref().is_some_and(was_purged) {
+                record.reply_to = None;
+            }
+        }
+        attachments.retain(|attachment| !attachment.message_id.as_ref().is_some_and(was_purged));
+        self.message_revisions
+            .lock()?
+            .retain(|record| !was_purged(&record.message_id));
+        self.hidden_messages
+            .lock()?
+            .retain(|(_, message_id)| !was_purged(message_id));
+        self.pinned_messages
+            .lock()?
+            .retain(|record| !was_purged(&record.message_id));
+        self.starred_messages
+            .lock()?
+            .retain(|record| !was_purged(&record.message_id));
+        self.read_markers
+            .lock()?
+            .retain(|_, message_id| !was_purged(message_id));
+        self.mentions
+            .lock()?
+            .retain(|(message_id, _)| !was_purged(message_id));
+        self.notifications
+            .lock()?
+            .retain(|notification| !notification.kind.message_id().is_some_and(was_purged));
+        Ok(purged)
+    }
+
+    async fn create_chat(&self, chat: &Chat, owner: &UserId) -> Result<(), Self::Error> {
+        self.chats.lock()?.insert(chat.id, chat.clone());
+        self.chat_members.lock()?.push(ChatMemberRecord {
+            chat_id: chat.id,
+            member: ChatMember {
+                user_id: *owner,
+                role: ChatRole::Owner,
+            },
+            joined_at: chat.created_at,
+        });
+        Ok(())
+    }
+
+    async fn fetch_chat(&self, chat_id: &ChatId) -> Result<Option<Chat>, Self::Error> {
+        Ok(self.chats.lock()?.get(chat_id).cloned())
+    }
+
+    async fn rename_chat(&self, chat_id: &ChatId, title: &str) -> Result<(), Self::Error> {
+        if let Some(chat) = self.chats.lock()?.get_mut(chat_id) {
+            chat.title = title.to_owned();
+        }
+        Ok(())
+    }
+
+    async fn fetch_chat_members(&self, chat_id: &ChatId) -> Result<Vec<ChatMember>, Self::Error> {
+        let res = self
+            .chat_members
+            .lock()?
+            .iter()
+            .filter(|record| &record.chat_id == chat_id)
+            .map(|record| record.member.clone())
+            .collect();
+        Ok(res)
+    }
+
+    async fn update_chat_member(
+        &self,
+        chat_id: &ChatId,
+        user_id: &UserId,
+        role: ChatRole,
+    ) -> Result<(), Self::Error> {
+        let mut chat_members_lock = self.chat_members.lock()?;
+        match chat_members_lock
+            .iter_mut()
+            .find(|record| &record.chat_id == chat_id && &record.member.user_id == user_id)
+        {
+            Some(record) => record.member.role = role,
+            None => chat_members_lock.push(ChatMemberRecord {
+                chat_id: *chat_id,
+                member: ChatMember {
+                    user_id: *user_id,
+                    role,
+                },
+                joined_at: Utc::now(),
+            }),
+        }
+        Ok(())
+    }
+
+    async fn delete_chat_member(
+        &self,
+        chat_id: &ChatId,
+        user_id: &UserId,
+    ) -> Result<(), Self::Error> {
+        self.chat_members
+            .lock()?
+            .retain(|record| !(&record.chat_id == chat_id && &record.member.user_id == user_id));
+        Ok(())
+    }
+
+    async fn create_attachment(&self, attachment: &Attachment) -> Result<(), Self::Error> {
+        self.attachments.lock()?.push(attachment.clone());
+        Ok(())
+    }
+
+    async fn fetch_attachment(
+        &self,
+        attachment_id: &AttachmentId,
+    ) -> Result<Option<Attachment>, Self::Error> {
+        let res = self
+            .attachments
+            .lock()?
+            .iter()
+            .find(|attachment| &attachment.id == attachment_id)
+            .cloned();
+        Ok(res)
+    }
+
+    async fn fetch_users_attachments(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<Attachment>, Self::Error> {
+        let res = self
+            .attachments
+            .lock()?
+            .iter()
+            .filter(|attachment| &attachment.uploader == user_id)
+            .cloned()
+            .collect();
+        Ok(res)
+    }
+}
+
+impl AuthStorage for Db {
+    type Error = Error;
+
+    async fn fetch_authentication(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<AuthenticationInfo>, Error> {
+        let res = self
+            .auth
+            .lock()?
+            .iter()
+            .filter_map(|record| {
+                if record.user_id == *user_id {
+                    Some(AuthenticationInfo::from(record.phc_string.clone()))
+                } else {
+                    None
+                }
+            })
+            .next();
+        Ok(res)
+    }
+
+    async fn update_authentication(
+        &self,
+        user_id: &UserId,
+        auth_info: AuthenticationInfo,
+    ) -> Result<Option<AuthenticationInfo>, Self::Error> {
+        let mut table_locked = self.auth.lock()?;
+        for record in table_locked.iter_mut() {
+            if record.user_id == *user_id {
+                let old_auth = record.phc_string.clone();
+                record.phc_string = auth_info.phc_string().clone();
+                return Ok(Some(old_auth.into()));
+            };
+        }
+        table_locked.push(AuthRecord {
             user_id: *user_id,
             phc_string: auth_info.phc_string().clone(),
         });
         Ok(None)
     }
+
+    async fn create_password_reset_token(
+        &self,
+        token: PasswordResetToken,
+    ) -> Result<(), Self::Error> {
+        self.password_reset_tokens
+            .lock()?
+            .push(PasswordResetTokenRecord { token, used: false });
+        Ok(())
+    }
+
+    async fn spend_password_reset_token(
+        &self,
+        token_hash: &str,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<UserId>, Self::Error> {
+        let mut table_locked = self.password_reset_tokens.lock()?;
+
+        let (user_id, valid) = match table_locked
+            .iter()
+            .find(|record| record.token.token_hash == token_hash)
+        {
+            Some(record) => (
+                record.token.user_id,
+                !record.used && record.token.expires_at > now,
+            ),
+            None => return Ok(None),
+        };
+
+        for record in table_locked.iter_mut() {
+            if record.token.user_id == user_id {
+                record.used = true;
+            }
+        }
+
+        Ok(if valid { Some(user_id) } else { None })
+    }
+
+    async fn fetch_totp(&self, user_id: &UserId) -> Result<Option<TotpInfo>, Self::Error> {
+        Ok(self.totp.lock()?.get(user_id).cloned())
+    }
+
+    async fn update_totp(
+        &self,
+        user_id: &UserId,
+        totp_info: Option<TotpInfo>,
+    ) -> Result<(), Self::Error> {
+        let mut table_locked = self.totp.lock()?;
+        match totp_info {
+            Some(totp_info) => table_locked.insert(*user_id, totp_info),
+            None => table_locked.remove(user_id),
+        };
+        Ok(())
+    }
+
+    async fn update_totp_last_used_step(
+        &self,
+        user_id: &UserId,
+        step: i64,
+    ) -> Result<bool, Self::Error> {
+        let mut table_locked = self.totp.lock()?;
+        match table_locked.get_mut(user_id) {
+            Some(totp_info) if totp_info.last_used_step.is_none_or(|last| last < step) => {
+                totp_info.last_used_step = Some(step);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        user_id: &UserId,
+        code_hashes: Option<Vec<String>>,
+    ) -> Result<(), Self::Error> {
+        let mut table_locked = self.recovery_codes.lock()?;
+        table_locked.retain(|record| record.user_id != *user_id);
+        for code_hash in code_hashes.unwrap_or_default() {
+            table_locked.push(RecoveryCodeRecord {
+                user_id: *user_id,
+                code_hash,
+                used: false,
+            });
+        }
+        Ok(())
+    }
+
+    async fn spend_recovery_code(
+        &self,
+        user_id: &UserId,
+        code_hash: &str,
+    ) -> Result<bool, Self::Error> {
+        let mut table_locked = self.recovery_codes.lock()?;
+        match table_locked.iter_mut().find(|record| {
+            record.user_id == *user_id && record.code_hash == code_hash && !record.used
+        }) {
+            Some(record) => {
+                record.used = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn fetch_login_failures(
+        &self,
+        key: &LoginThrottleKey,
+    ) -> Result<Option<LoginFailures>, Self::Error> {
+        Ok(self.login_failures.lock()?.get(key).cloned())
+    }
+
+    async fn replace_login_failures(
+        &self,
+        key: &LoginThrottleKey,
+        expected: Option<&LoginFailures>,
+        failures: Option<&LoginFailures>,
+    ) -> Result<bool, Self::Error> {
+        let mut table_locked = self.login_failures.lock()?;
+        if table_locked.get(key) != expected {
+            return Ok(false);
+        }
+        match failures {
+            Some(failures) => table_locked.insert(*key, failures.clone()),
+            None => table_locked.remove(key),
+        };
+        Ok(true)
+    }
+
+    async fn lock_login(
+        &self,
+        key: &LoginThrottleKey,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), Self::Error> {
+        if let Some(failures) = self.login_failures.lock()?.get_mut(key) {
+            failures.failed_attempts = 0;
+            failures.locked_until = Some(until);
+        }
+        Ok(())
+    }
+
+    async fn reset_login_failures(&self, key: &LoginThrottleKey) -> Result<(), Self::Error> {
+        self.login_failures.lock()?.remove(key);
+        Ok(())
+    }
+
+    async fn record_login_event(&self, event: LoginEvent) -> Result<(), Self::Error> {
+        self.login_events.lock()?.push(event);
+        Ok(())
+    }
+
+    async fn fetch_login_events(&self, user_id: &UserId) -> Result<Vec<LoginEvent>, Self::Error> {
+        let res = self
+            .login_events
+            .lock()?
+            .iter()
+            .rev()
+            .filter(|event| event.user_id == Some(*user_id))
+            .cloned()
+            .collect();
+        Ok(res)
+    }
+
+    async fn create_api_token(
+        &self,
+        token: &ApiToken,
+        token_hash: &str,
+    ) -> Result<(), Self::Error> {
+        self.api_tokens.lock()?.push(ApiTokenRecord {
+            token: token.clone(),
+            token_hash: token_hash.to_owned(),
+        });
+        Ok(())
+    }
+
+    async fn fetch_api_token_by_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ApiToken>, Self::Error> {
+        let res = self
+            .api_tokens
+            .lock()?
+            .iter()
+            .find(|record| record.token_hash == token_hash)
+            .map(|record| record.token.clone());
+        Ok(res)
+    }
+
+    async fn fetch_api_tokens(&self, user_id: &UserId) -> Result<Vec<ApiToken>, Self::Error> {
+        let res = self
+            .api_tokens
+            .lock()?
+            .iter()
+            .filter(|record| record.token.user_id == *user_id)
+            .map(|record| record.token.clone())
+            .collect();
+        Ok(res)
+    }
+
+    async fn delete_api_token(
+        &self,
+        user_id: &UserId,
+        token_id: &ApiTokenId,
+    ) -> Result<bool, Self::Error> {
+        let mut table_locked = self.api_tokens.lock()?;
+        let len_before = table_locked.len();
+        table_locked
+            .retain(|record| !(record.token.user_id == *user_id && record.token.id == *token_id));
+        Ok(table_locked.len() < len_before)
+    }
+
+    async fn fetch_external_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<UserId>, Self::Error> {
+        let res = self
+            .external_identities
+            .lock()?
+            .get(&(issuer.to_owned(), subject.to_owned()))
+            .copied();
+        Ok(res)
+    }
+
+    async fn create_external_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        user_id: &UserId,
+    ) -> Result<bool, Self::Error> {
+        let mut table_locked = self.external_identities.lock()?;
+        let key = (issuer.to_owned(), subject.to_owned());
+        if table_locked.contains_key(&key) {
+            return Ok(false);
+        }
+        table_locked.insert(key, *user_id);
+        Ok(true)
+    }
+}
+
+impl AuditLogStorage for Db {
+    type Error = Error;
+
+    async fn append_audit_event(
+        &self,
+        user_id: Option<&UserId>,
+        kind: AuditEventKind,
+        client_addr: Option<IpAddr>,
+        created_at: DateTime<Utc>,
+    ) -> Result<AuditEventId, Self::Error> {
+        let mut table_locked = self.audit_events.lock()?;
+        let id = table_locked.len() as AuditEventId + 1;
+        table_locked.push(AuditEvent {
+            id,
+            user_id: user_id.copied(),
+            kind,
+            client_addr,
+            created_at,
+        });
+        Ok(id)
+    }
+
+    async fn fetch_audit_events(
+        &self,
+        query: &AuditEventQuery,
+    ) -> Result<Vec<AuditEvent>, Self::Error> {
+        let res = self
+            .audit_events
+            .lock()?
+            .iter()
+            .rev()
+            .filter(|event| query.matches(event))
+            .take(query.limit as usize)
+            .cloned()
+            .collect();
+        Ok(res)
+    }
 }
@@ -1,20 +1,48 @@
 use std::fmt::{Debug, Display, Formatter};
+use std::net::IpAddr;
 use std::result::Result;
 
-use anyhow::{bail, Context, Error};
+use anyhow::{Context, Error};
 
-use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
-};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, SubsecRound, Utc};
+use sha2::{Digest, Sha256};
 use std::result;
 use std::str::FromStr;
 use thiserror::Error;
 
-use pheidippides_messenger::authorization::AuthService;
+use pheidippides_messenger::audit_log::{
+    AuditEvent, AuditEventId, AuditEventKind, AuditEventQuery,
+};
+use pheidippides_messenger::authorization::{
+    ApiScope, ApiToken, ApiTokenId, AuthService, ExternalIdentity, ExternalLoginStart,
+    PendingExternalLogin, TotpEnrollment, VerificationResult,
+};
 use pheidippides_messenger::UserId;
 
 use pheidippides_utils::async_result;
+use pheidippides_utils::utils::log_internal_error;
+
+pub use login_throttling::{
+    LoginEvent, LoginEventKind, LoginEventKindParsingError, LoginFailures, LoginThrottleKey,
+    LoginThrottlingConfig,
+};
+pub use password_hashing::{PasswordHashingConfig, Pepper};
+pub use secret_key::SecretKey;
+
+mod login_throttling;
+mod password_hashing;
+mod secret_key;
+pub mod totp;
+
+pub const PASSWORD_RESET_TOKEN_LIFETIME: chrono::TimeDelta = chrono::TimeDelta::hours(1);
+const PASSWORD_RESET_TOKEN_BYTES: usize = 32;
+const TOTP_ISSUER: &str = "Pheidippides";
+const RECOVERY_CODES_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const API_TOKEN_BYTES: usize = 32;
+/// Makes API tokens recognizable, e.g. by secret scanners
+const API_TOKEN_PREFIX: &str = "phd_";
 
 pub trait AuthStorage: 'static + Send + Sync + Clone {
     type Error: 'static + std::error::Error + Send + Sync;
@@ -24,6 +52,91 @@ pub trait AuthStorage: 'static + Send + Sync + Clone {
         user_id: &UserId,
         auth_info: AuthenticationInfo,
     ) -> async_result!(Option<AuthenticationInfo>);
+    fn create_password_reset_token(&self, token: PasswordResetToken) -> async_result!(());
+    /// Marks the token with the given hash as used along with all other tokens of its owner.
+    /// Returns the owner if the token was neither used nor expired at the moment `now`
+    fn spend_password_reset_token(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> async_result!(Option<UserId>);
+    fn fetch_totp(&self, user_id: &UserId) -> async_result!(Option<TotpInfo>);
+    /// Replaces the TOTP info of the user, None removes it
+    fn update_totp(&self, user_id: &UserId, totp_info: Option<TotpInfo>) -> async_result!(());
+    /// Records the time step of a successfully verified code.
+    /// Returns false and changes nothing if the same or a later step has already been recorded
+    fn update_totp_last_used_step(&self, user_id: &UserId, step: i64) -> async_result!(bool);
+    /// Replaces all recovery codes of the user, None removes them
+    fn replace_recovery_codes(
+        &self,
+        user_id: &UserId,
+        code_hashes: Option<Vec<String>>,
+    ) -> async_result!(());
+    /// Marks the code as used. Returns false if there is no such unused code
+    fn spend_recovery_code(&self, user_id: &UserId, code_hash: &str) -> async_result!(bool);
+    fn fetch_login_failures(&self, key: &LoginThrottleKey) -> async_result!(Option<LoginFailures>);
+    /// Atomically replaces the failures of the key, None meaning no failures,
+    /// if they are still `expected`. Returns false and changes nothing otherwise
+    fn replace_login_failures(
+        &self,
+        key: &LoginThrottleKey,
+        expected: Option<&LoginFailures>,
+        failures: Option<&LoginFailures>,
+    ) -> async_result!(bool);
+    /// Locks the key until the given moment and resets its failure counter
+    fn lock_login(&self, key: &LoginThrottleKey, until: DateTime<Utc>) -> async_result!(());
+    fn reset_login_failures(&self, key: &LoginThrottleKey) -> async_result!(());
+    fn record_login_event(&self, event: LoginEvent) -> async_result!(());
+    /// Returns events of the user, newest first
+    fn fetch_login_events(&self, user_id: &UserId) -> async_result!(Vec<LoginEvent>);
+    fn create_api_token(&self, token: &ApiToken, token_hash: &str) -> async_result!(());
+    fn fetch_api_token_by_hash(&self, token_hash: &str) -> async_result!(Option<ApiToken>);
+    /// Returns tokens of the user, oldest first
+    fn fetch_api_tokens(&self, user_id: &UserId) -> async_result!(Vec<ApiToken>);
+    /// Returns false if the user has no such token
+    fn delete_api_token(&self, user_id: &UserId, token_id: &ApiTokenId) -> async_result!(bool);
+    fn fetch_external_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> async_result!(Option<UserId>);
+    /// Returns false and changes nothing if the identity is already linked
+    fn create_external_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        user_id: &UserId,
+    ) -> async_result!(bool);
+}
+
+/// Append-only storage of authentication events
+pub trait AuditLogStorage: 'static + Send + Sync + Clone {
+    type Error: 'static + std::error::Error + Send + Sync;
+    /// Returns the id of the new event
+    fn append_audit_event(
+        &self,
+        user_id: Option<&UserId>,
+        kind: AuditEventKind,
+        client_addr: Option<IpAddr>,
+        created_at: DateTime<Utc>,
+    ) -> async_result!(AuditEventId);
+    /// Returns matching events, newest first
+    fn fetch_audit_events(&self, query: &AuditEventQuery) -> async_result!(Vec<AuditEvent>);
+}
+
+pub struct PasswordResetToken {
+    pub user_id: UserId,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct TotpInfo {
+    /// Encrypted with the [SecretKey] of the service
+    pub encrypted_secret: Vec<u8>,
+    /// Secret is only required on login after the user has proven that the authenticator app works
+    pub confirmed: bool,
+    pub last_used_step: Option<i64>,
 }
 
 #[derive(Debug)]
@@ -46,60 +159,745 @@ impl std::error::Error for AuthServiceError {}
 #[derive(Clone)]
 pub struct AuthServiceUsingArgon2<A> {
     storage: A,
+    secret_key: SecretKey,
+    password_hashing_config: PasswordHashingConfig,
+    login_throttling_config: LoginThrottlingConfig,
 }
 
 impl<A> AuthServiceUsingArgon2<A> {
-    pub fn new(storage: A) -> Self {
-        Self { storage }
+    pub fn new(storage: A, secret_key: SecretKey) -> Self {
+        Self {
+            storage,
+            secret_key,
+            password_hashing_config: PasswordHashingConfig::default(),
+            login_throttling_config: LoginThrottlingConfig::default(),
+        }
+    }
+
+    /// Passwords are verified against their stored hashes regardless of the configuration.
+    /// On successful login the password is rehashed if its hash doesn't match the configuration
+    pub fn with_password_hashing_config(
+        self,
+        password_hashing_config: PasswordHashingConfig,
+    ) -> Self {
+        Self {
+            password_hashing_config,
+            ..self
+        }
+    }
+
+    pub fn with_login_throttling_config(
+        self,
+        login_throttling_config: LoginThrottlingConfig,
+    ) -> Self {
+        Self {
+            login_throttling_config,
+            ..self
+        }
+    }
+
+    async fn hash_password(&self, password: String) -> anyhow::Result<AuthenticationInfo> {
+        let config = self.password_hashing_config.clone();
+        tokio::task::spawn_blocking(move || config.hash(&password))
+            .await
+            .context("Password hash generation thread failed")?
+    }
+}
+
+/// An attempt counted as a failure of the key before it is verified. Its time is truncated
+/// to microseconds like in storage, so that `reserved` can be compared with fetched failures
+struct LoginAttemptReservation {
+    key: LoginThrottleKey,
+    previous: Option<LoginFailures>,
+    reserved: LoginFailures,
+}
+
+impl<A: AuthStorage + AuditLogStorage> AuthServiceUsingArgon2<A> {
+    /// Returns the latest moment until which any of the keys is blocked
+    async fn blocked_until(
+        &self,
+        keys: &[LoginThrottleKey],
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let mut res = None;
+        for key in keys {
+            let failures = self
+                .storage
+                .fetch_login_failures(key)
+                .await
+                .with_context(|| format!("Couldn't fetch login failures for {key}"))?;
+            let blocked_until = failures.and_then(|failures| {
+                self.login_throttling_config
+                    .blocked_until(key, &failures, now)
+            });
+            res = res.max(blocked_until);
+        }
+        Ok(res)
+    }
+
+    /// Checks that none of the keys is blocked and counts the attempt as a failure of each of them
+    /// in the same conditional update, so that parallel attempts can't all pass the check before
+    /// any of them fails. Returns the moment until which the keys are blocked as an error
+    async fn reserve_login_attempt(
+        &self,
+        keys: &[LoginThrottleKey],
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Result<Vec<LoginAttemptReservation>, DateTime<Utc>>> {
+        let config = &self.login_throttling_config;
+        let mut reservations = Vec::with_capacity(keys.len());
+        for key in keys {
+            loop {
+                let previous = self
+                    .storage
+                    .fetch_login_failures(key)
+                    .await
+                    .with_context(|| format!("Couldn't fetch login failures for {key}"))?;
+                if let Some(blocked_until) = previous
+                    .as_ref()
+                    .and_then(|failures| config.blocked_until(key, failures, now))
+                {
+                    self.release_login_attempt(&reservations).await?;
+                    let until = self.blocked_until(keys, now).await?;
+                    return Ok(Err(
+                        until.map_or(blocked_until, |until| until.max(blocked_until))
+                    ));
+                }
+
+                let reserved = config.count_failure(previous.as_ref(), now);
+                // Another attempt changed the failures in between, so they are checked again
+                if self
+                    .storage
+                    .replace_login_failures(key, previous.as_ref(), Some(&reserved))
+                    .await
+                    .with_context(|| format!("Couldn't reserve login attempt for {key}"))?
+                {
+                    reservations.push(LoginAttemptReservation {
+                        key: *key,
+                        previous,
+                        reserved,
+                    });
+                    break;
+                }
+            }
+        }
+        Ok(Ok(reservations))
+    }
+
+    /// Takes back the failures counted for an attempt that succeeded. If no other attempt
+    /// counted a failure since, the failures are restored as they were before the attempt
+    async fn release_login_attempt(
+        &self,
+        reservations: &[LoginAttemptReservation],
+    ) -> anyhow::Result<()> {
+        for reservation in reservations {
+            let key = &reservation.key;
+            loop {
+                let current = self
+                    .storage
+                    .fetch_login_failures(key)
+                    .await
+                    .with_context(|| format!("Couldn't fetch login failures for {key}"))?;
+                let released = match &current {
+                    // Already reset or locked by another attempt
+                    None => break,
+                    Some(current) if *current == reservation.reserved => {
+                        reservation.previous.clone()
+                    }
+                    Some(current) => Some(LoginFailures {
+                        failed_attempts: (current.failed_attempts - 1).max(0),
+                        ..current.clone()
+                    }),
+                };
+                if self
+                    .storage
+                    .replace_login_failures(key, current.as_ref(), released.as_ref())
+                    .await
+                    .with_context(|| format!("Couldn't release login attempt for {key}"))?
+                {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Locks the keys whose reserved failures reached their threshold.
+    /// Returns the moment until which the attempts are locked, if any
+    async fn register_login_failure(
+        &self,
+        user_id: Option<&UserId>,
+        client_addr: Option<IpAddr>,
+        reservations: &[LoginAttemptReservation],
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let config = &self.login_throttling_config;
+        let mut locked_until = None;
+        for LoginAttemptReservation { key, reserved, .. } in reservations {
+            if reserved.failed_attempts >= config.lockout_threshold(key) as i32 {
+                let until = now + config.lockout_duration;
+                self.storage
+                    .lock_login(key, until)
+                    .await
+                    .with_context(|| format!("Couldn't lock login for {key}"))?;
+                locked_until = Some(until);
+            }
+        }
+
+        let kind = match locked_until {
+            Some(_) => LoginEventKind::Lockout,
+            None => LoginEventKind::Failure,
+        };
+        self.record_login_event(user_id, client_addr, kind, now)
+            .await?;
+        Ok(locked_until)
+    }
+
+    async fn record_login_event(
+        &self,
+        user_id: Option<&UserId>,
+        client_addr: Option<IpAddr>,
+        kind: LoginEventKind,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        let event = LoginEvent {
+            user_id: user_id.copied(),
+            client_addr,
+            kind,
+            created_at: now,
+        };
+        self.storage
+            .record_login_event(event)
+            .await
+            .context("Couldn't record login event")
+    }
+
+    /// Returns the time step of the code if it is valid and hasn't been used yet
+    async fn verify_totp_code(
+        &self,
+        user_id: &UserId,
+        totp_info: &TotpInfo,
+        code: &str,
+    ) -> anyhow::Result<Option<i64>> {
+        let secret = self
+            .secret_key
+            .decrypt(&totp_info.encrypted_secret)
+            .with_context(|| format!("Couldn't decrypt TOTP secret for {user_id}"))?;
+
+        let step = match totp::verify(&secret, code, Utc::now().timestamp()) {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+
+        let not_used_yet = self
+            .storage
+            .update_totp_last_used_step(user_id, step)
+            .await
+            .with_context(|| format!("Couldn't update last used TOTP step for {user_id}"))?;
+
+        Ok(not_used_yet.then_some(step))
     }
 }
 
-impl<A: AuthStorage> AuthService for AuthServiceUsingArgon2<A> {
+impl<A: AuthStorage + AuditLogStorage> AuthService for AuthServiceUsingArgon2<A> {
     type Error = AuthServiceError;
 
-    async fn verify_user(&self, user_id: &UserId, password: String) -> Result<bool, Self::Error> {
-        let auth_info = match self
+    async fn verify_user(
+        &self,
+        user_id: &UserId,
+        password: String,
+        client_addr: Option<IpAddr>,
+    ) -> Result<VerificationResult, Self::Error> {
+        let now = Utc::now().trunc_subsecs(6);
+        let mut keys = vec![LoginThrottleKey::User(*user_id)];
+        keys.extend(client_addr.map(LoginThrottleKey::Client));
+
+        let reservations = match self.reserve_login_attempt(&keys, now).await? {
+            Ok(reservations) => reservations,
+            Err(until) => {
+                self.record_login_event(Some(user_id), client_addr, LoginEventKind::Rejected, now)
+                    .await?;
+                return Ok(VerificationResult::Locked { until });
+            }
+        };
+
+        let auth_info = self
             .storage
             .fetch_authentication(user_id)
             .await
-            .with_context(|| format!("Couldn't fetch authentification for {user_id}"))?
+            .with_context(|| format!("Couldn't fetch authentification for {user_id}"))?;
+
+        let verification = match auth_info {
+            Some(auth_info) => {
+                let config = self.password_hashing_config.clone();
+                tokio::task::spawn_blocking(move || config.verify(&password, &auth_info))
+                    .await
+                    .context("Password verification thread failed")?
+                    .with_context(|| format!("Couldn't verify password for {user_id}"))?
+            }
+            None => None,
+        };
+
+        let new_auth_info = match verification {
+            Some(new_auth_info) => new_auth_info,
+            None => {
+                let res = match self
+                    .register_login_failure(Some(user_id), client_addr, &reservations, now)
+                    .await?
+                {
+                    Some(until) => VerificationResult::Locked { until },
+                    None => VerificationResult::Failed,
+                };
+                return Ok(res);
+            }
+        };
+
+        self.release_login_attempt(&reservations).await?;
+        // Failures of the client address are kept, otherwise logging into
+        // an own account would allow guessing passwords of others indefinitely.
+        // With a second factor the login isn't complete yet, so that knowing
+        // the password doesn't allow guessing codes indefinitely either
+        if !self.second_factor_enabled(user_id).await? {
+            self.storage
+                .reset_login_failures(&LoginThrottleKey::User(*user_id))
+                .await
+                .with_context(|| format!("Couldn't reset login failures for {user_id}"))?;
+        }
+
+        if let Some(new_auth_info) = new_auth_info {
+            // The password is correct anyway, so a failed upgrade must not prevent the login
+            if let Err(e) = self
+                .storage
+                .update_authentication(user_id, new_auth_info)
+                .await
+            {
+                log_internal_error(format!("Couldn't rehash password for {user_id}: {e}"));
+            }
+        }
+
+        Ok(VerificationResult::Verified)
+    }
+
+    async fn register_unknown_user_attempt(
+        &self,
+        client_addr: Option<IpAddr>,
+    ) -> Result<VerificationResult, Self::Error> {
+        let now = Utc::now().trunc_subsecs(6);
+        let keys: Vec<_> = client_addr
+            .map(LoginThrottleKey::Client)
+            .into_iter()
+            .collect();
+
+        let reservations = match self.reserve_login_attempt(&keys, now).await? {
+            Ok(reservations) => reservations,
+            Err(until) => {
+                self.record_login_event(None, client_addr, LoginEventKind::Rejected, now)
+                    .await?;
+                return Ok(VerificationResult::Locked { until });
+            }
+        };
+
+        let res = match self
+            .register_login_failure(None, client_addr, &reservations, now)
+            .await?
         {
-            Some(auth_info) => auth_info,
-            None => return Ok(false),
+            Some(until) => VerificationResult::Locked { until },
+            None => VerificationResult::Failed,
         };
+        Ok(res)
+    }
 
-        let handle = tokio::task::spawn_blocking(move || {
-            let password_hash = auth_info.phc_string().password_hash();
-            Argon2::default()
-                .verify_password(password.as_bytes(), &password_hash)
-                .is_ok()
-        });
+    async fn create_user(&self, user_id: &UserId, password: String) -> Result<(), Self::Error> {
+        let auth_info = self.hash_password(password).await?;
+        self.storage
+            .update_authentication(user_id, auth_info)
+            .await
+            .with_context(|| format!("Couldn't update authentification for {user_id}"))?; //TODO check if user already existed?
+        Ok(())
+    }
+
+    async fn create_password_reset_token(&self, user_id: &UserId) -> Result<String, Self::Error> {
+        let mut token_bytes = [0u8; PASSWORD_RESET_TOKEN_BYTES];
+        OsRng.fill_bytes(&mut token_bytes);
+        let token = to_hex(&token_bytes);
 
-        let res = handle
+        let reset_token = PasswordResetToken {
+            user_id: *user_id,
+            token_hash: token_hash(&token),
+            expires_at: Utc::now() + PASSWORD_RESET_TOKEN_LIFETIME,
+        };
+
+        self.storage
+            .create_password_reset_token(reset_token)
             .await
-            .context("Password verification thread failed")?;
+            .with_context(|| format!("Couldn't store password reset token for {user_id}"))?;
+
+        Ok(token)
+    }
+
+    async fn reset_password(
+        &self,
+        token: &str,
+        new_password: String,
+    ) -> Result<Option<UserId>, Self::Error> {
+        let user_id = match self
+            .storage
+            .spend_password_reset_token(&token_hash(token), Utc::now())
+            .await
+            .context("Couldn't spend password reset token")?
+        {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+
+        let auth_info = self.hash_password(new_password).await?;
+        self.storage
+            .update_authentication(&user_id, auth_info)
+            .await
+            .with_context(|| format!("Couldn't update authentification for {user_id}"))?;
+        // The owner has proven access to the account, so a lockout caused by someone else ends here
+        self.storage
+            .reset_login_failures(&LoginThrottleKey::User(user_id))
+            .await
+            .with_context(|| format!("Couldn't reset login failures for {user_id}"))?;
+
+        Ok(Some(user_id))
+    }
+
+    async fn second_factor_enabled(&self, user_id: &UserId) -> Result<bool, Self::Error> {
+        let totp_info = self
+            .storage
+            .fetch_totp(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch TOTP info for {user_id}"))?;
+        Ok(totp_info.is_some_and(|totp_info| totp_info.confirmed))
+    }
+
+    async fn start_totp_enrollment(
+        &self,
+        user_id: &UserId,
+        account_name: &str,
+    ) -> Result<TotpEnrollment, Self::Error> {
+        if self.second_factor_enabled(user_id).await? {
+            return Err(anyhow::anyhow!("TOTP is already enabled for {user_id}").into());
+        }
+
+        let mut secret = [0u8; totp::SECRET_LENGTH];
+        OsRng.fill_bytes(&mut secret);
+
+        let totp_info = TotpInfo {
+            encrypted_secret: self.secret_key.encrypt(&secret)?,
+            confirmed: false,
+            last_used_step: None,
+        };
+        self.storage
+            .update_totp(user_id, Some(totp_info))
+            .await
+            .with_context(|| format!("Couldn't store TOTP info for {user_id}"))?;
+
+        Ok(TotpEnrollment {
+            secret: totp::base32_encode(&secret),
+            uri: totp::provisioning_uri(TOTP_ISSUER, account_name, &secret),
+        })
+    }
+
+    async fn confirm_totp_enrollment(
+        &self,
+        user_id: &UserId,
+        code: &str,
+    ) -> Result<Option<Vec<String>>, Self::Error> {
+        let totp_info = match self
+            .storage
+            .fetch_totp(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch TOTP info for {user_id}"))?
+        {
+            Some(totp_info) if !totp_info.confirmed => totp_info,
+            _ => return Ok(None),
+        };
+
+        let step = match self.verify_totp_code(user_id, &totp_info, code).await? {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODES_COUNT)
+            .map(|_| generate_recovery_code())
+            .collect();
+        let code_hashes = recovery_codes
+            .iter()
+            .map(|code| token_hash(&normalize_recovery_code(code)))
+            .collect();
+        self.storage
+            .replace_recovery_codes(user_id, Some(code_hashes))
+            .await
+            .with_context(|| format!("Couldn't store recovery codes for {user_id}"))?;
+
+        let totp_info = TotpInfo {
+            confirmed: true,
+            last_used_step: Some(step),
+            ..totp_info
+        };
+        self.storage
+            .update_totp(user_id, Some(totp_info))
+            .await
+            .with_context(|| format!("Couldn't confirm TOTP for {user_id}"))?;
+
+        Ok(Some(recovery_codes))
+    }
+
+    async fn verify_second_factor(
+        &self,
+        user_id: &UserId,
+        code: &str,
+    ) -> Result<bool, Self::Error> {
+        let totp_info = match self
+            .storage
+            .fetch_totp(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch TOTP info for {user_id}"))?
+        {
+            Some(totp_info) if totp_info.confirmed => totp_info,
+            _ => return Ok(false),
+        };
+
+        if self
+            .verify_totp_code(user_id, &totp_info, code)
+            .await?
+            .is_some()
+        {
+            return Ok(true);
+        }
+
+        let res = self
+            .storage
+            .spend_recovery_code(user_id, &token_hash(&normalize_recovery_code(code)))
+            .await
+            .with_context(|| format!("Couldn't spend recovery code for {user_id}"))?;
         Ok(res)
     }
 
-    async fn create_user(&self, user_id: &UserId, password: String) -> Result<(), Self::Error> {
-        let handle = tokio::task::spawn_blocking(move || {
-            let salt = SaltString::generate(OsRng);
-            let password_hash = match Argon2::default().hash_password(password.as_bytes(), &salt) {
-                Ok(hash) => hash,
-                Err(e) => bail!("Couldn't generate hash from {password}: {e}"),
+    async fn verify_login_second_factor(
+        &self,
+        user_id: &UserId,
+        code: &str,
+        client_addr: Option<IpAddr>,
+    ) -> Result<VerificationResult, Self::Error> {
+        let now = Utc::now().trunc_subsecs(6);
+        let mut keys = vec![LoginThrottleKey::User(*user_id)];
+        keys.extend(client_addr.map(LoginThrottleKey::Client));
+
+        let reservations = match self.reserve_login_attempt(&keys, now).await? {
+            Ok(reservations) => reservations,
+            Err(until) => {
+                self.record_login_event(Some(user_id), client_addr, LoginEventKind::Rejected, now)
+                    .await?;
+                return Ok(VerificationResult::Locked { until });
+            }
+        };
+
+        if !self.verify_second_factor(user_id, code).await? {
+            let res = match self
+                .register_login_failure(Some(user_id), client_addr, &reservations, now)
+                .await?
+            {
+                Some(until) => VerificationResult::Locked { until },
+                None => VerificationResult::Failed,
             };
-            Ok(AuthenticationInfo::from(password_hash))
-        });
+            return Ok(res);
+        }
 
-        let auth_info = handle
+        self.release_login_attempt(&reservations).await?;
+        self.storage
+            .reset_login_failures(&LoginThrottleKey::User(*user_id))
             .await
-            .context("Password hash generation thread failed")??;
+            .with_context(|| format!("Couldn't reset login failures for {user_id}"))?;
+        Ok(VerificationResult::Verified)
+    }
+
+    async fn disable_totp(&self, user_id: &UserId) -> Result<(), Self::Error> {
         self.storage
-            .update_authentication(user_id, auth_info)
+            .update_totp(user_id, None)
             .await
-            .with_context(|| format!("Couldn't update authentification for {user_id}"))?; //TODO check if user already existed?
+            .with_context(|| format!("Couldn't remove TOTP info for {user_id}"))?;
+        self.storage
+            .replace_recovery_codes(user_id, None)
+            .await
+            .with_context(|| format!("Couldn't remove recovery codes for {user_id}"))?;
         Ok(())
     }
+
+    async fn create_api_token(
+        &self,
+        user_id: &UserId,
+        name: &str,
+        scopes: &[ApiScope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(ApiToken, String), Self::Error> {
+        let mut token_bytes = [0u8; API_TOKEN_BYTES];
+        OsRng.fill_bytes(&mut token_bytes);
+        let token = format!("{API_TOKEN_PREFIX}{}", to_hex(&token_bytes));
+
+        let api_token = ApiToken {
+            id: ApiTokenId::new_v4(),
+            user_id: *user_id,
+            name: name.to_owned(),
+            scopes: scopes.to_vec(),
+            created_at: Utc::now(),
+            expires_at,
+        };
+
+        self.storage
+            .create_api_token(&api_token, &token_hash(&token))
+            .await
+            .with_context(|| format!("Couldn't store API token for {user_id}"))?;
+
+        Ok((api_token, token))
+    }
+
+    async fn fetch_api_tokens(&self, user_id: &UserId) -> Result<Vec<ApiToken>, Self::Error> {
+        let res = self
+            .storage
+            .fetch_api_tokens(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch API tokens of {user_id}"))?;
+        Ok(res)
+    }
+
+    async fn revoke_api_token(
+        &self,
+        user_id: &UserId,
+        token_id: &ApiTokenId,
+    ) -> Result<bool, Self::Error> {
+        let res = self
+            .storage
+            .delete_api_token(user_id, token_id)
+            .await
+            .with_context(|| format!("Couldn't delete API token {token_id} of {user_id}"))?;
+        Ok(res)
+    }
+
+    async fn verify_api_token(&self, token: &str) -> Result<Option<ApiToken>, Self::Error> {
+        if !token.starts_with(API_TOKEN_PREFIX) {
+            return Ok(None);
+        }
+
+        let api_token = self
+            .storage
+            .fetch_api_token_by_hash(&token_hash(token))
+            .await
+            .context("Couldn't fetch API token")?;
+
+        let now = Utc::now();
+        Ok(api_token.filter(|api_token| {
+            api_token
+                .expires_at
+                .is_none_or(|expires_at| expires_at > now)
+        }))
+    }
+
+    fn password_login_enabled(&self) -> bool {
+        true
+    }
+
+    fn external_login_enabled(&self) -> bool {
+        false
+    }
+
+    async fn start_external_login(&self) -> Result<Option<ExternalLoginStart>, Self::Error> {
+        Ok(None)
+    }
+
+    async fn finish_external_login(
+        &self,
+        _pending: &PendingExternalLogin,
+        _code: &str,
+    ) -> Result<Option<ExternalIdentity>, Self::Error> {
+        Ok(None)
+    }
+
+    async fn fetch_external_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<UserId>, Self::Error> {
+        let res = self
+            .storage
+            .fetch_external_identity_user(issuer, subject)
+            .await
+            .with_context(|| format!("Couldn't fetch user of external identity {subject}"))?;
+        Ok(res)
+    }
+
+    async fn link_external_identity(
+        &self,
+        identity: &ExternalIdentity,
+        user_id: &UserId,
+    ) -> Result<bool, Self::Error> {
+        let res = self
+            .storage
+            .create_external_identity(&identity.issuer, &identity.subject, user_id)
+            .await
+            .with_context(|| {
+                format!(
+                    "Couldn't link external identity {} to {user_id}",
+                    identity.subject
+                )
+            })?;
+        Ok(res)
+    }
+
+    async fn record_audit_event(
+        &self,
+        user_id: Option<&UserId>,
+        kind: AuditEventKind,
+        client_addr: Option<IpAddr>,
+    ) -> Result<(), Self::Error> {
+        self.storage
+            .append_audit_event(user_id, kind, client_addr, Utc::now())
+            .await
+            .with_context(|| format!("Couldn't record audit event {kind}"))?;
+        Ok(())
+    }
+
+    async fn fetch_audit_events(
+        &self,
+        query: &AuditEventQuery,
+    ) -> Result<Vec<AuditEvent>, Self::Error> {
+        let res = AuditLogStorage::fetch_audit_events(&self.storage, query)
+            .await
+            .context("Couldn't fetch audit events")?;
+        Ok(res)
+    }
+}
+
+/// Recovery codes look like `abcde-fghij`, so that they are easy to write down
+fn generate_recovery_code() -> String {
+    let alphabet = b"abcdefghijkmnpqrstuvwxyz23456789";
+    let mut bytes = [0u8; RECOVERY_CODE_LENGTH];
+    OsRng.fill_bytes(&mut bytes);
+    let code: String = bytes
+        .iter()
+        .map(|byte| alphabet[*byte as usize % alphabet.len()] as char)
+        .collect();
+    let (first_half, second_half) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+    format!("{first_half}-{second_half}")
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Reset tokens and recovery codes are random and long enough,
+/// so a fast unsalted hash is sufficient for them
+fn token_hash(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
 }
 
 pub struct AuthenticationInfo {
@@ -0,0 +1,295 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use thiserror::Error;
+
+use pheidippides_messenger::UserId;
+
+/// Failed login attempts are counted separately for every account and every client address
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum LoginThrottleKey {
+    User(UserId),
+    Client(IpAddr),
+}
+
+impl Display for LoginThrottleKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginThrottleKey::User(user_id) => write!(f, "user:{user_id}"),
+            LoginThrottleKey::Client(addr) => write!(f, "client:{addr}"),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct LoginFailures {
+    /// Failures since the last successful login or lockout
+    pub failed_attempts: i32,
+    pub last_failure_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LoginEventKind {
+    /// Wrong password, wrong second factor code or unknown username
+    Failure,
+    /// Failure that pushed the account or the client address over the lockout threshold
+    Lockout,
+    /// Attempt made during a back-off delay or a lockout, the password wasn't checked
+    Rejected,
+}
+
+impl LoginEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginEventKind::Failure => "failure",
+            LoginEventKind::Lockout => "lockout",
+            LoginEventKind::Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown login event kind: {0}")]
+pub struct LoginEventKindParsingError(String);
+
+impl FromStr for LoginEventKind {
+    type Err = LoginEventKindParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failure" => Ok(LoginEventKind::Failure),
+            "lockout" => Ok(LoginEventKind::Lockout),
+            "rejected" => Ok(LoginEventKind::Rejected),
+            _ => Err(LoginEventKindParsingError(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct LoginEvent {
+    /// None if the username didn't match any user
+    pub user_id: Option<UserId>,
+    pub client_addr: Option<IpAddr>,
+    pub kind: LoginEventKind,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct LoginThrottlingConfig {
+    /// Failed attempts per account allowed without any delay
+    pub free_attempts: u32,
+    /// Delay after the first failure over the free attempts, doubled with every next failure
+    pub base_delay: TimeDelta,
+    pub max_delay: TimeDelta,
+    /// Failed attempts after which the account is locked
+    pub user_lockout_threshold: u32,
+    /// Failed attempts after which the client address is locked.
+    /// Client addresses aren't delayed, since many users may share one address
+    pub client_lockout_threshold: u32,
+    pub lockout_duration: TimeDelta,
+    /// Failures older than this are forgotten
+    pub failure_window: TimeDelta,
+}
+
+impl Default for LoginThrottlingConfig {
+    fn default() -> Self {
+        LoginThrottlingConfig {
+            free_attempts: 3,
+            base_delay: TimeDelta::seconds(1),
+            max_delay: TimeDelta::minutes(1),
+            user_lockout_threshold: 10,
+            client_lockout_threshold: 100,
+            lockout_duration: TimeDelta::minutes(15),
+            failure_window: TimeDelta::hours(1),
+        }
+    }
+}
+
+impl LoginThrottlingConfig {
+    pub(crate) fn lockout_threshold(&self, key: &LoginThrottleKey) -> u32 {
+        match key {
+            LoginThrottleKey::User(_) => self.user_lockout_threshold,
+            LoginThrottleKey::Client(_) => self.client_lockout_threshold,
+        }
+    }
+
+    /// Failures after one more failure at `now`.
+    /// The counter starts over if the previous failure is older than the failure window
+    pub(crate) fn count_failure(
+        &self,
+        failures: Option<&LoginFailures>,
+        now: DateTime<Utc>,
+    ) -> LoginFailures {
+        let failed_attempts = match failures {
+            Some(failures) if failures.last_failure_at + self.failure_window > now => {
+                failures.failed_attempts + 1
+            }
+            _ => 1,
+        };
+        LoginFailures {
+            failed_attempts,
+            last_failure_at: now,
+            locked_until: failures.and_then(|failures| failures.locked_until),
+        }
+    }
+
+    /// Returns the moment before which no attempts are accepted
+    pub(crate) fn blocked_until(
+        &self,
+        key: &LoginThrottleKey,
+        failures: &LoginFailures,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        if let Some(locked_until) = failures.locked_until {
+            if locked_until > now {
+                return Some(locked_until);
+            }
+        }
+
+        // Attempts still being verified reached the threshold and lock the key if they fail
+        if failures.failed_attempts >= self.lockout_threshold(key) as i32 {
+            let locked_until = failures.last_failure_at + self.lockout_duration;
+            if locked_until > now {
+                return Some(locked_until);
+            }
+        }
+
+        if matches!(key, LoginThrottleKey::Client(_))
+            || failures.last_failure_at + self.failure_window <= now
+        {
+            return None;
+        }
+
+        let extra_failures = u32::try_from(failures.failed_attempts)
+            .unwrap_or(0)
+            .checked_sub(self.free_attempts)?;
+        let delay = match extra_failures {
+            0 => return None,
+            // base_delay * 2^30 overflows the maximum anyway
+            n if n > 30 => self.max_delay,
+            n => self
+                .base_delay
+                .checked_mul(1 << (n - 1))
+                .map_or(self.max_delay, |delay| delay.min(self.max_delay)),
+        };
+
+        let blocked_until = failures.last_failure_at + delay;
+        (blocked_until > now).then_some(blocked_until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failures(failed_attempts: i32, last_failure_at: DateTime<Utc>) -> LoginFailures {
+        LoginFailures {
+            failed_attempts,
+            last_failure_at,
+            locked_until: None,
+        }
+    }
+
+    #[test]
+    fn delays_exponentially_after_free_attempts() {
+        let config = LoginThrottlingConfig {
+            user_lockout_threshold: 100,
+            ..LoginThrottlingConfig::default()
+        };
+        let key = LoginThrottleKey::User(UserId::new_v4());
+        let now = Utc::now();
+
+        assert_eq!(config.blocked_until(&key, &failures(3, now), now), None);
+        assert_eq!(
+            config.blocked_until(&key, &failures(4, now), now),
+            Some(now + TimeDelta::seconds(1))
+        );
+        assert_eq!(
+            config.blocked_until(&key, &failures(6, now), now),
+            Some(now + TimeDelta::seconds(4))
+        );
+        assert_eq!(
+            config.blocked_until(&key, &failures(60, now), now),
+            Some(now + TimeDelta::minutes(1))
+        );
+        assert_eq!(
+            config.blocked_until(&key, &failures(6, now), now + TimeDelta::seconds(4)),
+            None
+        );
+    }
+
+    #[test]
+    fn doesnt_delay_client_addresses() {
+        let config = LoginThrottlingConfig::default();
+        let key = LoginThrottleKey::Client("127.0.0.1".parse().unwrap());
+        let now = Utc::now();
+
+        assert_eq!(config.blocked_until(&key, &failures(50, now), now), None);
+    }
+
+    #[test]
+    fn blocks_while_attempts_reaching_threshold_are_verified() {
+        let config = LoginThrottlingConfig::default();
+        let key = LoginThrottleKey::Client("127.0.0.1".parse().unwrap());
+        let now = Utc::now();
+
+        assert_eq!(
+            config.blocked_until(&key, &failures(100, now), now),
+            Some(now + TimeDelta::minutes(15))
+        );
+    }
+
+    #[test]
+    fn counts_failures_within_window() {
+        let config = LoginThrottlingConfig::default();
+        let now = Utc::now();
+
+        assert_eq!(config.count_failure(None, now), failures(1, now));
+        assert_eq!(
+            config.count_failure(Some(&failures(3, now - TimeDelta::minutes(5))), now),
+            failures(4, now)
+        );
+        assert_eq!(
+            config.count_failure(Some(&failures(3, now - TimeDelta::hours(1))), now),
+            failures(1, now)
+        );
+    }
+
+    #[test]
+    fn forgets_old_failures() {
+        let config = LoginThrottlingConfig::default();
+        let key = LoginThrottleKey::User(UserId::new_v4());
+        let now = Utc::now();
+
+        assert_eq!(
+            config.blocked_until(&key, &failures(20, now - TimeDelta::hours(1)), now),
+            None
+        );
+    }
+
+    #[test]
+    fn respects_lockout() {
+        let config = LoginThrottlingConfig::default();
+        let now = Utc::now();
+        let locked_until = now + TimeDelta::minutes(10);
+        let failures = LoginFailures {
+            failed_attempts: 0,
+            last_failure_at: now,
+            locked_until: Some(locked_until),
+        };
+
+        for key in [
+            LoginThrottleKey::User(UserId::new_v4()),
+            LoginThrottleKey::Client("::1".parse().unwrap()),
+        ] {
+            assert_eq!(
+                config.blocked_until(&key, &failures, now),
+                Some(locked_until)
+            );
+            assert_eq!(config.blocked_until(&key, &failures, locked_until), None);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::bail;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, KeyId, Params, ParamsBuilder, Version};
+
+use crate::AuthenticationInfo;
+
+/// Server-side secret mixed into every password hash, so that leaked hashes alone can't be cracked.
+/// Hashes computed with a pepper are marked with its key id, so that hashes computed before
+/// the pepper was introduced can still be verified and upgraded. The key id is chosen by
+/// the operator rather than derived from the pepper, so that the stored hashes reveal nothing about it
+#[derive(Clone)]
+pub struct Pepper {
+    secret: Vec<u8>,
+    key_id: KeyId,
+}
+
+impl Pepper {
+    /// The key id must be at most 8 bytes long and should change whenever the pepper does
+    pub fn new(key_id: &str, secret: &[u8]) -> anyhow::Result<Self> {
+        if secret.is_empty() {
+            bail!("Pepper must not be empty");
+        }
+        if secret.len() > argon2::MAX_SECRET_LEN {
+            bail!("Pepper is too long");
+        }
+        if key_id.is_empty() {
+            bail!("Pepper key id must not be empty");
+        }
+        let key_id = match KeyId::new(key_id.as_bytes()) {
+            Ok(key_id) => key_id,
+            Err(e) => bail!("Incorrect pepper key id: {e}"),
+        };
+        Ok(Pepper {
+            secret: secret.to_owned(),
+            key_id,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct PasswordHashingConfig {
+    /// Memory size in KiB
+    pub memory_cost: u32,
+    /// Number of iterations
+    pub time_cost: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+    pub pepper: Option<Pepper>,
+}
+
+impl Default for PasswordHashingConfig {
+    fn default() -> Self {
+        PasswordHashingConfig {
+            memory_cost: Params::DEFAULT_M_COST,
+            time_cost: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            pepper: None,
+        }
+    }
+}
+
+impl PasswordHashingConfig {
+    fn params(&self) -> anyhow::Result<Params> {
+        let mut builder = ParamsBuilder::new();
+        builder
+            .m_cost(self.memory_cost)
+            .t_cost(self.time_cost)
+            .p_cost(self.parallelism);
+        if let Some(pepper) = &self.pepper {
+            builder.keyid(pepper.key_id);
+        }
+        match builder.build() {
+            Ok(params) => Ok(params),
+            Err(e) => bail!("Incorrect Argon2 parameters: {e}"),
+        }
+    }
+
+    /// Checks the parameters without hashing anything, so that misconfiguration is noticed on startup
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.params().map(|_| ())
+    }
+
+    pub fn hash(&self, password: &str) -> anyhow::Result<AuthenticationInfo> {
+        let params = self.params()?;
+        let argon2 = match &self.pepper {
+            Some(pepper) => {
+                match Argon2::new_with_secret(
+                    &pepper.secret,
+                    Algorithm::Argon2id,
+                    Version::V0x13,
+                    params,
+                ) {
+                    Ok(argon2) => argon2,
+                    Err(e) => bail!("Couldn't set up Argon2 with pepper: {e}"),
+                }
+            }
+            None => Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+        };
+
+        let salt = SaltString::generate(OsRng);
+        match argon2.hash_password(password.as_bytes(), &salt) {
+            Ok(hash) => Ok(AuthenticationInfo::from(hash)),
+            Err(e) => bail!("Couldn't generate password hash: {e}"),
+        }
+    }
+
+    /// Returns None if the password is incorrect.
+    /// Otherwise returns a new hash if the stored one doesn't match the current configuration
+    pub fn verify(
+        &self,
+        password: &str,
+        auth_info: &AuthenticationInfo,
+    ) -> anyhow::Result<Option<Option<AuthenticationInfo>>> {
+        let password_hash = auth_info.phc_string().password_hash();
+        let hash_params = match Params::try_from(&password_hash) {
+            Ok(params) => params,
+            Err(e) => bail!("Couldn't read Argon2 parameters from stored hash: {e}"),
+        };
+
+        let secret: &[u8] = if hash_params.keyid().is_empty() {
+            &[]
+        } else {
+            match &self.pepper {
+                Some(pepper) if pepper.key_id.as_bytes() == hash_params.keyid() => &pepper.secret,
+                _ => bail!("Stored hash was computed with a pepper that is not configured"),
+            }
+        };
+        let argon2 = match Argon2::new_with_secret(
+            secret,
+            Algorithm::default(),
+            Version::default(),
+            Params::default(),
+        ) {
+            Ok(argon2) => argon2,
+            Err(e) => bail!("Couldn't set up Argon2: {e}"),
+        };
+
+        if argon2
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_err()
+        {
+            return Ok(None);
+        }
+
+        if self.is_up_to_date(&password_hash, &hash_params)? {
+            Ok(Some(None))
+        } else {
+            Ok(Some(Some(self.hash(password)?)))
+        }
+    }
+
+    fn is_up_to_date(
+        &self,
+        password_hash: &PasswordHash,
+        hash_params: &Params,
+    ) -> anyhow::Result<bool> {
+        let current_params = self.params()?;
+        let algorithm_up_to_date = password_hash.algorithm == Algorithm::Argon2id.ident()
+            && password_hash.version == Some(Version::V0x13.into());
+        let params_up_to_date = hash_params.m_cost() == current_params.m_cost()
+            && hash_params.t_cost() == current_params.t_cost()
+            && hash_params.p_cost() == current_params.p_cost()
+            && hash_params.keyid() == current_params.keyid();
+        Ok(algorithm_up_to_date && params_up_to_date)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheap_config() -> PasswordHashingConfig {
+        PasswordHashingConfig {
+            memory_cost: 64,
+            time_cost: 1,
+            parallelism: 1,
+            pepper: None,
+        }
+    }
+
+    #[test]
+    fn verifies_password() {
+        let config = cheap_config();
+        let auth_info = config.hash("password").unwrap();
+        assert!(matches!(
+            config.verify("password", &auth_info),
+            Ok(Some(None))
+        ));
+        assert!(matches!(config.verify("passw0rd", &auth_info), Ok(None)));
+    }
+
+    #[test]
+    fn rehashes_with_outdated_params() {
+        let old_config = cheap_config();
+        let new_config = PasswordHashingConfig {
+            memory_cost: 128,
+            ..cheap_config()
+        };
+        let auth_info = old_config.hash("password").unwrap();
+
+        let new_auth_info = new_config
+            .verify("password", &auth_info)
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(new_auth_info.phc_string().as_str().contains("m=128"));
+        assert!(matches!(
+            new_config.verify("password", &new_auth_info),
+            Ok(Some(None))
+        ));
+        assert!(matches!(
+            new_config.verify("passw0rd", &auth_info),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn adds_pepper_to_existing_hashes() {
+        let config = cheap_config();
+        let peppered_config = PasswordHashingConfig {
+            pepper: Some(Pepper::new("1", b"pepper").unwrap()),
+            ..cheap_config()
+        };
+        let auth_info = config.hash("password").unwrap();
+
+        let peppered_auth_info = peppered_config
+            .verify("password", &auth_info)
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(peppered_auth_info.phc_string().as_str().contains("keyid="));
+        assert!(matches!(
+            peppered_config.verify("password", &peppered_auth_info),
+            Ok(Some(None))
+        ));
+
+        // the pepper really takes part in hashing
+        let other_pepper_config = PasswordHashingConfig {
+            pepper: Some(Pepper::new("1", b"other pepper").unwrap()),
+            ..cheap_config()
+        };
+        let other_key_id_config = PasswordHashingConfig {
+            pepper: Some(Pepper::new("2", b"pepper").unwrap()),
+            ..cheap_config()
+        };
+        assert!(config.verify("password", &peppered_auth_info).is_err());
+        assert!(matches!(
+            other_pepper_config.verify("password", &peppered_auth_info),
+            Ok(None)
+        ));
+        assert!(other_key_id_config
+            .verify("password", &peppered_auth_info)
+            .is_err());
+    }
+
+    #[test]
+    fn stores_only_the_configured_key_id() {
+        let config = PasswordHashingConfig {
+            pepper: Some(Pepper::new("key-1", b"pepper").unwrap()),
+            ..cheap_config()
+        };
+        let auth_info = config.hash("password").unwrap();
+        // base64 of "key-1"
+        assert!(auth_info.phc_string().as_str().contains("keyid=a2V5LTE$"));
+
+        assert!(Pepper::new("", b"pepper").is_err());
+        assert!(Pepper::new("too long key id", b"pepper").is_err());
+    }
+}
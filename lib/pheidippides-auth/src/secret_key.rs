@@ -0,0 +1,82 @@
+use anyhow::{bail, Context};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 24;
+
+/// Server-side key used to encrypt secrets that have to be stored in a recoverable form
+#[derive(Clone)]
+pub struct SecretKey([u8; KEY_LENGTH]);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LENGTH];
+        OsRng.fill_bytes(&mut key);
+        SecretKey(key)
+    }
+
+    pub fn from_hex(hex: &str) -> anyhow::Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != KEY_LENGTH * 2 {
+            bail!("Secret key must be {} hex digits long", KEY_LENGTH * 2);
+        }
+        let mut key = [0u8; KEY_LENGTH];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .context("Secret key must consist of hex digits")?;
+        }
+        Ok(SecretKey(key))
+    }
+
+    pub fn to_hex(&self) -> String {
+        crate::to_hex(&self.0)
+    }
+
+    /// Returns the random nonce followed by the ciphertext
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new(&self.0.into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = match cipher.encrypt(&nonce, plaintext) {
+            Ok(ciphertext) => ciphertext,
+            Err(e) => bail!("Encryption failed: {e}"),
+        };
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub(crate) fn decrypt(&self, encrypted: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if encrypted.len() < NONCE_LENGTH {
+            bail!("Encrypted value is too short");
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+        let cipher = XChaCha20Poly1305::new(&self.0.into());
+        match cipher.decrypt(XNonce::from_slice(nonce), ciphertext) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(e) => bail!("Decryption failed, the secret key might have changed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypts_encrypted_value() {
+        let key = SecretKey::generate();
+        let encrypted = key.encrypt(b"secret").unwrap();
+        assert_ne!(&encrypted[NONCE_LENGTH..], b"secret");
+        assert_eq!(key.decrypt(&encrypted).unwrap(), b"secret");
+        assert!(SecretKey::generate().decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn parses_hex() {
+        let key = SecretKey::generate();
+        assert_eq!(SecretKey::from_hex(&key.to_hex()).unwrap().0, key.0);
+        assert!(SecretKey::from_hex("abcd").is_err());
+        assert!(SecretKey::from_hex(&"zz".repeat(KEY_LENGTH)).is_err());
+    }
+}
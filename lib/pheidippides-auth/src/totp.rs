@@ -0,0 +1,174 @@
+//! RFC 6238 time-based one-time passwords with the parameters every authenticator app supports:
+//! HMAC-SHA1, 6 digits, 30 second steps
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+pub const SECRET_LENGTH: usize = 20;
+const DIGITS: u32 = 6;
+const STEP_SECONDS: i64 = 30;
+/// Codes from adjacent time steps are accepted as well to compensate for clock drift
+const ALLOWED_STEP_DRIFT: i64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn time_step(unix_timestamp: i64) -> i64 {
+    unix_timestamp.div_euclid(STEP_SECONDS)
+}
+
+pub fn code(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// Returns the time step the code belongs to if it is valid at the moment `unix_timestamp`.
+/// Codes are compared in constant time, anything but `DIGITS` digits is rejected at once
+pub fn verify(secret: &[u8], code_to_check: &str, unix_timestamp: i64) -> Option<i64> {
+    let code_to_check: String = code_to_check
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    if code_to_check.len() != DIGITS as usize || !code_to_check.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let current_step = time_step(unix_timestamp);
+    (current_step - ALLOWED_STEP_DRIFT..=current_step + ALLOWED_STEP_DRIFT).find(|step| {
+        code(secret, *step)
+            .as_bytes()
+            .ct_eq(code_to_check.as_bytes())
+            .into()
+    })
+}
+
+pub fn provisioning_uri(issuer: &str, account_name: &str, secret: &[u8]) -> String {
+    let issuer = url_escape::encode_component(issuer);
+    let account_name = url_escape::encode_component(account_name);
+    let secret = base32_encode(secret);
+    format!(
+        "otpauth://totp/{issuer}:{account_name}?secret={secret}&issuer={issuer}\
+        &algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}"
+    )
+}
+
+/// RFC 4648 base32 without padding, as expected in otpauth:// URIs
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut res = String::new();
+    let mut buffer: u16 = 0;
+    let mut bits_in_buffer = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u16;
+        bits_in_buffer += 8;
+        while bits_in_buffer >= 5 {
+            bits_in_buffer -= 5;
+            res.push(BASE32_ALPHABET[((buffer >> bits_in_buffer) & 0x1f) as usize] as char);
+        }
+    }
+    if bits_in_buffer > 0 {
+        res.push(BASE32_ALPHABET[((buffer << (5 - bits_in_buffer)) & 0x1f) as usize] as char);
+    }
+    res
+}
+
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::new();
+    let mut buffer: u16 = 0;
+    let mut bits_in_buffer = 0;
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u16;
+        bits_in_buffer += 5;
+        if bits_in_buffer >= 8 {
+            bits_in_buffer -= 8;
+            res.push((buffer >> bits_in_buffer) as u8);
+        }
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA1 test vectors truncated to 6 digits
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn generates_rfc_test_vectors() {
+        assert_eq!(code(RFC_SECRET, time_step(59)), "287082");
+        assert_eq!(code(RFC_SECRET, time_step(1111111109)), "081804");
+        assert_eq!(code(RFC_SECRET, time_step(1111111111)), "050471");
+        assert_eq!(code(RFC_SECRET, time_step(1234567890)), "005924");
+        assert_eq!(code(RFC_SECRET, time_step(2000000000)), "279037");
+        assert_eq!(code(RFC_SECRET, time_step(20000000000)), "353130");
+    }
+
+    #[test]
+    fn verifies_codes_within_drift() {
+        let now = 1111111111;
+        let step = time_step(now);
+        assert_eq!(verify(RFC_SECRET, "050471", now), Some(step));
+        assert_eq!(verify(RFC_SECRET, "050 471", now), Some(step));
+        assert_eq!(
+            verify(RFC_SECRET, &code(RFC_SECRET, step - 1), now),
+            Some(step - 1)
+        );
+        assert_eq!(
+            verify(RFC_SECRET, &code(RFC_SECRET, step + 1), now),
+            Some(step + 1)
+        );
+        assert_eq!(verify(RFC_SECRET, &code(RFC_SECRET, step - 2), now), None);
+        assert_eq!(verify(RFC_SECRET, "000000", now), None);
+        for malformed in ["", "05047", "0504710", "05047a", "０５０４７１", "+50471"] {
+            assert_eq!(verify(RFC_SECRET, malformed, now), None);
+        }
+    }
+
+    #[test]
+    fn encodes_base32() {
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"fo"), "MZXQ");
+        assert_eq!(base32_encode(b"foo"), "MZXW6");
+        assert_eq!(base32_encode(b"foob"), "MZXW6YQ");
+        assert_eq!(base32_encode(b"fooba"), "MZXW6YTB");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[test]
+    fn decodes_base32() {
+        for input in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(base32_decode(&base32_encode(input)).unwrap(), input);
+        }
+        assert_eq!(base32_decode("mzxw6ytboi======").unwrap(), b"foobar");
+        assert!(base32_decode("MZXW1").is_none());
+    }
+
+    #[test]
+    fn escapes_provisioning_uri_label() {
+        assert_eq!(
+            provisioning_uri("Pheidippides", "User 1", b"foobar"),
+            "otpauth://totp/Pheidippides:User%201?secret=MZXW6YTBOI&issuer=Pheidippides\
+            &algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
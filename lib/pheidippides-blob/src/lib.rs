@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use thiserror::Error;
+
+use pheidippides_messenger::blob_storage::BlobStorage;
+
+mod s3;
+
+pub use s3::S3BlobStorage;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid blob key: {0:?}")]
+    InvalidKey(String),
+    #[error("Invalid url: {0}")]
+    UrlError(#[from] url::ParseError),
+    #[error("HTTP error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("S3 responded with {status}: {body}")]
+    S3Error {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Thread poisoning error")]
+    ThreadPoisonError,
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_value: PoisonError<T>) -> Self {
+        Self::ThreadPoisonError
+    }
+}
+
+/// Keys are slash separated paths of ascii alphanumerics, dots, dashes and underscores,
+/// so that they can't escape the storage directory or need escaping in urls
+fn validate_key(key: &str) -> Result<(), Error> {
+    let valid = !key.is_empty()
+        && key.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidKey(key.to_owned()))
+    }
+}
+
+/// Keeps every blob in a file under the given directory
+#[derive(Clone)]
+pub struct FsBlobStorage {
+    directory: PathBuf,
+}
+
+impl FsBlobStorage {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        FsBlobStorage {
+            directory: directory.into(),
+        }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        validate_key(key)?;
+        Ok(self.directory.join(key))
+    }
+}
+
+impl BlobStorage for FsBlobStorage {
+    type Error = Error;
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), Self::Error> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, content).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Self::Error> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps blobs in memory. Meant for tests
+#[derive(Clone, Default)]
+pub struct InMemoryBlobStorage {
+    blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl InMemoryBlobStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>, Error> {
+        let mut res: Vec<String> = self.blobs.lock()?.keys().cloned().collect();
+        res.sort();
+        Ok(res)
+    }
+}
+
+impl BlobStorage for InMemoryBlobStorage {
+    type Error = Error;
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), Self::Error> {
+        validate_key(key)?;
+        self.blobs.lock()?.insert(key.to_owned(), content);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.blobs.lock()?.get(key).cloned())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Self::Error> {
+        self.blobs.lock()?.remove(key);
+        Ok(())
+    }
+}
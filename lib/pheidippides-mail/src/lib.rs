@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use thiserror::Error;
+
+use pheidippides_messenger::mailer::{Mail, Mailer};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("SMTP error: {0}")]
+    SmtpError(#[from] lettre::transport::smtp::Error),
+    #[error("Mail building error: {0}")]
+    MailBuildingError(#[from] lettre::error::Error),
+    #[error("Incorrect address: {0}")]
+    AddressError(#[from] lettre::address::AddressError),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Thread poisoning error")]
+    ThreadPoisonError,
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_value: PoisonError<T>) -> Self {
+        Self::ThreadPoisonError
+    }
+}
+
+/// Sends mail through an SMTP relay
+#[derive(Clone)]
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// Connection url format: smtp[s]://[user[:password]@]host[:port][?tls=required|opportunistic]
+    pub fn new(connection_url: &str, from: &str) -> Result<Self, Error> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(connection_url)?.build();
+        let from = from.parse()?;
+        Ok(SmtpMailer { transport, from })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    type Error = Error;
+
+    async fn send_mail(&self, mail: Mail) -> Result<(), Self::Error> {
+        let message = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(mail.to.parse()?)
+            .subject(mail.subject)
+            .body(mail.body)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Writes every mail into a separate file in the given directory. Meant for local development
+#[derive(Clone)]
+pub struct FileMailer {
+    directory: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        FileMailer {
+            directory: directory.into(),
+        }
+    }
+}
+
+impl Mailer for FileMailer {
+    type Error = Error;
+
+    async fn send_mail(&self, mail: Mail) -> Result<(), Self::Error> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let file_name = format!(
+            "{}_{}.txt",
+            chrono::Utc::now().format("%Y%m%d%H%M%S"),
+            uuid::Uuid::new_v4()
+        );
+        let content = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            mail.to, mail.subject, mail.body
+        );
+        tokio::fs::write(self.directory.join(file_name), content).await?;
+        Ok(())
+    }
+}
+
+/// Keeps sent mail in memory so that it can be inspected later. Meant for tests
+#[derive(Clone, Default)]
+pub struct InMemoryMailer {
+    sent_mail: Arc<Mutex<Vec<Mail>>>,
+}
+
+impl InMemoryMailer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sent_mail(&self) -> Result<Vec<Mail>, Error> {
+        Ok(self.sent_mail.lock()?.clone())
+    }
+}
+
+impl Mailer for InMemoryMailer {
+    type Error = Error;
+
+    async fn send_mail(&self, mail: Mail) -> Result<(), Self::Error> {
+        self.sent_mail.lock()?.push(mail);
+        Ok(())
+    }
+}
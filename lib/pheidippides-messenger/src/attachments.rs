@@ -0,0 +1,136 @@
+use std::io::Cursor;
+
+use chrono::{DateTime, Utc};
+use image::io::{Limits, Reader};
+use image::ImageOutputFormat;
+use uuid::Uuid;
+
+use crate::{MessageId, UserId};
+
+pub type AttachmentId = Uuid;
+
+pub const DEFAULT_MAX_ATTACHMENT_SIZE: u64 = 10 * 1024 * 1024;
+/// Thumbnails fit into a square of this side
+pub const THUMBNAIL_SIZE: u32 = 256;
+const MAX_FILE_NAME_LENGTH: usize = 255;
+/// Guards thumbnail generation against decompression bombs
+const MAX_IMAGE_DIMENSION: u32 = 10_000;
+const MAX_IMAGE_ALLOCATION: u64 = 256 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Attachment {
+    pub id: AttachmentId,
+    pub uploader: UserId,
+    /// None until the attachment is sent with a message
+    pub message_id: Option<MessageId>,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub has_thumbnail: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Attachment {
+    /// Blob storage key of the uploaded file
+    pub fn content_key(&self) -> String {
+        format!("attachments/{}", self.id)
+    }
+
+    /// Blob storage key of the PNG thumbnail, only present if `has_thumbnail` is set
+    pub fn thumbnail_key(&self) -> String {
+        format!("thumbnails/{}", self.id)
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.content_type.starts_with("image/")
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct AttachmentLimits {
+    pub max_size: u64,
+    pub allowed_content_types: Vec<String>,
+}
+
+impl Default for AttachmentLimits {
+    fn default() -> Self {
+        let allowed_content_types = [
+            "image/png",
+            "image/jpeg",
+            "image/gif",
+            "image/webp",
+            "application/pdf",
+            "text/plain",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        AttachmentLimits {
+            max_size: DEFAULT_MAX_ATTACHMENT_SIZE,
+            allowed_content_types,
+        }
+    }
+}
+
+impl AttachmentLimits {
+    pub fn allows(&self, content_type: &str, size: u64) -> bool {
+        size <= self.max_size
+            && self
+                .allowed_content_types
+                .iter()
+                .any(|allowed| allowed == content_type)
+    }
+}
+
+/// Checks the magic bytes of the content, so that a declared type can't smuggle in anything else
+pub(crate) fn content_matches_type(content_type: &str, content: &[u8]) -> bool {
+    match content_type {
+        "image/png" => content.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => content.starts_with(b"\xff\xd8\xff"),
+        "image/gif" => content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a"),
+        "image/webp" => {
+            content.len() >= 12 && content.starts_with(b"RIFF") && &content[8..12] == b"WEBP"
+        }
+        "application/pdf" => content.starts_with(b"%PDF-"),
+        "text/plain" => std::str::from_utf8(content).is_ok(),
+        // Types added to the limits by configuration are taken at face value
+        _ => true,
+    }
+}
+
+/// Keeps only the last path component and drops control characters
+pub(crate) fn sanitize_file_name(file_name: &str) -> String {
+    let file_name: String = file_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILE_NAME_LENGTH)
+        .collect();
+    match file_name.trim() {
+        "" | "." | ".." => "attachment".to_owned(),
+        file_name => file_name.to_owned(),
+    }
+}
+
+/// PNG thumbnail of the image, None if the image can't be decoded
+pub(crate) fn make_thumbnail(content: &[u8]) -> Option<Vec<u8>> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_IMAGE_ALLOCATION);
+
+    let mut reader = Reader::new(Cursor::new(content))
+        .with_guessed_format()
+        .ok()?;
+    reader.limits(limits);
+    let image = reader.decode().ok()?;
+
+    let mut res = Cursor::new(Vec::new());
+    image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut res, ImageOutputFormat::Png)
+        .ok()?;
+    Some(res.into_inner())
+}
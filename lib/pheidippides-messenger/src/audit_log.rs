@@ -0,0 +1,115 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use crate::UserId;
+
+pub type AuditEventId = i64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AuditEventKind {
+    Signup,
+    LoginSuccess,
+    LoginFailure,
+    Logout,
+    PasswordChange,
+    SessionRevocation,
+    UsernameChange,
+    AccountDeletion,
+}
+
+impl AuditEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEventKind::Signup => "signup",
+            AuditEventKind::LoginSuccess => "login_success",
+            AuditEventKind::LoginFailure => "login_failure",
+            AuditEventKind::Logout => "logout",
+            AuditEventKind::PasswordChange => "password_change",
+            AuditEventKind::SessionRevocation => "session_revocation",
+            AuditEventKind::UsernameChange => "username_change",
+            AuditEventKind::AccountDeletion => "account_deletion",
+        }
+    }
+}
+
+impl Display for AuditEventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown audit event kind: {0}")]
+pub struct AuditEventKindParsingError(String);
+
+impl FromStr for AuditEventKind {
+    type Err = AuditEventKindParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "signup" => Ok(AuditEventKind::Signup),
+            "login_success" => Ok(AuditEventKind::LoginSuccess),
+            "login_failure" => Ok(AuditEventKind::LoginFailure),
+            "logout" => Ok(AuditEventKind::Logout),
+            "password_change" => Ok(AuditEventKind::PasswordChange),
+            "session_revocation" => Ok(AuditEventKind::SessionRevocation),
+            "username_change" => Ok(AuditEventKind::UsernameChange),
+            "account_deletion" => Ok(AuditEventKind::AccountDeletion),
+            _ => Err(AuditEventKindParsingError(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct AuditEvent {
+    /// Grows with every recorded event
+    pub id: AuditEventId,
+    /// None for failed logins with an unknown username
+    pub user_id: Option<UserId>,
+    pub kind: AuditEventKind,
+    pub client_addr: Option<IpAddr>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Selects events matching all of the specified conditions
+#[derive(Clone, PartialEq, Debug)]
+pub struct AuditEventQuery {
+    pub user_id: Option<UserId>,
+    pub kind: Option<AuditEventKind>,
+    pub client_addr: Option<IpAddr>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only events recorded before the given one, for fetching the next page
+    pub before: Option<AuditEventId>,
+    pub limit: u32,
+}
+
+impl AuditEventQuery {
+    pub fn new(limit: u32) -> Self {
+        AuditEventQuery {
+            user_id: None,
+            kind: None,
+            client_addr: None,
+            since: None,
+            until: None,
+            before: None,
+            limit,
+        }
+    }
+
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.user_id
+            .is_none_or(|user_id| event.user_id == Some(user_id))
+            && self.kind.is_none_or(|kind| event.kind == kind)
+            && self
+                .client_addr
+                .is_none_or(|client_addr| event.client_addr == Some(client_addr))
+            && self.since.is_none_or(|since| event.created_at >= since)
+            && self.until.is_none_or(|until| event.created_at < until)
+            && self.before.is_none_or(|before| event.id < before)
+    }
+}
@@ -1,10 +1,206 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::audit_log::{AuditEvent, AuditEventKind, AuditEventQuery};
 use crate::UserId;
 
 use pheidippides_utils::async_result;
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VerificationResult {
+    Verified,
+    Failed,
+    /// Too many failed attempts, no attempts are accepted until the given moment
+    Locked {
+        until: DateTime<Utc>,
+    },
+}
+
+pub struct TotpEnrollment {
+    /// Base32-encoded secret for manual entry into an authenticator app
+    pub secret: String,
+    /// otpauth:// URI, usually presented as a QR code
+    pub uri: String,
+}
+
+pub type ApiTokenId = Uuid;
+
+/// What a personal API token is allowed to do
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ApiScope {
+    MessagesRead,
+    MessagesWrite,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::MessagesRead => "messages:read",
+            ApiScope::MessagesWrite => "messages:write",
+        }
+    }
+}
+
+impl Display for ApiScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown API scope: {0}")]
+pub struct ApiScopeParsingError(String);
+
+impl FromStr for ApiScope {
+    type Err = ApiScopeParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "messages:read" => Ok(ApiScope::MessagesRead),
+            "messages:write" => Ok(ApiScope::MessagesWrite),
+            _ => Err(ApiScopeParsingError(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ApiToken {
+    pub id: ApiTokenId,
+    pub user_id: UserId,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime<Utc>,
+    /// None if the token never expires
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Login through an external identity provider, e.g. OpenID Connect
+pub struct ExternalLoginStart {
+    /// Where the user is redirected to authenticate
+    pub authorization_url: String,
+    /// Has to be kept until the provider redirects the user back
+    pub pending: PendingExternalLogin,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PendingExternalLogin {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExternalIdentity {
+    pub issuer: String,
+    /// Identifier of the user, unique and never reassigned within the issuer
+    pub subject: String,
+    pub preferred_username: Option<String>,
+}
+
 pub trait AuthService: 'static + Send + Sync + Clone {
     type Error: 'static + std::error::Error + Send + Sync;
 
-    fn verify_user(&self, user_id: &UserId, password: String) -> async_result!(bool);
+    /// Attempts are throttled per user and per client address.
+    /// If the user has a second factor, their failures are only reset once it is verified too
+    fn verify_user(
+        &self,
+        user_id: &UserId,
+        password: String,
+        client_addr: Option<IpAddr>,
+    ) -> async_result!(VerificationResult);
+    /// Registers a login attempt with a username that doesn't exist,
+    /// so that guessing usernames is throttled per client address as well
+    fn register_unknown_user_attempt(
+        &self,
+        client_addr: Option<IpAddr>,
+    ) -> async_result!(VerificationResult);
     fn create_user(&self, user_id: &UserId, password: String) -> async_result!(());
+
+    /// Issues a single-use password reset token for the user.
+    /// Only the returned value can be used to reset the password, the service keeps just its hash
+    fn create_password_reset_token(&self, user_id: &UserId) -> async_result!(String);
+    /// Sets a new password and spends the token.
+    /// Returns the owner of the token, or None if the token is unknown, expired or already used
+    fn reset_password(&self, token: &str, new_password: String) -> async_result!(Option<UserId>);
+
+    fn second_factor_enabled(&self, user_id: &UserId) -> async_result!(bool);
+    /// Generates a new TOTP secret for the user. It replaces any previous enrollment that
+    /// wasn't confirmed yet and isn't required on login until confirmed
+    fn start_totp_enrollment(
+        &self,
+        user_id: &UserId,
+        account_name: &str,
+    ) -> async_result!(TotpEnrollment);
+    /// Enables TOTP if the code matches the pending secret.
+    /// Returns newly generated single-use recovery codes on success
+    fn confirm_totp_enrollment(
+        &self,
+        user_id: &UserId,
+        code: &str,
+    ) -> async_result!(Option<Vec<String>>);
+    /// Accepts either a TOTP code or an unused recovery code. Every code is accepted only once
+    fn verify_second_factor(&self, user_id: &UserId, code: &str) -> async_result!(bool);
+    /// Completes a login that passed the password check. Wrong codes count
+    /// as login failures and attempts are throttled the same way as passwords
+    fn verify_login_second_factor(
+        &self,
+        user_id: &UserId,
+        code: &str,
+        client_addr: Option<IpAddr>,
+    ) -> async_result!(VerificationResult);
+    fn disable_totp(&self, user_id: &UserId) -> async_result!(());
+
+    /// Returns the token along with its secret value.
+    /// Only the returned value can be used for authorization, the service keeps just its hash
+    fn create_api_token(
+        &self,
+        user_id: &UserId,
+        name: &str,
+        scopes: &[ApiScope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> async_result!((ApiToken, String));
+    fn fetch_api_tokens(&self, user_id: &UserId) -> async_result!(Vec<ApiToken>);
+    /// Returns false if the user has no such token
+    fn revoke_api_token(&self, user_id: &UserId, token_id: &ApiTokenId) -> async_result!(bool);
+    /// Returns None if the token is unknown, revoked or expired
+    fn verify_api_token(&self, token: &str) -> async_result!(Option<ApiToken>);
+
+    /// False if users can only log in through an external identity provider
+    fn password_login_enabled(&self) -> bool;
+    fn external_login_enabled(&self) -> bool;
+    /// Returns None if external login isn't enabled
+    fn start_external_login(&self) -> async_result!(Option<ExternalLoginStart>);
+    /// Exchanges the authorization code returned by the identity provider.
+    /// Returns None if the code or the identity provider's response is invalid
+    fn finish_external_login(
+        &self,
+        pending: &PendingExternalLogin,
+        code: &str,
+    ) -> async_result!(Option<ExternalIdentity>);
+    fn fetch_external_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> async_result!(Option<UserId>);
+    /// Returns false if the identity is already linked to a user
+    fn link_external_identity(
+        &self,
+        identity: &ExternalIdentity,
+        user_id: &UserId,
+    ) -> async_result!(bool);
+
+    /// Appends an event to the audit log. Recorded events are never changed
+    fn record_audit_event(
+        &self,
+        user_id: Option<&UserId>,
+        kind: AuditEventKind,
+        client_addr: Option<IpAddr>,
+    ) -> async_result!(());
+    /// Returns matching events, newest first
+    fn fetch_audit_events(&self, query: &AuditEventQuery) -> async_result!(Vec<AuditEvent>);
 }
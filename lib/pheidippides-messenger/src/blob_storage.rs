@@ -0,0 +1,12 @@
+use pheidippides_utils::async_result;
+
+/// Stores attachment contents under opaque keys
+pub trait BlobStorage: 'static + Send + Sync + Clone {
+    type Error: 'static + std::error::Error + Send + Sync;
+
+    fn put(&self, key: &str, content: Vec<u8>) -> async_result!(());
+    /// None if nothing is stored under the key
+    fn get(&self, key: &str) -> async_result!(Option<Vec<u8>>);
+    /// Deleting a missing key is not an error
+    fn delete(&self, key: &str) -> async_result!(());
+}
@@ -0,0 +1,109 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use crate::attachments::AttachmentId;
+use crate::profiles::Profile;
+use crate::{ChatId, ConversationId, User, UserId};
+
+pub const MAX_CHAT_TITLE_LENGTH: usize = 150;
+
+/// Group chat, its members are stored separately
+#[derive(Clone, PartialEq, Debug)]
+pub struct Chat {
+    pub id: ChatId,
+    pub title: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ChatRole {
+    Member,
+    /// Renames the chat and manages members
+    Admin,
+    /// Also manages admins, there is exactly one owner while the chat has members
+    Owner,
+}
+
+impl ChatRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatRole::Member => "member",
+            ChatRole::Admin => "admin",
+            ChatRole::Owner => "owner",
+        }
+    }
+
+    pub fn manages_chat(&self) -> bool {
+        *self >= ChatRole::Admin
+    }
+}
+
+impl Display for ChatRole {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown chat role: {0}")]
+pub struct ChatRoleParsingError(String);
+
+impl FromStr for ChatRole {
+    type Err = ChatRoleParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(ChatRole::Member),
+            "admin" => Ok(ChatRole::Admin),
+            "owner" => Ok(ChatRole::Owner),
+            _ => Err(ChatRoleParsingError(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChatMember {
+    pub user_id: UserId,
+    pub role: ChatRole,
+}
+
+/// An entry of a user's chat list
+#[derive(Clone, PartialEq, Debug)]
+pub struct Conversation {
+    pub id: ConversationId,
+    /// Name of the other user for direct conversations
+    pub title: String,
+    /// Messages of other participants after the user's read marker
+    pub unread_count: u64,
+    /// Messages are delivered, but the user isn't notified of them
+    pub muted: bool,
+    /// Avatar of the other user of a direct conversation
+    pub avatar: Option<AttachmentId>,
+}
+
+impl From<User> for Conversation {
+    fn from(user: User) -> Self {
+        Conversation {
+            id: ConversationId::Direct(user.id),
+            title: user.username,
+            unread_count: 0,
+            muted: false,
+            avatar: None,
+        }
+    }
+}
+
+impl From<Profile> for Conversation {
+    fn from(profile: Profile) -> Self {
+        Conversation {
+            id: ConversationId::Direct(profile.user_id),
+            title: profile.name().to_owned(),
+            unread_count: 0,
+            muted: false,
+            avatar: profile.avatar,
+        }
+    }
+}
@@ -1,7 +1,16 @@
-use crate::{Message, MessageId, User, UserId};
+use crate::attachments::{Attachment, AttachmentId};
+use crate::chats::{Chat, ChatMember, ChatRole, Conversation};
+use crate::notifications::{Notification, NotificationCursor, NotificationId};
+use crate::pins::PinnedMessage;
+use crate::profiles::Profile;
+use crate::scheduled::{ScheduledMessage, ScheduledMessageId};
+use crate::search::{MessageSearchQuery, MessageSearchResult};
+use crate::stars::{StarCursor, StarredMessage};
+use crate::{ChatId, ConversationId, Message, MessageId, MessageRevision, User, UserId};
 
 pub const MESSAGE_LOAD_BUF_SIZE: i32 = 50;
 
+use chrono::{DateTime, TimeDelta, Utc};
 use pheidippides_utils::async_result;
 
 pub trait DataAccess: 'static + Send + Sync + Clone {
@@ -64,19 +73,242 @@ pub trait DataAccess: 'static + Send + Sync + Clone {
         }
     }
     fn create_user(&self, username: &str) -> async_result!(Option<UserId>);
+    fn fetch_user_email(&self, user_id: &UserId) -> async_result!(Option<String>);
+    fn update_user_email(&self, user_id: &UserId, email: Option<&str>) -> async_result!(());
+    fn fetch_profile(&self, user_id: &UserId) -> async_result!(Option<Profile>);
+    fn update_profile(
+        &self,
+        user_id: &UserId,
+        display_name: Option<&str>,
+        bio: &str,
+    ) -> async_result!(());
+    fn update_avatar(&self, user_id: &UserId, avatar: Option<&AttachmentId>) -> async_result!(());
+    /// Returns false if another user has the username, compared case-insensitively
+    fn update_username(&self, user_id: &UserId, username: &str) -> async_result!(bool);
+    /// Atomically creates `replacement` and hands the user's messages, their sent attachments and
+    /// the conversations with them over to it. Everything else of the user is erased: credentials,
+    /// profile, memberships, reactions and unsent attachments, the latter are returned.
+    /// Ownership of the user's group chats passes to an admin or, if there is none, a member
+    fn delete_user(
+        &self,
+        user_id: &UserId,
+        replacement: &Profile,
+    ) -> async_result!(Vec<Attachment>);
+    /// Does nothing if the user is already blocked
+    fn block_user(
+        &self,
+        user_id: &UserId,
+        blocked_user_id: &UserId,
+        created_at: DateTime<Utc>,
+    ) -> async_result!(());
+    fn unblock_user(&self, user_id: &UserId, blocked_user_id: &UserId) -> async_result!(());
+    /// Users blocked by the user, in the order of blocking
+    fn fetch_blocked_users(&self, user_id: &UserId) -> async_result!(Vec<UserId>);
+    /// Users who have blocked the user
+    fn fetch_users_blocking(&self, user_id: &UserId) -> async_result!(Vec<UserId>);
 
-    fn find_users_chats(&self, user_id: &UserId) -> async_result!(Vec<User>);
+    /// Direct conversations and group chats of the user, most recently active first.
+    /// Unread counts skip hidden and deleted messages
+    fn find_users_chats(&self, user_id: &UserId) -> async_result!(Vec<Conversation>);
 
+    /// Skips messages the user has hidden, tombstones of deleted messages are included
     fn fetch_last_messages_in_chat(
         &self,
-        user_id_1: &UserId,
-        user_id_2: &UserId,
+        user_id: &UserId,
+        conversation: &ConversationId,
         starting_point: Option<&MessageId>,
     ) -> async_result!(Vec<Message>);
+    /// Includes messages of group chats the user is currently a member of,
+    /// skips messages the user has hidden
     fn fetch_users_messages_since(
         &self,
         user_id: &UserId,
         starting_point: &MessageId,
     ) -> async_result!(Vec<Message>);
+    /// Also links the message's attachments to it
     fn create_message(&self, message: &Message) -> async_result!(());
+    fn fetch_message(&self, message_id: &MessageId) -> async_result!(Option<Message>);
+    /// Replaces the text of the message, keeping the previous one as a revision
+    fn edit_message(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        edited_at: DateTime<Utc>,
+    ) -> async_result!(());
+    /// Oldest first, the current text is not included
+    fn fetch_message_revisions(
+        &self,
+        message_id: &MessageId,
+    ) -> async_result!(Vec<MessageRevision>);
+    /// Turns the message into a tombstone: its text, revisions, attachments, reactions,
+    /// pins and stars are erased
+    fn delete_message(
+        &self,
+        message_id: &MessageId,
+        deleted_at: DateTime<Utc>,
+    ) -> async_result!(());
+    /// Does nothing if the user has already reacted with the emoji
+    fn add_reaction(
+        &self,
+        message_id: &MessageId,
+        user_id: &UserId,
+        emoji: &str,
+        created_at: DateTime<Utc>,
+    ) -> async_result!(());
+    fn remove_reaction(
+        &self,
+        message_id: &MessageId,
+        user_id: &UserId,
+        emoji: &str,
+    ) -> async_result!(());
+    /// Does nothing if the message is already pinned
+    fn pin_message(
+        &self,
+        message_id: &MessageId,
+        pinned_by: &UserId,
+        pinned_at: DateTime<Utc>,
+    ) -> async_result!(());
+    fn unpin_message(&self, message_id: &MessageId) -> async_result!(());
+    /// Pins of the conversation as seen by the user, most recently pinned first.
+    /// Messages hidden by the user are skipped
+    fn fetch_pinned_messages(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> async_result!(Vec<PinnedMessage>);
+    /// Does nothing if the user has already starred the message
+    fn star_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+        starred_at: DateTime<Utc>,
+    ) -> async_result!(());
+    fn unstar_message(&self, user_id: &UserId, message_id: &MessageId) -> async_result!(());
+    /// Messages starred by the user in the conversations they take part in, skipping hidden ones.
+    /// Most recently starred first, at most `limit` after the cursor
+    fn fetch_starred_messages(
+        &self,
+        user_id: &UserId,
+        cursor: Option<&StarCursor>,
+        limit: u32,
+    ) -> async_result!(Vec<StarredMessage>);
+    /// Hides the message from the user's own view only
+    fn hide_message(&self, user_id: &UserId, message_id: &MessageId) -> async_result!(());
+    /// Messages containing every word of the query in the conversations the user takes part in,
+    /// skipping hidden and deleted ones. Ordered by rank, then newest first, at most `query.limit`
+    fn search_messages(
+        &self,
+        user_id: &UserId,
+        query: &MessageSearchQuery,
+    ) -> async_result!(Vec<MessageSearchResult>);
+
+    /// The last message the user has read in the conversation as seen by them
+    fn fetch_read_marker(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> async_result!(Option<MessageId>);
+    fn update_read_marker(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        message_id: &MessageId,
+    ) -> async_result!(());
+    /// The conversation is as seen by the user
+    fn update_conversation_muted(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        muted: bool,
+    ) -> async_result!(());
+    /// The conversation is as seen by the user
+    fn is_conversation_muted(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> async_result!(bool);
+    /// Timer of disappearing messages in the conversation, which is as seen by the user.
+    /// Both participants of a direct conversation share it
+    fn fetch_message_ttl(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> async_result!(Option<TimeDelta>);
+    fn update_message_ttl(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        ttl: Option<TimeDelta>,
+    ) -> async_result!(());
+    fn create_scheduled_message(&self, scheduled: &ScheduledMessage) -> async_result!(());
+    /// Messages scheduled by the user, the earliest to be sent first
+    fn fetch_scheduled_messages(&self, user_id: &UserId) -> async_result!(Vec<ScheduledMessage>);
+    /// Up to `limit` messages of all users due by `now`, the earliest to be sent first.
+    /// A message whose delivery failed is due at its `retry_at`
+    fn fetch_due_scheduled_messages(
+        &self,
+        now: DateTime<Utc>,
+        limit: u32,
+    ) -> async_result!(Vec<ScheduledMessage>);
+    /// Counts a failed delivery of the message and postpones the next one until `retry_at`
+    fn postpone_scheduled_message(
+        &self,
+        scheduled_message_id: &ScheduledMessageId,
+        retry_at: DateTime<Utc>,
+    ) -> async_result!(());
+    /// Returns false if the user has no such scheduled message
+    fn delete_scheduled_message(
+        &self,
+        user_id: &UserId,
+        scheduled_message_id: &ScheduledMessageId,
+    ) -> async_result!(bool);
+    /// Users mentioned in the message
+    fn fetch_mentions(&self, message_id: &MessageId) -> async_result!(Vec<UserId>);
+    /// Users already mentioned in the message are skipped
+    fn create_mentions(&self, message_id: &MessageId, user_ids: &[UserId]) -> async_result!(());
+    fn create_notification(&self, notification: &Notification) -> async_result!(());
+    /// The user's notifications, newest first, at most `limit` after the cursor
+    fn fetch_notifications(
+        &self,
+        user_id: &UserId,
+        cursor: Option<&NotificationCursor>,
+        limit: u32,
+    ) -> async_result!(Vec<Notification>);
+    fn count_unread_notifications(&self, user_id: &UserId) -> async_result!(u32);
+    /// Marks the user's unread notification as read or, if `notification_id` is None,
+    /// all of them. Returns the number of notifications marked
+    fn mark_notifications_read(
+        &self,
+        user_id: &UserId,
+        notification_id: Option<&NotificationId>,
+        read_at: DateTime<Utc>,
+    ) -> async_result!(u64);
+    /// Deletes up to `limit` messages that expired by `now` or were sent before `sent_before`,
+    /// together with everything referring to them. Returns the deleted messages with their attachments
+    fn purge_expired_messages(
+        &self,
+        now: DateTime<Utc>,
+        sent_before: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> async_result!(Vec<Message>);
+
+    /// Creates the chat with `owner` as its only member
+    fn create_chat(&self, chat: &Chat, owner: &UserId) -> async_result!(());
+    fn fetch_chat(&self, chat_id: &ChatId) -> async_result!(Option<Chat>);
+    fn rename_chat(&self, chat_id: &ChatId, title: &str) -> async_result!(());
+    /// Ordered by the time of joining
+    fn fetch_chat_members(&self, chat_id: &ChatId) -> async_result!(Vec<ChatMember>);
+    /// Adds the user to the chat or changes their role
+    fn update_chat_member(
+        &self,
+        chat_id: &ChatId,
+        user_id: &UserId,
+        role: ChatRole,
+    ) -> async_result!(());
+    fn delete_chat_member(&self, chat_id: &ChatId, user_id: &UserId) -> async_result!(());
+
+    fn create_attachment(&self, attachment: &Attachment) -> async_result!(());
+    fn fetch_attachment(&self, attachment_id: &AttachmentId) -> async_result!(Option<Attachment>);
+    /// Attachments uploaded by the user, sent or not, oldest first
+    fn fetch_users_attachments(&self, user_id: &UserId) -> async_result!(Vec<Attachment>);
 }
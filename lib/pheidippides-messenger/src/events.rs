@@ -0,0 +1,47 @@
+use crate::notifications::Notification;
+use crate::presence::Presence;
+use crate::{ConversationId, Message, MessageId, UserId};
+
+/// Message events are delivered to subscribers among the participants of the message's conversation
+#[derive(Clone, PartialEq, Debug)]
+pub enum Event {
+    NewMessage(Message),
+    MessageEdited(Message),
+    /// Carries the tombstone of a message deleted for everyone
+    MessageDeleted(Message),
+    /// Carries a disappearing message or one past the retention period, purged for good
+    MessageExpired(Message),
+    /// Carries the message with its updated reactions
+    ReactionsChanged(Message),
+    MessagePinned {
+        pinned_by: UserId,
+        message: Message,
+    },
+    MessageUnpinned {
+        unpinned_by: UserId,
+        message: Message,
+    },
+    /// The reader has read the message's conversation up to and including the message
+    MessagesRead {
+        reader: UserId,
+        message: Message,
+    },
+    /// Delivered to the user who hid the message only
+    MessageHidden {
+        user_id: UserId,
+        message_id: MessageId,
+    },
+    /// Delivered to the other participants of the conversation, which is as seen by the typist.
+    /// Not persisted, repeated events are throttled
+    Typing {
+        user_id: UserId,
+        conversation: ConversationId,
+    },
+    /// Delivered to the users sharing a conversation with the user
+    PresenceChanged {
+        user_id: UserId,
+        presence: Presence,
+    },
+    /// Delivered to the owner of the notification feed only
+    Notification(Notification),
+}
@@ -0,0 +1,23 @@
+use crate::attachments::Attachment;
+use crate::audit_log::AuditEvent;
+use crate::authorization::ApiToken;
+use crate::chats::Conversation;
+use crate::profiles::Profile;
+use crate::{Message, UserId};
+
+/// Everything stored about a user, for them to download.
+/// Contents of the attachments are fetched separately
+#[derive(Clone, PartialEq, Debug)]
+pub struct UserDataExport {
+    pub profile: Profile,
+    pub email: Option<String>,
+    pub conversations: Vec<Conversation>,
+    /// Messages of the user's conversations that they can see, oldest first
+    pub messages: Vec<Message>,
+    pub blocked_users: Vec<UserId>,
+    pub api_tokens: Vec<ApiToken>,
+    /// Newest first
+    pub audit_events: Vec<AuditEvent>,
+    /// Uploaded by the user, sent or not
+    pub attachments: Vec<Attachment>,
+}
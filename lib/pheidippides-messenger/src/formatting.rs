@@ -0,0 +1,438 @@
+const FENCE: &str = "```";
+/// Schemes of the URLs links may point to, compared case-insensitively
+const LINK_SCHEMES: &[&str] = &["http://", "https://", "mailto:"];
+const AUTOLINK_SCHEMES: &[&str] = &["http://", "https://"];
+const LINK_ATTRIBUTES: &str = r#" rel="noopener noreferrer nofollow" target="_blank""#;
+const MAX_LANGUAGE_LENGTH: usize = 20;
+/// Characters that may precede a bare URL besides whitespace
+const OPENING_PUNCTUATION: &[char] = &['(', '[', '{', '«', '"', '\'', '*', '_', '|'];
+/// Characters stripped from the end of a bare URL
+const TRAILING_PUNCTUATION: &[char] = &[
+    '.', ',', '!', '?', ':', ';', ')', ']', '}', '»', '"', '\'', '*', '_', '|',
+];
+
+/// Renders the markdown-lite source of a message as HTML that is safe to insert into a page.
+/// Supports `**bold**`, `*italic*` or `_italic_`, `` `code` ``, code blocks fenced with
+/// ```` ``` ````, `[links](https://example.com)`, bare `https://` links and `||spoilers||`.
+/// Markers are escaped with a backslash. Everything else, HTML included, is shown as is
+pub fn render_html(text: &str) -> String {
+    let text = text.replace("\r\n", "\n");
+    let mut html = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    let mut after_block = false;
+    while let Some(start) = rest.find(FENCE) {
+        let Some(length) = rest[start + FENCE.len()..].find(FENCE) else {
+            break;
+        };
+        let before = &rest[..start];
+        let before = before.strip_suffix('\n').unwrap_or(before);
+        let before = match after_block {
+            true => before.strip_prefix('\n').unwrap_or(before),
+            false => before,
+        };
+        render_inline(before, true, &mut html);
+        render_code_block(&rest[start + FENCE.len()..][..length], &mut html);
+        rest = &rest[start + FENCE.len() + length + FENCE.len()..];
+        after_block = true;
+    }
+    let rest = match after_block {
+        true => rest.strip_prefix('\n').unwrap_or(rest),
+        false => rest,
+    };
+    render_inline(rest, true, &mut html);
+    html
+}
+
+/// The first line of the block names its language if it's a single word
+fn render_code_block(code: &str, html: &mut String) {
+    let language = code.split_once('\n').and_then(|(first_line, body)| {
+        let is_language = !first_line.is_empty()
+            && first_line.len() <= MAX_LANGUAGE_LENGTH
+            && first_line
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+#-_.".contains(c));
+        is_language.then_some((first_line, body))
+    });
+    let code = match language {
+        Some((language, body)) => {
+            html.push_str(r#"<pre><code class="language-"#);
+            escape_into(language, html);
+            html.push_str(r#"">"#);
+            body
+        }
+        None => {
+            html.push_str("<pre><code>");
+            code.strip_prefix('\n').unwrap_or(code)
+        }
+    };
+    escape_into(code.strip_suffix('\n').unwrap_or(code), html);
+    html.push_str("</code></pre>");
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Style {
+    /// `**`
+    Strong,
+    /// `*`
+    Emphasis,
+    /// `_`, a separate style so that it's never closed by `*`
+    Underscore,
+    /// `||`
+    Spoiler,
+}
+
+impl Style {
+    const COUNT: usize = 4;
+
+    fn marker(&self) -> &'static str {
+        match self {
+            Style::Strong => "**",
+            Style::Emphasis => "*",
+            Style::Underscore => "_",
+            Style::Spoiler => "||",
+        }
+    }
+
+    fn open_tag(&self) -> &'static str {
+        match self {
+            Style::Strong => "<strong>",
+            Style::Emphasis | Style::Underscore => "<em>",
+            Style::Spoiler => r#"<span class="spoiler">"#,
+        }
+    }
+
+    fn close_tag(&self) -> &'static str {
+        match self {
+            Style::Strong => "</strong>",
+            Style::Emphasis | Style::Underscore => "</em>",
+            Style::Spoiler => "</span>",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Token<'a> {
+    Text(&'a str),
+    Code(&'a str),
+    /// Bare URLs have no label and show the URL itself
+    Link {
+        href: &'a str,
+        label: Option<&'a str>,
+    },
+    LineBreak,
+    /// A marker not yet matched with its pair, shown as is if it never is
+    Delimiter {
+        style: Style,
+        can_open: bool,
+        can_close: bool,
+    },
+    Open(Style),
+    Close(Style),
+}
+
+fn render_inline(text: &str, links: bool, html: &mut String) {
+    let mut tokens = tokenize(text, links);
+    match_delimiters(&mut tokens);
+    for token in tokens {
+        match token {
+            Token::Text(text) => escape_into(text, html),
+            Token::Code(code) => {
+                html.push_str("<code>");
+                escape_into(code, html);
+                html.push_str("</code>");
+            }
+            Token::Link { href, label } => {
+                html.push_str(r#"<a href=""#);
+                escape_into(href, html);
+                html.push('"');
+                html.push_str(LINK_ATTRIBUTES);
+                html.push('>');
+                match label {
+                    Some(label) => render_inline(label, false, html),
+                    None => escape_into(href, html),
+                }
+                html.push_str("</a>");
+            }
+            Token::LineBreak => html.push_str("<br>"),
+            Token::Delimiter { style, .. } => escape_into(style.marker(), html),
+            Token::Open(style) => html.push_str(style.open_tag()),
+            Token::Close(style) => html.push_str(style.close_tag()),
+        }
+    }
+}
+
+/// Splits the text into tokens in a single pass, so that rendering takes linear time
+/// whatever the text is
+fn tokenize(text: &str, links: bool) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    // Lengths of backtick runs no later run matches, so that they aren't searched for again
+    let mut unmatched_code_runs = vec![];
+    let mut text_start = 0;
+    let mut index = 0;
+    while let Some(c) = text[index..].chars().next() {
+        let previous = text[..index].chars().next_back();
+        let pending = &text[text_start..index];
+        match c {
+            '\\' => match text[index + 1..].chars().next() {
+                Some(escaped) if escaped.is_ascii_punctuation() => {
+                    push(
+                        &mut tokens,
+                        pending,
+                        Token::Text(&text[index + 1..index + 2]),
+                    );
+                    index += 2;
+                    text_start = index;
+                    continue;
+                }
+                _ => {}
+            },
+            '\n' => {
+                push(&mut tokens, pending, Token::LineBreak);
+                index += 1;
+                text_start = index;
+                continue;
+            }
+            '`' => {
+                let run = run_length(&text[index..], '`');
+                let code_start = index + run;
+                if !unmatched_code_runs.contains(&run) {
+                    match find_run(&text[code_start..], '`', run) {
+                        Some(length) => {
+                            push(
+                                &mut tokens,
+                                pending,
+                                Token::Code(&text[code_start..][..length]),
+                            );
+                            index = code_start + length + run;
+                            text_start = index;
+                            continue;
+                        }
+                        None => unmatched_code_runs.push(run),
+                    }
+                }
+                index = code_start;
+                continue;
+            }
+            '*' | '_' | '|' => {
+                let run = run_length(&text[index..], c);
+                let next = text[index + run..].chars().next();
+                let delimiters = delimiters(c, run, previous, next);
+                if !delimiters.is_empty() {
+                    if !pending.is_empty() {
+                        tokens.push(Token::Text(pending));
+                    }
+                    tokens.extend(delimiters);
+                    text_start = index + run;
+                }
+                index += run;
+                continue;
+            }
+            '[' if links => {
+                if let Some((length, href, label)) = parse_link(&text[index..]) {
+                    let link = Token::Link {
+                        href,
+                        label: Some(label),
+                    };
+                    push(&mut tokens, pending, link);
+                    index += length;
+                    text_start = index;
+                    continue;
+                }
+            }
+            'h' | 'H'
+                if links
+                    && previous
+                        .is_none_or(|p| p.is_whitespace() || OPENING_PUNCTUATION.contains(&p)) =>
+            {
+                if let Some(href) = parse_bare_url(&text[index..]) {
+                    push(&mut tokens, pending, Token::Link { href, label: None });
+                    index += href.len();
+                    text_start = index;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        index += c.len_utf8();
+    }
+    if text_start < text.len() {
+        tokens.push(Token::Text(&text[text_start..]));
+    }
+    tokens
+}
+
+/// Pushes the token after the plain text preceding it
+fn push<'a>(tokens: &mut Vec<Token<'a>>, pending: &'a str, token: Token<'a>) {
+    if !pending.is_empty() {
+        tokens.push(Token::Text(pending));
+    }
+    tokens.push(token);
+}
+
+fn run_length(text: &str, c: char) -> usize {
+    text.len() - text.trim_start_matches(c).len()
+}
+
+/// Offset of the first run of exactly `length` characters `c`
+fn find_run(text: &str, c: char, length: usize) -> Option<usize> {
+    let mut index = 0;
+    while let Some(offset) = text[index..].find(c) {
+        let start = index + offset;
+        let run = run_length(&text[start..], c);
+        if run == length {
+            return Some(start);
+        }
+        index = start + run;
+    }
+    None
+}
+
+fn is_punctuation(c: char) -> bool {
+    !c.is_alphanumeric() && !c.is_whitespace()
+}
+
+/// Delimiters of a run of markers, outermost first. Empty if the run is plain text
+fn delimiters<'a>(
+    marker: char,
+    run: usize,
+    previous: Option<char>,
+    next: Option<char>,
+) -> Vec<Token<'a>> {
+    // A run opens a span when it's followed by text, and closes one when it follows text
+    let left_flanking = next.is_some_and(|n| !n.is_whitespace())
+        && (!next.is_some_and(is_punctuation)
+            || previous.is_none_or(|p| p.is_whitespace() || is_punctuation(p)));
+    let right_flanking = previous.is_some_and(|p| !p.is_whitespace())
+        && (!previous.is_some_and(is_punctuation)
+            || next.is_none_or(|n| n.is_whitespace() || is_punctuation(n)));
+    let (mut can_open, mut can_close) = (left_flanking, right_flanking);
+    let styles = match (marker, run) {
+        ('_', 1) => {
+            // Underscores inside words, like in snake_case, are plain text
+            can_open = left_flanking && (!right_flanking || previous.is_some_and(is_punctuation));
+            can_close = right_flanking && (!left_flanking || next.is_some_and(is_punctuation));
+            vec![Style::Underscore]
+        }
+        ('*', _) => {
+            let mut styles = vec![Style::Strong; run / 2];
+            if run % 2 == 1 {
+                // The innermost span is closed first
+                match right_flanking && !left_flanking {
+                    true => styles.insert(0, Style::Emphasis),
+                    false => styles.push(Style::Emphasis),
+                }
+            }
+            styles
+        }
+        ('|', 2..) => vec![Style::Spoiler; run / 2],
+        _ => return vec![],
+    };
+    let mut tokens: Vec<Token<'a>> = styles
+        .into_iter()
+        .map(|style| Token::Delimiter {
+            style,
+            can_open,
+            can_close,
+        })
+        .collect();
+    if marker == '|' && run % 2 == 1 {
+        tokens.push(Token::Text("|"));
+    }
+    tokens
+}
+
+/// Pairs openers with the nearest closers of the same style. Openers between a pair
+/// stay unmatched, so that the tags are always properly nested
+fn match_delimiters(tokens: &mut [Token<'_>]) {
+    // Indices of the tokens of unmatched openers, innermost last
+    let mut openers: Vec<usize> = vec![];
+    // Positions in `openers` of each style, so that a closer finds its opener at once
+    let mut positions: [Vec<usize>; Style::COUNT] = Default::default();
+    for index in 0..tokens.len() {
+        let Token::Delimiter {
+            style,
+            can_open,
+            can_close,
+        } = tokens[index]
+        else {
+            continue;
+        };
+        if can_close {
+            if let Some(position) = positions[style as usize].pop() {
+                tokens[openers[position]] = Token::Open(style);
+                tokens[index] = Token::Close(style);
+                openers.truncate(position);
+                for positions in positions.iter_mut() {
+                    while positions.last().is_some_and(|p| *p >= position) {
+                        positions.pop();
+                    }
+                }
+                continue;
+            }
+        }
+        if can_open {
+            positions[style as usize].push(openers.len());
+            openers.push(index);
+        }
+    }
+}
+
+/// `[label](url)` at the start of the text: its length, the URL and the label.
+/// None unless the URL is one links may point to
+fn parse_link(text: &str) -> Option<(usize, &str, &str)> {
+    let label_end = text[1..].find(['[', ']', '\n'])? + 1;
+    let label = &text[1..label_end];
+    let rest = text[label_end..].strip_prefix("](")?;
+    // URLs end before the next `[`, so that no text is scanned for links more than twice
+    let url_end = rest.find(|c: char| c == ')' || c == '[' || c.is_whitespace())?;
+    let url = &rest[..url_end];
+    if label.is_empty() || !rest[url_end..].starts_with(')') || !is_safe_url(url, LINK_SCHEMES) {
+        return None;
+    }
+    Some((label_end + 2 + url_end + 1, url, label))
+}
+
+/// An `http://` or `https://` URL at the start of the text, without trailing punctuation
+fn parse_bare_url(text: &str) -> Option<&str> {
+    let end = text
+        .find(|c: char| c.is_whitespace() || c.is_control() || c == '<' || c == '>')
+        .unwrap_or(text.len());
+    let mut url = &text[..end];
+    // Closing parentheses belong to the URL while they pair with opening ones
+    let opening = url.matches('(').count();
+    let mut closing = url.matches(')').count();
+    while let Some(c) = url.chars().next_back() {
+        if !TRAILING_PUNCTUATION.contains(&c) || (c == ')' && closing <= opening) {
+            break;
+        }
+        if c == ')' {
+            closing -= 1;
+        }
+        url = &url[..url.len() - c.len_utf8()];
+    }
+    is_safe_url(url, AUTOLINK_SCHEMES).then_some(url)
+}
+
+/// Whether the URL has one of the schemes and something after it. Other schemes,
+/// such as `javascript:` or `data:`, are never linked
+fn is_safe_url(url: &str, schemes: &[&str]) -> bool {
+    schemes.iter().any(|scheme| {
+        url.len() > scheme.len()
+            && url
+                .get(..scheme.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+    }) && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn escape_into(text: &str, html: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+}
@@ -1,14 +1,62 @@
+use crate::attachments::{Attachment, AttachmentId};
 use crate::data_access::DataAccess;
+use crate::reactions::Reaction;
 use chrono::DateTime;
 use uuid::Uuid;
 
+pub mod attachments;
+pub mod audit_log;
 pub mod authorization;
+pub mod blob_storage;
+pub mod chats;
 pub mod data_access;
+pub mod events;
+pub mod export;
+pub mod formatting;
+pub mod mailer;
+pub mod mentions;
 pub mod messenger;
+pub mod notifications;
+pub mod pins;
+pub mod presence;
+pub mod profiles;
+pub mod reactions;
+pub mod retention;
+pub mod scheduled;
+pub mod search;
+pub mod stars;
 mod subscriptions_handler;
 
 pub type MessageId = Uuid;
 pub type UserId = Uuid;
+pub type ChatId = Uuid;
+
+/// A conversation as seen by one of its participants
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ConversationId {
+    /// One-to-one conversation with another user
+    Direct(UserId),
+    Group(ChatId),
+}
+
+impl ConversationId {
+    pub fn uuid(&self) -> &Uuid {
+        match self {
+            ConversationId::Direct(user_id) => user_id,
+            ConversationId::Group(chat_id) => chat_id,
+        }
+    }
+
+    pub fn is_group(&self) -> bool {
+        matches!(self, ConversationId::Group(_))
+    }
+}
+
+impl std::fmt::Display for ConversationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.uuid().fmt(f)
+    }
+}
 
 #[derive(PartialEq, Hash, Debug)]
 pub struct User {
@@ -26,7 +74,84 @@ impl User {
 pub struct Message {
     pub id: MessageId,
     pub from: UserId,
-    pub to: UserId,
+    /// The conversation as seen by the sender
+    pub to: ConversationId,
     pub message: String,
     pub timestamp: DateTime<chrono::Utc>,
+    /// Time of the latest edit
+    pub edited_at: Option<DateTime<chrono::Utc>>,
+    /// Messages deleted for everyone are kept as tombstones without text
+    pub deleted_at: Option<DateTime<chrono::Utc>>,
+    /// Disappearing messages are purged after this time
+    pub expires_at: Option<DateTime<chrono::Utc>>,
+    pub attachments: Vec<Attachment>,
+    /// The earlier message of the same conversation this one replies to
+    pub reply_to: Option<MessageId>,
+    /// Set for copies of messages forwarded from another conversation
+    pub forwarded_from: Option<ForwardedFrom>,
+    pub reactions: Vec<Reaction>,
+}
+
+/// Author and send time of the original of a forwarded message,
+/// kept when the copy is forwarded further
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ForwardedFrom {
+    pub from: UserId,
+    pub timestamp: DateTime<chrono::Utc>,
+}
+
+impl Message {
+    /// The conversation this message belongs to as seen by `user_id`
+    pub fn conversation_for(&self, user_id: &UserId) -> ConversationId {
+        match self.to {
+            ConversationId::Direct(to) if &to == user_id => ConversationId::Direct(self.from),
+            to => to,
+        }
+    }
+
+    /// Provenance of a copy of this message
+    pub fn forwarded_from(&self) -> ForwardedFrom {
+        self.forwarded_from.unwrap_or(ForwardedFrom {
+            from: self.from,
+            timestamp: self.timestamp,
+        })
+    }
+
+    pub fn has_reaction(&self, user_id: &UserId, emoji: &str) -> bool {
+        self.reactions
+            .iter()
+            .any(|reaction| reaction.emoji == emoji && reaction.users.contains(user_id))
+    }
+}
+
+/// What a user sends, the rest of the message is filled in when it's sent
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct OutgoingMessage {
+    pub text: String,
+    pub attachments: Vec<AttachmentId>,
+    pub reply_to: Option<MessageId>,
+}
+
+impl OutgoingMessage {
+    pub fn new(text: impl Into<String>) -> Self {
+        OutgoingMessage {
+            text: text.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Text of a message before one of its edits
+#[derive(Clone, PartialEq, Debug)]
+pub struct MessageRevision {
+    pub message: String,
+    /// When this text was sent or put in place by an earlier edit
+    pub created_at: DateTime<chrono::Utc>,
+}
+
+/// The user has read the conversation up to and including the message
+#[derive(Clone, PartialEq, Debug)]
+pub struct ReadReceipt {
+    pub user_id: UserId,
+    pub message_id: MessageId,
 }
@@ -0,0 +1,14 @@
+use pheidippides_utils::async_result;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: 'static + Send + Sync + Clone {
+    type Error: 'static + std::error::Error + Send + Sync;
+
+    fn send_mail(&self, mail: Mail) -> async_result!(());
+}
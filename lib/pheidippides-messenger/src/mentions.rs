@@ -0,0 +1,43 @@
+/// Mentions of a message beyond this many are ignored
+pub const MAX_MENTIONS: usize = 20;
+
+/// Characters that end a mention when no user has them at the end of the username
+const TRAILING_PUNCTUATION: &[char] =
+    &['.', ',', '!', '?', ':', ';', ')', ']', '}', '»', '"', '\''];
+/// Characters that may precede a mention besides whitespace
+const OPENING_PUNCTUATION: &[char] = &['(', '[', '{', '«', '"', '\''];
+
+/// Usernames that each `@username` mention of the text may refer to, longest first:
+/// "@bob!" mentions the user "bob!" if there is one, otherwise "bob".
+/// `@` in the middle of a word, like in e-mail addresses, is not a mention
+pub fn mention_candidates(text: &str) -> Vec<Vec<&str>> {
+    let mut mentions = vec![];
+    let mut previous = None;
+    for (index, c) in text.char_indices() {
+        let starts_word =
+            previous.is_none_or(|p: char| p.is_whitespace() || OPENING_PUNCTUATION.contains(&p));
+        previous = Some(c);
+        if c != '@' || !starts_word {
+            continue;
+        }
+
+        let rest = &text[index + c.len_utf8()..];
+        let word = rest.split(char::is_whitespace).next().unwrap_or_default();
+        let mut candidates = vec![];
+        let mut candidate = word;
+        while !candidate.is_empty() && !candidate.starts_with('@') {
+            candidates.push(candidate);
+            match candidate.strip_suffix(TRAILING_PUNCTUATION) {
+                Some(stripped) => candidate = stripped,
+                None => break,
+            }
+        }
+        if !candidates.is_empty() {
+            mentions.push(candidates);
+        }
+        if mentions.len() == MAX_MENTIONS {
+            break;
+        }
+    }
+    mentions
+}
@@ -1,33 +1,134 @@
-use anyhow::{Context, Result};
+use std::net::IpAddr;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SubsecRound, TimeDelta, Utc};
 use pheidippides_utils::utils::log_internal_error;
 use tokio::sync::mpsc;
+use uuid::Uuid;
 
-use crate::authorization::AuthService;
+use crate::attachments::{self, Attachment, AttachmentId, AttachmentLimits};
+use crate::audit_log::{AuditEvent, AuditEventId, AuditEventKind, AuditEventQuery};
+use crate::authorization::{
+    ApiScope, ApiToken, ApiTokenId, AuthService, ExternalIdentity, ExternalLoginStart,
+    PendingExternalLogin, TotpEnrollment, VerificationResult,
+};
+use crate::blob_storage::BlobStorage;
+use crate::chats::{Chat, ChatMember, ChatRole, Conversation};
 use crate::data_access::DataAccess;
+use crate::events::Event;
+use crate::export::UserDataExport;
+use crate::mailer::{Mail, Mailer};
+use crate::mentions;
+use crate::notifications::{
+    self, Notification, NotificationCursor, NotificationId, NotificationKind, NotificationPage,
+};
+use crate::pins::PinnedMessage;
+use crate::presence::Presence;
+use crate::profiles::{self, Profile, MAX_BIO_LENGTH, MAX_DISPLAY_NAME_LENGTH};
+use crate::reactions;
+use crate::retention::{self, PURGE_BATCH_SIZE};
+use crate::scheduled::{self, ScheduledMessage, ScheduledMessageId};
+use crate::search::{self, MessageSearchHit, MessageSearchPage, MessageSearchQuery};
+use crate::stars::{self, StarCursor, StarredMessage, StarredPage};
 use crate::subscriptions_handler::SubscriptionsHandler;
-use crate::{Message, MessageId, User, UserId};
+use crate::{
+    ChatId, ConversationId, ForwardedFrom, Message, MessageId, MessageRevision, OutgoingMessage,
+    ReadReceipt, User, UserId,
+};
+
+/// Leaves room for a suffix within the 150 characters allowed for usernames
+const MAX_EXTERNAL_USERNAME_LENGTH: usize = 100;
+pub const DEFAULT_MESSAGE_EDIT_WINDOW: TimeDelta = TimeDelta::hours(48);
+/// Shown instead of the name of a deleted user
+pub const DELETED_USER_DISPLAY_NAME: &str = "Удалённый аккаунт";
+const EXPORT_AUDIT_PAGE_SIZE: u32 = 100;
 
 #[derive(Clone)]
-pub struct Messenger<D, A> {
+pub struct Messenger<D, A, M, B> {
     data_access: D,
     authorization_service: A,
+    mailer: M,
+    blob_storage: B,
     subscriptions_handler: SubscriptionsHandler<D>,
+    /// Users allowed to query the audit log of all users
+    admins: Vec<UserId>,
+    /// How long after sending a message its author may edit it
+    message_edit_window: TimeDelta,
+    attachment_limits: AttachmentLimits,
+    /// Messages older than this are purged from all conversations
+    message_retention: Option<TimeDelta>,
 }
 
 pub enum UserCreationError {
     UsernameTaken,
 }
 
-impl<D: DataAccess, A> Messenger<D, A> {
-    pub fn new(data_access: D, authorization_service: A) -> Self {
+#[derive(Debug)]
+pub enum LoginResult {
+    Success(UserId),
+    Failed,
+    Locked { until: DateTime<Utc> },
+}
+
+impl LoginResult {
+    fn from_verification(user_id: Option<UserId>, verification: VerificationResult) -> Self {
+        match (user_id, verification) {
+            (Some(user_id), VerificationResult::Verified) => LoginResult::Success(user_id),
+            (_, VerificationResult::Locked { until }) => LoginResult::Locked { until },
+            _ => LoginResult::Failed,
+        }
+    }
+}
+
+impl<D: DataAccess, A, M, B> Messenger<D, A, M, B> {
+    pub fn new(data_access: D, authorization_service: A, mailer: M, blob_storage: B) -> Self {
         let subscriptions_handler = SubscriptionsHandler::new(data_access.clone());
         Messenger {
             data_access,
             authorization_service,
+            mailer,
+            blob_storage,
             subscriptions_handler,
+            admins: Vec::new(),
+            message_edit_window: DEFAULT_MESSAGE_EDIT_WINDOW,
+            attachment_limits: AttachmentLimits::default(),
+            message_retention: None,
+        }
+    }
+
+    pub fn with_admins(self, admins: Vec<UserId>) -> Self {
+        Self { admins, ..self }
+    }
+
+    pub fn with_message_edit_window(self, message_edit_window: TimeDelta) -> Self {
+        Self {
+            message_edit_window,
+            ..self
+        }
+    }
+
+    pub fn with_attachment_limits(self, attachment_limits: AttachmentLimits) -> Self {
+        Self {
+            attachment_limits,
+            ..self
         }
     }
 
+    pub fn with_message_retention(self, message_retention: Option<TimeDelta>) -> Self {
+        Self {
+            message_retention,
+            ..self
+        }
+    }
+
+    pub fn attachment_limits(&self) -> &AttachmentLimits {
+        &self.attachment_limits
+    }
+
+    pub fn is_admin(&self, user_id: &UserId) -> bool {
+        self.admins.contains(user_id)
+    }
+
     pub async fn fetch_user(&self, user_id: &UserId) -> Result<Option<User>> {
         let user = self
             .data_access
@@ -37,46 +138,323 @@ impl<D: DataAccess, A> Messenger<D, A> {
         Ok(user)
     }
 
-    async fn find_user_by_username(&self, username: &str) -> Result<Option<UserId>> {
+    pub async fn find_user_by_username(&self, username: &str) -> Result<Option<UserId>> {
         self.data_access
             .find_user_by_username(username)
             .await
             .with_context(|| format!("Couldn't fetch user_id for username {username}"))
     }
 
-    pub async fn fetch_users_chats(&self, user_id: &UserId) -> Result<Vec<User>> {
-        let chats = self
+    pub async fn update_user_email(&self, user_id: &UserId, email: Option<&str>) -> Result<()> {
+        self.data_access
+            .update_user_email(user_id, email)
+            .await
+            .with_context(|| format!("Couldn't update email for user {user_id}"))
+    }
+
+    pub async fn fetch_profile(&self, user_id: &UserId) -> Result<Option<Profile>> {
+        self.data_access
+            .fetch_profile(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch profile of user {user_id}"))
+    }
+
+    /// An empty display name is unset, so that the username is shown.
+    /// Returns false if the display name or the bio is too long
+    pub async fn update_profile(
+        &self,
+        user_id: &UserId,
+        display_name: Option<&str>,
+        bio: &str,
+    ) -> Result<bool> {
+        let display_name = display_name
+            .map(str::trim)
+            .filter(|display_name| !display_name.is_empty());
+        let bio = bio.trim();
+        if display_name.is_some_and(|display_name| {
+            display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH
+                || display_name.chars().any(char::is_control)
+        }) || bio.chars().count() > MAX_BIO_LENGTH
+        {
+            return Ok(false);
+        }
+
+        self.data_access
+            .update_profile(user_id, display_name, bio)
+            .await
+            .with_context(|| format!("Couldn't update profile of user {user_id}"))?;
+        Ok(true)
+    }
+
+    /// The avatar must be an image the user has uploaded. Returns false otherwise
+    pub async fn set_avatar(
+        &self,
+        user_id: &UserId,
+        avatar: Option<&AttachmentId>,
+    ) -> Result<bool> {
+        if let Some(attachment_id) = avatar {
+            let attachment = self
+                .data_access
+                .fetch_attachment(attachment_id)
+                .await
+                .with_context(|| format!("Couldn't fetch attachment {attachment_id}"))?;
+            match attachment {
+                Some(attachment) if &attachment.uploader == user_id && attachment.has_thumbnail => {
+                }
+                _ => return Ok(false),
+            }
+        }
+
+        self.data_access
+            .update_avatar(user_id, avatar)
+            .await
+            .with_context(|| format!("Couldn't update avatar of user {user_id}"))?;
+        Ok(true)
+    }
+
+    /// Direct conversations with blocked users are left out
+    pub async fn fetch_users_chats(&self, user_id: &UserId) -> Result<Vec<Conversation>> {
+        let mut chats = self
             .data_access
             .find_users_chats(user_id)
             .await
             .with_context(|| format!("Couldn't fetch chats for user {user_id}"))?;
 
+        let blocked_users = self.blocked_user_ids(user_id).await?;
+        chats.retain(|chat| match chat.id {
+            ConversationId::Direct(other) => !blocked_users.contains(&other),
+            ConversationId::Group(_) => true,
+        });
+
         Ok(chats)
     }
 
-    pub async fn find_users_by_substring(&self, substring: &str) -> Result<Vec<User>> {
-        let users = self
+    /// Users blocked by the searching user or blocking them aren't found
+    pub async fn find_users_by_substring(
+        &self,
+        user_id: &UserId,
+        substring: &str,
+    ) -> Result<Vec<User>> {
+        let mut users = self
             .data_access
             .find_users_by_substring(substring)
             .await
             .with_context(|| {
                 format!("Could't process users search request by substring: {substring}")
             })?;
+
+        let mut hidden_users = self.blocked_user_ids(user_id).await?;
+        hidden_users.extend(
+            self.data_access
+                .fetch_users_blocking(user_id)
+                .await
+                .with_context(|| format!("Couldn't fetch users blocking user {user_id}"))?,
+        );
+        users.retain(|user| !hidden_users.contains(&user.id));
+
+        Ok(users)
+    }
+
+    /// Blocked users can't send direct messages to the user and vice versa.
+    /// Returns false if the user tries to block themselves or a user that doesn't exist
+    pub async fn block_user(&self, user_id: &UserId, blocked_user_id: &UserId) -> Result<bool> {
+        if user_id == blocked_user_id || self.fetch_user(blocked_user_id).await?.is_none() {
+            return Ok(false);
+        }
+
+        self.data_access
+            .block_user(user_id, blocked_user_id, chrono::Utc::now())
+            .await
+            .with_context(|| format!("Couldn't block user {blocked_user_id} for user {user_id}"))?;
+        Ok(true)
+    }
+
+    pub async fn unblock_user(&self, user_id: &UserId, blocked_user_id: &UserId) -> Result<()> {
+        self.data_access
+            .unblock_user(user_id, blocked_user_id)
+            .await
+            .with_context(|| format!("Couldn't unblock user {blocked_user_id} for user {user_id}"))
+    }
+
+    /// Users blocked by the user, in the order of blocking
+    pub async fn fetch_blocked_users(&self, user_id: &UserId) -> Result<Vec<User>> {
+        let mut users = vec![];
+        for blocked_user_id in self.blocked_user_ids(user_id).await? {
+            if let Some(user) = self.fetch_user(&blocked_user_id).await? {
+                users.push(user);
+            }
+        }
         Ok(users)
     }
 
+    /// Whether either of the users has blocked the other one
+    pub async fn is_blocked(&self, user_id: &UserId, other_user_id: &UserId) -> Result<bool> {
+        Ok(self
+            .blocked_user_ids(user_id)
+            .await?
+            .contains(other_user_id)
+            || self
+                .blocked_user_ids(other_user_id)
+                .await?
+                .contains(user_id))
+    }
+
+    /// Fails with the same error as sending a direct message if either of the users blocks the other
+    async fn ensure_not_blocked(&self, user_id: &UserId, other_user_id: &UserId) -> Result<()> {
+        if self.is_blocked(user_id, other_user_id).await? {
+            bail!("User {user_id} can't send messages to user {other_user_id}");
+        }
+        Ok(())
+    }
+
+    async fn blocked_user_ids(&self, user_id: &UserId) -> Result<Vec<UserId>> {
+        self.data_access
+            .fetch_blocked_users(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch users blocked by user {user_id}"))
+    }
+
+    /// Messages of a muted conversation are still delivered, but the user isn't notified of them.
+    /// Returns false if the user doesn't take part in the conversation
+    pub async fn set_conversation_muted(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        muted: bool,
+    ) -> Result<bool> {
+        if let ConversationId::Group(chat_id) = conversation {
+            if self.chat_role(chat_id, user_id).await?.is_none() {
+                return Ok(false);
+            }
+        }
+
+        self.data_access
+            .update_conversation_muted(user_id, conversation, muted)
+            .await
+            .with_context(|| {
+                format!("Couldn't update muting of conversation {conversation} for user {user_id}")
+            })?;
+        Ok(true)
+    }
+
+    /// Messages sent to the conversation afterwards disappear `ttl` after sending, earlier ones
+    /// keep their timers. Anyone in a direct conversation sets the timer for both participants,
+    /// in group chats only the owner and admins do. Returns false if the user isn't allowed to
+    pub async fn set_message_ttl(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        ttl: Option<TimeDelta>,
+    ) -> Result<bool> {
+        if let Some(ttl) = &ttl {
+            if !retention::is_valid_message_ttl(ttl) {
+                bail!("Incorrect message timer {ttl}");
+            }
+        }
+        if let ConversationId::Group(chat_id) = conversation {
+            match self.chat_role(chat_id, user_id).await? {
+                Some(role) if role.manages_chat() => {}
+                _ => return Ok(false),
+            }
+        }
+
+        self.data_access
+            .update_message_ttl(user_id, conversation, ttl)
+            .await
+            .with_context(|| {
+                format!("Couldn't update message timer of {conversation} for user {user_id}")
+            })?;
+        Ok(true)
+    }
+
+    pub async fn fetch_message_ttl(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<Option<TimeDelta>> {
+        self.ensure_participant(user_id, conversation).await?;
+
+        self.data_access
+            .fetch_message_ttl(user_id, conversation)
+            .await
+            .with_context(|| {
+                format!("Couldn't fetch message timer of {conversation} for user {user_id}")
+            })
+    }
+
     pub async fn send_message(
         &self,
         message_text: String,
         from: UserId,
-        to: UserId,
+        to: ConversationId,
+    ) -> Result<MessageId> {
+        self.send_outgoing_message(from, to, OutgoingMessage::new(message_text))
+            .await
+    }
+
+    /// Attachments must have been uploaded by the sender and not sent with another message yet.
+    /// A reply must refer to a message of the same conversation
+    pub async fn send_outgoing_message(
+        &self,
+        from: UserId,
+        to: ConversationId,
+        outgoing: OutgoingMessage,
+    ) -> Result<MessageId> {
+        self.deliver_message(uuid::Uuid::new_v4(), from, to, outgoing, None)
+            .await
+    }
+
+    /// Sends the message with the given id to the conversation and notifies the participants
+    async fn deliver_message(
+        &self,
+        id: MessageId,
+        from: UserId,
+        to: ConversationId,
+        outgoing: OutgoingMessage,
+        forwarded_from: Option<ForwardedFrom>,
+    ) -> Result<MessageId> {
+        let attachments = self.check_outgoing_message(&from, &to, &outgoing).await?;
+        self.create_checked_message(id, from, to, outgoing, attachments, forwarded_from)
+            .await
+    }
+
+    /// Stores and fans out a message that passed [Self::check_outgoing_message]
+    async fn create_checked_message(
+        &self,
+        id: MessageId,
+        from: UserId,
+        to: ConversationId,
+        outgoing: OutgoingMessage,
+        attachments: Vec<Attachment>,
+        forwarded_from: Option<ForwardedFrom>,
     ) -> Result<MessageId> {
+        let ttl = self
+            .data_access
+            .fetch_message_ttl(&from, &to)
+            .await
+            .with_context(|| format!("Couldn't fetch message timer of {to} for user {from}"))?;
+
+        let attachments = attachments
+            .into_iter()
+            .map(|attachment| Attachment {
+                message_id: Some(id),
+                ..attachment
+            })
+            .collect();
+        let timestamp = chrono::Utc::now();
         let message = Message {
-            id: uuid::Uuid::new_v4(),
+            id,
             from,
             to,
-            message: message_text,
-            timestamp: chrono::Utc::now(),
+            message: outgoing.text,
+            timestamp,
+            edited_at: None,
+            deleted_at: None,
+            expires_at: ttl.map(|ttl| timestamp + ttl),
+            attachments,
+            reply_to: outgoing.reply_to,
+            forwarded_from,
+            reactions: vec![],
         };
 
         self.data_access
@@ -84,71 +462,2030 @@ impl<D: DataAccess, A> Messenger<D, A> {
             .await
             .with_context(|| format!("Couldn't create message from {from} to {to}"))?;
 
-        if let Err(e) = self.subscriptions_handler.handle_new_message(&message) {
-            log_internal_error(e);
+        // Forwarded copies repeat someone else's mentions
+        if message.forwarded_from.is_none() {
+            if let Err(e) = self.notify_mentions(&message).await {
+                log_internal_error(e);
+            }
+        }
+
+        let message_id = message.id;
+        self.notify_subscribers(Event::NewMessage(message)).await;
+
+        Ok(message_id)
+    }
+
+    /// Records the participants mentioned in the message and notifies those who weren't
+    /// mentioned in it before an edit
+    async fn notify_mentions(&self, message: &Message) -> Result<()> {
+        let participants: Vec<UserId> = match message.to {
+            ConversationId::Direct(to) => vec![to],
+            ConversationId::Group(chat_id) => self
+                .chat_members(&chat_id)
+                .await?
+                .into_iter()
+                .map(|member| member.user_id)
+                .collect(),
         };
 
-        Ok(message.id)
+        let mut mentioned = vec![];
+        for candidates in mentions::mention_candidates(&message.message) {
+            for username in candidates {
+                if let Some(user_id) = self.find_user_by_username(username).await? {
+                    if user_id != message.from
+                        && participants.contains(&user_id)
+                        && !mentioned.contains(&user_id)
+                    {
+                        mentioned.push(user_id);
+                    }
+                    break;
+                }
+            }
+        }
+        if mentioned.is_empty() {
+            return Ok(());
+        }
+
+        let already_mentioned = self
+            .data_access
+            .fetch_mentions(&message.id)
+            .await
+            .with_context(|| format!("Couldn't fetch mentions of message {}", message.id))?;
+        self.data_access
+            .create_mentions(&message.id, &mentioned)
+            .await
+            .with_context(|| format!("Couldn't create mentions of message {}", message.id))?;
+        for user_id in mentioned {
+            if !already_mentioned.contains(&user_id) {
+                let kind = NotificationKind::Mention {
+                    message_id: message.id,
+                    conversation: message.conversation_for(&user_id),
+                };
+                self.add_notification(user_id, message.from, kind).await;
+            }
+        }
+        Ok(())
     }
 
-    pub async fn fetch_last_messages(
+    /// Adds to the user's feed unless either of the user and the actor blocks the other or,
+    /// for mentions and reactions, the user muted the conversation of the message.
+    /// Errors are only logged, the action notified about has already happened
+    async fn add_notification(&self, user_id: UserId, actor: UserId, kind: NotificationKind) {
+        let res = async {
+            if self.is_blocked(&user_id, &actor).await? {
+                return Ok(());
+            }
+            if let NotificationKind::Mention { conversation, .. }
+            | NotificationKind::Reaction { conversation, .. } = &kind
+            {
+                let muted = self
+                    .data_access
+                    .is_conversation_muted(&user_id, conversation)
+                    .await
+                    .with_context(|| {
+                        format!("Couldn't check muting of conversation {conversation} for user {user_id}")
+                    })?;
+                if muted {
+                    return Ok(());
+                }
+            }
+            let notification = Notification {
+                id: Uuid::new_v4(),
+                user_id,
+                actor,
+                kind,
+                created_at: Utc::now().trunc_subsecs(6),
+                read_at: None,
+            };
+            self.data_access
+                .create_notification(&notification)
+                .await
+                .with_context(|| format!("Couldn't create notification for {user_id}"))?;
+            self.notify_subscribers(Event::Notification(notification))
+                .await;
+            Ok::<_, anyhow::Error>(())
+        };
+        if let Err(e) = res.await {
+            log_internal_error(e);
+        }
+    }
+
+    /// The user's notification feed, newest first.
+    /// The limit is capped at `notifications::MAX_NOTIFICATIONS_PAGE_SIZE`
+    pub async fn fetch_notifications(
         &self,
-        current_user: &UserId,
-        other_user: &UserId,
-        starting_point: Option<&MessageId>,
-    ) -> Result<Vec<Message>> {
-        self.data_access.fetch_last_messages_in_chat(current_user, other_user, starting_point).await
-            .with_context(|| format!("Could not fetch last messages.\
-                current_user: {current_user}, other_user: {other_user}, starting_point: {starting_point:?}"))
+        user_id: &UserId,
+        cursor: Option<&NotificationCursor>,
+        limit: u32,
+    ) -> Result<NotificationPage> {
+        let limit = limit.clamp(1, notifications::MAX_NOTIFICATIONS_PAGE_SIZE);
+        // One extra notification tells whether there is a next page
+        let mut notifications = self
+            .data_access
+            .fetch_notifications(user_id, cursor, limit + 1)
+            .await
+            .with_context(|| format!("Couldn't fetch notifications of {user_id}"))?;
+
+        let next_cursor = if notifications.len() > limit as usize {
+            notifications.truncate(limit as usize);
+            notifications.last().map(Notification::cursor)
+        } else {
+            None
+        };
+        let unread_count = self
+            .data_access
+            .count_unread_notifications(user_id)
+            .await
+            .with_context(|| format!("Couldn't count unread notifications of {user_id}"))?;
+        Ok(NotificationPage {
+            notifications,
+            next_cursor,
+            unread_count,
+        })
     }
 
-    pub async fn subscribe_to_new_messages(
+    /// Marks the notification or, if `notification_id` is None, the whole feed as read.
+    /// Returns false if the notification isn't an unread one of the user
+    pub async fn mark_notifications_read(
         &self,
-        user_id: UserId,
-        starting_point: Option<MessageId>,
-    ) -> Result<mpsc::UnboundedReceiver<Message>> {
-        self.subscriptions_handler
-            .subscribe_new_messages(user_id, starting_point)
+        user_id: &UserId,
+        notification_id: Option<&NotificationId>,
+    ) -> Result<bool> {
+        let marked = self
+            .data_access
+            .mark_notifications_read(user_id, notification_id, Utc::now())
             .await
+            .with_context(|| format!("Couldn't mark notifications of {user_id} as read"))?;
+        Ok(marked > 0 || notification_id.is_none())
     }
-}
 
-impl<D: DataAccess, A: AuthService> Messenger<D, A> {
-    pub async fn verify_user(&self, username: &str, password: String) -> Result<Option<UserId>> {
-        let user_id = match self.find_user_by_username(username).await? {
-            Some(user_id) => user_id,
-            None => return Ok(None),
+    /// Fails unless the user may send the message to the conversation,
+    /// returns the attachments to be sent with it
+    async fn check_outgoing_message(
+        &self,
+        from: &UserId,
+        to: &ConversationId,
+        outgoing: &OutgoingMessage,
+    ) -> Result<Vec<Attachment>> {
+        let (from, to) = (*from, *to);
+        self.ensure_participant(&from, &to).await?;
+
+        if let ConversationId::Direct(receiver) = &to {
+            let deleted = self
+                .fetch_profile(receiver)
+                .await?
+                .is_some_and(|profile| profile.deleted);
+            if deleted {
+                bail!("User {from} can't send messages to user {receiver}");
+            }
+            self.ensure_not_blocked(&from, receiver).await?;
+        }
+
+        if let Some(reply_to) = &outgoing.reply_to {
+            match self.fetch_message(reply_to).await? {
+                Some(original) if original.conversation_for(&from) == to => {}
+                _ => bail!("Message {reply_to} can't be replied to in conversation {to}"),
+            }
+        }
+
+        let mut attachments: Vec<Attachment> = vec![];
+        for attachment_id in &outgoing.attachments {
+            let attachment = self
+                .data_access
+                .fetch_attachment(attachment_id)
+                .await
+                .with_context(|| format!("Couldn't fetch attachment {attachment_id}"))?;
+            let attachment = match attachment {
+                Some(attachment)
+                    if attachment.uploader == from
+                        && attachment.message_id.is_none()
+                        && !attachments.iter().any(|a| &a.id == attachment_id) =>
+                {
+                    attachment
+                }
+                _ => bail!("Attachment {attachment_id} can't be sent by user {from}"),
+            };
+            attachments.push(attachment);
+        }
+        Ok(attachments)
+    }
+
+    /// The message is checked now and once more when it's due, it is dropped if by then
+    /// the user can't send it anymore
+    pub async fn schedule_message(
+        &self,
+        from: UserId,
+        to: ConversationId,
+        outgoing: OutgoingMessage,
+        send_at: DateTime<Utc>,
+    ) -> Result<ScheduledMessageId> {
+        let now = Utc::now();
+        if send_at <= now || send_at - now > scheduled::MAX_SCHEDULE_AHEAD {
+            bail!("Message can't be scheduled for {send_at}");
+        }
+        self.check_outgoing_message(&from, &to, &outgoing).await?;
+
+        let scheduled = ScheduledMessage {
+            id: Uuid::new_v4(),
+            from,
+            to,
+            outgoing,
+            send_at,
+            created_at: now,
+            failed_attempts: 0,
+            retry_at: None,
         };
+        self.data_access
+            .create_scheduled_message(&scheduled)
+            .await
+            .with_context(|| format!("Couldn't schedule message from {from} to {to}"))?;
+        Ok(scheduled.id)
+    }
 
-        let res = self
-            .authorization_service
-            .verify_user(&user_id, password)
+    /// Messages waiting to be sent, the earliest first
+    pub async fn fetch_scheduled_messages(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<ScheduledMessage>> {
+        self.data_access
+            .fetch_scheduled_messages(user_id)
             .await
-            .with_context(|| format!("Authorization error: couldn't verify user {}", &user_id))?;
+            .with_context(|| format!("Couldn't fetch messages scheduled by {user_id}"))
+    }
 
-        if res {
-            Ok(Some(user_id))
-        } else {
-            Ok(None)
+    /// Returns false unless the user has scheduled the message and it hasn't been sent yet
+    pub async fn cancel_scheduled_message(
+        &self,
+        user_id: &UserId,
+        scheduled_message_id: &ScheduledMessageId,
+    ) -> Result<bool> {
+        self.data_access
+            .delete_scheduled_message(user_id, scheduled_message_id)
+            .await
+            .with_context(|| {
+                format!("Couldn't cancel message {scheduled_message_id} scheduled by {user_id}")
+            })
+    }
+
+    /// Sends the scheduled messages that are due, in batches. Messages the author can't send
+    /// anymore are dropped, other failed deliveries are retried with a growing delay
+    /// up to [scheduled::MAX_DELIVERY_ATTEMPTS] times. Returns the number of messages
+    /// taken off the queue
+    pub async fn deliver_scheduled_messages(&self) -> Result<usize> {
+        let mut delivered = 0;
+        loop {
+            let due = self
+                .data_access
+                .fetch_due_scheduled_messages(Utc::now(), scheduled::DELIVERY_BATCH_SIZE)
+                .await
+                .context("Couldn't fetch due scheduled messages")?;
+            let last_batch = due.len() < scheduled::DELIVERY_BATCH_SIZE as usize;
+
+            for scheduled in due {
+                let id = scheduled.id;
+                if let Err(e) = self.deliver_scheduled_message(&scheduled).await {
+                    let failed_attempts = scheduled.failed_attempts + 1;
+                    if failed_attempts < scheduled::MAX_DELIVERY_ATTEMPTS {
+                        log_internal_error(
+                            e.context(format!("Couldn't deliver scheduled message {id}, retrying")),
+                        );
+                        let retry_at =
+                            Utc::now() + scheduled::delivery_retry_delay(failed_attempts);
+                        self.data_access
+                            .postpone_scheduled_message(&id, retry_at)
+                            .await
+                            .with_context(|| format!("Couldn't postpone scheduled message {id}"))?;
+                        continue;
+                    }
+                    log_internal_error(e.context(format!(
+                        "Couldn't deliver scheduled message {id} in {failed_attempts} attempts, dropping it"
+                    )));
+                }
+                self.data_access
+                    .delete_scheduled_message(&scheduled.from, &id)
+                    .await
+                    .with_context(|| format!("Couldn't dequeue scheduled message {id}"))?;
+                delivered += 1;
+            }
+            if last_batch {
+                return Ok(delivered);
+            }
         }
     }
 
-    pub async fn create_user(&self, login: &str, password: String) -> Result<Option<UserId>> {
-        let user_id = match self
-            .data_access
-            .create_user(login)
+    /// Fails only if the delivery should be retried, a message the author
+    /// can't send anymore is rejected for good
+    async fn deliver_scheduled_message(&self, scheduled: &ScheduledMessage) -> Result<()> {
+        // Sent before a restart, but not taken off the queue
+        if self.fetch_message(&scheduled.id).await?.is_some() {
+            return Ok(());
+        }
+        let attachments = match self
+            .check_outgoing_message(&scheduled.from, &scheduled.to, &scheduled.outgoing)
             .await
-            .with_context(|| format!("Couldn't create user {}", login))?
         {
-            Some(user_id) => user_id,
-            None => return Ok(None),
+            Ok(attachments) => attachments,
+            Err(e) => {
+                log_internal_error(e.context(format!(
+                    "Scheduled message {} was rejected, dropping it",
+                    scheduled.id
+                )));
+                return Ok(());
+            }
         };
+        self.create_checked_message(
+            scheduled.id,
+            scheduled.from,
+            scheduled.to,
+            scheduled.outgoing.clone(),
+            attachments,
+            None,
+        )
+        .await?;
+        Ok(())
+    }
 
-        self.authorization_service
-            .create_user(&user_id, password)
+    /// Only the author may edit a message, within the edit window since it was sent
+    pub async fn edit_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+        message_text: String,
+    ) -> Result<bool> {
+        let message = match self.fetch_message(message_id).await? {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        let now = Utc::now();
+        if &message.from != user_id
+            || message.deleted_at.is_some()
+            || now - message.timestamp > self.message_edit_window
+            || !self.is_participant(user_id, &message).await?
+        {
+            return Ok(false);
+        }
+
+        self.data_access
+            .edit_message(message_id, &message_text, now)
             .await
-            .with_context(|| format!("Authorization error: couldn't create user {}", login))?;
+            .with_context(|| format!("Couldn't edit message {message_id}"))?;
 
-        Ok(Some(user_id))
+        let message = Message {
+            message: message_text,
+            edited_at: Some(now),
+            ..message
+        };
+        if message.forwarded_from.is_none() {
+            if let Err(e) = self.notify_mentions(&message).await {
+                log_internal_error(e);
+            }
+        }
+        self.notify_subscribers(Event::MessageEdited(message)).await;
+
+        Ok(true)
+    }
+
+    /// Hides the message for the user only, any participant may hide any message
+    pub async fn hide_message(&self, user_id: &UserId, message_id: &MessageId) -> Result<bool> {
+        let message = match self.fetch_message(message_id).await? {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        if !self.is_participant(user_id, &message).await? {
+            return Ok(false);
+        }
+
+        self.data_access
+            .hide_message(user_id, message_id)
+            .await
+            .with_context(|| format!("Couldn't hide message {message_id} for {user_id}"))?;
+
+        self.notify_subscribers(Event::MessageHidden {
+            user_id: *user_id,
+            message_id: *message_id,
+        })
+        .await;
+
+        Ok(true)
+    }
+
+    /// Participants react to messages that aren't deleted, reacting twice with the same emoji
+    /// changes nothing. Returns false if the user can't react to the message
+    pub async fn add_reaction(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+        emoji: &str,
+    ) -> Result<bool> {
+        if !reactions::is_valid_reaction(emoji) {
+            bail!("Invalid reaction {emoji:?}");
+        }
+        let message = match self.fetch_message(message_id).await? {
+            Some(message) if message.deleted_at.is_none() => message,
+            _ => return Ok(false),
+        };
+        if !self.is_participant(user_id, &message).await? {
+            return Ok(false);
+        }
+        if message.has_reaction(user_id, emoji) {
+            return Ok(true);
+        }
+
+        self.data_access
+            .add_reaction(message_id, user_id, emoji, Utc::now())
+            .await
+            .with_context(|| {
+                format!("Couldn't add reaction of {user_id} to message {message_id}")
+            })?;
+        self.notify_reactions_changed(message_id).await?;
+        if &message.from != user_id {
+            let kind = NotificationKind::Reaction {
+                message_id: *message_id,
+                conversation: message.conversation_for(&message.from),
+                emoji: emoji.to_owned(),
+            };
+            self.add_notification(message.from, *user_id, kind).await;
+        }
+        Ok(true)
+    }
+
+    /// Returns false if the user can't react to the message
+    pub async fn remove_reaction(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+        emoji: &str,
+    ) -> Result<bool> {
+        let message = match self.fetch_message(message_id).await? {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        if !self.is_participant(user_id, &message).await? {
+            return Ok(false);
+        }
+        if !message.has_reaction(user_id, emoji) {
+            return Ok(true);
+        }
+
+        self.data_access
+            .remove_reaction(message_id, user_id, emoji)
+            .await
+            .with_context(|| {
+                format!("Couldn't remove reaction of {user_id} to message {message_id}")
+            })?;
+        self.notify_reactions_changed(message_id).await?;
+        Ok(true)
+    }
+
+    async fn notify_reactions_changed(&self, message_id: &MessageId) -> Result<()> {
+        if let Some(message) = self.fetch_message(message_id).await? {
+            self.notify_subscribers(Event::ReactionsChanged(message))
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Pins the message to its conversation for all of the participants. In group chats
+    /// only the owner and admins may pin. Returns false if the user can't pin the message
+    pub async fn pin_message(&self, user_id: &UserId, message_id: &MessageId) -> Result<bool> {
+        let Some(message) = self.fetch_pinnable_message(user_id, message_id).await? else {
+            return Ok(false);
+        };
+
+        let conversation = message.conversation_for(user_id);
+        if self.is_pinned(user_id, &conversation, message_id).await? {
+            return Ok(true);
+        }
+        self.data_access
+            .pin_message(message_id, user_id, Utc::now())
+            .await
+            .with_context(|| format!("Couldn't pin message {message_id}"))?;
+        self.notify_subscribers(Event::MessagePinned {
+            pinned_by: *user_id,
+            message,
+        })
+        .await;
+        Ok(true)
+    }
+
+    /// Returns false if the user can't unpin the message, the same users as can pin it
+    pub async fn unpin_message(&self, user_id: &UserId, message_id: &MessageId) -> Result<bool> {
+        let Some(message) = self.fetch_pinnable_message(user_id, message_id).await? else {
+            return Ok(false);
+        };
+
+        let conversation = message.conversation_for(user_id);
+        if !self.is_pinned(user_id, &conversation, message_id).await? {
+            return Ok(true);
+        }
+        self.data_access
+            .unpin_message(message_id)
+            .await
+            .with_context(|| format!("Couldn't unpin message {message_id}"))?;
+        self.notify_subscribers(Event::MessageUnpinned {
+            unpinned_by: *user_id,
+            message,
+        })
+        .await;
+        Ok(true)
+    }
+
+    /// Most recently pinned first
+    pub async fn fetch_pinned_messages(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<Vec<PinnedMessage>> {
+        self.ensure_participant(user_id, conversation).await?;
+
+        self.data_access
+            .fetch_pinned_messages(user_id, conversation)
+            .await
+            .with_context(|| {
+                format!("Couldn't fetch pinned messages of {user_id} in {conversation}")
+            })
+    }
+
+    async fn fetch_pinnable_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+    ) -> Result<Option<Message>> {
+        let message = match self.fetch_message(message_id).await? {
+            Some(message) if message.deleted_at.is_none() => message,
+            _ => return Ok(None),
+        };
+        let permitted = match &message.to {
+            ConversationId::Direct(to) => &message.from == user_id || to == user_id,
+            ConversationId::Group(chat_id) => self
+                .chat_role(chat_id, user_id)
+                .await?
+                .is_some_and(|role| role.manages_chat()),
+        };
+        Ok(permitted.then_some(message))
+    }
+
+    async fn is_pinned(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        message_id: &MessageId,
+    ) -> Result<bool> {
+        let pinned = self
+            .fetch_pinned_messages(user_id, conversation)
+            .await?
+            .iter()
+            .any(|pin| &pin.message.id == message_id);
+        Ok(pinned)
+    }
+
+    /// Stars are private to the user. Returns false if the user can't see the message
+    pub async fn star_message(&self, user_id: &UserId, message_id: &MessageId) -> Result<bool> {
+        let message = match self.fetch_message(message_id).await? {
+            Some(message) if message.deleted_at.is_none() => message,
+            _ => return Ok(false),
+        };
+        if !self.is_participant(user_id, &message).await? {
+            return Ok(false);
+        }
+
+        self.data_access
+            // Cursors carry microseconds, as much as postgres stores
+            .star_message(user_id, message_id, Utc::now().trunc_subsecs(6))
+            .await
+            .with_context(|| format!("Couldn't star message {message_id} for {user_id}"))?;
+        Ok(true)
+    }
+
+    pub async fn unstar_message(&self, user_id: &UserId, message_id: &MessageId) -> Result<()> {
+        self.data_access
+            .unstar_message(user_id, message_id)
+            .await
+            .with_context(|| format!("Couldn't unstar message {message_id} for {user_id}"))
+    }
+
+    /// Messages starred by the user across all of their conversations, most recently starred first.
+    /// The limit is capped at `stars::MAX_STARRED_PAGE_SIZE`
+    pub async fn fetch_starred_messages(
+        &self,
+        user_id: &UserId,
+        cursor: Option<&StarCursor>,
+        limit: u32,
+    ) -> Result<StarredPage> {
+        let limit = limit.clamp(1, stars::MAX_STARRED_PAGE_SIZE);
+        // One extra message tells whether there is a next page
+        let mut messages = self
+            .data_access
+            .fetch_starred_messages(user_id, cursor, limit + 1)
+            .await
+            .with_context(|| format!("Couldn't fetch starred messages of {user_id}"))?;
+
+        let next_cursor = if messages.len() > limit as usize {
+            messages.truncate(limit as usize);
+            messages.last().map(StarredMessage::cursor)
+        } else {
+            None
+        };
+        Ok(StarredPage {
+            messages,
+            next_cursor,
+        })
+    }
+
+    /// Moves the user's read marker in the message's conversation forward to the message.
+    /// Returns false unless the user participates in the conversation
+    pub async fn mark_read(&self, user_id: &UserId, message_id: &MessageId) -> Result<bool> {
+        let message = match self.fetch_message(message_id).await? {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        if !self.is_participant(user_id, &message).await? {
+            return Ok(false);
+        }
+
+        let conversation = message.conversation_for(user_id);
+        let read_marker = self
+            .data_access
+            .fetch_read_marker(user_id, &conversation)
+            .await
+            .with_context(|| {
+                format!("Couldn't fetch read marker of {user_id} in {conversation}")
+            })?;
+        if let Some(read_marker) = read_marker {
+            if let Some(last_read) = self.fetch_message(&read_marker).await? {
+                // Markers only move forward
+                if (last_read.timestamp, last_read.id) >= (message.timestamp, message.id) {
+                    return Ok(true);
+                }
+            }
+        }
+
+        self.data_access
+            .update_read_marker(user_id, &conversation, message_id)
+            .await
+            .with_context(|| {
+                format!("Couldn't update read marker of {user_id} in {conversation}")
+            })?;
+
+        self.notify_subscribers(Event::MessagesRead {
+            reader: *user_id,
+            message,
+        })
+        .await;
+
+        Ok(true)
+    }
+
+    /// Read markers of the other participants of the conversation
+    pub async fn fetch_read_receipts(
+        &self,
+        current_user: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<Vec<ReadReceipt>> {
+        self.ensure_participant(current_user, conversation).await?;
+
+        // Each reader sees the conversation from their side
+        let readers: Vec<(UserId, ConversationId)> = match conversation {
+            ConversationId::Direct(other_user) if other_user == current_user => vec![],
+            ConversationId::Direct(other_user) => {
+                vec![(*other_user, ConversationId::Direct(*current_user))]
+            }
+            ConversationId::Group(chat_id) => self
+                .chat_members(chat_id)
+                .await?
+                .into_iter()
+                .filter(|member| &member.user_id != current_user)
+                .map(|member| (member.user_id, *conversation))
+                .collect(),
+        };
+
+        let mut res = vec![];
+        for (user_id, conversation) in readers {
+            let read_marker = self
+                .data_access
+                .fetch_read_marker(&user_id, &conversation)
+                .await
+                .with_context(|| {
+                    format!("Couldn't fetch read marker of {user_id} in {conversation}")
+                })?;
+            if let Some(message_id) = read_marker {
+                res.push(ReadReceipt {
+                    user_id,
+                    message_id,
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    /// Earlier texts of the message, oldest first.
+    /// Returns None unless the user participates in the message's conversation
+    pub async fn fetch_message_revisions(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+    ) -> Result<Option<Vec<MessageRevision>>> {
+        let message = match self.fetch_message(message_id).await? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        if !self.is_participant(user_id, &message).await? {
+            return Ok(None);
+        }
+
+        let revisions = self
+            .data_access
+            .fetch_message_revisions(message_id)
+            .await
+            .with_context(|| format!("Couldn't fetch revisions of message {message_id}"))?;
+        Ok(Some(revisions))
+    }
+
+    async fn fetch_message(&self, message_id: &MessageId) -> Result<Option<Message>> {
+        self.data_access
+            .fetch_message(message_id)
+            .await
+            .with_context(|| format!("Couldn't fetch message {message_id}"))
+    }
+
+    async fn is_participant(&self, user_id: &UserId, message: &Message) -> Result<bool> {
+        let res = match message.to {
+            ConversationId::Direct(to) => &message.from == user_id || &to == user_id,
+            ConversationId::Group(chat_id) => self.chat_role(&chat_id, user_id).await?.is_some(),
+        };
+        Ok(res)
+    }
+
+    async fn notify_subscribers(&self, event: Event) {
+        if let Err(e) = self.subscriptions_handler.handle_event(&event).await {
+            log_internal_error(e);
+        };
+    }
+
+    pub async fn fetch_last_messages(
+        &self,
+        current_user: &UserId,
+        conversation: &ConversationId,
+        starting_point: Option<&MessageId>,
+    ) -> Result<Vec<Message>> {
+        self.ensure_participant(current_user, conversation).await?;
+
+        self.data_access.fetch_last_messages_in_chat(current_user, conversation, starting_point).await
+            .with_context(|| format!("Could not fetch last messages.\
+                current_user: {current_user}, conversation: {conversation}, starting_point: {starting_point:?}"))
+    }
+
+    /// Messages of the user's conversations containing every word of the query,
+    /// best matches first. The limit is capped at `search::MAX_SEARCH_PAGE_SIZE`
+    pub async fn search_messages(
+        &self,
+        user_id: &UserId,
+        query: &MessageSearchQuery,
+    ) -> Result<MessageSearchPage> {
+        if search::search_terms(&query.text).is_empty() {
+            return Ok(MessageSearchPage {
+                hits: vec![],
+                next_cursor: None,
+            });
+        }
+
+        let limit = query.limit.clamp(1, search::MAX_SEARCH_PAGE_SIZE);
+        // One extra result tells whether there is a next page
+        let data_access_query = MessageSearchQuery {
+            limit: limit + 1,
+            ..query.clone()
+        };
+        let mut results = self
+            .data_access
+            .search_messages(user_id, &data_access_query)
+            .await
+            .with_context(|| format!("Couldn't search messages of user {user_id}"))?;
+
+        let next_cursor = if results.len() > limit as usize {
+            results.truncate(limit as usize);
+            results.last().map(|result| result.cursor())
+        } else {
+            None
+        };
+        let hits = results
+            .into_iter()
+            .map(|result| MessageSearchHit {
+                snippet: search::snippet(&result.message.message, &query.text),
+                message: result.message,
+                rank: result.rank,
+            })
+            .collect();
+        Ok(MessageSearchPage { hits, next_cursor })
+    }
+
+    /// Interprets an id from a url as a group chat of the user or as another user
+    pub async fn resolve_conversation(
+        &self,
+        user_id: &UserId,
+        id: &Uuid,
+    ) -> Result<Option<ConversationId>> {
+        if self.chat_role(id, user_id).await?.is_some() {
+            return Ok(Some(ConversationId::Group(*id)));
+        }
+
+        let user = self.fetch_user(id).await?;
+        Ok(user.map(|user| ConversationId::Direct(user.id)))
+    }
+
+    /// Creates a group chat owned by `owner`, fails if the owner and any of the members
+    /// block one another
+    pub async fn create_chat(
+        &self,
+        owner: &UserId,
+        title: &str,
+        members: &[UserId],
+    ) -> Result<ChatId> {
+        for member in members.iter().filter(|member| *member != owner) {
+            self.ensure_not_blocked(owner, member).await?;
+        }
+
+        let chat = Chat {
+            id: Uuid::new_v4(),
+            title: title.to_owned(),
+            created_at: Utc::now(),
+        };
+
+        self.data_access
+            .create_chat(&chat, owner)
+            .await
+            .with_context(|| format!("Couldn't create chat for {owner}"))?;
+
+        for member in members.iter().filter(|member| *member != owner) {
+            self.data_access
+                .update_chat_member(&chat.id, member, ChatRole::Member)
+                .await
+                .with_context(|| format!("Couldn't add {member} to chat {}", chat.id))?;
+            let kind = NotificationKind::NewChat { chat_id: chat.id };
+            self.add_notification(*member, *owner, kind).await;
+        }
+
+        Ok(chat.id)
+    }
+
+    /// Returns None unless the user is a member of the chat
+    pub async fn fetch_chat(&self, user_id: &UserId, chat_id: &ChatId) -> Result<Option<Chat>> {
+        if self.chat_role(chat_id, user_id).await?.is_none() {
+            return Ok(None);
+        }
+
+        self.data_access
+            .fetch_chat(chat_id)
+            .await
+            .with_context(|| format!("Couldn't fetch chat {chat_id}"))
+    }
+
+    /// Returns None unless the user is a member of the chat
+    pub async fn fetch_chat_members(
+        &self,
+        user_id: &UserId,
+        chat_id: &ChatId,
+    ) -> Result<Option<Vec<ChatMember>>> {
+        let members = self.chat_members(chat_id).await?;
+        if members.iter().any(|member| &member.user_id == user_id) {
+            Ok(Some(members))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Only the owner and admins may rename the chat
+    pub async fn rename_chat(
+        &self,
+        user_id: &UserId,
+        chat_id: &ChatId,
+        title: &str,
+    ) -> Result<bool> {
+        if !self
+            .chat_role(chat_id, user_id)
+            .await?
+            .is_some_and(|role| role.manages_chat())
+        {
+            return Ok(false);
+        }
+
+        self.data_access
+            .rename_chat(chat_id, title)
+            .await
+            .with_context(|| format!("Couldn't rename chat {chat_id}"))?;
+        Ok(true)
+    }
+
+    /// Only the owner and admins may add members.
+    /// Fails if the user and the new member block one another
+    pub async fn add_chat_member(
+        &self,
+        user_id: &UserId,
+        chat_id: &ChatId,
+        new_member: &UserId,
+    ) -> Result<bool> {
+        let members = self.chat_members(chat_id).await?;
+        let may_add = members
+            .iter()
+            .any(|member| &member.user_id == user_id && member.role.manages_chat());
+        let already_member = members.iter().any(|member| &member.user_id == new_member);
+        if !may_add || already_member || self.fetch_user(new_member).await?.is_none() {
+            return Ok(false);
+        }
+        self.ensure_not_blocked(user_id, new_member).await?;
+
+        self.data_access
+            .update_chat_member(chat_id, new_member, ChatRole::Member)
+            .await
+            .with_context(|| format!("Couldn't add {new_member} to chat {chat_id}"))?;
+        let kind = NotificationKind::NewChat { chat_id: *chat_id };
+        self.add_notification(*new_member, *user_id, kind).await;
+        Ok(true)
+    }
+
+    /// Members can only be removed by someone with a higher role, see also [Self::leave_chat]
+    pub async fn remove_chat_member(
+        &self,
+        user_id: &UserId,
+        chat_id: &ChatId,
+        member: &UserId,
+    ) -> Result<bool> {
+        let members = self.chat_members(chat_id).await?;
+        let role_of = |user_id: &UserId| {
+            members
+                .iter()
+                .find(|member| &member.user_id == user_id)
+                .map(|member| member.role)
+        };
+        let may_remove = match (role_of(user_id), role_of(member)) {
+            (Some(role), Some(member_role)) => role.manages_chat() && role > member_role,
+            _ => false,
+        };
+        if !may_remove {
+            return Ok(false);
+        }
+
+        self.data_access
+            .delete_chat_member(chat_id, member)
+            .await
+            .with_context(|| format!("Couldn't remove {member} from chat {chat_id}"))?;
+        Ok(true)
+    }
+
+    /// Only the owner may appoint and dismiss admins
+    pub async fn set_chat_member_role(
+        &self,
+        user_id: &UserId,
+        chat_id: &ChatId,
+        member: &UserId,
+        role: ChatRole,
+    ) -> Result<bool> {
+        let members = self.chat_members(chat_id).await?;
+        let is_owner = members
+            .iter()
+            .any(|member| &member.user_id == user_id && member.role == ChatRole::Owner);
+        let is_member = members.iter().any(|m| &m.user_id == member);
+        if !is_owner || !is_member || role == ChatRole::Owner || member == user_id {
+            return Ok(false);
+        }
+
+        self.data_access
+            .update_chat_member(chat_id, member, role)
+            .await
+            .with_context(|| format!("Couldn't change role of {member} in chat {chat_id}"))?;
+        Ok(true)
+    }
+
+    /// A leaving owner passes the chat to the longest standing admin,
+    /// or to the longest standing member if there are no admins
+    pub async fn leave_chat(&self, user_id: &UserId, chat_id: &ChatId) -> Result<bool> {
+        let members = self.chat_members(chat_id).await?;
+        let role = match members.iter().find(|member| &member.user_id == user_id) {
+            Some(member) => member.role,
+            None => return Ok(false),
+        };
+
+        if role == ChatRole::Owner {
+            let remaining = members.iter().filter(|member| &member.user_id != user_id);
+            let successor = remaining
+                .clone()
+                .find(|member| member.role == ChatRole::Admin)
+                .or(remaining.clone().next());
+            if let Some(successor) = successor {
+                self.data_access
+                    .update_chat_member(chat_id, &successor.user_id, ChatRole::Owner)
+                    .await
+                    .with_context(|| format!("Couldn't pass ownership of chat {chat_id}"))?;
+            }
+        }
+
+        self.data_access
+            .delete_chat_member(chat_id, user_id)
+            .await
+            .with_context(|| format!("Couldn't remove {user_id} from chat {chat_id}"))?;
+        Ok(true)
+    }
+
+    async fn chat_members(&self, chat_id: &ChatId) -> Result<Vec<ChatMember>> {
+        self.data_access
+            .fetch_chat_members(chat_id)
+            .await
+            .with_context(|| format!("Couldn't fetch members of chat {chat_id}"))
+    }
+
+    async fn chat_role(&self, chat_id: &ChatId, user_id: &UserId) -> Result<Option<ChatRole>> {
+        let role = self
+            .chat_members(chat_id)
+            .await?
+            .into_iter()
+            .find(|member| &member.user_id == user_id)
+            .map(|member| member.role);
+        Ok(role)
+    }
+
+    /// Anyone can write to any user directly, group chats are for members only
+    async fn ensure_participant(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<()> {
+        if let ConversationId::Group(chat_id) = conversation {
+            if self.chat_role(chat_id, user_id).await?.is_none() {
+                bail!("User {user_id} is not a member of chat {chat_id}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the other participants that the user is typing, repeated notifications are throttled.
+    /// Users blocking the user or blocked by them aren't told
+    pub async fn notify_typing(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<()> {
+        self.ensure_participant(user_id, conversation).await?;
+        if let ConversationId::Direct(other_user_id) = conversation {
+            self.ensure_not_blocked(user_id, other_user_id).await?;
+        }
+
+        self.notify_subscribers(Event::Typing {
+            user_id: *user_id,
+            conversation: *conversation,
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Presence is only shown to the user's contacts, None for other users
+    /// and when either of the users blocks the other
+    pub async fn fetch_presence(
+        &self,
+        user_id: &UserId,
+        other_user_id: &UserId,
+    ) -> Result<Option<Presence>> {
+        if user_id != other_user_id
+            && (!self
+                .subscriptions_handler
+                .is_contact(user_id, other_user_id)
+                .await?
+                || self.is_blocked(user_id, other_user_id).await?)
+        {
+            return Ok(None);
+        }
+        self.subscriptions_handler.presence(other_user_id).map(Some)
+    }
+
+    pub async fn subscribe_to_new_messages(
+        &self,
+        user_id: UserId,
+        starting_point: Option<MessageId>,
+    ) -> Result<mpsc::UnboundedReceiver<Event>> {
+        self.subscriptions_handler
+            .subscribe_new_messages(user_id, starting_point)
+            .await
+    }
+}
+
+impl<D: DataAccess, A, M, B: BlobStorage> Messenger<D, A, M, B> {
+    /// Stores the file until it is sent with a message, images get a thumbnail.
+    /// Returns None if the file breaks the attachment limits or isn't of the declared type
+    pub async fn upload_attachment(
+        &self,
+        uploader: &UserId,
+        file_name: &str,
+        content_type: &str,
+        content: Vec<u8>,
+    ) -> Result<Option<Attachment>> {
+        let size = content.len() as u64;
+        if !self.attachment_limits.allows(content_type, size)
+            || !attachments::content_matches_type(content_type, &content)
+        {
+            return Ok(None);
+        }
+
+        let mut attachment = Attachment {
+            id: Uuid::new_v4(),
+            uploader: *uploader,
+            message_id: None,
+            file_name: attachments::sanitize_file_name(file_name),
+            content_type: content_type.to_owned(),
+            size,
+            has_thumbnail: false,
+            created_at: Utc::now(),
+        };
+
+        let thumbnail = if attachment.is_image() {
+            let content = content.clone();
+            tokio::task::spawn_blocking(move || attachments::make_thumbnail(&content))
+                .await
+                .context("Thumbnail generation panicked")?
+        } else {
+            None
+        };
+
+        self.blob_storage
+            .put(&attachment.content_key(), content)
+            .await
+            .with_context(|| format!("Couldn't store content of attachment {}", attachment.id))?;
+        if let Some(thumbnail) = thumbnail {
+            self.blob_storage
+                .put(&attachment.thumbnail_key(), thumbnail)
+                .await
+                .with_context(|| {
+                    format!("Couldn't store thumbnail of attachment {}", attachment.id)
+                })?;
+            attachment.has_thumbnail = true;
+        }
+
+        self.data_access
+            .create_attachment(&attachment)
+            .await
+            .with_context(|| format!("Couldn't create attachment {}", attachment.id))?;
+
+        Ok(Some(attachment))
+    }
+
+    /// The file or its thumbnail. Only the uploader and the participants of the conversation
+    /// the attachment was sent to may fetch it, otherwise returns None
+    pub async fn fetch_attachment_content(
+        &self,
+        user_id: &UserId,
+        attachment_id: &AttachmentId,
+        thumbnail: bool,
+    ) -> Result<Option<(Attachment, Vec<u8>)>> {
+        let attachment = self
+            .data_access
+            .fetch_attachment(attachment_id)
+            .await
+            .with_context(|| format!("Couldn't fetch attachment {attachment_id}"))?;
+        let attachment = match attachment {
+            Some(attachment) => attachment,
+            None => return Ok(None),
+        };
+
+        let permitted = match attachment.message_id {
+            _ if &attachment.uploader == user_id => true,
+            Some(message_id) => match self.fetch_message(&message_id).await? {
+                Some(message) => self.is_participant(user_id, &message).await?,
+                None => false,
+            },
+            None => false,
+        };
+        if !permitted || (thumbnail && !attachment.has_thumbnail) {
+            return Ok(None);
+        }
+
+        let key = if thumbnail {
+            attachment.thumbnail_key()
+        } else {
+            attachment.content_key()
+        };
+        let content = self
+            .blob_storage
+            .get(&key)
+            .await
+            .with_context(|| format!("Couldn't fetch content of attachment {attachment_id}"))?;
+        Ok(content.map(|content| (attachment, content)))
+    }
+
+    /// Copies a message the user can see into another conversation, attachments included.
+    /// The copy keeps the author and send time of the original, forwarded copies keep those
+    /// of the first one. Returns None if the user can't see the message
+    pub async fn forward_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+        to: ConversationId,
+    ) -> Result<Option<MessageId>> {
+        let original = match self.fetch_message(message_id).await? {
+            Some(message)
+                if message.deleted_at.is_none()
+                    && self.is_participant(user_id, &message).await? =>
+            {
+                message
+            }
+            _ => return Ok(None),
+        };
+        let mut outgoing = OutgoingMessage::new(original.message.clone());
+        // Before copying the attachments, so that none are left behind
+        self.check_outgoing_message(user_id, &to, &outgoing).await?;
+
+        for attachment in &original.attachments {
+            let copy = Attachment {
+                id: Uuid::new_v4(),
+                uploader: *user_id,
+                message_id: None,
+                created_at: Utc::now(),
+                ..attachment.clone()
+            };
+            let mut keys = vec![(attachment.content_key(), copy.content_key())];
+            if attachment.has_thumbnail {
+                keys.push((attachment.thumbnail_key(), copy.thumbnail_key()));
+            }
+            for (key, copy_key) in keys {
+                let content = self
+                    .blob_storage
+                    .get(&key)
+                    .await
+                    .with_context(|| {
+                        format!("Couldn't fetch content of attachment {}", attachment.id)
+                    })?
+                    .with_context(|| {
+                        format!("Content of attachment {} is missing", attachment.id)
+                    })?;
+                self.blob_storage
+                    .put(&copy_key, content)
+                    .await
+                    .with_context(|| format!("Couldn't store content of attachment {}", copy.id))?;
+            }
+            self.data_access
+                .create_attachment(&copy)
+                .await
+                .with_context(|| format!("Couldn't create attachment {}", copy.id))?;
+            outgoing.attachments.push(copy.id);
+        }
+
+        let forwarded_from = original.forwarded_from();
+        let id = self
+            .deliver_message(Uuid::new_v4(), *user_id, to, outgoing, Some(forwarded_from))
+            .await?;
+        Ok(Some(id))
+    }
+
+    /// Only the author may delete a message for everyone, a tombstone is left in its place
+    pub async fn delete_message_for_everyone(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+    ) -> Result<bool> {
+        let message = match self.fetch_message(message_id).await? {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        if &message.from != user_id
+            || message.deleted_at.is_some()
+            || !self.is_participant(user_id, &message).await?
+        {
+            return Ok(false);
+        }
+
+        let now = Utc::now();
+        self.data_access
+            .delete_message(message_id, now)
+            .await
+            .with_context(|| format!("Couldn't delete message {message_id}"))?;
+        for attachment in &message.attachments {
+            self.delete_attachment_content(attachment).await;
+        }
+
+        let message = Message {
+            message: String::new(),
+            deleted_at: Some(now),
+            attachments: vec![],
+            reactions: vec![],
+            ..message
+        };
+        self.notify_subscribers(Event::MessageDeleted(message))
+            .await;
+
+        Ok(true)
+    }
+
+    /// The thumbnail of the user's avatar, visible to everyone
+    pub async fn fetch_avatar(&self, user_id: &UserId) -> Result<Option<Vec<u8>>> {
+        let Some(avatar) = self
+            .fetch_profile(user_id)
+            .await?
+            .and_then(|profile| profile.avatar)
+        else {
+            return Ok(None);
+        };
+        let attachment = self
+            .data_access
+            .fetch_attachment(&avatar)
+            .await
+            .with_context(|| format!("Couldn't fetch attachment {avatar}"))?;
+        let Some(attachment) = attachment.filter(|attachment| attachment.has_thumbnail) else {
+            return Ok(None);
+        };
+
+        self.blob_storage
+            .get(&attachment.thumbnail_key())
+            .await
+            .with_context(|| format!("Couldn't fetch avatar of user {user_id}"))
+    }
+
+    /// Failures are only logged, the attachment is already gone from the message
+    /// Deletes disappearing messages past their timers and messages older than the retention
+    /// period in batches, telling the participants. Returns the number of purged messages
+    pub async fn purge_expired_messages(&self) -> Result<usize> {
+        let mut purged = 0;
+        loop {
+            let now = Utc::now();
+            let sent_before = self.message_retention.map(|retention| now - retention);
+            let messages = self
+                .data_access
+                .purge_expired_messages(now, sent_before, PURGE_BATCH_SIZE)
+                .await
+                .context("Couldn't purge expired messages")?;
+            purged += messages.len();
+            let last_batch = messages.len() < PURGE_BATCH_SIZE as usize;
+
+            for message in messages {
+                for attachment in &message.attachments {
+                    self.delete_attachment_content(attachment).await;
+                }
+                self.notify_subscribers(Event::MessageExpired(message))
+                    .await;
+            }
+            if last_batch {
+                return Ok(purged);
+            }
+        }
+    }
+
+    async fn delete_attachment_content(&self, attachment: &Attachment) {
+        let mut keys = vec![attachment.content_key()];
+        if attachment.has_thumbnail {
+            keys.push(attachment.thumbnail_key());
+        }
+        for key in keys {
+            if let Err(e) = self.blob_storage.delete(&key).await {
+                log_internal_error(e);
+            }
+        }
+    }
+}
+
+impl<D: DataAccess, A: AuthService, M, B> Messenger<D, A, M, B> {
+    pub async fn log_in(
+        &self,
+        username: &str,
+        password: String,
+        client_addr: Option<IpAddr>,
+    ) -> Result<LoginResult> {
+        let user_id = match self.find_user_by_username(username).await? {
+            Some(user_id) => user_id,
+            None => {
+                let res = self
+                    .authorization_service
+                    .register_unknown_user_attempt(client_addr)
+                    .await
+                    .context("Authorization error: couldn't register login attempt")?;
+                self.record_audit_event(None, AuditEventKind::LoginFailure, client_addr)
+                    .await?;
+                return Ok(LoginResult::from_verification(None, res));
+            }
+        };
+
+        let res = self
+            .authorization_service
+            .verify_user(&user_id, password, client_addr)
+            .await
+            .with_context(|| format!("Authorization error: couldn't verify user {}", user_id))?;
+
+        let login_result = LoginResult::from_verification(Some(user_id), res);
+        match login_result {
+            // The login is complete only after the second factor check, if there is one
+            LoginResult::Success(_) if self.second_factor_enabled(&user_id).await? => {}
+            LoginResult::Success(_) => {
+                self.record_audit_event(Some(&user_id), AuditEventKind::LoginSuccess, client_addr)
+                    .await?
+            }
+            LoginResult::Failed | LoginResult::Locked { .. } => {
+                self.record_audit_event(Some(&user_id), AuditEventKind::LoginFailure, client_addr)
+                    .await?
+            }
+        }
+
+        Ok(login_result)
+    }
+
+    /// Completes a login that passed the password check with the second factor.
+    /// Wrong codes are throttled together with wrong passwords
+    pub async fn log_in_second_factor(
+        &self,
+        user_id: &UserId,
+        code: &str,
+        client_addr: Option<IpAddr>,
+    ) -> Result<LoginResult> {
+        let res = self
+            .authorization_service
+            .verify_login_second_factor(user_id, code, client_addr)
+            .await
+            .with_context(|| {
+                format!("Authorization error: couldn't verify second factor for {user_id}")
+            })?;
+
+        let login_result = LoginResult::from_verification(Some(*user_id), res);
+        let kind = match login_result {
+            LoginResult::Success(_) => AuditEventKind::LoginSuccess,
+            LoginResult::Failed | LoginResult::Locked { .. } => AuditEventKind::LoginFailure,
+        };
+        self.record_audit_event(Some(user_id), kind, client_addr)
+            .await?;
+        Ok(login_result)
+    }
+
+    /// Same as [Messenger::log_in] for a client with unknown address, a locked account isn't verified
+    pub async fn verify_user(&self, username: &str, password: String) -> Result<Option<UserId>> {
+        match self.log_in(username, password, None).await? {
+            LoginResult::Success(user_id) => Ok(Some(user_id)),
+            LoginResult::Failed | LoginResult::Locked { .. } => Ok(None),
+        }
+    }
+
+    /// Same as [Messenger::sign_up] for a client with unknown address
+    pub async fn create_user(&self, login: &str, password: String) -> Result<Option<UserId>> {
+        self.sign_up(login, password, None).await
+    }
+
+    pub async fn sign_up(
+        &self,
+        login: &str,
+        password: String,
+        client_addr: Option<IpAddr>,
+    ) -> Result<Option<UserId>> {
+        let user_id = match self
+            .data_access
+            .create_user(login)
+            .await
+            .with_context(|| format!("Couldn't create user {}", login))?
+        {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+
+        self.authorization_service
+            .create_user(&user_id, password)
+            .await
+            .with_context(|| format!("Authorization error: couldn't create user {}", login))?;
+
+        self.record_audit_event(Some(&user_id), AuditEventKind::Signup, client_addr)
+            .await?;
+
+        Ok(Some(user_id))
+    }
+
+    pub async fn second_factor_enabled(&self, user_id: &UserId) -> Result<bool> {
+        self.authorization_service
+            .second_factor_enabled(user_id)
+            .await
+            .with_context(|| {
+                format!("Authorization error: couldn't check second factor for {user_id}")
+            })
+    }
+
+    pub async fn start_totp_enrollment(&self, user_id: &UserId) -> Result<Option<TotpEnrollment>> {
+        let user = match self.fetch_user(user_id).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        let enrollment = self
+            .authorization_service
+            .start_totp_enrollment(user_id, &user.username)
+            .await
+            .with_context(|| {
+                format!("Authorization error: couldn't start TOTP enrollment for {user_id}")
+            })?;
+        Ok(Some(enrollment))
+    }
+
+    pub async fn confirm_totp_enrollment(
+        &self,
+        user_id: &UserId,
+        code: &str,
+    ) -> Result<Option<Vec<String>>> {
+        self.authorization_service
+            .confirm_totp_enrollment(user_id, code)
+            .await
+            .with_context(|| {
+                format!("Authorization error: couldn't confirm TOTP enrollment for {user_id}")
+            })
+    }
+
+    pub async fn verify_second_factor(&self, user_id: &UserId, code: &str) -> Result<bool> {
+        self.authorization_service
+            .verify_second_factor(user_id, code)
+            .await
+            .with_context(|| {
+                format!("Authorization error: couldn't verify second factor for {user_id}")
+            })
+    }
+
+    /// Turning off the second factor requires a valid code, so that a stolen session is not enough
+    pub async fn disable_totp(&self, user_id: &UserId, code: &str) -> Result<bool> {
+        if !self.verify_second_factor(user_id, code).await? {
+            return Ok(false);
+        }
+
+        self.authorization_service
+            .disable_totp(user_id)
+            .await
+            .with_context(|| format!("Authorization error: couldn't disable TOTP for {user_id}"))?;
+        Ok(true)
+    }
+
+    pub async fn reset_password(
+        &self,
+        token: &str,
+        new_password: String,
+        client_addr: Option<IpAddr>,
+    ) -> Result<Option<UserId>> {
+        let user_id = self
+            .authorization_service
+            .reset_password(token, new_password)
+            .await
+            .context("Authorization error: couldn't reset password")?;
+
+        if let Some(user_id) = &user_id {
+            self.record_audit_event(Some(user_id), AuditEventKind::PasswordChange, client_addr)
+                .await?;
+        }
+
+        Ok(user_id)
+    }
+
+    /// Usernames are unique regardless of case, returns false if the username is taken.
+    /// The user may change the case of their own username
+    pub async fn change_username(
+        &self,
+        user_id: &UserId,
+        username: &str,
+        client_addr: Option<IpAddr>,
+    ) -> Result<bool> {
+        if !profiles::is_valid_username(username) {
+            bail!("Username {username:?} is not valid");
+        }
+
+        let changed = self
+            .data_access
+            .update_username(user_id, username)
+            .await
+            .with_context(|| format!("Couldn't change username of user {user_id}"))?;
+        if changed {
+            self.record_audit_event(Some(user_id), AuditEventKind::UsernameChange, client_addr)
+                .await?;
+        }
+        Ok(changed)
+    }
+
+    pub async fn record_audit_event(
+        &self,
+        user_id: Option<&UserId>,
+        kind: AuditEventKind,
+        client_addr: Option<IpAddr>,
+    ) -> Result<()> {
+        self.authorization_service
+            .record_audit_event(user_id, kind, client_addr)
+            .await
+            .with_context(|| format!("Authorization error: couldn't record audit event {kind}"))
+    }
+
+    /// Returns a page of the user's own audit log, newest first.
+    /// The next page starts before the last returned event
+    pub async fn fetch_user_audit_events(
+        &self,
+        user_id: &UserId,
+        before: Option<AuditEventId>,
+        limit: u32,
+    ) -> Result<Vec<AuditEvent>> {
+        let query = AuditEventQuery {
+            user_id: Some(*user_id),
+            before,
+            ..AuditEventQuery::new(limit)
+        };
+        self.authorization_service
+            .fetch_audit_events(&query)
+            .await
+            .with_context(|| format!("Authorization error: couldn't fetch audit log of {user_id}"))
+    }
+
+    /// Returns None if the user is not an admin
+    pub async fn query_audit_events(
+        &self,
+        user_id: &UserId,
+        query: &AuditEventQuery,
+    ) -> Result<Option<Vec<AuditEvent>>> {
+        if !self.is_admin(user_id) {
+            return Ok(None);
+        }
+
+        let events = self
+            .authorization_service
+            .fetch_audit_events(query)
+            .await
+            .context("Authorization error: couldn't query audit log")?;
+        Ok(Some(events))
+    }
+
+    pub async fn create_api_token(
+        &self,
+        user_id: &UserId,
+        name: &str,
+        scopes: &[ApiScope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(ApiToken, String)> {
+        self.authorization_service
+            .create_api_token(user_id, name, scopes, expires_at)
+            .await
+            .with_context(|| {
+                format!("Authorization error: couldn't create API token for {user_id}")
+            })
+    }
+
+    pub async fn fetch_api_tokens(&self, user_id: &UserId) -> Result<Vec<ApiToken>> {
+        self.authorization_service
+            .fetch_api_tokens(user_id)
+            .await
+            .with_context(|| format!("Authorization error: couldn't fetch API tokens of {user_id}"))
+    }
+
+    pub async fn revoke_api_token(&self, user_id: &UserId, token_id: &ApiTokenId) -> Result<bool> {
+        self.authorization_service
+            .revoke_api_token(user_id, token_id)
+            .await
+            .with_context(|| {
+                format!("Authorization error: couldn't revoke API token {token_id} of {user_id}")
+            })
+    }
+
+    pub fn password_login_enabled(&self) -> bool {
+        self.authorization_service.password_login_enabled()
+    }
+
+    pub fn external_login_enabled(&self) -> bool {
+        self.authorization_service.external_login_enabled()
+    }
+
+    pub async fn start_external_login(&self) -> Result<Option<ExternalLoginStart>> {
+        self.authorization_service
+            .start_external_login()
+            .await
+            .context("Authorization error: couldn't start external login")
+    }
+
+    /// Returns the owner of the token if it exists and is allowed the scope
+    pub async fn verify_api_token(&self, token: &str, scope: ApiScope) -> Result<Option<UserId>> {
+        let api_token = self
+            .authorization_service
+            .verify_api_token(token)
+            .await
+            .context("Authorization error: couldn't verify API token")?;
+        Ok(api_token
+            .filter(|api_token| api_token.scopes.contains(&scope))
+            .map(|api_token| api_token.user_id))
+    }
+}
+
+impl<D: DataAccess, A: AuthService, M, B> Messenger<D, A, M, B> {
+    /// Logs in the owner of the external identity. An identity that isn't linked yet
+    /// is linked to `current_user` if specified, otherwise a new user is created for it.
+    /// Returns None if the login failed or the identity is linked to another user
+    pub async fn finish_external_login(
+        &self,
+        pending: &PendingExternalLogin,
+        code: &str,
+        current_user: Option<UserId>,
+        client_addr: Option<IpAddr>,
+    ) -> Result<Option<UserId>> {
+        let identity = match self
+            .authorization_service
+            .finish_external_login(pending, code)
+            .await
+            .context("Authorization error: couldn't finish external login")?
+        {
+            Some(identity) => identity,
+            None => {
+                self.record_audit_event(
+                    current_user.as_ref(),
+                    AuditEventKind::LoginFailure,
+                    client_addr,
+                )
+                .await?;
+                return Ok(None);
+            }
+        };
+
+        let linked_user = self
+            .authorization_service
+            .fetch_external_identity_user(&identity.issuer, &identity.subject)
+            .await
+            .with_context(|| {
+                format!(
+                    "Authorization error: couldn't fetch user of external identity {}",
+                    identity.subject
+                )
+            })?;
+
+        let user_id = match (linked_user, current_user) {
+            (Some(linked_user), None) => {
+                self.record_audit_event(
+                    Some(&linked_user),
+                    AuditEventKind::LoginSuccess,
+                    client_addr,
+                )
+                .await?;
+                return Ok(Some(linked_user));
+            }
+            (Some(linked_user), Some(current_user)) => {
+                let kind = match linked_user == current_user {
+                    true => AuditEventKind::LoginSuccess,
+                    false => AuditEventKind::LoginFailure,
+                };
+                self.record_audit_event(Some(&current_user), kind, client_addr)
+                    .await?;
+                return Ok((linked_user == current_user).then_some(linked_user));
+            }
+            (None, Some(current_user)) => current_user,
+            (None, None) => {
+                let user_id = self.create_external_user(&identity).await?;
+                self.record_audit_event(Some(&user_id), AuditEventKind::Signup, client_addr)
+                    .await?;
+                user_id
+            }
+        };
+
+        let linked = self
+            .authorization_service
+            .link_external_identity(&identity, &user_id)
+            .await
+            .with_context(|| {
+                format!(
+                    "Authorization error: couldn't link external identity {} to {user_id}",
+                    identity.subject
+                )
+            })?;
+
+        let kind = match linked {
+            true => AuditEventKind::LoginSuccess,
+            false => AuditEventKind::LoginFailure,
+        };
+        self.record_audit_event(Some(&user_id), kind, client_addr)
+            .await?;
+
+        Ok(linked.then_some(user_id))
+    }
+
+    /// Takes the preferred username if it's free, otherwise adds a suffix to it
+    async fn create_external_user(&self, identity: &ExternalIdentity) -> Result<UserId> {
+        let base: String = identity
+            .preferred_username
+            .as_deref()
+            .map(str::trim)
+            .filter(|username| !username.is_empty())
+            .unwrap_or("user")
+            .chars()
+            .take(MAX_EXTERNAL_USERNAME_LENGTH)
+            .collect();
+
+        let candidates = std::iter::once(base.clone())
+            .chain((2..10).map(|i| format!("{base}_{i}")))
+            .chain(std::iter::once(format!(
+                "{base}_{}",
+                &Uuid::new_v4().simple().to_string()[..8]
+            )));
+
+        for username in candidates {
+            if let Some(user_id) = self
+                .data_access
+                .create_user(&username)
+                .await
+                .with_context(|| format!("Couldn't create user {username}"))?
+            {
+                return Ok(user_id);
+            }
+        }
+
+        bail!(
+            "Couldn't find a free username for external identity {}",
+            identity.subject
+        )
+    }
+}
+
+impl<D: DataAccess, A: AuthService, M: Mailer, B> Messenger<D, A, M, B> {
+    /// Sends a password reset token to the user's email.
+    /// Returns false if there is no such user, the user has no email or the mail couldn't be sent
+    pub async fn request_password_reset(&self, username: &str) -> Result<bool> {
+        if !self.authorization_service.password_login_enabled() {
+            return Ok(false);
+        }
+
+        let user_id = match self.find_user_by_username(username).await? {
+            Some(user_id) => user_id,
+            None => return Ok(false),
+        };
+
+        let email = match self
+            .data_access
+            .fetch_user_email(&user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch email for user {user_id}"))?
+        {
+            Some(email) => email,
+            None => return Ok(false),
+        };
+
+        let token = self
+            .authorization_service
+            .create_password_reset_token(&user_id)
+            .await
+            .with_context(|| {
+                format!("Authorization error: couldn't create password reset token for {user_id}")
+            })?;
+
+        let mail = Mail {
+            to: email,
+            subject: "Pheidippides password reset".to_owned(),
+            body: format!(
+                "Someone requested a password reset for your account {username}.\n\
+                If it was you, enter the following code on the password reset page: {token}\n\
+                If it wasn't you, simply ignore this message."
+            ),
+        };
+
+        // A failure is only logged, otherwise the error would reveal that the account exists
+        if let Err(e) = self
+            .mailer
+            .send_mail(mail)
+            .await
+            .with_context(|| format!("Couldn't send password reset mail for user {user_id}"))
+        {
+            log_internal_error(e);
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
+
+impl<D: DataAccess, A: AuthService, M, B: BlobStorage> Messenger<D, A, M, B> {
+    /// Collects everything stored about the user. Returns None if the user doesn't exist
+    pub async fn export_user_data(&self, user_id: &UserId) -> Result<Option<UserDataExport>> {
+        let Some(profile) = self.fetch_profile(user_id).await? else {
+            return Ok(None);
+        };
+        let email = self
+            .data_access
+            .fetch_user_email(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch email of user {user_id}"))?;
+
+        let conversations = self
+            .data_access
+            .find_users_chats(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch chats of user {user_id}"))?;
+        let mut messages = vec![];
+        for conversation in &conversations {
+            let mut starting_point = None;
+            loop {
+                let page = self
+                    .data_access
+                    .fetch_last_messages_in_chat(user_id, &conversation.id, starting_point.as_ref())
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Couldn't fetch messages of user {user_id} in {}",
+                            conversation.id
+                        )
+                    })?;
+                let Some(last) = page.last() else {
+                    break;
+                };
+                starting_point = Some(last.id);
+                messages.extend(page);
+            }
+        }
+        messages.sort_by_key(|message| message.timestamp);
+
+        let blocked_users = self
+            .data_access
+            .fetch_blocked_users(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch users blocked by user {user_id}"))?;
+        let api_tokens = self.fetch_api_tokens(user_id).await?;
+
+        let mut audit_events = vec![];
+        loop {
+            let before = audit_events.last().map(|event: &AuditEvent| event.id);
+            let page = self
+                .fetch_user_audit_events(user_id, before, EXPORT_AUDIT_PAGE_SIZE)
+                .await?;
+            let page_size = page.len();
+            audit_events.extend(page);
+            if page_size < EXPORT_AUDIT_PAGE_SIZE as usize {
+                break;
+            }
+        }
+
+        let attachments = self
+            .data_access
+            .fetch_users_attachments(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch attachments of user {user_id}"))?;
+
+        Ok(Some(UserDataExport {
+            profile,
+            email,
+            conversations,
+            messages,
+            blocked_users,
+            api_tokens,
+            audit_events,
+            attachments,
+        }))
+    }
+
+    /// Erases the user's account. Their messages stay with the counterparties,
+    /// attributed to a placeholder user. Returns false if there is no such account.
+    /// Sessions are not tracked by the messenger and have to be revoked by the caller
+    pub async fn delete_account(
+        &self,
+        user_id: &UserId,
+        client_addr: Option<IpAddr>,
+    ) -> Result<bool> {
+        match self.fetch_profile(user_id).await? {
+            Some(profile) if !profile.deleted => {}
+            _ => return Ok(false),
+        }
+
+        let replacement_id = Uuid::new_v4();
+        let replacement = Profile {
+            user_id: replacement_id,
+            username: format!("deleted-{}", replacement_id.simple()),
+            display_name: Some(DELETED_USER_DISPLAY_NAME.to_owned()),
+            bio: String::new(),
+            avatar: None,
+            deleted: true,
+        };
+        let unsent_attachments = self
+            .data_access
+            .delete_user(user_id, &replacement)
+            .await
+            .with_context(|| format!("Couldn't delete user {user_id}"))?;
+
+        for attachment in &unsent_attachments {
+            self.delete_attachment_content(attachment).await;
+        }
+        self.record_audit_event(Some(user_id), AuditEventKind::AccountDeletion, client_addr)
+            .await?;
+        Ok(true)
+    }
+}
+
+impl<D: DataAccess, A: AuthService, M: Mailer, B: BlobStorage> Messenger<D, A, M, B> {
+    /// Sends scheduled messages when they are due, checking the queue every `interval`
+    /// until the returned task is aborted. Messages that fell due while the server was down
+    /// are sent on the first check
+    pub fn spawn_scheduler(&self, interval: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        let messenger = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = messenger.deliver_scheduled_messages().await {
+                    log_internal_error(e);
+                }
+            }
+        })
+    }
+
+    /// Purges expired messages every `interval` until the returned task is aborted
+    pub fn spawn_purge_job(&self, interval: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        let messenger = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = messenger.purge_expired_messages().await {
+                    log_internal_error(e);
+                }
+            }
+        })
     }
 }
@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{ChatId, ConversationId, MessageId, UserId};
+
+pub type NotificationId = Uuid;
+
+pub const DEFAULT_NOTIFICATIONS_PAGE_SIZE: u32 = 20;
+pub const MAX_NOTIFICATIONS_PAGE_SIZE: u32 = 100;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum NotificationKind {
+    /// The actor mentioned the user in the message
+    Mention {
+        message_id: MessageId,
+        /// As seen by the mentioned user
+        conversation: ConversationId,
+    },
+    /// The actor added the user to the group chat
+    NewChat { chat_id: ChatId },
+    /// The actor reacted to the user's message
+    Reaction {
+        message_id: MessageId,
+        /// As seen by the author of the message
+        conversation: ConversationId,
+        emoji: String,
+    },
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Mention { .. } => "mention",
+            NotificationKind::NewChat { .. } => "new_chat",
+            NotificationKind::Reaction { .. } => "reaction",
+        }
+    }
+
+    /// The message the notification is about, removing it removes the notification
+    pub fn message_id(&self) -> Option<&MessageId> {
+        match self {
+            NotificationKind::Mention { message_id, .. }
+            | NotificationKind::Reaction { message_id, .. } => Some(message_id),
+            NotificationKind::NewChat { .. } => None,
+        }
+    }
+}
+
+/// An entry of the user's notification feed
+#[derive(Clone, PartialEq, Debug)]
+pub struct Notification {
+    pub id: NotificationId,
+    pub user_id: UserId,
+    pub actor: UserId,
+    pub kind: NotificationKind,
+    pub created_at: DateTime<Utc>,
+    /// None while the notification is unread
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+impl Notification {
+    pub fn cursor(&self) -> NotificationCursor {
+        NotificationCursor {
+            created_at: self.created_at,
+            notification_id: self.id,
+        }
+    }
+}
+
+/// Position in the notification feed, ordered by creation time, newest first.
+/// Only notifications ordered after it are returned
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NotificationCursor {
+    pub created_at: DateTime<Utc>,
+    pub notification_id: NotificationId,
+}
+
+impl NotificationCursor {
+    /// Whether the notification is ordered after the cursor
+    pub fn precedes(&self, created_at: &DateTime<Utc>, notification_id: &NotificationId) -> bool {
+        (created_at, notification_id) < (&self.created_at, &self.notification_id)
+    }
+}
+
+impl std::fmt::Display for NotificationCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}_{}",
+            self.created_at.timestamp_micros(),
+            self.notification_id
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Incorrect notification cursor: {0}")]
+pub struct NotificationCursorParsingError(String);
+
+impl std::str::FromStr for NotificationCursor {
+    type Err = NotificationCursorParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || NotificationCursorParsingError(s.to_owned());
+        let (created_at, notification_id) = s.split_once('_').ok_or_else(error)?;
+        let created_at = created_at.parse().map_err(|_| error())?;
+        Ok(NotificationCursor {
+            created_at: DateTime::from_timestamp_micros(created_at).ok_or_else(error)?,
+            notification_id: notification_id.parse().map_err(|_| error())?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct NotificationPage {
+    pub notifications: Vec<Notification>,
+    /// Continues the listing, None on the last page
+    pub next_cursor: Option<NotificationCursor>,
+    /// Unread notifications in the whole feed
+    pub unread_count: u32,
+}
@@ -0,0 +1,11 @@
+use chrono::{DateTime, Utc};
+
+use crate::{Message, UserId};
+
+/// A message pinned to its conversation, seen by all of the participants
+#[derive(Clone, PartialEq, Debug)]
+pub struct PinnedMessage {
+    pub message: Message,
+    pub pinned_by: UserId,
+    pub pinned_at: DateTime<Utc>,
+}
@@ -0,0 +1,10 @@
+use chrono::{DateTime, Utc};
+
+/// Online status derived from open event streams, kept in memory only
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Presence {
+    pub online: bool,
+    /// When the last event stream of the user was closed, unknown for users
+    /// who haven't been online since the server started
+    pub last_seen: Option<DateTime<Utc>>,
+}
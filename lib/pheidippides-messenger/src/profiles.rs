@@ -0,0 +1,38 @@
+use crate::attachments::AttachmentId;
+use crate::UserId;
+
+/// The length of the `users.username` column
+pub const MAX_USERNAME_LENGTH: usize = 150;
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+pub const MAX_BIO_LENGTH: usize = 1000;
+
+/// What other users see about a user
+#[derive(Clone, PartialEq, Debug)]
+pub struct Profile {
+    pub user_id: UserId,
+    /// Unique login, compared case-insensitively
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: String,
+    /// Image attachment uploaded by the user, shown by its thumbnail
+    pub avatar: Option<AttachmentId>,
+    /// Set for the placeholder that takes over the messages of a deleted account
+    pub deleted: bool,
+}
+
+impl Profile {
+    /// The display name, or the username if it isn't set
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.username)
+    }
+}
+
+/// Usernames are used to log in and to mention users, so they are a single word
+pub fn is_valid_username(username: &str) -> bool {
+    let length = username.chars().count();
+    length > 0
+        && length <= MAX_USERNAME_LENGTH
+        && username
+            .chars()
+            .all(|c| !c.is_whitespace() && !c.is_control())
+}
@@ -0,0 +1,44 @@
+use crate::UserId;
+
+/// Longest accepted reaction in characters, enough for emoji joined with zero-width joiners
+const MAX_REACTION_LENGTH: usize = 10;
+
+/// Users who reacted to a message with the same emoji, in the order they reacted
+#[derive(Clone, PartialEq, Debug)]
+pub struct Reaction {
+    pub emoji: String,
+    pub users: Vec<UserId>,
+}
+
+impl Reaction {
+    pub fn count(&self) -> usize {
+        self.users.len()
+    }
+}
+
+/// Reactions are short sequences of symbols: no letters, digits, whitespace or ascii
+pub fn is_valid_reaction(emoji: &str) -> bool {
+    let length = emoji.chars().count();
+    length > 0
+        && length <= MAX_REACTION_LENGTH
+        && emoji
+            .chars()
+            .all(|c| !c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace() && !c.is_control())
+}
+
+/// Groups reactions given in the order they were made, emoji reacted with first come first
+pub fn group_reactions<'a>(
+    reactions: impl IntoIterator<Item = (&'a str, UserId)>,
+) -> Vec<Reaction> {
+    let mut res: Vec<Reaction> = vec![];
+    for (emoji, user_id) in reactions {
+        match res.iter_mut().find(|reaction| reaction.emoji == emoji) {
+            Some(reaction) => reaction.users.push(user_id),
+            None => res.push(Reaction {
+                emoji: emoji.to_owned(),
+                users: vec![user_id],
+            }),
+        }
+    }
+    res
+}
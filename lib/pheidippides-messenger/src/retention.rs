@@ -0,0 +1,12 @@
+use chrono::TimeDelta;
+
+/// Shortest timer of disappearing messages
+pub const MIN_MESSAGE_TTL: TimeDelta = TimeDelta::minutes(1);
+/// Longest timer of disappearing messages
+pub const MAX_MESSAGE_TTL: TimeDelta = TimeDelta::days(365);
+/// Messages deleted by a single statement, so that purging doesn't lock the table for long
+pub const PURGE_BATCH_SIZE: u32 = 500;
+
+pub fn is_valid_message_ttl(ttl: &TimeDelta) -> bool {
+    (MIN_MESSAGE_TTL..=MAX_MESSAGE_TTL).contains(ttl)
+}
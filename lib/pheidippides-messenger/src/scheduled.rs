@@ -0,0 +1,38 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use uuid::Uuid;
+
+use crate::{ConversationId, OutgoingMessage, UserId};
+
+pub type ScheduledMessageId = Uuid;
+
+/// Furthest in the future a message can be scheduled
+pub const MAX_SCHEDULE_AHEAD: TimeDelta = TimeDelta::days(365);
+/// Due messages fetched from the queue at once
+pub const DELIVERY_BATCH_SIZE: u32 = 100;
+/// Delay before retrying a delivery that failed for the first time, it doubles with every failure
+pub const DELIVERY_RETRY_DELAY: TimeDelta = TimeDelta::minutes(1);
+/// Failed deliveries after which a message is dropped
+pub const MAX_DELIVERY_ATTEMPTS: i32 = 10;
+
+/// A message waiting in the queue to be sent on behalf of its author
+#[derive(Clone, PartialEq, Debug)]
+pub struct ScheduledMessage {
+    /// Becomes the id of the sent message, so that it's never delivered twice
+    pub id: ScheduledMessageId,
+    pub from: UserId,
+    /// The conversation as seen by the sender
+    pub to: ConversationId,
+    pub outgoing: OutgoingMessage,
+    pub send_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    /// Deliveries that failed for other reasons than the message being rejected
+    pub failed_attempts: i32,
+    /// When the delivery is retried after a failure
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+/// Delay before the next delivery after `failed_attempts` failures
+pub fn delivery_retry_delay(failed_attempts: i32) -> TimeDelta {
+    let exponent = failed_attempts.clamp(1, MAX_DELIVERY_ATTEMPTS) - 1;
+    DELIVERY_RETRY_DELAY * (1 << exponent)
+}
@@ -0,0 +1,196 @@
+use chrono::{DateTime, Utc};
+
+use crate::{ConversationId, Message, MessageId, UserId};
+
+pub const DEFAULT_SEARCH_PAGE_SIZE: u32 = 20;
+pub const MAX_SEARCH_PAGE_SIZE: u32 = 100;
+/// Words shown before the first match in a snippet
+const SNIPPET_CONTEXT_WORDS: usize = 4;
+const SNIPPET_WORDS: usize = 16;
+
+/// Messages must contain every word of `text`, the other fields narrow the search down
+#[derive(Clone, PartialEq, Debug)]
+pub struct MessageSearchQuery {
+    pub text: String,
+    /// The conversation as seen by the searching user
+    pub conversation: Option<ConversationId>,
+    pub sender: Option<UserId>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Continues after the last result of the previous page
+    pub cursor: Option<SearchCursor>,
+    pub limit: u32,
+}
+
+impl MessageSearchQuery {
+    pub fn new(text: impl Into<String>) -> Self {
+        MessageSearchQuery {
+            text: text.into(),
+            conversation: None,
+            sender: None,
+            since: None,
+            until: None,
+            cursor: None,
+            limit: DEFAULT_SEARCH_PAGE_SIZE,
+        }
+    }
+}
+
+/// Results are ordered by rank, then by time, newest first
+#[derive(Clone, PartialEq, Debug)]
+pub struct MessageSearchResult {
+    pub message: Message,
+    pub rank: f32,
+}
+
+impl MessageSearchResult {
+    pub fn cursor(&self) -> SearchCursor {
+        SearchCursor {
+            rank: self.rank,
+            timestamp: self.message.timestamp,
+            message_id: self.message.id,
+        }
+    }
+}
+
+/// Position in the search results, only results ordered after it are returned
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SearchCursor {
+    pub rank: f32,
+    pub timestamp: DateTime<Utc>,
+    pub message_id: MessageId,
+}
+
+impl SearchCursor {
+    /// Whether the result is ordered after the cursor
+    pub fn precedes(&self, rank: f32, timestamp: &DateTime<Utc>, message_id: &MessageId) -> bool {
+        (rank, timestamp, message_id) < (self.rank, &self.timestamp, &self.message_id)
+    }
+}
+
+impl std::fmt::Display for SearchCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}_{}_{}",
+            self.rank,
+            self.timestamp.timestamp_micros(),
+            self.message_id
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Incorrect search cursor: {0}")]
+pub struct SearchCursorParsingError(String);
+
+impl std::str::FromStr for SearchCursor {
+    type Err = SearchCursorParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || SearchCursorParsingError(s.to_owned());
+        let mut parts = s.splitn(3, '_');
+        let (Some(rank), Some(timestamp), Some(message_id)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(error());
+        };
+        let timestamp = timestamp.parse().map_err(|_| error())?;
+        Ok(SearchCursor {
+            rank: rank.parse().map_err(|_| error())?,
+            timestamp: DateTime::from_timestamp_micros(timestamp).ok_or_else(error)?,
+            message_id: message_id.parse().map_err(|_| error())?,
+        })
+    }
+}
+
+/// A found message with an excerpt around the first match
+#[derive(Clone, PartialEq, Debug)]
+pub struct MessageSearchHit {
+    pub message: Message,
+    pub rank: f32,
+    pub snippet: Vec<SnippetFragment>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SnippetFragment {
+    pub text: String,
+    /// Set for words matching the query
+    pub highlighted: bool,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct MessageSearchPage {
+    pub hits: Vec<MessageSearchHit>,
+    /// Set if there may be more results
+    pub next_cursor: Option<SearchCursor>,
+}
+
+/// Byte ranges of the words of the text: runs of alphanumeric characters
+fn word_ranges(text: &str) -> Vec<(usize, usize)> {
+    let mut res = vec![];
+    let mut start = None;
+    for (index, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(index),
+            (false, Some(word_start)) => {
+                res.push((word_start, index));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(word_start) = start {
+        res.push((word_start, text.len()));
+    }
+    res
+}
+
+/// Lowercase words of the text the way they are indexed
+pub fn search_terms(text: &str) -> Vec<String> {
+    word_ranges(text)
+        .into_iter()
+        .map(|(start, end)| text[start..end].to_lowercase())
+        .collect()
+}
+
+/// Excerpt of the text around the first word matching the query, with matching words highlighted
+pub fn snippet(text: &str, query: &str) -> Vec<SnippetFragment> {
+    let terms = search_terms(query);
+    let words = word_ranges(text);
+    let is_match =
+        |&(start, end): &(usize, usize)| terms.contains(&text[start..end].to_lowercase());
+
+    let first_match = words.iter().position(is_match).unwrap_or(0);
+    let first_word = first_match.saturating_sub(SNIPPET_CONTEXT_WORDS);
+    let last_word = (first_word + SNIPPET_WORDS).min(words.len());
+
+    let mut res: Vec<SnippetFragment> = vec![];
+    let mut push = |text: &str, highlighted: bool| match res.last_mut() {
+        Some(last) if last.highlighted == highlighted => last.text.push_str(text),
+        _ => res.push(SnippetFragment {
+            text: text.to_owned(),
+            highlighted,
+        }),
+    };
+
+    let mut position = match words.get(first_word) {
+        Some(&(start, _)) if first_word > 0 => {
+            push("…", false);
+            start
+        }
+        _ => 0,
+    };
+    for word in &words[first_word..last_word] {
+        push(&text[position..word.0], false);
+        push(&text[word.0..word.1], is_match(word));
+        position = word.1;
+    }
+    if last_word < words.len() {
+        push("…", false);
+    } else {
+        push(&text[position..], false);
+    }
+    res.retain(|fragment| !fragment.text.is_empty());
+    res
+}
@@ -0,0 +1,73 @@
+use chrono::{DateTime, Utc};
+
+use crate::{Message, MessageId};
+
+pub const DEFAULT_STARRED_PAGE_SIZE: u32 = 20;
+pub const MAX_STARRED_PAGE_SIZE: u32 = 100;
+
+/// A message the user starred for themselves, stars aren't visible to anyone else
+#[derive(Clone, PartialEq, Debug)]
+pub struct StarredMessage {
+    pub message: Message,
+    pub starred_at: DateTime<Utc>,
+}
+
+impl StarredMessage {
+    pub fn cursor(&self) -> StarCursor {
+        StarCursor {
+            starred_at: self.starred_at,
+            message_id: self.message.id,
+        }
+    }
+}
+
+/// Position in the starred messages, ordered by the time of starring, newest first.
+/// Only messages ordered after it are returned
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct StarCursor {
+    pub starred_at: DateTime<Utc>,
+    pub message_id: MessageId,
+}
+
+impl StarCursor {
+    /// Whether the star is ordered after the cursor
+    pub fn precedes(&self, starred_at: &DateTime<Utc>, message_id: &MessageId) -> bool {
+        (starred_at, message_id) < (&self.starred_at, &self.message_id)
+    }
+}
+
+impl std::fmt::Display for StarCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}_{}",
+            self.starred_at.timestamp_micros(),
+            self.message_id
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Incorrect star cursor: {0}")]
+pub struct StarCursorParsingError(String);
+
+impl std::str::FromStr for StarCursor {
+    type Err = StarCursorParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || StarCursorParsingError(s.to_owned());
+        let (starred_at, message_id) = s.split_once('_').ok_or_else(error)?;
+        let starred_at = starred_at.parse().map_err(|_| error())?;
+        Ok(StarCursor {
+            starred_at: DateTime::from_timestamp_micros(starred_at).ok_or_else(error)?,
+            message_id: message_id.parse().map_err(|_| error())?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct StarredPage {
+    pub messages: Vec<StarredMessage>,
+    /// Continues the listing, None on the last page
+    pub next_cursor: Option<StarCursor>,
+}
@@ -1,62 +1,58 @@
 use crate::data_access::DataAccess;
-use crate::{Message, MessageId, UserId};
+use crate::events::Event;
+use crate::presence::Presence;
+use crate::{ChatId, ConversationId, MessageId, UserId};
 use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
 use pheidippides_utils::async_utils;
 use pheidippides_utils::utils::log_internal_error;
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast::Sender;
 use tokio::sync::mpsc;
 
 const SUBSCRIPTIONS_CLEANUP_INTERVAL: Duration = Duration::from_secs(5);
+/// A user's typing events in a conversation are fanned out at most once per interval
+const TYPING_THROTTLE_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(Default)]
+struct PresenceRecord {
+    /// Open event streams of the user
+    connections: usize,
+    last_seen: Option<DateTime<Utc>>,
+}
 
 #[derive(Clone)]
 pub struct SubscriptionsHandler<D> {
     data_access: D,
-    new_messages: Arc<RwLock<HashMap<UserId, Sender<Message>>>>,
+    new_messages: Arc<RwLock<HashMap<UserId, Sender<Event>>>>,
+    presence: Arc<Mutex<HashMap<UserId, PresenceRecord>>>,
+    /// When the last typing event of a user in a conversation was fanned out
+    typing: Arc<Mutex<HashMap<(UserId, ConversationId), Instant>>>,
 }
 
 impl<D> SubscriptionsHandler<D> {
     pub fn new(data_access: D) -> Self {
-        let new_messages_subscriptions: Arc<RwLock<HashMap<UserId, Sender<Message>>>> =
+        let new_messages_subscriptions: Arc<RwLock<HashMap<UserId, Sender<Event>>>> =
             Arc::new(RwLock::new(HashMap::new()));
+        let typing = Arc::new(Mutex::new(HashMap::new()));
 
-        Self::spawn_cleanup_job(new_messages_subscriptions.clone());
+        Self::spawn_cleanup_job(new_messages_subscriptions.clone(), Arc::clone(&typing));
 
         SubscriptionsHandler {
             data_access,
             new_messages: new_messages_subscriptions,
+            presence: Arc::new(Mutex::new(HashMap::new())),
+            typing,
         }
     }
 
-    pub fn handle_new_message(&self, message: &Message) -> anyhow::Result<()> {
-        let subscriptions_read = match self.new_messages.read() {
-            Ok(read_lock) => read_lock,
-            Err(e) => bail!("Could not lock new_messages_subscriptions for read: {e}"),
-        };
-
-        if let Some(sender) = subscriptions_read.get(&message.from) {
-            Self::send_event_to_subscribers(sender, message).with_context(|| {
-                format!("Couldn't send subscription events for {}", &message.from)
-            })?;
-        };
-
-        if message.from != message.to {
-            if let Some(sender) = subscriptions_read.get(&message.to) {
-                Self::send_event_to_subscribers(sender, message).with_context(|| {
-                    format!("Couldn't send subscription events for {}", &message.to)
-                })?;
-            };
-        };
-
-        Ok(())
-    }
-
     fn spawn_cleanup_job(
-        new_messages_subscriptions: Arc<RwLock<HashMap<UserId, Sender<Message>>>>,
+        new_messages_subscriptions: Arc<RwLock<HashMap<UserId, Sender<Event>>>>,
+        typing: Arc<Mutex<HashMap<(UserId, ConversationId), Instant>>>,
     ) {
-        // Periodically removes unused subscriptions
+        // Periodically removes unused subscriptions and expired typing throttles
         tokio::spawn(async move {
             loop {
                 tokio::time::sleep(SUBSCRIPTIONS_CLEANUP_INTERVAL).await;
@@ -67,10 +63,52 @@ impl<D> SubscriptionsHandler<D> {
                     }
                     Err(e) => log_internal_error(e),
                 }
+                match typing.lock() {
+                    Ok(mut lock) => {
+                        lock.retain(|_, sent_at| sent_at.elapsed() < TYPING_THROTTLE_INTERVAL);
+                        lock.shrink_to_fit();
+                    }
+                    Err(e) => log_internal_error(e),
+                }
             }
         });
     }
 
+    pub fn presence(&self, user_id: &UserId) -> anyhow::Result<Presence> {
+        let presence_lock = match self.presence.lock() {
+            Ok(lock) => lock,
+            Err(e) => bail!("Could not lock presence: {e}"),
+        };
+        let presence = presence_lock
+            .get(user_id)
+            .map(|record| Presence {
+                online: record.connections > 0,
+                last_seen: record.last_seen,
+            })
+            .unwrap_or_default();
+        Ok(presence)
+    }
+
+    /// Returns false if the user's last typing event in the conversation was sent too recently
+    fn pass_typing_throttle(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> anyhow::Result<bool> {
+        let mut typing_lock = match self.typing.lock() {
+            Ok(lock) => lock,
+            Err(e) => bail!("Could not lock typing throttles: {e}"),
+        };
+        let now = Instant::now();
+        match typing_lock.get(&(*user_id, *conversation)) {
+            Some(sent_at) if now.duration_since(*sent_at) < TYPING_THROTTLE_INTERVAL => Ok(false),
+            _ => {
+                typing_lock.insert((*user_id, *conversation), now);
+                Ok(true)
+            }
+        }
+    }
+
     fn send_event_to_subscribers<T: Clone>(sender: &Sender<T>, event: &T) -> anyhow::Result<()> {
         match sender.send(event.clone()) {
             Ok(_) => Ok(()),
@@ -80,11 +118,201 @@ impl<D> SubscriptionsHandler<D> {
 }
 
 impl<D: DataAccess> SubscriptionsHandler<D> {
+    pub async fn handle_event(&self, event: &Event) -> anyhow::Result<()> {
+        let message = match event {
+            Event::NewMessage(message)
+            | Event::MessageEdited(message)
+            | Event::MessageDeleted(message)
+            | Event::MessageExpired(message)
+            | Event::ReactionsChanged(message)
+            | Event::MessagePinned { message, .. }
+            | Event::MessageUnpinned { message, .. }
+            | Event::MessagesRead { message, .. } => message,
+            Event::MessageHidden { user_id, .. } => return self.send_event(&[*user_id], event),
+            Event::Notification(notification) => {
+                return self.send_event(&[notification.user_id], event)
+            }
+            Event::Typing {
+                user_id,
+                conversation,
+            } => {
+                if !self.pass_typing_throttle(user_id, conversation)? {
+                    return Ok(());
+                }
+                let recipients: Vec<_> = match conversation {
+                    ConversationId::Direct(other_user) => vec![*other_user],
+                    ConversationId::Group(chat_id) => self.chat_members(chat_id).await?,
+                };
+                let recipients: Vec<_> = recipients
+                    .into_iter()
+                    .filter(|recipient| recipient != user_id)
+                    .collect();
+                let recipients = self.without_blocked(user_id, recipients).await?;
+                return self.send_event(&recipients, event);
+            }
+            Event::PresenceChanged { user_id, .. } => {
+                let contacts = self.contacts(user_id).await?;
+                let contacts = self.without_blocked(user_id, contacts).await?;
+                return self.send_event(&contacts, event);
+            }
+        };
+        let recipients = match message.to {
+            ConversationId::Direct(to) if to == message.from => vec![to],
+            ConversationId::Direct(to) => vec![message.from, to],
+            ConversationId::Group(chat_id) => self.chat_members(&chat_id).await?,
+        };
+
+        self.send_event(&recipients, event)
+    }
+
+    async fn chat_members(&self, chat_id: &ChatId) -> anyhow::Result<Vec<UserId>> {
+        let members = self
+            .data_access
+            .fetch_chat_members(chat_id)
+            .await
+            .with_context(|| format!("Couldn't fetch members of chat {chat_id}"))?
+            .into_iter()
+            .map(|member| member.user_id)
+            .collect();
+        Ok(members)
+    }
+
+    /// Users sharing a direct conversation or a group chat with the user
+    async fn contacts(&self, user_id: &UserId) -> anyhow::Result<Vec<UserId>> {
+        let chats = self
+            .data_access
+            .find_users_chats(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch chats of {user_id}"))?;
+
+        let mut contacts = HashSet::new();
+        for chat in chats {
+            match chat.id {
+                ConversationId::Direct(other_user) => {
+                    contacts.insert(other_user);
+                }
+                ConversationId::Group(chat_id) => {
+                    contacts.extend(self.chat_members(&chat_id).await?)
+                }
+            }
+        }
+        contacts.remove(user_id);
+        Ok(contacts.into_iter().collect())
+    }
+
+    /// Leaves out the recipients blocking the user or blocked by them
+    async fn without_blocked(
+        &self,
+        user_id: &UserId,
+        recipients: Vec<UserId>,
+    ) -> anyhow::Result<Vec<UserId>> {
+        let blocked_users = self.blocked_users(user_id).await?;
+        let mut res = Vec::with_capacity(recipients.len());
+        for recipient in recipients {
+            if !blocked_users.contains(&recipient)
+                && !self.blocked_users(&recipient).await?.contains(user_id)
+            {
+                res.push(recipient);
+            }
+        }
+        Ok(res)
+    }
+
+    async fn blocked_users(&self, user_id: &UserId) -> anyhow::Result<Vec<UserId>> {
+        self.data_access
+            .fetch_blocked_users(user_id)
+            .await
+            .with_context(|| format!("Couldn't fetch users blocked by user {user_id}"))
+    }
+
+    pub async fn is_contact(
+        &self,
+        user_id: &UserId,
+        other_user_id: &UserId,
+    ) -> anyhow::Result<bool> {
+        Ok(self.contacts(user_id).await?.contains(other_user_id))
+    }
+
+    /// Counts the subscription towards the user's presence until its receiver is dropped
+    async fn track_connection(&self, user_id: UserId, connection: mpsc::UnboundedSender<Event>) {
+        let came_online = self.update_presence(&user_id, |record| {
+            record.connections += 1;
+            record.connections == 1
+        });
+        match came_online {
+            Ok(true) => self.announce_presence(user_id).await,
+            Ok(false) => {}
+            Err(e) => log_internal_error(e),
+        }
+
+        let subscriptions_handler = self.clone();
+        tokio::spawn(async move {
+            connection.closed().await;
+            let went_offline = subscriptions_handler.update_presence(&user_id, |record| {
+                record.connections -= 1;
+                if record.connections == 0 {
+                    record.last_seen = Some(Utc::now());
+                }
+                record.connections == 0
+            });
+            match went_offline {
+                Ok(true) => subscriptions_handler.announce_presence(user_id).await,
+                Ok(false) => {}
+                Err(e) => log_internal_error(e),
+            }
+        });
+    }
+
+    fn update_presence<T>(
+        &self,
+        user_id: &UserId,
+        f: impl FnOnce(&mut PresenceRecord) -> T,
+    ) -> anyhow::Result<T> {
+        let mut presence_lock = match self.presence.lock() {
+            Ok(lock) => lock,
+            Err(e) => bail!("Could not lock presence: {e}"),
+        };
+        Ok(f(presence_lock.entry(*user_id).or_default()))
+    }
+
+    async fn announce_presence(&self, user_id: UserId) {
+        let res = match self.presence(&user_id) {
+            Ok(presence) => {
+                self.handle_event(&Event::PresenceChanged { user_id, presence })
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            log_internal_error(e.context(format!("Couldn't announce presence of {user_id}")));
+        }
+    }
+
+    fn send_event(&self, recipients: &[UserId], event: &Event) -> anyhow::Result<()> {
+        let subscriptions_read = match self.new_messages.read() {
+            Ok(read_lock) => read_lock,
+            Err(e) => bail!("Could not lock new_messages_subscriptions for read: {e}"),
+        };
+
+        // One member's stale subscription shouldn't keep the message from the others
+        for recipient in recipients {
+            if let Some(sender) = subscriptions_read.get(recipient) {
+                if let Err(e) = Self::send_event_to_subscribers(sender, event) {
+                    log_internal_error(
+                        e.context(format!("Couldn't send subscription events for {recipient}")),
+                    );
+                }
+            };
+        }
+
+        Ok(())
+    }
+
     pub async fn subscribe_new_messages(
         &self,
         user_id: UserId,
         starting_point: Option<MessageId>,
-    ) -> anyhow::Result<mpsc::UnboundedReceiver<Message>> {
+    ) -> anyhow::Result<mpsc::UnboundedReceiver<Event>> {
         let subscription = {
             // let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
             let mut subscriptions_lock = match self.new_messages.write() {
@@ -98,36 +326,30 @@ impl<D: DataAccess> SubscriptionsHandler<D> {
                 .subscribe()
         };
 
-        match starting_point {
-            None => {
-                // no extra channel needed, simply convert broadcast to an unbounded channel
-                Ok(async_utils::pipe_broadcast(subscription, Some))
-            }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let subscription = match starting_point {
+            None => async_utils::pipe_broadcast(subscription, Some),
             Some(starting_point) => {
                 let previous_messages = self
                     .data_access
                     .fetch_users_messages_since(&user_id, &starting_point)
                     .await?;
-                let (sender, receiver) = mpsc::unbounded_channel();
 
                 let mut sent_messages = HashSet::new();
                 for message in previous_messages {
                     sent_messages.insert(message.id);
-                    sender.send(message)?; // Receiver can't be dropped at this point, if .send() returns an error, propagate it back for debugging
+                    sender.send(Event::NewMessage(message))?; // Receiver can't be dropped at this point, if .send() returns an error, propagate it back for debugging
                 }
 
-                let subscription_filtered =
-                    async_utils::pipe_broadcast(subscription, move |message| {
-                        if sent_messages.contains(&message.id) {
-                            None
-                        } else {
-                            Some(message)
-                        }
-                    });
-
-                async_utils::redirect_unbounded_channel(subscription_filtered, sender);
-                Ok(receiver)
+                async_utils::pipe_broadcast(subscription, move |event| match &event {
+                    Event::NewMessage(message) if sent_messages.contains(&message.id) => None,
+                    _ => Some(event),
+                })
             }
-        }
+        };
+
+        async_utils::redirect_unbounded_channel(subscription, sender.clone());
+        self.track_connection(user_id, sender).await;
+        Ok(receiver)
     }
 }
@@ -0,0 +1,243 @@
+use std::sync::{Arc, RwLock};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use pheidippides_messenger::authorization::{
+    ExternalIdentity, ExternalLoginStart, PendingExternalLogin,
+};
+
+use crate::Error;
+
+const RANDOM_VALUE_BYTES: usize = 32;
+const SCOPES: &str = "openid profile";
+/// Symmetric algorithms are excluded, the client secret must not be usable to forge tokens
+const ALLOWED_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    /// Has to match the issuer in the discovery document exactly
+    pub issuer_url: String,
+    pub client_id: String,
+    /// None for public clients, which rely on PKCE only
+    pub client_secret: Option<String>,
+    /// Callback url of the server, registered with the identity provider
+    pub redirect_url: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+}
+
+/// OpenID Connect relying party using the authorization code flow with PKCE
+#[derive(Clone)]
+pub struct OidcClient {
+    config: OidcConfig,
+    metadata: ProviderMetadata,
+    /// Refetched when a token is signed with an unknown key, so that key rotation is picked up
+    jwks: Arc<RwLock<JwkSet>>,
+    http_client: reqwest::Client,
+}
+
+impl OidcClient {
+    /// Fetches the discovery document and the signing keys of the identity provider
+    pub async fn discover(config: OidcConfig) -> Result<Self, Error> {
+        let http_client = reqwest::Client::new();
+
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer_url.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = http_client
+            .get(discovery_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if metadata.issuer != config.issuer_url {
+            return Err(Error::IssuerMismatch(metadata.issuer));
+        }
+
+        let jwks = fetch_jwks(&http_client, &metadata.jwks_uri).await?;
+
+        Ok(OidcClient {
+            config,
+            metadata,
+            jwks: Arc::new(RwLock::new(jwks)),
+            http_client,
+        })
+    }
+
+    pub fn start_login(&self) -> Result<ExternalLoginStart, Error> {
+        let pending = PendingExternalLogin {
+            state: random_value(),
+            nonce: random_value(),
+            code_verifier: random_value(),
+        };
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(&pending.code_verifier));
+
+        let authorization_url = Url::parse_with_params(
+            &self.metadata.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_url),
+                ("scope", SCOPES),
+                ("state", &pending.state),
+                ("nonce", &pending.nonce),
+                ("code_challenge", &code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+
+        Ok(ExternalLoginStart {
+            authorization_url: authorization_url.into(),
+            pending,
+        })
+    }
+
+    /// Returns None if the identity provider rejects the code or returns an invalid ID token
+    pub async fn finish_login(
+        &self,
+        pending: &PendingExternalLogin,
+        code: &str,
+    ) -> Result<Option<ExternalIdentity>, Error> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &pending.code_verifier),
+        ];
+        if let Some(client_secret) = &self.config.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+
+        let response = self
+            .http_client
+            .post(&self.metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+        let token_response: TokenResponse = response.json().await?;
+
+        let claims = match self.validate_id_token(&token_response.id_token).await? {
+            Some(claims) => claims,
+            None => return Ok(None),
+        };
+        if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Ok(None);
+        }
+
+        Ok(Some(ExternalIdentity {
+            issuer: claims.iss,
+            subject: claims.sub,
+            preferred_username: claims.preferred_username,
+        }))
+    }
+
+    async fn validate_id_token(&self, id_token: &str) -> Result<Option<IdTokenClaims>, Error> {
+        let header = match jsonwebtoken::decode_header(id_token) {
+            Ok(header) => header,
+            Err(_) => return Ok(None),
+        };
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Ok(None);
+        }
+
+        let jwk = match self.find_jwk(header.kid.as_deref())? {
+            Some(jwk) => jwk,
+            None => {
+                let jwks = fetch_jwks(&self.http_client, &self.metadata.jwks_uri).await?;
+                *self.jwks.write()? = jwks;
+                match self.find_jwk(header.kid.as_deref())? {
+                    Some(jwk) => jwk,
+                    None => return Ok(None),
+                }
+            }
+        };
+        let key = match DecodingKey::from_jwk(&jwk) {
+            Ok(key) => key,
+            Err(_) => return Ok(None),
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&self.metadata.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        match jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation) {
+            Ok(token) => Ok(Some(token.claims)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// A token without key id is accepted only if the provider has a single key
+    fn find_jwk(&self, kid: Option<&str>) -> Result<Option<Jwk>, Error> {
+        let jwks = self.jwks.read()?;
+        let res = match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+        Ok(res)
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.metadata.issuer
+    }
+}
+
+async fn fetch_jwks(http_client: &reqwest::Client, jwks_uri: &str) -> Result<JwkSet, Error> {
+    let jwks = http_client
+        .get(jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(jwks)
+}
+
+fn random_value() -> String {
+    let mut bytes = [0u8; RANDOM_VALUE_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
@@ -0,0 +1,234 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use pheidippides_messenger::audit_log::{AuditEvent, AuditEventKind, AuditEventQuery};
+use pheidippides_messenger::authorization::{
+    ApiScope, ApiToken, ApiTokenId, AuthService, ExternalIdentity, ExternalLoginStart,
+    PendingExternalLogin, TotpEnrollment, VerificationResult,
+};
+use pheidippides_messenger::UserId;
+
+mod client;
+
+pub use client::{OidcClient, OidcConfig};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    HttpError(#[from] reqwest::Error),
+    #[error(transparent)]
+    UrlParseError(#[from] url::ParseError),
+    #[error("Identity provider reported a different issuer: {0}")]
+    IssuerMismatch(String),
+    #[error("Key set lock poisoned")]
+    ThreadPoisonError,
+    #[error("Unsupported when logging in through an identity provider: {0}")]
+    Unsupported(&'static str),
+    #[error(transparent)]
+    InnerServiceError(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_: std::sync::PoisonError<T>) -> Self {
+        Error::ThreadPoisonError
+    }
+}
+
+fn inner_error(err: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::InnerServiceError(Box::new(err))
+}
+
+/// Logs users in through an OpenID Connect identity provider instead of passwords.
+/// API tokens, linked identities and the audit log are managed by the inner service
+#[derive(Clone)]
+pub struct OidcAuthService<S> {
+    inner: S,
+    client: OidcClient,
+}
+
+impl<S: AuthService> OidcAuthService<S> {
+    pub fn new(inner: S, client: OidcClient) -> Self {
+        OidcAuthService { inner, client }
+    }
+}
+
+impl<S: AuthService> AuthService for OidcAuthService<S> {
+    type Error = Error;
+
+    async fn verify_user(
+        &self,
+        _user_id: &UserId,
+        _password: String,
+        _client_addr: Option<IpAddr>,
+    ) -> Result<VerificationResult, Self::Error> {
+        Ok(VerificationResult::Failed)
+    }
+
+    async fn register_unknown_user_attempt(
+        &self,
+        _client_addr: Option<IpAddr>,
+    ) -> Result<VerificationResult, Self::Error> {
+        Ok(VerificationResult::Failed)
+    }
+
+    async fn create_user(&self, _user_id: &UserId, _password: String) -> Result<(), Self::Error> {
+        Err(Error::Unsupported("password signup"))
+    }
+
+    async fn create_password_reset_token(&self, _user_id: &UserId) -> Result<String, Self::Error> {
+        Err(Error::Unsupported("password reset"))
+    }
+
+    async fn reset_password(
+        &self,
+        _token: &str,
+        _new_password: String,
+    ) -> Result<Option<UserId>, Self::Error> {
+        Ok(None)
+    }
+
+    async fn second_factor_enabled(&self, _user_id: &UserId) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    async fn start_totp_enrollment(
+        &self,
+        _user_id: &UserId,
+        _account_name: &str,
+    ) -> Result<TotpEnrollment, Self::Error> {
+        Err(Error::Unsupported("two-factor authentication"))
+    }
+
+    async fn confirm_totp_enrollment(
+        &self,
+        _user_id: &UserId,
+        _code: &str,
+    ) -> Result<Option<Vec<String>>, Self::Error> {
+        Err(Error::Unsupported("two-factor authentication"))
+    }
+
+    async fn verify_second_factor(
+        &self,
+        _user_id: &UserId,
+        _code: &str,
+    ) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    async fn verify_login_second_factor(
+        &self,
+        _user_id: &UserId,
+        _code: &str,
+        _client_addr: Option<IpAddr>,
+    ) -> Result<VerificationResult, Self::Error> {
+        Ok(VerificationResult::Failed)
+    }
+
+    async fn disable_totp(&self, _user_id: &UserId) -> Result<(), Self::Error> {
+        Err(Error::Unsupported("two-factor authentication"))
+    }
+
+    async fn create_api_token(
+        &self,
+        user_id: &UserId,
+        name: &str,
+        scopes: &[ApiScope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(ApiToken, String), Self::Error> {
+        self.inner
+            .create_api_token(user_id, name, scopes, expires_at)
+            .await
+            .map_err(inner_error)
+    }
+
+    async fn fetch_api_tokens(&self, user_id: &UserId) -> Result<Vec<ApiToken>, Self::Error> {
+        self.inner
+            .fetch_api_tokens(user_id)
+            .await
+            .map_err(inner_error)
+    }
+
+    async fn revoke_api_token(
+        &self,
+        user_id: &UserId,
+        token_id: &ApiTokenId,
+    ) -> Result<bool, Self::Error> {
+        self.inner
+            .revoke_api_token(user_id, token_id)
+            .await
+            .map_err(inner_error)
+    }
+
+    async fn verify_api_token(&self, token: &str) -> Result<Option<ApiToken>, Self::Error> {
+        self.inner
+            .verify_api_token(token)
+            .await
+            .map_err(inner_error)
+    }
+
+    fn password_login_enabled(&self) -> bool {
+        false
+    }
+
+    fn external_login_enabled(&self) -> bool {
+        true
+    }
+
+    async fn start_external_login(&self) -> Result<Option<ExternalLoginStart>, Self::Error> {
+        Ok(Some(self.client.start_login()?))
+    }
+
+    async fn finish_external_login(
+        &self,
+        pending: &PendingExternalLogin,
+        code: &str,
+    ) -> Result<Option<ExternalIdentity>, Self::Error> {
+        self.client.finish_login(pending, code).await
+    }
+
+    async fn fetch_external_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<UserId>, Self::Error> {
+        self.inner
+            .fetch_external_identity_user(issuer, subject)
+            .await
+            .map_err(inner_error)
+    }
+
+    async fn link_external_identity(
+        &self,
+        identity: &ExternalIdentity,
+        user_id: &UserId,
+    ) -> Result<bool, Self::Error> {
+        self.inner
+            .link_external_identity(identity, user_id)
+            .await
+            .map_err(inner_error)
+    }
+
+    async fn record_audit_event(
+        &self,
+        user_id: Option<&UserId>,
+        kind: AuditEventKind,
+        client_addr: Option<IpAddr>,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .record_audit_event(user_id, kind, client_addr)
+            .await
+            .map_err(inner_error)
+    }
+
+    async fn fetch_audit_events(
+        &self,
+        query: &AuditEventQuery,
+    ) -> Result<Vec<AuditEvent>, Self::Error> {
+        self.inner
+            .fetch_audit_events(query)
+            .await
+            .map_err(inner_error)
+    }
+}
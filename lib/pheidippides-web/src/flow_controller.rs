@@ -1,4 +1,4 @@
-use std::ops::{ControlFlow, FromResidual, Try};
+use std::ops::{ControlFlow, FromResidual, Residual, Try};
 
 use http_server::response::Response;
 
@@ -49,6 +49,10 @@ impl<T> FromResidual for HttpResponseFlowController<T> {
 
 pub struct HttpResponseFlowControllerResidual(Response);
 
+impl<T> Residual<T> for HttpResponseFlowControllerResidual {
+    type TryType = HttpResponseFlowController<T>;
+}
+
 impl FromResidual<HttpResponseFlowControllerResidual> for Response {
     fn from_residual(residual: HttpResponseFlowControllerResidual) -> Self {
         residual.0
@@ -77,6 +81,13 @@ pub trait HttpResponseContextExtension: HttpResponseContext + Sized {
         }
     }
 
+    fn or_not_found(self) -> HttpResponseFlowController<Self::Output> {
+        match self.check() {
+            Some(value) => HttpResponseFlowController::Value(value),
+            None => HttpResponseFlowController::HttpResponse(Response::NotFound),
+        }
+    }
+
     // TODO add unauthorized redirect, or_empty
 }
 
@@ -94,11 +105,8 @@ impl<T, E> HttpResponseContext for Result<T, E> {
     type Output = T;
 
     fn check(self) -> Option<Self::Output> {
-        match self {
-            Ok(value) => Some(value),
-            // TODO handle error information here
-            Err(_) => None,
-        }
+        // TODO handle error information here
+        self.ok()
     }
 }
 
@@ -113,6 +121,7 @@ mod tests {
         assert!(control_flow_option_some_server_error().is_empty());
         assert!(control_flow_option_none_bad_request().is_bad_request());
         assert!(control_flow_option_none_server_error().is_internal_server_error());
+        assert!(control_flow_option_none_not_found().is_not_found());
     }
 
     fn control_flow_option_some_bad_request() -> Response {
@@ -126,12 +135,17 @@ mod tests {
     }
 
     fn control_flow_option_none_bad_request() -> Response {
-        let _ = None.or_bad_request()?;
+        let _ = None::<&str>.or_bad_request()?;
         Response::Empty
     }
 
     fn control_flow_option_none_server_error() -> Response {
-        let _ = None.or_server_error()?;
+        let _ = None::<&str>.or_server_error()?;
+        Response::Empty
+    }
+
+    fn control_flow_option_none_not_found() -> Response {
+        let _ = None::<&str>.or_not_found()?;
         Response::Empty
     }
 
@@ -1,22 +1,67 @@
 use crate::routing;
+use chrono::TimeDelta;
 use http_server::request::Request;
 use http_server::response::Response;
+use pheidippides_messenger::attachments::AttachmentLimits;
 use pheidippides_messenger::authorization::AuthService;
+use pheidippides_messenger::blob_storage::BlobStorage;
 use pheidippides_messenger::data_access::DataAccess;
+use pheidippides_messenger::mailer::Mailer;
 use pheidippides_messenger::messenger::Messenger;
+use pheidippides_messenger::UserId;
 use tokio::io::AsyncRead;
 
 #[derive(Clone)]
-pub struct RequestHandler<D: DataAccess, A> {
-    app: Messenger<D, A>,
+pub struct RequestHandler<D: DataAccess, A, M, B> {
+    app: Messenger<D, A, M, B>,
 }
 
-impl<D: DataAccess, A> RequestHandler<D, A> {
-    pub fn new(db_access: D, auth_storage: A) -> Self {
+impl<D: DataAccess, A, M, B> RequestHandler<D, A, M, B> {
+    pub fn new(db_access: D, auth_storage: A, mailer: M, blob_storage: B) -> Self {
         RequestHandler {
-            app: Messenger::new(db_access, auth_storage),
+            app: Messenger::new(db_access, auth_storage, mailer, blob_storage),
         }
     }
+
+    /// Admins can query the audit log of all users
+    pub fn with_admins(self, admins: Vec<UserId>) -> Self {
+        RequestHandler {
+            app: self.app.with_admins(admins),
+        }
+    }
+
+    /// Authors can edit their messages for this long after sending them
+    pub fn with_message_edit_window(self, message_edit_window: TimeDelta) -> Self {
+        RequestHandler {
+            app: self.app.with_message_edit_window(message_edit_window),
+        }
+    }
+
+    /// Uploads breaking these limits are rejected
+    pub fn with_attachment_limits(self, attachment_limits: AttachmentLimits) -> Self {
+        RequestHandler {
+            app: self.app.with_attachment_limits(attachment_limits),
+        }
+    }
+
+    /// Messages older than this are purged from all conversations
+    pub fn with_message_retention(self, message_retention: Option<TimeDelta>) -> Self {
+        RequestHandler {
+            app: self.app.with_message_retention(message_retention),
+        }
+    }
+}
+
+impl<D: DataAccess, A: AuthService, M: Mailer, B: BlobStorage> RequestHandler<D, A, M, B> {
+    /// Purges expired messages every `interval` until the returned task is aborted
+    pub fn spawn_purge_job(&self, interval: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        self.app.spawn_purge_job(interval)
+    }
+
+    /// Sends due scheduled messages every `interval` until the returned task is aborted
+    pub fn spawn_scheduler(&self, interval: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        self.app.spawn_scheduler(interval)
+    }
 }
 
 #[derive(Debug)]
@@ -38,8 +83,13 @@ impl std::fmt::Display for RequestHandlerError {
 
 impl std::error::Error for RequestHandlerError {}
 
-impl<D: DataAccess, A: AuthService, T: AsyncRead + Unpin + Sync + Send>
-    http_server::server::RequestHandler<Request<T>> for RequestHandler<D, A>
+impl<
+        D: DataAccess,
+        A: AuthService,
+        M: Mailer,
+        B: BlobStorage,
+        T: AsyncRead + Unpin + Sync + Send,
+    > http_server::server::RequestHandler<Request<T>> for RequestHandler<D, A, M, B>
 {
     type Error = RequestHandlerError;
 
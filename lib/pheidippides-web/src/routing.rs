@@ -7,14 +7,17 @@ mod tools;
 use std::collections::HashMap;
 
 use anyhow::Result;
-use pheidippides_messenger::authorization::AuthService;
+use chrono::{DateTime, Utc};
+use pheidippides_messenger::authorization::{ApiScope, AuthService};
 use tokio::io::AsyncRead;
 
 use http_server::request::Request;
 use http_server::response::Response;
 use http_server::{self};
 
+use pheidippides_messenger::blob_storage::BlobStorage;
 use pheidippides_messenger::data_access::DataAccess;
+use pheidippides_messenger::mailer::Mailer;
 use pheidippides_messenger::messenger::Messenger;
 use pheidippides_messenger::UserId;
 use pheidippides_utils::http::get_cookies_hashmap;
@@ -23,9 +26,9 @@ use pheidippides_utils::utils::CaseInsensitiveString;
 use crate::request_handler::RequestHandlerError;
 use crate::sessions;
 
-pub async fn route<T: AsyncRead + Unpin>(
+pub async fn route<T: AsyncRead + Unpin, M: Mailer, B: BlobStorage>(
     request: &mut Request<T>,
-    app: Messenger<impl DataAccess, impl AuthService>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
 ) -> Result<Response, RequestHandlerError> {
     let url = request.url();
     let (path, params_anchor) = match url.split_once('?') {
@@ -53,15 +56,165 @@ pub async fn route<T: AsyncRead + Unpin>(
     use http_server::method::Method::*;
     let response = match query {
         (Get, None, ..) => pages::main(),
-        (Get, Some("login"), None, ..) => pages::authorization().await,
-        (Get, Some("signup"), None, ..) => pages::signup().await,
+        (Get, Some("login"), None, ..) => pages::authorization(app).await,
+        (Get, Some("login"), Some("oidc"), None, ..) => {
+            actions::start_external_login(request, app).await
+        }
+        (Get, Some("login"), Some("oidc"), Some("callback"), None, ..) => {
+            actions::finish_external_login(request, app, params).await
+        }
+        (Get, Some("signup"), None, ..) => pages::signup(app).await,
+        (Get, Some("reset_password"), None, ..) => pages::reset_password().await,
+        (Get, Some("reset_password"), Some("confirm"), None, ..) => {
+            pages::reset_password_confirm(params).await
+        }
+        (Post, Some("reset_password"), None, ..) => {
+            actions::request_password_reset(request, app).await
+        }
+        (Post, Some("reset_password"), Some("confirm"), None, ..) => {
+            actions::reset_password(request, app).await
+        }
+        (Post, Some("account"), Some("email"), None, ..) => {
+            actions::update_email(request, app).await
+        }
+        (Get, Some("account"), Some("profile"), None, ..) => {
+            pages::account_profile(request, app).await
+        }
+        (Post, Some("account"), Some("profile"), None, ..) => {
+            actions::update_profile(request, app).await
+        }
+        (Post, Some("account"), Some("avatar"), None, ..) => {
+            actions::set_avatar(request, app).await
+        }
+        (Post, Some("account"), Some("username"), None, ..) => {
+            actions::change_username(request, app).await
+        }
+        (Get, Some("account"), Some("export"), None, ..) => {
+            actions::export_account(request, app, params).await
+        }
+        (Post, Some("account"), Some("delete"), None, ..) => {
+            actions::delete_account(request, app).await
+        }
         (Get, Some("chat"), chat_id, None, ..) => pages::chat(request, app, chat_id).await,
         (Post, Some("signup"), None, ..) => actions::signup(request, app).await,
-        (Get, Some("logout"), None, ..) => actions::logout(request),
+        (Get, Some("logout"), None, ..) => actions::logout(request, app).await,
         (Post, Some("authorize"), None, ..) => actions::authorize(request, app).await,
+        (Post, Some("authorize"), Some("totp"), None, ..) => {
+            actions::authorize_totp(request, app).await
+        }
+        (Get, Some("account"), Some("totp"), None, ..) => pages::account_totp(request, app).await,
+        (Get, Some("account"), Some("audit_log"), None, ..) => {
+            pages::account_audit_log(request, app, params).await
+        }
+        (Post, Some("account"), Some("totp"), Some("enroll"), None) => {
+            actions::enroll_totp(request, app).await
+        }
+        (Post, Some("account"), Some("totp"), Some("confirm"), None) => {
+            actions::confirm_totp(request, app).await
+        }
+        (Post, Some("account"), Some("totp"), Some("disable"), None) => {
+            actions::disable_totp(request, app).await
+        }
+        (Post, Some("api_tokens"), None, ..) => actions::create_api_token(request, app).await,
+        (Delete, Some("api_tokens"), Some(token_id), None, ..) => {
+            actions::revoke_api_token(request, app, token_id).await
+        }
         (Post, Some("message"), Some(receiver), None, ..) => {
             actions::send_message(request, app, receiver).await
         }
+        (Post, Some("message"), Some(message_id), Some("edit"), None) => {
+            actions::edit_message(request, app, message_id).await
+        }
+        (Post, Some("message"), Some(message_id), Some("delete"), None) => {
+            actions::delete_message(request, app, message_id).await
+        }
+        (Post, Some("message"), Some(message_id), Some("forward"), None) => {
+            actions::forward_message(request, app, message_id).await
+        }
+        (Post, Some("message"), Some(message_id), Some("hide"), None) => {
+            actions::hide_message(request, app, message_id).await
+        }
+        (Post, Some("message"), Some(message_id), Some("read"), None) => {
+            actions::mark_read(request, app, message_id).await
+        }
+        (Post, Some("message"), Some(message_id), Some("react"), None) => {
+            actions::react_to_message(request, app, message_id, true).await
+        }
+        (Post, Some("message"), Some(message_id), Some("unreact"), None) => {
+            actions::react_to_message(request, app, message_id, false).await
+        }
+        (Post, Some("message"), Some(message_id), Some("pin"), None) => {
+            actions::pin_message(request, app, message_id, true).await
+        }
+        (Post, Some("message"), Some(message_id), Some("unpin"), None) => {
+            actions::pin_message(request, app, message_id, false).await
+        }
+        (Post, Some("message"), Some(message_id), Some("star"), None) => {
+            actions::star_message(request, app, message_id, true).await
+        }
+        (Post, Some("message"), Some(message_id), Some("unstar"), None) => {
+            actions::star_message(request, app, message_id, false).await
+        }
+        (Post, Some("attachments"), None, ..) => {
+            // Reading the body needs the request mutably, while params borrow its url
+            let params = params.to_owned();
+            actions::upload_attachment(request, app, &params).await
+        }
+        (Get, Some("attachments"), Some(attachment_id), None, ..) => {
+            actions::attachment_content(request, app, attachment_id, false).await
+        }
+        (Get, Some("attachments"), Some(attachment_id), Some("thumbnail"), None) => {
+            actions::attachment_content(request, app, attachment_id, true).await
+        }
+        (Get, Some("avatars"), Some(user_id), None, ..) => {
+            actions::avatar(request, app, user_id).await
+        }
+        (Post, Some("users"), Some(user_id), Some("block"), None) => {
+            actions::block_user(request, app, user_id, true).await
+        }
+        (Post, Some("users"), Some(user_id), Some("unblock"), None) => {
+            actions::block_user(request, app, user_id, false).await
+        }
+        (Post, Some("schedule"), Some(chat_id), None, ..) => {
+            actions::schedule_message(request, app, chat_id).await
+        }
+        (Post, Some("scheduled"), Some(scheduled_message_id), Some("cancel"), None) => {
+            actions::cancel_scheduled_message(request, app, scheduled_message_id).await
+        }
+        (Post, Some("notifications"), Some("read"), None, ..) => {
+            actions::mark_notifications_read(request, app, None).await
+        }
+        (Post, Some("notifications"), Some(notification_id), Some("read"), None) => {
+            actions::mark_notifications_read(request, app, Some(notification_id)).await
+        }
+        (Post, Some("message_ttl"), Some(chat_id), None, ..) => {
+            actions::set_message_ttl(request, app, chat_id).await
+        }
+        (Post, Some("mute"), Some(chat_id), None, ..) => {
+            actions::mute_conversation(request, app, chat_id, true).await
+        }
+        (Post, Some("unmute"), Some(chat_id), None, ..) => {
+            actions::mute_conversation(request, app, chat_id, false).await
+        }
+        (Post, Some("typing"), Some(chat_id), None, ..) => {
+            actions::notify_typing(request, app, chat_id).await
+        }
+        (Post, Some("chats"), None, ..) => actions::create_chat(request, app).await,
+        (Post, Some("chats"), Some(chat_id), Some("title"), None) => {
+            actions::rename_chat(request, app, chat_id).await
+        }
+        (Post, Some("chats"), Some(chat_id), Some("members"), None) => {
+            actions::add_chat_member(request, app, chat_id).await
+        }
+        (Delete, Some("chats"), Some(chat_id), Some("members"), Some(member)) => {
+            actions::remove_chat_member(request, app, chat_id, member).await
+        }
+        (Post, Some("chats"), Some(chat_id), Some("roles"), Some(member)) => {
+            actions::set_chat_member_role(request, app, chat_id, member).await
+        }
+        (Post, Some("chats"), Some(chat_id), Some("leave"), None) => {
+            actions::leave_chat(request, app, chat_id).await
+        }
         (Get, Some("subscribe"), Some("new_messages"), None, ..) => {
             actions::subscribe_new_messages(request, app, params).await
         }
@@ -69,14 +222,56 @@ pub async fn route<T: AsyncRead + Unpin>(
             html::chats_html_response(request, app).await
         }
         (Get, Some("html"), Some("chatsearch"), None, ..) => {
-            html::chatsearch_html(app, params).await
+            html::chatsearch_html(request, app, params).await
         }
         (Get, Some("html"), Some("chat"), Some(chat_id), ..) => {
-            html::chat_html_response(app, chat_id).await
+            html::chat_html_response(request, app, chat_id).await
         }
         (Get, Some("json"), Some("messages"), Some(chat_id), None, ..) => {
             json::messages_json(request, app, chat_id, params).await
         }
+        (Get, Some("json"), Some("messages"), Some(message_id), Some("revisions")) => {
+            json::message_revisions_json(request, app, message_id).await
+        }
+        (Get, Some("json"), Some("message_ttl"), Some(chat_id), None) => {
+            json::message_ttl_json(request, app, chat_id).await
+        }
+        (Get, Some("json"), Some("pins"), Some(chat_id), None) => {
+            json::pinned_messages_json(request, app, chat_id).await
+        }
+        (Get, Some("json"), Some("notifications"), None, ..) => {
+            json::notifications_json(request, app, params).await
+        }
+        (Get, Some("json"), Some("scheduled"), None, ..) => {
+            json::scheduled_messages_json(request, app).await
+        }
+        (Get, Some("json"), Some("starred"), None, ..) => {
+            json::starred_messages_json(request, app, params).await
+        }
+        (Get, Some("json"), Some("search"), None, ..) => {
+            json::search_json(request, app, params).await
+        }
+        (Get, Some("json"), Some("presence"), Some(user_id), None) => {
+            json::presence_json(request, app, user_id).await
+        }
+        (Get, Some("json"), Some("chats"), Some(chat_id), None) => {
+            json::chat_json(request, app, chat_id).await
+        }
+        (Get, Some("json"), Some("profile"), Some(user_id), None) => {
+            json::profile_json(request, app, user_id).await
+        }
+        (Get, Some("json"), Some("blocked_users"), None, ..) => {
+            json::blocked_users_json(request, app).await
+        }
+        (Get, Some("json"), Some("api_tokens"), None, ..) => {
+            json::api_tokens_json(request, app).await
+        }
+        (Get, Some("json"), Some("audit_log"), None, ..) => {
+            json::audit_log_json(request, app, params).await
+        }
+        (Get, Some("json"), Some("admin"), Some("audit_log"), None) => {
+            json::admin_audit_log_json(request, app, params).await
+        }
         (Get, Some("tools"), Some("event_source"), None, ..) => tools::event_source(request),
         (Get, Some("favicon.ico"), None, ..) => Response::Empty,
         _ => Response::BadRequest,
@@ -93,6 +288,14 @@ fn failed_login_response() -> Result<Response> {
     })
 }
 
+fn locked_login_response(until: DateTime<Utc>) -> Result<Response> {
+    let content = html::login_locked_page(until)?;
+    Ok(Response::Html {
+        content,
+        headers: Vec::new(),
+    })
+}
+
 fn unauthorized_redirect() -> Response {
     Response::Redirect {
         location: "/login".into(),
@@ -100,7 +303,19 @@ fn unauthorized_redirect() -> Response {
     }
 }
 
-fn get_authorization(headers: &HashMap<CaseInsensitiveString, String>) -> Result<Option<UserId>> {
+/// Sessions are authorized for everything. API tokens (`Authorization: Bearer <token>`)
+/// are accepted only if `scope` is specified and the token is allowed it
+async fn get_authorization<M, B>(
+    headers: &HashMap<CaseInsensitiveString, String>,
+    app: &Messenger<impl DataAccess, impl AuthService, M, B>,
+    scope: Option<ApiScope>,
+) -> Result<Option<UserId>> {
+    if let Some(scope) = scope {
+        if let Some(token) = get_bearer_token(headers) {
+            return app.verify_api_token(token, scope).await;
+        }
+    }
+
     let cookies = match get_cookies_hashmap(headers) {
         Ok(cookies) => cookies,
         Err(_) => return Ok(None),
@@ -113,3 +328,11 @@ fn get_authorization(headers: &HashMap<CaseInsensitiveString, String>) -> Result
     let session_info = sessions::get_session_info(session_id)?;
     Ok(session_info.map(|v| v.user_id))
 }
+
+fn get_bearer_token(headers: &HashMap<CaseInsensitiveString, String>) -> Option<&str> {
+    let authorization = headers.get(&CaseInsensitiveString::from("Authorization"))?;
+    let (auth_scheme, token) = authorization.split_once(' ')?;
+    auth_scheme
+        .eq_ignore_ascii_case("Bearer")
+        .then_some(token.trim())
+}
@@ -1,22 +1,47 @@
+use chrono::{TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncRead;
 
+use std::io::Write;
+
 use crate::flow_controller::HttpResponseContextExtension;
-use crate::routing::json::MessageJson;
+use crate::routing::html;
+use crate::routing::json::{
+    ApiTokenJson, AttachmentJson, MessageJson, NotificationJson, PresenceJson, ReadReceiptJson,
+    UserDataExportJson,
+};
 use crate::{routing, sessions};
 use http_server::event_source::EventSourceEvent;
 use http_server::request::Request;
 use http_server::response::Response;
-use pheidippides_messenger::authorization::AuthService;
+use pheidippides_messenger::attachments::AttachmentId;
+use pheidippides_messenger::audit_log::AuditEventKind;
+use pheidippides_messenger::authorization::{ApiScope, ApiTokenId, AuthService};
+use pheidippides_messenger::blob_storage::BlobStorage;
+use pheidippides_messenger::chats::{ChatRole, MAX_CHAT_TITLE_LENGTH};
 use pheidippides_messenger::data_access::DataAccess;
-use pheidippides_messenger::messenger::Messenger;
-use pheidippides_messenger::{MessageId, UserId};
+use pheidippides_messenger::events::Event;
+use pheidippides_messenger::mailer::Mailer;
+use pheidippides_messenger::messenger::{LoginResult, Messenger};
+use pheidippides_messenger::notifications::NotificationId;
+use pheidippides_messenger::profiles;
+use pheidippides_messenger::reactions;
+use pheidippides_messenger::retention;
+use pheidippides_messenger::scheduled::{self, ScheduledMessageId};
+use pheidippides_messenger::{ChatId, ConversationId, MessageId, OutgoingMessage, UserId};
 use pheidippides_utils::async_utils;
 use pheidippides_utils::http::{get_cookies_hashmap, header_set_cookie};
 use pheidippides_utils::serde::form_data;
-use pheidippides_utils::utils::CaseInsensitiveString;
+use pheidippides_utils::utils::{log_internal_error, CaseInsensitiveString};
+use uuid::Uuid;
+
+const MAX_API_TOKEN_NAME_LENGTH: usize = 100;
+const MAX_API_TOKEN_LIFETIME_DAYS: i64 = 365;
 
-pub fn logout<T: AsyncRead + Unpin>(request: &Request<T>) -> Response {
+pub async fn logout<T: AsyncRead + Unpin, M, B>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
     let headers = request.headers();
     let cookies = get_cookies_hashmap(headers).or_bad_request()?;
 
@@ -25,14 +50,26 @@ pub fn logout<T: AsyncRead + Unpin>(request: &Request<T>) -> Response {
         None => return routing::unauthorized_redirect(),
     };
 
+    let session_info = sessions::get_session_info(session_id).or_server_error()?;
     sessions::remove_session_info(session_id).or_server_error()?;
 
+    if let Some(session_info) = session_info {
+        let client_addr = request.remote_addr().map(|addr| addr.ip());
+        app.record_audit_event(
+            Some(&session_info.user_id),
+            AuditEventKind::Logout,
+            client_addr,
+        )
+        .await
+        .or_server_error()?;
+    }
+
     routing::unauthorized_redirect()
 }
 
-pub async fn authorize<T: AsyncRead + Unpin>(
+pub async fn authorize<T: AsyncRead + Unpin, M, B>(
     request: &mut Request<T>,
-    app: Messenger<impl DataAccess, impl AuthService>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
 ) -> Response {
     let content = request.content().await.or_server_error()?;
 
@@ -45,37 +82,288 @@ pub async fn authorize<T: AsyncRead + Unpin>(
     let authorization_params: AuthorizationParams =
         form_data::from_str(&content).or_bad_request()?;
 
-    let user_verification = app
-        .verify_user(&authorization_params.login, authorization_params.password)
+    let client_addr = request.remote_addr().map(|addr| addr.ip());
+    let login_result = app
+        .log_in(
+            &authorization_params.login,
+            authorization_params.password,
+            client_addr,
+        )
         .await
         .or_server_error()?;
 
-    match user_verification {
-        Some(user_id) => {
-            let session_id = sessions::generate_session_id();
+    let user_id = match login_result {
+        LoginResult::Success(user_id) => user_id,
+        LoginResult::Failed => return routing::failed_login_response().or_server_error()?,
+        LoginResult::Locked { until } => {
+            return routing::locked_login_response(until).or_server_error()?
+        }
+    };
 
-            sessions::update_session_info(session_id.clone(), sessions::SessionInfo { user_id })
-                .or_server_error()?;
+    if app
+        .second_factor_enabled(&user_id)
+        .await
+        .or_server_error()?
+    {
+        let pending_login = sessions::create_pending_login(user_id).or_server_error()?;
+        let content = html::login_totp_page(&pending_login, false).or_server_error()?;
+        return Response::Html {
+            content,
+            headers: Vec::new(),
+        };
+    }
 
-            let location = "/chat".into();
-            let headers = vec![header_set_cookie(sessions::SESSION_ID_COOKIE, &session_id)];
+    start_session_response(user_id)
+}
+
+pub async fn authorize_totp<T: AsyncRead + Unpin, M, B>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    let content = request.content().await.or_server_error()?;
+
+    #[derive(Deserialize)]
+    struct AuthorizeTotpParams {
+        pending_login: String,
+        code: String,
+    }
+
+    let params: AuthorizeTotpParams = form_data::from_str(&content).or_bad_request()?;
+
+    let user_id =
+        match sessions::register_pending_login_attempt(&params.pending_login).or_server_error()? {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
 
-            Response::Redirect { location, headers }
+    let client_addr = request.remote_addr().map(|addr| addr.ip());
+    match app
+        .log_in_second_factor(&user_id, &params.code, client_addr)
+        .await
+        .or_server_error()?
+    {
+        LoginResult::Success(_) => {}
+        LoginResult::Failed => {
+            let content = html::login_totp_page(&params.pending_login, true).or_server_error()?;
+            return Response::Html {
+                content,
+                headers: Vec::new(),
+            };
+        }
+        LoginResult::Locked { until } => {
+            sessions::remove_pending_login(&params.pending_login).or_server_error()?;
+            return routing::locked_login_response(until).or_server_error()?;
         }
+    }
+
+    sessions::remove_pending_login(&params.pending_login).or_server_error()?;
+    start_session_response(user_id)
+}
+
+fn start_session_response(user_id: UserId) -> Response {
+    let session_id = sessions::generate_session_id();
+
+    sessions::update_session_info(session_id.clone(), sessions::SessionInfo::new(user_id))
+        .or_server_error()?;
+
+    let location = "/chat".into();
+    let headers = vec![header_set_cookie(sessions::SESSION_ID_COOKIE, &session_id)];
+
+    Response::Redirect { location, headers }
+}
+
+/// Redirects to the identity provider. A logged in user gets the identity linked to their account
+pub async fn start_external_login<T: AsyncRead + Unpin, M, B>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    let login_start = app
+        .start_external_login()
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    let linking_user = routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?;
+
+    let state = login_start.pending.state.clone();
+    sessions::create_pending_external_login(login_start.pending, linking_user).or_server_error()?;
+
+    Response::Redirect {
+        location: login_start.authorization_url,
+        headers: vec![header_set_cookie(
+            sessions::EXTERNAL_LOGIN_STATE_COOKIE,
+            &state,
+        )],
+    }
+}
+
+pub async fn finish_external_login<T: AsyncRead + Unpin, M, B>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct CallbackParams {
+        state: String,
+        /// Missing if the identity provider reports an error instead
+        code: Option<String>,
+    }
+
+    let callback_params: CallbackParams = form_data::from_str(params).or_bad_request()?;
+
+    // The state has to come back to the same browser that started the login
+    let cookies = get_cookies_hashmap(request.headers()).or_bad_request()?;
+    if cookies.get(sessions::EXTERNAL_LOGIN_STATE_COOKIE) != Some(&callback_params.state) {
+        return Response::BadRequest;
+    }
+
+    let (pending, linking_user) =
+        match sessions::take_pending_external_login(&callback_params.state).or_server_error()? {
+            Some(res) => res,
+            None => return routing::failed_login_response().or_server_error()?,
+        };
+
+    let code = match callback_params.code {
+        Some(code) => code,
+        None => return routing::failed_login_response().or_server_error()?,
+    };
+
+    let client_addr = request.remote_addr().map(|addr| addr.ip());
+    match app
+        .finish_external_login(&pending, &code, linking_user, client_addr)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => start_session_response(user_id),
         None => routing::failed_login_response().or_server_error()?,
     }
 }
 
-pub async fn signup<T: AsyncRead + Unpin>(
+pub async fn enroll_totp<T: AsyncRead + Unpin, M, B>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    if !app.password_login_enabled() {
+        return Response::BadRequest;
+    }
+
+    if app
+        .second_factor_enabled(&user_id)
+        .await
+        .or_server_error()?
+    {
+        return Response::Redirect {
+            location: "/account/totp".into(),
+            headers: Vec::new(),
+        };
+    }
+
+    let enrollment = app
+        .start_totp_enrollment(&user_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    let content = html::totp_enroll_page(Some(enrollment), false).or_server_error()?;
+    Response::Html {
+        content,
+        headers: Vec::new(),
+    }
+}
+
+#[derive(Deserialize)]
+struct TotpCodeParams {
+    code: String,
+}
+
+pub async fn confirm_totp<T: AsyncRead + Unpin, M, B>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let content = request.content().await.or_server_error()?;
+    let params: TotpCodeParams = form_data::from_str(&content).or_bad_request()?;
+
+    let content = match app
+        .confirm_totp_enrollment(&user_id, &params.code)
+        .await
+        .or_server_error()?
+    {
+        Some(recovery_codes) => html::totp_recovery_codes_page(recovery_codes).or_server_error()?,
+        None => html::totp_enroll_page(None, true).or_server_error()?,
+    };
+
+    Response::Html {
+        content,
+        headers: Vec::new(),
+    }
+}
+
+pub async fn disable_totp<T: AsyncRead + Unpin, M, B>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let content = request.content().await.or_server_error()?;
+    let params: TotpCodeParams = form_data::from_str(&content).or_bad_request()?;
+
+    if app
+        .disable_totp(&user_id, &params.code)
+        .await
+        .or_server_error()?
+    {
+        Response::Redirect {
+            location: "/account/totp".into(),
+            headers: Vec::new(),
+        }
+    } else {
+        let content = html::account_totp_page(true, true).or_server_error()?;
+        Response::Html {
+            content,
+            headers: Vec::new(),
+        }
+    }
+}
+
+pub async fn signup<T: AsyncRead + Unpin, M, B>(
     request: &mut Request<T>,
-    app: Messenger<impl DataAccess, impl AuthService>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
 ) -> Response {
+    if !app.password_login_enabled() {
+        return Response::BadRequest;
+    }
+
     let content = request.content().await.or_server_error()?;
 
     #[derive(Deserialize)]
     struct SignupParams {
         login: String,
         password: String,
+        email: Option<String>,
     }
 
     #[derive(Serialize)]
@@ -87,23 +375,42 @@ pub async fn signup<T: AsyncRead + Unpin>(
     #[derive(Serialize)]
     enum SignupError {
         UsernameTaken,
+        EmailIncorrect,
     }
 
     let signup_params: SignupParams = serde_json::from_str(&content).or_bad_request()?;
 
+    let email = signup_params.email.as_deref().map(str::trim);
+    if email.is_some_and(|email| !is_email_valid(email)) {
+        let signup_response = SignupResponse {
+            success: false,
+            errors: vec![SignupError::EmailIncorrect],
+        };
+
+        return Response::Json {
+            content: serde_json::json!(signup_response).to_string(),
+            headers: vec![],
+        };
+    }
+
+    let client_addr = request.remote_addr().map(|addr| addr.ip());
     match app
-        .create_user(&signup_params.login, signup_params.password)
+        .sign_up(&signup_params.login, signup_params.password, client_addr)
         .await
         .or_server_error()?
     {
         Some(user_id) => {
+            app.update_user_email(&user_id, email)
+                .await
+                .or_server_error()?;
+
             let signup_response = serde_json::json!(SignupResponse {
                 success: true,
                 errors: vec![]
             });
             let session_id = sessions::generate_session_id();
             let headers = vec![header_set_cookie(sessions::SESSION_ID_COOKIE, &session_id)];
-            sessions::update_session_info(session_id, sessions::SessionInfo { user_id })
+            sessions::update_session_info(session_id, sessions::SessionInfo::new(user_id))
                 .or_server_error()?;
 
             Response::Json {
@@ -125,85 +432,1857 @@ pub async fn signup<T: AsyncRead + Unpin>(
     }
 }
 
-pub async fn send_message<D: DataAccess, A, T: AsyncRead + Unpin>(
+pub async fn update_email<A: AuthService, M, B, T: AsyncRead + Unpin>(
     request: &mut Request<T>,
-    app: Messenger<D, A>,
-    receiver: &str,
+    app: Messenger<impl DataAccess, A, M, B>,
 ) -> Response {
     #[derive(Deserialize)]
-    struct SendMessageParams {
-        message: String,
+    struct UpdateEmailParams {
+        email: Option<String>,
     }
 
-    let receiver: UserId = receiver.parse().or_bad_request()?;
+    #[derive(Serialize)]
+    struct UpdateEmailResponse {
+        success: bool,
+    }
 
-    let headers = request.headers();
-    let authorization = routing::get_authorization(headers).or_server_error()?;
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
 
-    let user_id = match authorization {
+    let content = request.content().await.or_server_error()?;
+    let params: UpdateEmailParams = serde_json::from_str(&content).or_bad_request()?;
+
+    let email = params.email.as_deref().map(str::trim);
+    let success = email.is_none_or(is_email_valid);
+    if success {
+        app.update_user_email(&user_id, email)
+            .await
+            .or_server_error()?;
+    }
+
+    Response::Json {
+        content: serde_json::json!(UpdateEmailResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// An empty display name falls back to the username.
+/// Responds with `{"success": false}` if a field is too long
+pub async fn update_profile<A: AuthService, M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, A, M, B>,
+) -> Response {
+    #[derive(Deserialize)]
+    struct UpdateProfileParams {
+        display_name: Option<String>,
+        #[serde(default)]
+        bio: String,
+    }
+
+    #[derive(Serialize)]
+    struct UpdateProfileResponse {
+        success: bool,
+    }
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
         Some(user_id) => user_id,
         None => return routing::unauthorized_redirect(),
     };
 
-    let content = &request.content().await.or_server_error()?;
-    let params: SendMessageParams = serde_json::from_str(content).or_bad_request()?;
+    let content = request.content().await.or_server_error()?;
+    let params: UpdateProfileParams = serde_json::from_str(&content).or_bad_request()?;
 
-    app.send_message(params.message, user_id, receiver)
+    let success = app
+        .update_profile(&user_id, params.display_name.as_deref(), &params.bio)
         .await
         .or_server_error()?;
 
-    Response::Html {
-        content: "ok.".to_owned(),
-        headers: Vec::new(),
+    Response::Json {
+        content: serde_json::json!(UpdateProfileResponse { success }).to_string(),
+        headers: vec![],
     }
 }
 
-pub async fn subscribe_new_messages<A, T: AsyncRead + Unpin>(
-    request: &Request<T>,
-    app: Messenger<impl DataAccess, A>,
-    params: &str,
+/// Sets an uploaded image as the avatar, `{"attachment_id": null}` removes the avatar
+pub async fn set_avatar<A: AuthService, M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, A, M, B>,
 ) -> Response {
     #[derive(Deserialize)]
-    struct SubscribeNewMessagesParams {
-        last_message_id: Option<String>,
+    struct SetAvatarParams {
+        attachment_id: Option<String>,
     }
 
-    let user_id = routing::get_authorization(request.headers())
-        .or_server_error()?
-        .or_bad_request()?;
-
-    let subscribe_new_messages_params: SubscribeNewMessagesParams =
-        form_data::from_str(params).or_bad_request()?;
+    #[derive(Serialize)]
+    struct SetAvatarResponse {
+        success: bool,
+    }
 
-    let last_message_id_params = match subscribe_new_messages_params.last_message_id {
-        Some(s) => Some(s.parse().or_bad_request()?),
-        None => None,
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
     };
 
-    let last_event_id_header = request
-        .headers()
-        .get(&CaseInsensitiveString::from("Last-Event-ID"));
-    let last_message_id_header: Option<MessageId> = match last_event_id_header {
-        Some(header_value) => Some(header_value.parse().or_bad_request()?),
+    let content = request.content().await.or_server_error()?;
+    let params: SetAvatarParams = serde_json::from_str(&content).or_bad_request()?;
+    let attachment_id: Option<AttachmentId> = match params.attachment_id {
+        Some(attachment_id) => Some(attachment_id.parse().or_bad_request()?),
         None => None,
     };
 
-    let starting_point = last_message_id_header.or(last_message_id_params);
-
-    let subscription = app
-        .subscribe_to_new_messages(user_id, starting_point)
+    let success = app
+        .set_avatar(&user_id, attachment_id.as_ref())
         .await
         .or_server_error()?;
 
-    let stream = async_utils::pipe_unbounded_channel(subscription, |message| {
-        let id = message.id.to_string();
-        let data = serde_json::json!(MessageJson::from(message)).to_string();
-        let event = None;
-        Some(EventSourceEvent { data, id, event })
-    });
+    Response::Json {
+        content: serde_json::json!(SetAvatarResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
 
-    Response::EventSource {
-        retry: None,
-        stream,
+pub async fn change_username<M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    #[derive(Deserialize)]
+    struct ChangeUsernameParams {
+        username: String,
+    }
+
+    #[derive(Serialize)]
+    struct ChangeUsernameResponse {
+        success: bool,
+        errors: Vec<ChangeUsernameError>,
+    }
+
+    #[derive(Serialize)]
+    enum ChangeUsernameError {
+        UsernameTaken,
+        UsernameIncorrect,
+    }
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let content = request.content().await.or_server_error()?;
+    let params: ChangeUsernameParams = serde_json::from_str(&content).or_bad_request()?;
+    let username = params.username.trim();
+
+    let response = if !profiles::is_valid_username(username) {
+        ChangeUsernameResponse {
+            success: false,
+            errors: vec![ChangeUsernameError::UsernameIncorrect],
+        }
+    } else {
+        let client_addr = request.remote_addr().map(|addr| addr.ip());
+        match app
+            .change_username(&user_id, username, client_addr)
+            .await
+            .or_server_error()?
+        {
+            true => ChangeUsernameResponse {
+                success: true,
+                errors: vec![],
+            },
+            false => ChangeUsernameResponse {
+                success: false,
+                errors: vec![ChangeUsernameError::UsernameTaken],
+            },
+        }
+    };
+
+    Response::Json {
+        content: serde_json::json!(response).to_string(),
+        headers: vec![],
+    }
+}
+
+pub async fn request_password_reset<T: AsyncRead + Unpin, B: BlobStorage>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, impl Mailer, B>,
+) -> Response {
+    #[derive(Deserialize)]
+    struct RequestPasswordResetParams {
+        login: String,
+    }
+
+    let content = request.content().await.or_server_error()?;
+    let params: RequestPasswordResetParams = form_data::from_str(&content).or_bad_request()?;
+
+    // The response is the same whether the mail was sent or not so that it can't be used to probe accounts.
+    // The request doesn't wait for the lookup and the mail either, so that its timing reveals nothing
+    tokio::spawn(async move {
+        if let Err(e) = app.request_password_reset(&params.login).await {
+            log_internal_error(e);
+        }
+    });
+
+    let content = html::reset_password_sent_page().or_server_error()?;
+    Response::Html {
+        content,
+        headers: Vec::new(),
+    }
+}
+
+pub async fn reset_password<T: AsyncRead + Unpin, M, B>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    #[derive(Deserialize)]
+    struct ResetPasswordParams {
+        token: String,
+        password: String,
+    }
+
+    let content = request.content().await.or_server_error()?;
+    let params: ResetPasswordParams = form_data::from_str(&content).or_bad_request()?;
+
+    let token = params.token.trim();
+    let client_addr = request.remote_addr().map(|addr| addr.ip());
+    match app
+        .reset_password(token, params.password, client_addr)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => {
+            sessions::remove_user_sessions(&user_id).or_server_error()?;
+            app.record_audit_event(
+                Some(&user_id),
+                AuditEventKind::SessionRevocation,
+                client_addr,
+            )
+            .await
+            .or_server_error()?;
+            routing::unauthorized_redirect()
+        }
+        None => {
+            let content = html::reset_password_confirm_page(token, true).or_server_error()?;
+            Response::Html {
+                content,
+                headers: Vec::new(),
+            }
+        }
+    }
+}
+
+pub async fn send_message<D: DataAccess, A: AuthService, M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<D, A, M, B>,
+    receiver: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct SendMessageParams {
+        message: String,
+        #[serde(default)]
+        attachments: Vec<String>,
+        reply_to: Option<String>,
+    }
+
+    let receiver: Uuid = receiver.parse().or_bad_request()?;
+
+    let headers = request.headers();
+    let authorization = routing::get_authorization(headers, &app, Some(ApiScope::MessagesWrite))
+        .await
+        .or_server_error()?;
+
+    let user_id = match authorization {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let receiver = app
+        .resolve_conversation(&user_id, &receiver)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    if let ConversationId::Direct(receiver) = &receiver {
+        let deleted = app
+            .fetch_profile(receiver)
+            .await
+            .or_server_error()?
+            .is_some_and(|profile| profile.deleted);
+        if deleted || app.is_blocked(&user_id, receiver).await.or_server_error()? {
+            return Response::BadRequest;
+        }
+    }
+
+    let content = &request.content().await.or_server_error()?;
+    let params: SendMessageParams = serde_json::from_str(content).or_bad_request()?;
+    let attachments: Vec<AttachmentId> = params
+        .attachments
+        .iter()
+        .map(|attachment_id| attachment_id.parse())
+        .collect::<Result<_, _>>()
+        .or_bad_request()?;
+    let reply_to: Option<MessageId> = match params.reply_to {
+        Some(reply_to) => Some(reply_to.parse().or_bad_request()?),
+        None => None,
+    };
+
+    let outgoing = OutgoingMessage {
+        text: params.message,
+        attachments,
+        reply_to,
+    };
+    app.send_outgoing_message(user_id, receiver, outgoing)
+        .await
+        .or_server_error()?;
+
+    Response::Html {
+        content: "ok.".to_owned(),
+        headers: Vec::new(),
+    }
+}
+
+/// Queues the message to be sent at `send_at`, which must be in the future
+pub async fn schedule_message<D: DataAccess, A: AuthService, M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<D, A, M, B>,
+    chat_id: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct ScheduleMessageParams {
+        message: String,
+        #[serde(default)]
+        attachments: Vec<String>,
+        reply_to: Option<String>,
+        send_at: String,
+    }
+
+    #[derive(Serialize)]
+    struct ScheduleMessageResponse {
+        success: bool,
+        id: String,
+    }
+
+    let chat_id: Uuid = chat_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let conversation = app
+        .resolve_conversation(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    let content = request.content().await.or_server_error()?;
+    let params: ScheduleMessageParams = serde_json::from_str(&content).or_bad_request()?;
+    let send_at: chrono::DateTime<Utc> = chrono::DateTime::parse_from_rfc3339(&params.send_at)
+        .or_bad_request()?
+        .into();
+    let now = Utc::now();
+    if send_at <= now || send_at - now > scheduled::MAX_SCHEDULE_AHEAD {
+        return Response::BadRequest;
+    }
+    let attachments: Vec<AttachmentId> = params
+        .attachments
+        .iter()
+        .map(|attachment_id| attachment_id.parse())
+        .collect::<Result<_, _>>()
+        .or_bad_request()?;
+    let reply_to: Option<MessageId> = match params.reply_to {
+        Some(reply_to) => Some(reply_to.parse().or_bad_request()?),
+        None => None,
+    };
+
+    let outgoing = OutgoingMessage {
+        text: params.message,
+        attachments,
+        reply_to,
+    };
+    let id = app
+        .schedule_message(user_id, conversation, outgoing, send_at)
+        .await
+        .or_bad_request()?;
+
+    Response::Json {
+        content: serde_json::json!(ScheduleMessageResponse {
+            success: true,
+            id: id.to_string(),
+        })
+        .to_string(),
+        headers: vec![],
+    }
+}
+
+/// Takes the message off the queue unless it has been sent already
+pub async fn cancel_scheduled_message<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    scheduled_message_id: &str,
+) -> Response {
+    #[derive(Serialize)]
+    struct CancelScheduledMessageResponse {
+        success: bool,
+    }
+
+    let scheduled_message_id: ScheduledMessageId = scheduled_message_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let success = app
+        .cancel_scheduled_message(&user_id, &scheduled_message_id)
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(CancelScheduledMessageResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// The thumbnail of the user's avatar, 404 Not Found if they have none
+pub async fn avatar<M, B: BlobStorage, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    user_id: &str,
+) -> Response {
+    let avatar_user_id: UserId = user_id.parse().or_bad_request()?;
+
+    if routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+        .is_none()
+    {
+        return routing::unauthorized_redirect();
+    }
+
+    let content = app
+        .fetch_avatar(&avatar_user_id)
+        .await
+        .or_server_error()?
+        .or_not_found()?;
+
+    // Clients ask for a new URL when the avatar changes
+    Response::Bytes {
+        content,
+        content_type: "image/png".to_owned(),
+        headers: vec![
+            (
+                CaseInsensitiveString::from("X-Content-Type-Options"),
+                "nosniff".to_owned(),
+            ),
+            (
+                CaseInsensitiveString::from("Cache-Control"),
+                "private, max-age=86400".to_owned(),
+            ),
+        ],
+    }
+}
+
+/// Everything stored about the user: a zip archive of `data.json` and the attachments they uploaded,
+/// or just the json with `format=json`
+pub async fn export_account<M, B: BlobStorage, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct ExportParams {
+        format: Option<String>,
+    }
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let export_params: ExportParams = form_data::from_str(params).or_bad_request()?;
+    let json_only = match export_params.format.as_deref() {
+        None | Some("zip") => false,
+        Some("json") => true,
+        Some(_) => return Response::BadRequest,
+    };
+
+    let export = app
+        .export_user_data(&user_id)
+        .await
+        .or_server_error()?
+        .or_not_found()?;
+    let attachments = export.attachments.clone();
+    let sessions = sessions::fetch_user_sessions(&user_id).or_server_error()?;
+    let data = serde_json::to_string_pretty(&UserDataExportJson::new(export, sessions))
+        .or_server_error()?;
+
+    if json_only {
+        return Response::Json {
+            content: data,
+            headers: vec![],
+        };
+    }
+
+    let mut archive = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    let options = zip::write::FileOptions::default();
+    archive.start_file("data.json", options).or_server_error()?;
+    archive.write_all(data.as_bytes()).or_server_error()?;
+    for attachment in attachments {
+        // Blobs of attachments deleted meanwhile are skipped
+        let Some((attachment, content)) = app
+            .fetch_attachment_content(&user_id, &attachment.id, false)
+            .await
+            .or_server_error()?
+        else {
+            continue;
+        };
+        // File names are sanitized on upload
+        let path = format!("attachments/{}/{}", attachment.id, attachment.file_name);
+        archive.start_file(path, options).or_server_error()?;
+        archive.write_all(&content).or_server_error()?;
+    }
+    let content = archive.finish().or_server_error()?.into_inner();
+
+    let file_name = format!("pheidippides-export-{}.zip", Utc::now().format("%Y-%m-%d"));
+    Response::Bytes {
+        content,
+        content_type: "application/zip".to_owned(),
+        headers: vec![
+            (
+                CaseInsensitiveString::from("Content-Disposition"),
+                format!("attachment; filename=\"{file_name}\""),
+            ),
+            (
+                CaseInsensitiveString::from("Cache-Control"),
+                "no-store".to_owned(),
+            ),
+        ],
+    }
+}
+
+/// Deletes the account of the current user, who confirms it by typing their username.
+/// All of their sessions end
+pub async fn delete_account<M, B: BlobStorage, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    #[derive(Deserialize)]
+    struct DeleteAccountParams {
+        username: String,
+    }
+
+    #[derive(Serialize)]
+    struct DeleteAccountResponse {
+        success: bool,
+    }
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let content = request.content().await.or_server_error()?;
+    let params: DeleteAccountParams = serde_json::from_str(&content).or_bad_request()?;
+
+    let profile = app
+        .fetch_profile(&user_id)
+        .await
+        .or_server_error()?
+        .or_not_found()?;
+    let success = if params.username.trim() == profile.username {
+        let client_addr = request.remote_addr().map(|addr| addr.ip());
+        let deleted = app
+            .delete_account(&user_id, client_addr)
+            .await
+            .or_server_error()?;
+        sessions::remove_user_sessions(&user_id).or_server_error()?;
+        deleted
+    } else {
+        false
+    };
+
+    Response::Json {
+        content: serde_json::json!(DeleteAccountResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Stores the request body as an attachment to be sent with a message later.
+/// The file name comes in the `name` parameter, the type in the Content-Type header
+pub async fn upload_attachment<M, B: BlobStorage, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct UploadAttachmentParams {
+        name: String,
+    }
+
+    #[derive(Serialize)]
+    struct UploadAttachmentResponse {
+        success: bool,
+        attachment: Option<AttachmentJson>,
+    }
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let params: UploadAttachmentParams = form_data::from_str(params).or_bad_request()?;
+    let content_type = request
+        .headers()
+        .get(&CaseInsensitiveString::from("Content-Type"))
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|content_type| content_type.trim().to_ascii_lowercase())
+        .or_bad_request()?;
+
+    // Oversized uploads are refused before reading them
+    let content_length = request
+        .content_length()
+        .or_bad_request()?
+        .or_bad_request()?;
+    let attachment = if app
+        .attachment_limits()
+        .allows(&content_type, content_length as u64)
+    {
+        let content = request.content_bytes().await.or_server_error()?;
+        app.upload_attachment(&user_id, &params.name, &content_type, content)
+            .await
+            .or_server_error()?
+    } else {
+        None
+    };
+
+    Response::Json {
+        content: serde_json::json!(UploadAttachmentResponse {
+            success: attachment.is_some(),
+            attachment: attachment.map(AttachmentJson::from),
+        })
+        .to_string(),
+        headers: vec![],
+    }
+}
+
+/// Serves the attachment or its thumbnail to its uploader and the participants
+/// of the conversation it was sent to only
+pub async fn attachment_content<M, B: BlobStorage, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    attachment_id: &str,
+    thumbnail: bool,
+) -> Response {
+    let attachment_id: AttachmentId = attachment_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let (attachment, content) = app
+        .fetch_attachment_content(&user_id, &attachment_id, thumbnail)
+        .await
+        .or_server_error()?
+        .or_not_found()?;
+
+    let content_type = match (thumbnail, attachment.content_type.as_str()) {
+        (true, _) => "image/png".to_owned(),
+        (false, "text/plain") => "text/plain; charset=utf-8".to_owned(),
+        (false, content_type) => content_type.to_owned(),
+    };
+    // Only images are shown in the browser, anything else is downloaded
+    let disposition = if attachment.is_image() {
+        "inline"
+    } else {
+        "attachment"
+    };
+    let file_name: String = attachment
+        .file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+
+    Response::Bytes {
+        content,
+        content_type,
+        headers: vec![
+            (
+                CaseInsensitiveString::from("Content-Disposition"),
+                format!("{disposition}; filename=\"{file_name}\""),
+            ),
+            (
+                CaseInsensitiveString::from("X-Content-Type-Options"),
+                "nosniff".to_owned(),
+            ),
+            (
+                CaseInsensitiveString::from("Cache-Control"),
+                "private, max-age=86400".to_owned(),
+            ),
+        ],
+    }
+}
+
+/// Shows the other participants that the user is typing
+pub async fn notify_typing<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+) -> Response {
+    let chat_id: Uuid = chat_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let conversation = app
+        .resolve_conversation(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    if let ConversationId::Direct(other_user_id) = &conversation {
+        if app
+            .is_blocked(&user_id, other_user_id)
+            .await
+            .or_server_error()?
+        {
+            return Response::BadRequest;
+        }
+    }
+
+    app.notify_typing(&user_id, &conversation)
+        .await
+        .or_server_error()?;
+
+    Response::Html {
+        content: "ok.".to_owned(),
+        headers: Vec::new(),
+    }
+}
+
+/// Responds with `{"success": false}` if the user isn't the author or the edit window has passed
+pub async fn edit_message<M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    message_id: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct EditMessageParams {
+        message: String,
+    }
+
+    #[derive(Serialize)]
+    struct EditMessageResponse {
+        success: bool,
+    }
+
+    let message_id: MessageId = message_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let content = request.content().await.or_server_error()?;
+    let params: EditMessageParams = serde_json::from_str(&content).or_bad_request()?;
+
+    let success = app
+        .edit_message(&user_id, &message_id, params.message)
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(EditMessageResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Adds or, if `add` isn't set, removes the user's reaction given as `{"emoji": "..."}`.
+/// Responds with `{"success": false}` unless the user participates in the message's conversation
+pub async fn react_to_message<M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    message_id: &str,
+    add: bool,
+) -> Response {
+    #[derive(Deserialize)]
+    struct ReactionParams {
+        emoji: String,
+    }
+
+    #[derive(Serialize)]
+    struct ReactionResponse {
+        success: bool,
+    }
+
+    let message_id: MessageId = message_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let content = request.content().await.or_server_error()?;
+    let params: ReactionParams = serde_json::from_str(&content).or_bad_request()?;
+    if !reactions::is_valid_reaction(&params.emoji) {
+        return Response::BadRequest;
+    }
+
+    let success = if add {
+        app.add_reaction(&user_id, &message_id, &params.emoji)
+            .await
+            .or_server_error()?
+    } else {
+        app.remove_reaction(&user_id, &message_id, &params.emoji)
+            .await
+            .or_server_error()?
+    };
+
+    Response::Json {
+        content: serde_json::json!(ReactionResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Pins the message to its conversation or unpins it
+pub async fn pin_message<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    message_id: &str,
+    pin: bool,
+) -> Response {
+    #[derive(Serialize)]
+    struct PinResponse {
+        success: bool,
+    }
+
+    let message_id: MessageId = message_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let success = if pin {
+        app.pin_message(&user_id, &message_id)
+            .await
+            .or_server_error()?
+    } else {
+        app.unpin_message(&user_id, &message_id)
+            .await
+            .or_server_error()?
+    };
+
+    Response::Json {
+        content: serde_json::json!(PinResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Stars the message for the user only or takes the star back
+pub async fn star_message<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    message_id: &str,
+    star: bool,
+) -> Response {
+    #[derive(Serialize)]
+    struct StarResponse {
+        success: bool,
+    }
+
+    let message_id: MessageId = message_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let success = if star {
+        app.star_message(&user_id, &message_id)
+            .await
+            .or_server_error()?
+    } else {
+        app.unstar_message(&user_id, &message_id)
+            .await
+            .or_server_error()?;
+        true
+    };
+
+    Response::Json {
+        content: serde_json::json!(StarResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Responds with `{"success": false}` unless the user is the author of the message
+pub async fn delete_message<M, B: BlobStorage, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    message_id: &str,
+) -> Response {
+    #[derive(Serialize)]
+    struct DeleteMessageResponse {
+        success: bool,
+    }
+
+    let message_id: MessageId = message_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let success = app
+        .delete_message_for_everyone(&user_id, &message_id)
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(DeleteMessageResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Copies the message into the conversation given as `{"to": "<chat id>"}`.
+/// Responds with `{"success": false}` if the user can't see the message
+pub async fn forward_message<M, B: BlobStorage, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    message_id: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct ForwardMessageParams {
+        to: String,
+    }
+
+    #[derive(Serialize)]
+    struct ForwardMessageResponse {
+        success: bool,
+        id: Option<String>,
+    }
+
+    let message_id: MessageId = message_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let content = request.content().await.or_server_error()?;
+    let params: ForwardMessageParams = serde_json::from_str(&content).or_bad_request()?;
+    let to: Uuid = params.to.parse().or_bad_request()?;
+    let to = app
+        .resolve_conversation(&user_id, &to)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    let id = app
+        .forward_message(&user_id, &message_id, to)
+        .await
+        .or_bad_request()?;
+
+    Response::Json {
+        content: serde_json::json!(ForwardMessageResponse {
+            success: id.is_some(),
+            id: id.map(|id| id.to_string()),
+        })
+        .to_string(),
+        headers: vec![],
+    }
+}
+
+/// Marks a notification or, without `notification_id`, the whole feed as read
+pub async fn mark_notifications_read<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    notification_id: Option<&str>,
+) -> Response {
+    #[derive(Serialize)]
+    struct MarkNotificationsReadResponse {
+        success: bool,
+    }
+
+    let notification_id: Option<NotificationId> = match notification_id {
+        Some(notification_id) => Some(notification_id.parse().or_bad_request()?),
+        None => None,
+    };
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let success = app
+        .mark_notifications_read(&user_id, notification_id.as_ref())
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(MarkNotificationsReadResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Hides the message for the current user only
+pub async fn hide_message<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    message_id: &str,
+) -> Response {
+    #[derive(Serialize)]
+    struct HideMessageResponse {
+        success: bool,
+    }
+
+    let message_id: MessageId = message_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let success = app
+        .hide_message(&user_id, &message_id)
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(HideMessageResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Responds with `{"success": false}` if the user is blocking themselves or doesn't exist
+pub async fn block_user<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    blocked_user_id: &str,
+    block: bool,
+) -> Response {
+    #[derive(Serialize)]
+    struct BlockUserResponse {
+        success: bool,
+    }
+
+    let blocked_user_id: UserId = blocked_user_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let success = if block {
+        app.block_user(&user_id, &blocked_user_id)
+            .await
+            .or_server_error()?
+    } else {
+        app.unblock_user(&user_id, &blocked_user_id)
+            .await
+            .or_server_error()?;
+        true
+    };
+
+    Response::Json {
+        content: serde_json::json!(BlockUserResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Responds with `{"success": false}` if the user isn't a member of the group chat
+pub async fn mute_conversation<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+    muted: bool,
+) -> Response {
+    #[derive(Serialize)]
+    struct MuteConversationResponse {
+        success: bool,
+    }
+
+    let chat_id: Uuid = chat_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let conversation = app
+        .resolve_conversation(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    let success = app
+        .set_conversation_muted(&user_id, &conversation, muted)
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(MuteConversationResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Sets the timer of disappearing messages in the conversation, `null` turns it off
+pub async fn set_message_ttl<M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct MessageTtlParams {
+        ttl_seconds: Option<i64>,
+    }
+
+    #[derive(Serialize)]
+    struct MessageTtlResponse {
+        success: bool,
+    }
+
+    let chat_id: Uuid = chat_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesWrite))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let content = request.content().await.or_server_error()?;
+    let params: MessageTtlParams = serde_json::from_str(&content).or_bad_request()?;
+    let ttl = match params.ttl_seconds {
+        Some(ttl_seconds) => Some(TimeDelta::try_seconds(ttl_seconds).or_bad_request()?),
+        None => None,
+    };
+    if ttl.is_some_and(|ttl| !retention::is_valid_message_ttl(&ttl)) {
+        return Response::BadRequest;
+    }
+
+    let conversation = app
+        .resolve_conversation(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    let success = app
+        .set_message_ttl(&user_id, &conversation, ttl)
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(MessageTtlResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Marks the conversation of the message as read up to and including the message
+pub async fn mark_read<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    message_id: &str,
+) -> Response {
+    #[derive(Serialize)]
+    struct MarkReadResponse {
+        success: bool,
+    }
+
+    let message_id: MessageId = message_id.parse().or_bad_request()?;
+
+    let user_id =
+        match routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+            .await
+            .or_server_error()?
+        {
+            Some(user_id) => user_id,
+            None => return routing::unauthorized_redirect(),
+        };
+
+    let success = app
+        .mark_read(&user_id, &message_id)
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(MarkReadResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+pub async fn subscribe_new_messages<A: AuthService, M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, A, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct SubscribeNewMessagesParams {
+        last_message_id: Option<String>,
+    }
+
+    let user_id = routing::get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    let subscribe_new_messages_params: SubscribeNewMessagesParams =
+        form_data::from_str(params).or_bad_request()?;
+
+    let last_message_id_params = match subscribe_new_messages_params.last_message_id {
+        Some(s) => Some(s.parse().or_bad_request()?),
+        None => None,
+    };
+
+    let last_event_id_header = request
+        .headers()
+        .get(&CaseInsensitiveString::from("Last-Event-ID"));
+    let last_message_id_header: Option<MessageId> = match last_event_id_header {
+        Some(header_value) => Some(header_value.parse().or_bad_request()?),
+        None => None,
+    };
+
+    let starting_point = last_message_id_header.or(last_message_id_params);
+
+    let subscription = app
+        .subscribe_to_new_messages(user_id, starting_point)
+        .await
+        .or_server_error()?;
+
+    #[derive(Serialize)]
+    struct MessageHiddenJson {
+        #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+        id: MessageId,
+    }
+
+    #[derive(Serialize)]
+    struct MessagesReadJson {
+        /// The conversation as seen by the subscriber
+        #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+        chat_id: Uuid,
+        #[serde(flatten)]
+        read_receipt: ReadReceiptJson,
+    }
+
+    #[derive(Serialize)]
+    struct TypingJson {
+        #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+        user_id: UserId,
+        /// The conversation as seen by the subscriber
+        #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+        chat_id: Uuid,
+    }
+
+    #[derive(Serialize)]
+    struct PinChangedJson {
+        /// The conversation as seen by the subscriber
+        #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+        chat_id: Uuid,
+        /// Who pinned or unpinned the message
+        #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+        user_id: UserId,
+        message: MessageJson,
+    }
+
+    let stream = async_utils::pipe_unbounded_channel(subscription, move |event| match event {
+        Event::NewMessage(message) => {
+            let id = Some(message.id.to_string());
+            let data = serde_json::json!(MessageJson::from(message)).to_string();
+            let event = None;
+            Some(EventSourceEvent { data, id, event })
+        }
+        Event::MessageEdited(message) => {
+            let data = serde_json::json!(MessageJson::from(message)).to_string();
+            let event = Some("message_edited".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        Event::MessageDeleted(message) => {
+            let data = serde_json::json!(MessageJson::from(message)).to_string();
+            let event = Some("message_deleted".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        Event::ReactionsChanged(message) => {
+            let data = serde_json::json!(MessageJson::from(message)).to_string();
+            let event = Some("reactions_changed".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        Event::MessagePinned { pinned_by, message } => {
+            let data = serde_json::json!(PinChangedJson {
+                chat_id: *message.conversation_for(&user_id).uuid(),
+                user_id: pinned_by,
+                message: MessageJson::from(message),
+            })
+            .to_string();
+            let event = Some("message_pinned".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        Event::MessageUnpinned {
+            unpinned_by,
+            message,
+        } => {
+            let data = serde_json::json!(PinChangedJson {
+                chat_id: *message.conversation_for(&user_id).uuid(),
+                user_id: unpinned_by,
+                message: MessageJson::from(message),
+            })
+            .to_string();
+            let event = Some("message_unpinned".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        Event::MessagesRead { reader, message } => {
+            let data = serde_json::json!(MessagesReadJson {
+                chat_id: *message.conversation_for(&user_id).uuid(),
+                read_receipt: ReadReceiptJson {
+                    user_id: reader,
+                    message_id: message.id,
+                },
+            })
+            .to_string();
+            let event = Some("messages_read".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        Event::Notification(notification) => {
+            let data = serde_json::json!(NotificationJson::from(notification)).to_string();
+            let event = Some("notification".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        Event::Typing {
+            user_id: typist,
+            conversation,
+        } => {
+            let chat_id = match conversation {
+                ConversationId::Direct(_) => typist,
+                ConversationId::Group(chat_id) => chat_id,
+            };
+            let data = serde_json::json!(TypingJson {
+                user_id: typist,
+                chat_id
+            })
+            .to_string();
+            let event = Some("typing".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        Event::PresenceChanged {
+            user_id: contact,
+            presence,
+        } => {
+            let data = serde_json::json!(PresenceJson::new(contact, presence)).to_string();
+            let event = Some("presence".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        Event::MessageHidden { message_id, .. } => {
+            let data = serde_json::json!(MessageHiddenJson { id: message_id }).to_string();
+            let event = Some("message_hidden".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+        // Clients remove purged messages just like hidden ones
+        Event::MessageExpired(message) => {
+            let data = serde_json::json!(MessageHiddenJson { id: message.id }).to_string();
+            let event = Some("message_expired".to_owned());
+            Some(EventSourceEvent {
+                data,
+                id: None,
+                event,
+            })
+        }
+    });
+
+    Response::EventSource {
+        retry: None,
+        stream,
+    }
+}
+
+pub async fn create_api_token<M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    #[derive(Deserialize)]
+    struct CreateApiTokenParams {
+        name: String,
+        scopes: Vec<String>,
+        expires_in_days: Option<i64>,
+    }
+
+    #[derive(Serialize)]
+    struct CreateApiTokenResponse {
+        success: bool,
+        /// Shown only once, the server keeps just its hash
+        token: Option<String>,
+        api_token: Option<ApiTokenJson>,
+    }
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let content = request.content().await.or_server_error()?;
+    let params: CreateApiTokenParams = serde_json::from_str(&content).or_bad_request()?;
+
+    let name = params.name.trim();
+    let mut scopes = Vec::new();
+    for scope in &params.scopes {
+        let scope: ApiScope = scope.parse().or_bad_request()?;
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    let expires_in = match params.expires_in_days {
+        Some(days) if (1..=MAX_API_TOKEN_LIFETIME_DAYS).contains(&days) => {
+            Some(TimeDelta::days(days))
+        }
+        Some(_) => return Response::BadRequest,
+        None => None,
+    };
+
+    let valid_name = !name.is_empty() && name.chars().count() <= MAX_API_TOKEN_NAME_LENGTH;
+    if !valid_name || scopes.is_empty() {
+        let response = CreateApiTokenResponse {
+            success: false,
+            token: None,
+            api_token: None,
+        };
+        return Response::Json {
+            content: serde_json::json!(response).to_string(),
+            headers: vec![],
+        };
+    }
+
+    let expires_at = expires_in.map(|expires_in| Utc::now() + expires_in);
+    let (api_token, token) = app
+        .create_api_token(&user_id, name, &scopes, expires_at)
+        .await
+        .or_server_error()?;
+
+    let response = CreateApiTokenResponse {
+        success: true,
+        token: Some(token),
+        api_token: Some(api_token.into()),
+    };
+    Response::Json {
+        content: serde_json::json!(response).to_string(),
+        headers: vec![],
+    }
+}
+
+pub async fn revoke_api_token<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    token_id: &str,
+) -> Response {
+    #[derive(Serialize)]
+    struct RevokeApiTokenResponse {
+        success: bool,
+    }
+
+    let token_id: ApiTokenId = token_id.parse().or_bad_request()?;
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let success = app
+        .revoke_api_token(&user_id, &token_id)
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(RevokeApiTokenResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+struct ChatActionResponse {
+    success: bool,
+}
+
+fn chat_action_response(success: bool) -> Response {
+    Response::Json {
+        content: serde_json::json!(ChatActionResponse { success }).to_string(),
+        headers: vec![],
+    }
+}
+
+fn normalize_chat_title(title: &str) -> Option<&str> {
+    let title = title.trim();
+    let valid = !title.is_empty() && title.chars().count() <= MAX_CHAT_TITLE_LENGTH;
+    valid.then_some(title)
+}
+
+/// Members are listed by username
+pub async fn create_chat<M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    #[derive(Deserialize)]
+    struct CreateChatParams {
+        title: String,
+        #[serde(default)]
+        members: Vec<String>,
+    }
+
+    #[derive(Serialize)]
+    struct CreateChatResponse {
+        success: bool,
+        chat_id: Option<String>,
+    }
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let content = request.content().await.or_server_error()?;
+    let params: CreateChatParams = serde_json::from_str(&content).or_bad_request()?;
+
+    let mut members = Vec::with_capacity(params.members.len());
+    for username in &params.members {
+        let member = app
+            .find_user_by_username(username)
+            .await
+            .or_server_error()?;
+        match member {
+            Some(member) if app.is_blocked(&user_id, &member).await.or_server_error()? => {
+                return Response::BadRequest
+            }
+            Some(member) => members.push(member),
+            None => {
+                let response = CreateChatResponse {
+                    success: false,
+                    chat_id: None,
+                };
+                return Response::Json {
+                    content: serde_json::json!(response).to_string(),
+                    headers: vec![],
+                };
+            }
+        }
+    }
+
+    let chat_id = match normalize_chat_title(&params.title) {
+        Some(title) => Some(
+            app.create_chat(&user_id, title, &members)
+                .await
+                .or_server_error()?,
+        ),
+        None => None,
+    };
+
+    let response = CreateChatResponse {
+        success: chat_id.is_some(),
+        chat_id: chat_id.map(|chat_id| chat_id.to_string()),
+    };
+    Response::Json {
+        content: serde_json::json!(response).to_string(),
+        headers: vec![],
+    }
+}
+
+pub async fn rename_chat<M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct RenameChatParams {
+        title: String,
+    }
+
+    let chat_id: ChatId = chat_id.parse().or_bad_request()?;
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let content = request.content().await.or_server_error()?;
+    let params: RenameChatParams = serde_json::from_str(&content).or_bad_request()?;
+
+    let success = match normalize_chat_title(&params.title) {
+        Some(title) => app
+            .rename_chat(&user_id, &chat_id, title)
+            .await
+            .or_server_error()?,
+        None => false,
+    };
+
+    chat_action_response(success)
+}
+
+pub async fn add_chat_member<M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct AddChatMemberParams {
+        username: String,
+    }
+
+    let chat_id: ChatId = chat_id.parse().or_bad_request()?;
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let content = request.content().await.or_server_error()?;
+    let params: AddChatMemberParams = serde_json::from_str(&content).or_bad_request()?;
+
+    let new_member = app
+        .find_user_by_username(&params.username)
+        .await
+        .or_server_error()?;
+    let success = match new_member {
+        Some(new_member)
+            if app
+                .is_blocked(&user_id, &new_member)
+                .await
+                .or_server_error()? =>
+        {
+            return Response::BadRequest
+        }
+        Some(new_member) => app
+            .add_chat_member(&user_id, &chat_id, &new_member)
+            .await
+            .or_server_error()?,
+        None => false,
+    };
+
+    chat_action_response(success)
+}
+
+/// Removing oneself is the same as leaving the chat
+pub async fn remove_chat_member<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+    member: &str,
+) -> Response {
+    let chat_id: ChatId = chat_id.parse().or_bad_request()?;
+    let member: UserId = member.parse().or_bad_request()?;
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let success = if member == user_id {
+        app.leave_chat(&user_id, &chat_id).await
+    } else {
+        app.remove_chat_member(&user_id, &chat_id, &member).await
+    }
+    .or_server_error()?;
+
+    chat_action_response(success)
+}
+
+pub async fn leave_chat<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+) -> Response {
+    let chat_id: ChatId = chat_id.parse().or_bad_request()?;
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let success = app.leave_chat(&user_id, &chat_id).await.or_server_error()?;
+
+    chat_action_response(success)
+}
+
+/// Accepts "admin" and "member", the owner role is only passed on by leaving the chat
+pub async fn set_chat_member_role<M, B, T: AsyncRead + Unpin>(
+    request: &mut Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+    member: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct SetChatMemberRoleParams {
+        role: String,
+    }
+
+    let chat_id: ChatId = chat_id.parse().or_bad_request()?;
+    let member: UserId = member.parse().or_bad_request()?;
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let content = request.content().await.or_server_error()?;
+    let params: SetChatMemberRoleParams = serde_json::from_str(&content).or_bad_request()?;
+    let role: ChatRole = params.role.parse().or_bad_request()?;
+
+    let success = app
+        .set_chat_member_role(&user_id, &chat_id, &member, role)
+        .await
+        .or_server_error()?;
+
+    chat_action_response(success)
+}
+
+fn is_email_valid(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty() && !domain.is_empty() && !email.contains(char::is_whitespace)
+        }
+        None => false,
     }
 }
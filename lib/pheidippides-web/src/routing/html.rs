@@ -1,5 +1,6 @@
 use anyhow::{Context, Result};
 use askama::Template;
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use tokio::io::AsyncRead;
 
@@ -9,9 +10,14 @@ use http_server::response::Response;
 use pheidippides_utils::serde::form_data as serde_form_data;
 
 use crate::flow_controller::HttpResponseContextExtension;
+use pheidippides_messenger::audit_log::{AuditEvent, AuditEventId, AuditEventKind};
+use pheidippides_messenger::authorization::{AuthService, TotpEnrollment};
+use pheidippides_messenger::chats::Conversation;
 use pheidippides_messenger::data_access::DataAccess;
 use pheidippides_messenger::messenger::Messenger;
-use pheidippides_messenger::{User, UserId};
+use pheidippides_messenger::profiles::{Profile, MAX_BIO_LENGTH, MAX_DISPLAY_NAME_LENGTH};
+use pheidippides_messenger::{ConversationId, UserId};
+use uuid::Uuid;
 
 use crate::routing::get_authorization;
 
@@ -20,18 +26,21 @@ use crate::routing::get_authorization;
 struct ChatPage<'a> {
     username: &'a str,
     user_id: &'a UserId,
-    chats: Vec<User>,
+    chats: Vec<Conversation>,
 }
 
 #[derive(Template)]
 #[template(path = "elements/chats.html")]
 struct ChatHtmlElements {
-    chats: Vec<User>,
+    chats: Vec<Conversation>,
 }
 
 #[derive(Template)]
 #[template(path = "login.html")]
-struct LoginPage {}
+struct LoginPage {
+    password_login: bool,
+    external_login: bool,
+}
 
 #[derive(Template)]
 #[template(path = "signup.html")]
@@ -41,14 +50,35 @@ struct SignUpPage {}
 #[template(path = "login_fail.html")]
 struct LoginFailPage {}
 
-pub async fn chat_page<A>(
-    app: &Messenger<impl DataAccess, A>,
+#[derive(Template)]
+#[template(path = "login_locked.html")]
+struct LoginLockedPage {
+    wait: String,
+}
+
+#[derive(Template)]
+#[template(path = "reset_password.html")]
+struct ResetPasswordPage {}
+
+#[derive(Template)]
+#[template(path = "reset_password_sent.html")]
+struct ResetPasswordSentPage {}
+
+#[derive(Template)]
+#[template(path = "reset_password_confirm.html")]
+struct ResetPasswordConfirmPage<'a> {
+    token: &'a str,
+    invalid_token: bool,
+}
+
+pub async fn chat_page<A, M, B>(
+    app: &Messenger<impl DataAccess, A, M, B>,
     user_id: &UserId,
 ) -> Result<Option<String>> {
-    let user = app.fetch_user(user_id).await?;
+    let profile = app.fetch_profile(user_id).await?;
 
-    let username = match user {
-        Some(user) => user.username,
+    let username = match profile {
+        Some(profile) => profile.name().to_owned(),
         None => return Ok(None),
     };
 
@@ -65,8 +95,13 @@ pub async fn chat_page<A>(
     ))
 }
 
-pub fn login_page() -> Result<String> {
-    LoginPage {}.render().context("Could not render login.html")
+pub fn login_page(password_login: bool, external_login: bool) -> Result<String> {
+    LoginPage {
+        password_login,
+        external_login,
+    }
+    .render()
+    .context("Could not render login.html")
 }
 
 pub fn signup_page() -> Result<String> {
@@ -81,12 +116,186 @@ pub fn login_fail_page() -> Result<String> {
         .context("Could not render login_fail.html")
 }
 
-pub async fn chats_html_response<A, T: AsyncRead + Unpin>(
+pub fn login_locked_page(until: DateTime<Utc>) -> Result<String> {
+    let seconds = (until - Utc::now()).num_seconds().max(1);
+    let wait = if seconds < 60 {
+        format!("{seconds} сек.")
+    } else {
+        format!("{} мин.", (seconds + 59) / 60)
+    };
+    LoginLockedPage { wait }
+        .render()
+        .context("Could not render login_locked.html")
+}
+
+#[derive(Template)]
+#[template(path = "login_totp.html")]
+struct LoginTotpPage<'a> {
+    pending_login: &'a str,
+    invalid_code: bool,
+}
+
+#[derive(Template)]
+#[template(path = "account_totp.html")]
+struct AccountTotpPage {
+    enabled: bool,
+    invalid_code: bool,
+}
+
+struct TotpEnrollmentView {
+    secret: String,
+    qr_svg: String,
+}
+
+#[derive(Template)]
+#[template(path = "totp_enroll.html")]
+struct TotpEnrollPage {
+    enrollment: Option<TotpEnrollmentView>,
+    invalid_code: bool,
+}
+
+#[derive(Template)]
+#[template(path = "totp_recovery_codes.html")]
+struct TotpRecoveryCodesPage {
+    recovery_codes: Vec<String>,
+}
+
+pub fn login_totp_page(pending_login: &str, invalid_code: bool) -> Result<String> {
+    LoginTotpPage {
+        pending_login,
+        invalid_code,
+    }
+    .render()
+    .context("Could not render login_totp.html")
+}
+
+#[derive(Template)]
+#[template(path = "audit_log.html")]
+struct AuditLogPage {
+    events: Vec<AuditEventView>,
+    next_page: Option<AuditEventId>,
+}
+
+struct AuditEventView {
+    kind: &'static str,
+    client_addr: String,
+    created_at: String,
+}
+
+#[derive(Template)]
+#[template(path = "profile.html")]
+struct ProfilePage {
+    profile: Profile,
+    max_display_name_length: usize,
+    max_bio_length: usize,
+}
+
+pub fn profile_page(profile: Profile) -> Result<String> {
+    ProfilePage {
+        profile,
+        max_display_name_length: MAX_DISPLAY_NAME_LENGTH,
+        max_bio_length: MAX_BIO_LENGTH,
+    }
+    .render()
+    .context("Could not render profile.html")
+}
+
+pub fn audit_log_page(events: Vec<AuditEvent>, next_page: Option<AuditEventId>) -> Result<String> {
+    let events = events
+        .into_iter()
+        .map(|event| AuditEventView {
+            kind: match event.kind {
+                AuditEventKind::Signup => "Регистрация",
+                AuditEventKind::LoginSuccess => "Вход",
+                AuditEventKind::LoginFailure => "Неудачная попытка входа",
+                AuditEventKind::Logout => "Выход",
+                AuditEventKind::PasswordChange => "Смена пароля",
+                AuditEventKind::SessionRevocation => "Завершение всех сеансов",
+                AuditEventKind::UsernameChange => "Смена имени пользователя",
+                AuditEventKind::AccountDeletion => "Удаление аккаунта",
+            },
+            client_addr: event
+                .client_addr
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            created_at: event.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        })
+        .collect();
+
+    AuditLogPage { events, next_page }
+        .render()
+        .context("Could not render audit_log.html")
+}
+
+pub fn account_totp_page(enabled: bool, invalid_code: bool) -> Result<String> {
+    AccountTotpPage {
+        enabled,
+        invalid_code,
+    }
+    .render()
+    .context("Could not render account_totp.html")
+}
+
+/// Without enrollment info only the confirmation form is displayed
+pub fn totp_enroll_page(enrollment: Option<TotpEnrollment>, invalid_code: bool) -> Result<String> {
+    let enrollment = match enrollment {
+        Some(enrollment) => {
+            let qr_svg = qrcode::QrCode::new(&enrollment.uri)
+                .context("Could not encode otpauth uri as a QR code")?
+                .render::<qrcode::render::svg::Color>()
+                .min_dimensions(200, 200)
+                .build();
+            Some(TotpEnrollmentView {
+                secret: enrollment.secret,
+                qr_svg,
+            })
+        }
+        None => None,
+    };
+
+    TotpEnrollPage {
+        enrollment,
+        invalid_code,
+    }
+    .render()
+    .context("Could not render totp_enroll.html")
+}
+
+pub fn totp_recovery_codes_page(recovery_codes: Vec<String>) -> Result<String> {
+    TotpRecoveryCodesPage { recovery_codes }
+        .render()
+        .context("Could not render totp_recovery_codes.html")
+}
+
+pub fn reset_password_page() -> Result<String> {
+    ResetPasswordPage {}
+        .render()
+        .context("Could not render reset_password.html")
+}
+
+pub fn reset_password_sent_page() -> Result<String> {
+    ResetPasswordSentPage {}
+        .render()
+        .context("Could not render reset_password_sent.html")
+}
+
+pub fn reset_password_confirm_page(token: &str, invalid_token: bool) -> Result<String> {
+    ResetPasswordConfirmPage {
+        token,
+        invalid_token,
+    }
+    .render()
+    .context("Could not render reset_password_confirm.html")
+}
+
+pub async fn chats_html_response<A: AuthService, M, B, T: AsyncRead + Unpin>(
     request: &Request<T>,
-    app: Messenger<impl DataAccess, A>,
+    app: Messenger<impl DataAccess, A, M, B>,
 ) -> Response {
     let headers = request.headers();
-    let authorization = get_authorization(headers).or_bad_request()?;
+    let authorization = get_authorization(headers, &app, None)
+        .await
+        .or_bad_request()?;
     let response_string = match authorization {
         Some(user_id) => chats_html(&app, &user_id).await.or_server_error()?,
         None => String::from("Unauthorized"),
@@ -98,8 +307,8 @@ pub async fn chats_html_response<A, T: AsyncRead + Unpin>(
     }
 }
 
-pub async fn chats_html<A>(
-    app: &Messenger<impl DataAccess, A>,
+pub async fn chats_html<A, M, B>(
+    app: &Messenger<impl DataAccess, A, M, B>,
     user_id: &UserId,
 ) -> Result<String> {
     let chats = app.fetch_users_chats(user_id).await?;
@@ -113,15 +322,31 @@ struct ChatSearchParams {
     query: String,
 }
 
-pub async fn chatsearch_html<A>(app: Messenger<impl DataAccess, A>, params: &str) -> Response {
-    // TODO check authorization
+pub async fn chatsearch_html<A: AuthService, M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, A, M, B>,
+    params: &str,
+) -> Response {
+    let user_id = match get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
 
     let search_params: ChatSearchParams = serde_form_data::from_str(params).or_bad_request()?;
 
-    let chats = app
-        .find_users_by_substring(&search_params.query)
+    let users = app
+        .find_users_by_substring(&user_id, &search_params.query)
         .await
         .or_server_error()?;
+    let mut chats = vec![];
+    for user in users {
+        if let Some(profile) = app.fetch_profile(&user.id).await.or_server_error()? {
+            chats.push(Conversation::from(profile));
+        }
+    }
 
     let chats_html = ChatHtmlElements { chats }.render().or_server_error()?;
 
@@ -131,11 +356,41 @@ pub async fn chatsearch_html<A>(app: Messenger<impl DataAccess, A>, params: &str
     }
 }
 
-pub async fn chat_html_response<A>(app: Messenger<impl DataAccess, A>, chat_id: &str) -> Response {
-    // TODO authorization first??
+pub async fn chat_html_response<A: AuthService, M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, A, M, B>,
+    chat_id: &str,
+) -> Response {
+    let chat_id: Uuid = chat_id.parse().or_bad_request()?;
+
+    let user_id = get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
 
-    let chat_id: UserId = chat_id.parse().or_bad_request()?;
-    let chat_info = app.fetch_user(&chat_id).await.or_server_error()?;
+    let chat_info = match app
+        .resolve_conversation(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+    {
+        Some(ConversationId::Direct(other_user)) => app
+            .fetch_profile(&other_user)
+            .await
+            .or_server_error()?
+            .map(Conversation::from),
+        Some(ConversationId::Group(chat_id)) => app
+            .fetch_chat(&user_id, &chat_id)
+            .await
+            .or_server_error()?
+            .map(|chat| Conversation {
+                id: ConversationId::Group(chat.id),
+                title: chat.title,
+                unread_count: 0,
+                muted: false,
+                avatar: None,
+            }),
+        None => None,
+    };
     let res = ChatHtmlElements {
         chats: chat_info.into_iter().collect(),
     }
@@ -7,12 +7,42 @@ use pheidippides_utils::serde::form_data as serde_form_data;
 use http_server::request::Request;
 use http_server::response::Response;
 
-use crate::flow_controller::HttpResponseContextExtension;
+use crate::flow_controller::{HttpResponseContextExtension, HttpResponseFlowController};
+use pheidippides_messenger::attachments::{Attachment, AttachmentId};
+use pheidippides_messenger::audit_log::{AuditEvent, AuditEventId, AuditEventQuery};
+use pheidippides_messenger::authorization::{ApiScope, ApiToken, ApiTokenId, AuthService};
+use pheidippides_messenger::chats::{Chat, ChatMember, Conversation};
 use pheidippides_messenger::data_access::DataAccess;
+use pheidippides_messenger::export::UserDataExport;
+use pheidippides_messenger::formatting;
 use pheidippides_messenger::messenger::Messenger;
-use pheidippides_messenger::{Message, MessageId, UserId};
+use pheidippides_messenger::notifications::{
+    Notification, NotificationCursor, NotificationId, NotificationKind,
+    DEFAULT_NOTIFICATIONS_PAGE_SIZE, MAX_NOTIFICATIONS_PAGE_SIZE,
+};
+use pheidippides_messenger::pins::PinnedMessage;
+use pheidippides_messenger::presence::Presence;
+use pheidippides_messenger::profiles::Profile;
+use pheidippides_messenger::reactions::Reaction;
+use pheidippides_messenger::scheduled::{ScheduledMessage, ScheduledMessageId};
+use pheidippides_messenger::search::{
+    MessageSearchHit, MessageSearchQuery, SnippetFragment, DEFAULT_SEARCH_PAGE_SIZE,
+    MAX_SEARCH_PAGE_SIZE,
+};
+use pheidippides_messenger::stars::{
+    StarCursor, StarredMessage, DEFAULT_STARRED_PAGE_SIZE, MAX_STARRED_PAGE_SIZE,
+};
+use pheidippides_messenger::{
+    ChatId, ConversationId, ForwardedFrom, Message, MessageId, MessageRevision, ReadReceipt, User,
+    UserId,
+};
+use uuid::Uuid;
 
 use crate::routing::get_authorization;
+use crate::sessions::SessionInfo;
+
+const MAX_AUDIT_LOG_PAGE_SIZE: u32 = 500;
+const DEFAULT_AUDIT_LOG_PAGE_SIZE: u32 = 100;
 
 #[derive(Serialize)]
 pub struct MessageJson {
@@ -20,11 +50,28 @@ pub struct MessageJson {
     pub id: MessageId,
     #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
     pub from: UserId,
+    /// The receiving user or, for group messages, the chat
     #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
-    pub to: UserId,
+    pub to: Uuid,
+    /// Set for group messages only
+    pub chat_id: Option<String>,
+    /// The markdown-lite source, as written by the sender
     pub message: String,
+    /// The source rendered as sanitized HTML
+    pub message_html: String,
     #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
     pub timestamp: DateTime<chrono::Utc>,
+    pub edited_at: Option<String>,
+    /// Set for tombstones of messages deleted for everyone, their text is empty
+    pub deleted_at: Option<String>,
+    /// Set for disappearing messages, they are removed by `message_expired` events after this time
+    pub expires_at: Option<String>,
+    pub attachments: Vec<AttachmentJson>,
+    /// The message this one replies to, older messages are loaded with `from` to reach it
+    pub reply_to: Option<String>,
+    /// Set for copies forwarded from another conversation
+    pub forwarded_from: Option<ForwardedFromJson>,
+    pub reactions: Vec<ReactionJson>,
 }
 
 impl From<Message> for MessageJson {
@@ -32,9 +79,113 @@ impl From<Message> for MessageJson {
         Self {
             id: message.id,
             from: message.from,
-            to: message.to,
+            to: *message.to.uuid(),
+            chat_id: match message.to {
+                ConversationId::Direct(_) => None,
+                ConversationId::Group(chat_id) => Some(chat_id.to_string()),
+            },
+            message_html: formatting::render_html(&message.message),
             message: message.message,
             timestamp: message.timestamp,
+            edited_at: message.edited_at.map(|edited_at| edited_at.to_rfc3339()),
+            deleted_at: message.deleted_at.map(|deleted_at| deleted_at.to_rfc3339()),
+            expires_at: message.expires_at.map(|expires_at| expires_at.to_rfc3339()),
+            attachments: message
+                .attachments
+                .into_iter()
+                .map(AttachmentJson::from)
+                .collect(),
+            reply_to: message.reply_to.map(|reply_to| reply_to.to_string()),
+            forwarded_from: message.forwarded_from.map(ForwardedFromJson::from),
+            reactions: message
+                .reactions
+                .into_iter()
+                .map(ReactionJson::from)
+                .collect(),
+        }
+    }
+}
+
+/// Author and send time of the original message
+#[derive(Serialize)]
+pub struct ForwardedFromJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub from: UserId,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub timestamp: DateTime<chrono::Utc>,
+}
+
+impl From<ForwardedFrom> for ForwardedFromJson {
+    fn from(forwarded_from: ForwardedFrom) -> Self {
+        Self {
+            from: forwarded_from.from,
+            timestamp: forwarded_from.timestamp,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReactionJson {
+    pub emoji: String,
+    pub count: usize,
+    /// In the order they reacted
+    pub users: Vec<String>,
+}
+
+impl From<Reaction> for ReactionJson {
+    fn from(reaction: Reaction) -> Self {
+        Self {
+            count: reaction.count(),
+            users: reaction.users.iter().map(UserId::to_string).collect(),
+            emoji: reaction.emoji,
+        }
+    }
+}
+
+/// The file is served at `/attachments/<id>`, the thumbnail at `/attachments/<id>/thumbnail`
+#[derive(Serialize)]
+pub struct AttachmentJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub id: AttachmentId,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub has_thumbnail: bool,
+}
+
+impl From<Attachment> for AttachmentJson {
+    fn from(attachment: Attachment) -> Self {
+        Self {
+            id: attachment.id,
+            file_name: attachment.file_name,
+            content_type: attachment.content_type,
+            size: attachment.size,
+            has_thumbnail: attachment.has_thumbnail,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiTokenJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub id: ApiTokenId,
+    pub name: String,
+    pub scopes: Vec<&'static str>,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub created_at: DateTime<chrono::Utc>,
+    pub expires_at: Option<String>,
+}
+
+impl From<ApiToken> for ApiTokenJson {
+    fn from(api_token: ApiToken) -> Self {
+        Self {
+            id: api_token.id,
+            name: api_token.name,
+            scopes: api_token.scopes.iter().map(ApiScope::as_str).collect(),
+            created_at: api_token.created_at,
+            expires_at: api_token
+                .expires_at
+                .map(|expires_at| expires_at.to_rfc3339()),
         }
     }
 }
@@ -48,21 +199,40 @@ struct MessagesUrlParams {
 struct MessagesResponse {
     success: bool,
     messages: Vec<MessageJson>,
+    /// Read markers of the other participants
+    read_receipts: Vec<ReadReceiptJson>,
     error: Option<MessageResponseError>,
 }
 
+#[derive(Serialize)]
+pub struct ReadReceiptJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub user_id: UserId,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub message_id: MessageId,
+}
+
+impl From<ReadReceipt> for ReadReceiptJson {
+    fn from(read_receipt: ReadReceipt) -> Self {
+        Self {
+            user_id: read_receipt.user_id,
+            message_id: read_receipt.message_id,
+        }
+    }
+}
+
 #[derive(Serialize)]
 enum MessageResponseError {
     Unauthorized,
 }
 
-pub async fn messages_json<A, T: AsyncRead + Unpin>(
+pub async fn messages_json<A: AuthService, M, B, T: AsyncRead + Unpin>(
     request: &Request<T>,
-    app: Messenger<impl DataAccess, A>,
+    app: Messenger<impl DataAccess, A, M, B>,
     chat_id: &str,
     params: &str,
 ) -> Response {
-    let chat_id: UserId = chat_id.parse().or_bad_request()?;
+    let chat_id: Uuid = chat_id.parse().or_bad_request()?;
     let query_params: MessagesUrlParams = serde_form_data::from_str(params).or_bad_request()?;
 
     let starting_from: Option<MessageId> = match query_params.from {
@@ -71,12 +241,16 @@ pub async fn messages_json<A, T: AsyncRead + Unpin>(
     };
 
     let headers = request.headers();
-    let user_id = match get_authorization(headers).or_server_error()? {
+    let user_id = match get_authorization(headers, &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
         Some(res) => res,
         None => {
             let response = MessagesResponse {
                 success: false,
                 messages: vec![],
+                read_receipts: vec![],
                 error: Some(MessageResponseError::Unauthorized),
             };
             return Response::Json {
@@ -86,8 +260,14 @@ pub async fn messages_json<A, T: AsyncRead + Unpin>(
         }
     };
 
+    let conversation = app
+        .resolve_conversation(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
     let messages: Vec<_> = app
-        .fetch_last_messages(&user_id, &chat_id, starting_from.as_ref())
+        .fetch_last_messages(&user_id, &conversation, starting_from.as_ref())
         .await
         .or_server_error()?
         .into_iter()
@@ -95,9 +275,18 @@ pub async fn messages_json<A, T: AsyncRead + Unpin>(
         .rev()
         .collect();
 
+    let read_receipts = app
+        .fetch_read_receipts(&user_id, &conversation)
+        .await
+        .or_server_error()?
+        .into_iter()
+        .map(ReadReceiptJson::from)
+        .collect();
+
     let response = MessagesResponse {
         success: true,
         messages,
+        read_receipts,
         error: None,
     };
     let json_response = serde_json::json!(response);
@@ -107,3 +296,1037 @@ pub async fn messages_json<A, T: AsyncRead + Unpin>(
         headers: vec![],
     }
 }
+
+#[derive(Serialize)]
+pub struct SearchHitJson {
+    pub message: MessageJson,
+    pub rank: f32,
+    /// Excerpt of the message around the first match, to be joined in order
+    pub snippet: Vec<SnippetFragmentJson>,
+}
+
+impl From<MessageSearchHit> for SearchHitJson {
+    fn from(hit: MessageSearchHit) -> Self {
+        Self {
+            message: hit.message.into(),
+            rank: hit.rank,
+            snippet: hit
+                .snippet
+                .into_iter()
+                .map(SnippetFragmentJson::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SnippetFragmentJson {
+    pub text: String,
+    pub highlighted: bool,
+}
+
+impl From<SnippetFragment> for SnippetFragmentJson {
+    fn from(fragment: SnippetFragment) -> Self {
+        Self {
+            text: fragment.text,
+            highlighted: fragment.highlighted,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    hits: Vec<SearchHitJson>,
+    /// Passed as `cursor` to fetch the next page, None on the last one
+    next_cursor: Option<String>,
+}
+
+/// Messages of the user's conversations containing every word of `q`, best matches first.
+/// `chat` and `from` narrow the search down to a conversation and a sender,
+/// `since` and `until` are RFC 3339 timestamps
+pub async fn search_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct SearchParams {
+        q: String,
+        chat: Option<String>,
+        from: Option<String>,
+        since: Option<String>,
+        until: Option<String>,
+        cursor: Option<String>,
+        limit: Option<String>,
+    }
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let params: SearchParams = serde_form_data::from_str(params).or_bad_request()?;
+    let conversation = match params.chat {
+        Some(chat) => {
+            let chat: Uuid = chat.parse().or_bad_request()?;
+            let conversation = app
+                .resolve_conversation(&user_id, &chat)
+                .await
+                .or_server_error()?
+                .or_bad_request()?;
+            Some(conversation)
+        }
+        None => None,
+    };
+    let limit = match params.limit {
+        Some(limit) => limit.parse().or_bad_request()?,
+        None => DEFAULT_SEARCH_PAGE_SIZE,
+    };
+    if limit == 0 || limit > MAX_SEARCH_PAGE_SIZE {
+        return Response::BadRequest;
+    }
+    let query = MessageSearchQuery {
+        text: params.q,
+        conversation,
+        sender: match params.from {
+            Some(from) => Some(from.parse().or_bad_request()?),
+            None => None,
+        },
+        since: match params.since {
+            Some(since) => Some(
+                DateTime::parse_from_rfc3339(&since)
+                    .or_bad_request()?
+                    .into(),
+            ),
+            None => None,
+        },
+        until: match params.until {
+            Some(until) => Some(
+                DateTime::parse_from_rfc3339(&until)
+                    .or_bad_request()?
+                    .into(),
+            ),
+            None => None,
+        },
+        cursor: match params.cursor {
+            Some(cursor) => Some(cursor.parse().or_bad_request()?),
+            None => None,
+        },
+        limit,
+    };
+
+    let page = app
+        .search_messages(&user_id, &query)
+        .await
+        .or_server_error()?;
+    let response = SearchResponse {
+        hits: page.hits.into_iter().map(SearchHitJson::from).collect(),
+        next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+    };
+
+    Response::Json {
+        content: serde_json::json!(response).to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+pub struct PinnedMessageJson {
+    pub message: MessageJson,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub pinned_by: UserId,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub pinned_at: DateTime<chrono::Utc>,
+}
+
+impl From<PinnedMessage> for PinnedMessageJson {
+    fn from(pin: PinnedMessage) -> Self {
+        Self {
+            message: MessageJson::from(pin.message),
+            pinned_by: pin.pinned_by,
+            pinned_at: pin.pinned_at,
+        }
+    }
+}
+
+/// Messages pinned to the conversation, most recently pinned first
+pub async fn pinned_messages_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+) -> Response {
+    #[derive(Serialize)]
+    struct PinnedMessagesResponse {
+        pins: Vec<PinnedMessageJson>,
+    }
+
+    let chat_id: Uuid = chat_id.parse().or_bad_request()?;
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let conversation = app
+        .resolve_conversation(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+    let pins = app
+        .fetch_pinned_messages(&user_id, &conversation)
+        .await
+        .or_server_error()?
+        .into_iter()
+        .map(PinnedMessageJson::from)
+        .collect();
+
+    Response::Json {
+        content: serde_json::json!(PinnedMessagesResponse { pins }).to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+pub struct StarredMessageJson {
+    pub message: MessageJson,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub starred_at: DateTime<chrono::Utc>,
+}
+
+impl From<StarredMessage> for StarredMessageJson {
+    fn from(star: StarredMessage) -> Self {
+        Self {
+            message: MessageJson::from(star.message),
+            starred_at: star.starred_at,
+        }
+    }
+}
+
+/// Messages starred by the user in all of their conversations, most recently starred first
+pub async fn starred_messages_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct StarredParams {
+        cursor: Option<String>,
+        limit: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct StarredResponse {
+        messages: Vec<StarredMessageJson>,
+        /// Passed as `cursor` to fetch the next page, None on the last one
+        next_cursor: Option<String>,
+    }
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let params: StarredParams = serde_form_data::from_str(params).or_bad_request()?;
+    let cursor: Option<StarCursor> = match params.cursor {
+        Some(cursor) => Some(cursor.parse().or_bad_request()?),
+        None => None,
+    };
+    let limit = match params.limit {
+        Some(limit) => limit.parse().or_bad_request()?,
+        None => DEFAULT_STARRED_PAGE_SIZE,
+    };
+    if limit == 0 || limit > MAX_STARRED_PAGE_SIZE {
+        return Response::BadRequest;
+    }
+
+    let page = app
+        .fetch_starred_messages(&user_id, cursor.as_ref(), limit)
+        .await
+        .or_server_error()?;
+    let response = StarredResponse {
+        messages: page
+            .messages
+            .into_iter()
+            .map(StarredMessageJson::from)
+            .collect(),
+        next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+    };
+
+    Response::Json {
+        content: serde_json::json!(response).to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+pub struct NotificationJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub id: NotificationId,
+    /// `mention`, `new_chat` or `reaction`
+    pub kind: &'static str,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub actor: UserId,
+    /// The conversation as seen by the user
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub chat_id: Uuid,
+    /// Set for mentions and reactions
+    pub message_id: Option<String>,
+    /// Set for reactions
+    pub emoji: Option<String>,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub created_at: DateTime<chrono::Utc>,
+    pub read: bool,
+}
+
+impl From<Notification> for NotificationJson {
+    fn from(notification: Notification) -> Self {
+        let (chat_id, message_id, emoji) = match notification.kind {
+            NotificationKind::Mention {
+                message_id,
+                conversation,
+            } => (*conversation.uuid(), Some(message_id), None),
+            NotificationKind::NewChat { chat_id } => (chat_id, None, None),
+            NotificationKind::Reaction {
+                message_id,
+                conversation,
+                ref emoji,
+            } => (*conversation.uuid(), Some(message_id), Some(emoji.clone())),
+        };
+        Self {
+            id: notification.id,
+            kind: notification.kind.as_str(),
+            actor: notification.actor,
+            chat_id,
+            message_id: message_id.map(|message_id| message_id.to_string()),
+            emoji,
+            created_at: notification.created_at,
+            read: notification.read_at.is_some(),
+        }
+    }
+}
+
+/// The user's notification feed, newest first
+pub async fn notifications_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct NotificationsParams {
+        cursor: Option<String>,
+        limit: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct NotificationsResponse {
+        notifications: Vec<NotificationJson>,
+        /// Passed as `cursor` to fetch the next page, None on the last one
+        next_cursor: Option<String>,
+        /// Unread notifications in the whole feed
+        unread_count: u32,
+    }
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let params: NotificationsParams = serde_form_data::from_str(params).or_bad_request()?;
+    let cursor: Option<NotificationCursor> = match params.cursor {
+        Some(cursor) => Some(cursor.parse().or_bad_request()?),
+        None => None,
+    };
+    let limit = match params.limit {
+        Some(limit) => limit.parse().or_bad_request()?,
+        None => DEFAULT_NOTIFICATIONS_PAGE_SIZE,
+    };
+    if limit == 0 || limit > MAX_NOTIFICATIONS_PAGE_SIZE {
+        return Response::BadRequest;
+    }
+
+    let page = app
+        .fetch_notifications(&user_id, cursor.as_ref(), limit)
+        .await
+        .or_server_error()?;
+    let response = NotificationsResponse {
+        notifications: page
+            .notifications
+            .into_iter()
+            .map(NotificationJson::from)
+            .collect(),
+        next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+        unread_count: page.unread_count,
+    };
+
+    Response::Json {
+        content: serde_json::json!(response).to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+pub struct PresenceJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub user_id: UserId,
+    pub online: bool,
+    pub last_seen: Option<String>,
+}
+
+impl PresenceJson {
+    pub fn new(user_id: UserId, presence: Presence) -> Self {
+        Self {
+            user_id,
+            online: presence.online,
+            last_seen: presence.last_seen.map(|last_seen| last_seen.to_rfc3339()),
+        }
+    }
+}
+
+pub async fn presence_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    user_id: &str,
+) -> Response {
+    let other_user: UserId = user_id.parse().or_bad_request()?;
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let presence = app
+        .fetch_presence(&user_id, &other_user)
+        .await
+        .or_server_error()?
+        .or_not_found()?;
+
+    Response::Json {
+        content: serde_json::json!(PresenceJson::new(other_user, presence)).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Timer of disappearing messages in the conversation, None if it's off
+pub async fn message_ttl_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+) -> Response {
+    #[derive(Serialize)]
+    struct MessageTtlResponse {
+        ttl_seconds: Option<i64>,
+    }
+
+    let chat_id: Uuid = chat_id.parse().or_bad_request()?;
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let conversation = app
+        .resolve_conversation(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+    let ttl = app
+        .fetch_message_ttl(&user_id, &conversation)
+        .await
+        .or_server_error()?;
+
+    Response::Json {
+        content: serde_json::json!(MessageTtlResponse {
+            ttl_seconds: ttl.map(|ttl| ttl.num_seconds()),
+        })
+        .to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+pub struct ScheduledMessageJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub id: ScheduledMessageId,
+    /// The receiving user or the chat
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub chat_id: Uuid,
+    pub message: String,
+    pub attachments: Vec<String>,
+    pub reply_to: Option<String>,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub send_at: DateTime<chrono::Utc>,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub created_at: DateTime<chrono::Utc>,
+}
+
+impl From<ScheduledMessage> for ScheduledMessageJson {
+    fn from(scheduled: ScheduledMessage) -> Self {
+        Self {
+            id: scheduled.id,
+            chat_id: *scheduled.to.uuid(),
+            message: scheduled.outgoing.text,
+            attachments: scheduled
+                .outgoing
+                .attachments
+                .iter()
+                .map(AttachmentId::to_string)
+                .collect(),
+            reply_to: scheduled
+                .outgoing
+                .reply_to
+                .map(|reply_to| reply_to.to_string()),
+            send_at: scheduled.send_at,
+            created_at: scheduled.created_at,
+        }
+    }
+}
+
+/// Messages the user has scheduled in all conversations, the earliest to be sent first
+pub async fn scheduled_messages_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    #[derive(Serialize)]
+    struct ScheduledMessagesResponse {
+        messages: Vec<ScheduledMessageJson>,
+    }
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let messages = app
+        .fetch_scheduled_messages(&user_id)
+        .await
+        .or_server_error()?
+        .into_iter()
+        .map(ScheduledMessageJson::from)
+        .collect();
+
+    Response::Json {
+        content: serde_json::json!(ScheduledMessagesResponse { messages }).to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+pub struct MessageRevisionJson {
+    pub message: String,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub created_at: DateTime<chrono::Utc>,
+}
+
+impl From<MessageRevision> for MessageRevisionJson {
+    fn from(revision: MessageRevision) -> Self {
+        Self {
+            message: revision.message,
+            created_at: revision.created_at,
+        }
+    }
+}
+
+/// Earlier texts of an edited message, oldest first
+pub async fn message_revisions_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    message_id: &str,
+) -> Response {
+    #[derive(Serialize)]
+    struct MessageRevisionsResponse {
+        revisions: Vec<MessageRevisionJson>,
+    }
+
+    let message_id: MessageId = message_id.parse().or_bad_request()?;
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let revisions = app
+        .fetch_message_revisions(&user_id, &message_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?
+        .into_iter()
+        .map(MessageRevisionJson::from)
+        .collect();
+
+    Response::Json {
+        content: serde_json::json!(MessageRevisionsResponse { revisions }).to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+pub struct ChatMemberJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub user_id: UserId,
+    pub username: String,
+    pub role: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct ChatJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub id: ChatId,
+    pub title: String,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub created_at: DateTime<chrono::Utc>,
+    pub members: Vec<ChatMemberJson>,
+}
+
+/// Group chat with its members, available to the members only
+pub async fn chat_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    chat_id: &str,
+) -> Response {
+    let chat_id: ChatId = chat_id.parse().or_bad_request()?;
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let chat: Chat = app
+        .fetch_chat(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+    let chat_members: Vec<ChatMember> = app
+        .fetch_chat_members(&user_id, &chat_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    let mut members = Vec::with_capacity(chat_members.len());
+    for member in chat_members {
+        let username = app
+            .fetch_user(&member.user_id)
+            .await
+            .or_server_error()?
+            .map(|user| user.username)
+            .unwrap_or_default();
+        members.push(ChatMemberJson {
+            user_id: member.user_id,
+            username,
+            role: member.role.as_str(),
+        });
+    }
+
+    let response = ChatJson {
+        id: chat.id,
+        title: chat.title,
+        created_at: chat.created_at,
+        members,
+    };
+    Response::Json {
+        content: serde_json::json!(response).to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub user_id: UserId,
+    pub username: String,
+}
+
+impl From<User> for UserJson {
+    fn from(user: User) -> Self {
+        UserJson {
+            user_id: user.id,
+            username: user.username,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProfileJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub user_id: UserId,
+    pub username: String,
+    pub display_name: Option<String>,
+    /// The display name or the username
+    pub name: String,
+    pub bio: String,
+    /// Changes with the avatar
+    pub avatar_url: Option<String>,
+}
+
+impl From<Profile> for ProfileJson {
+    fn from(profile: Profile) -> Self {
+        ProfileJson {
+            user_id: profile.user_id,
+            name: profile.name().to_owned(),
+            avatar_url: profile
+                .avatar
+                .map(|avatar| format!("/avatars/{}?v={avatar}", profile.user_id)),
+            username: profile.username,
+            display_name: profile.display_name,
+            bio: profile.bio,
+        }
+    }
+}
+
+pub async fn profile_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    user_id: &str,
+) -> Response {
+    let profile_user_id: UserId = user_id.parse().or_bad_request()?;
+
+    if get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+        .is_none()
+    {
+        return crate::routing::unauthorized_redirect();
+    }
+
+    let profile = app
+        .fetch_profile(&profile_user_id)
+        .await
+        .or_server_error()?
+        .or_not_found()?;
+
+    Response::Json {
+        content: serde_json::json!(ProfileJson::from(profile)).to_string(),
+        headers: vec![],
+    }
+}
+
+pub async fn blocked_users_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    #[derive(Serialize)]
+    struct BlockedUsersResponse {
+        users: Vec<UserJson>,
+    }
+
+    let user_id = match get_authorization(request.headers(), &app, Some(ApiScope::MessagesRead))
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let users = app
+        .fetch_blocked_users(&user_id)
+        .await
+        .or_server_error()?
+        .into_iter()
+        .map(UserJson::from)
+        .collect();
+
+    Response::Json {
+        content: serde_json::json!(BlockedUsersResponse { users }).to_string(),
+        headers: vec![],
+    }
+}
+
+pub async fn api_tokens_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    #[derive(Serialize)]
+    struct ApiTokensResponse {
+        tokens: Vec<ApiTokenJson>,
+    }
+
+    let user_id = match get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let tokens = app
+        .fetch_api_tokens(&user_id)
+        .await
+        .or_server_error()?
+        .into_iter()
+        .map(ApiTokenJson::from)
+        .collect();
+
+    Response::Json {
+        content: serde_json::json!(ApiTokensResponse { tokens }).to_string(),
+        headers: vec![],
+    }
+}
+
+#[derive(Serialize)]
+pub struct ConversationJson {
+    /// The other user of a direct conversation or the group chat
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_uuid")]
+    pub id: Uuid,
+    pub is_group: bool,
+    pub title: String,
+    pub muted: bool,
+}
+
+impl From<Conversation> for ConversationJson {
+    fn from(conversation: Conversation) -> Self {
+        Self {
+            id: *conversation.id.uuid(),
+            is_group: conversation.id.is_group(),
+            title: conversation.title,
+            muted: conversation.muted,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SessionJson {
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub created_at: DateTime<chrono::Utc>,
+}
+
+impl From<SessionInfo> for SessionJson {
+    fn from(session_info: SessionInfo) -> Self {
+        Self {
+            created_at: session_info.created_at,
+        }
+    }
+}
+
+/// `data.json` of the account export archive.
+/// Attachments are stored next to it as `attachments/<id>/<file name>`
+#[derive(Serialize)]
+pub struct UserDataExportJson {
+    pub profile: ProfileJson,
+    pub email: Option<String>,
+    pub conversations: Vec<ConversationJson>,
+    /// Oldest first
+    pub messages: Vec<MessageJson>,
+    pub blocked_users: Vec<String>,
+    pub api_tokens: Vec<ApiTokenJson>,
+    /// Currently active web sessions, oldest first
+    pub sessions: Vec<SessionJson>,
+    /// Newest first
+    pub audit_events: Vec<AuditEventJson>,
+    pub attachments: Vec<AttachmentJson>,
+}
+
+impl UserDataExportJson {
+    pub fn new(export: UserDataExport, sessions: Vec<SessionInfo>) -> Self {
+        Self {
+            profile: ProfileJson::from(export.profile),
+            email: export.email,
+            conversations: export
+                .conversations
+                .into_iter()
+                .map(ConversationJson::from)
+                .collect(),
+            messages: export.messages.into_iter().map(MessageJson::from).collect(),
+            blocked_users: export.blocked_users.iter().map(UserId::to_string).collect(),
+            api_tokens: export
+                .api_tokens
+                .into_iter()
+                .map(ApiTokenJson::from)
+                .collect(),
+            sessions: sessions.into_iter().map(SessionJson::from).collect(),
+            audit_events: export
+                .audit_events
+                .into_iter()
+                .map(AuditEventJson::from)
+                .collect(),
+            attachments: export
+                .attachments
+                .into_iter()
+                .map(AttachmentJson::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuditEventJson {
+    pub id: AuditEventId,
+    pub user_id: Option<String>,
+    pub kind: &'static str,
+    pub client_addr: Option<String>,
+    #[serde(serialize_with = "pheidippides_utils::serde::serialize_datetime")]
+    pub created_at: DateTime<chrono::Utc>,
+}
+
+impl From<AuditEvent> for AuditEventJson {
+    fn from(event: AuditEvent) -> Self {
+        Self {
+            id: event.id,
+            user_id: event.user_id.map(|user_id| user_id.to_string()),
+            kind: event.kind.as_str(),
+            client_addr: event.client_addr.map(|addr| addr.to_string()),
+            created_at: event.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditEventsResponse {
+    events: Vec<AuditEventJson>,
+}
+
+/// Events of the user, newest first. The next page is requested with `before` set to the id
+/// of the last returned event
+pub async fn audit_log_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct AuditLogParams {
+        before: Option<String>,
+        limit: Option<String>,
+    }
+
+    let user_id = match get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let audit_log_params: AuditLogParams = serde_form_data::from_str(params).or_bad_request()?;
+    let before = match audit_log_params.before {
+        Some(before) => Some(before.parse().or_bad_request()?),
+        None => None,
+    };
+    let limit = parse_audit_log_limit(audit_log_params.limit)?;
+
+    let events = app
+        .fetch_user_audit_events(&user_id, before, limit)
+        .await
+        .or_server_error()?
+        .into_iter()
+        .map(AuditEventJson::from)
+        .collect();
+
+    Response::Json {
+        content: serde_json::json!(AuditEventsResponse { events }).to_string(),
+        headers: vec![],
+    }
+}
+
+/// Events of all users, available to admins only.
+/// `since` and `until` are RFC 3339 timestamps, pagination is the same as for [audit_log_json]
+pub async fn admin_audit_log_json<M, B, T: AsyncRead + Unpin>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct AdminAuditLogParams {
+        user_id: Option<String>,
+        kind: Option<String>,
+        client_addr: Option<String>,
+        since: Option<String>,
+        until: Option<String>,
+        before: Option<String>,
+        limit: Option<String>,
+    }
+
+    let user_id = match get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return crate::routing::unauthorized_redirect(),
+    };
+
+    let params: AdminAuditLogParams = serde_form_data::from_str(params).or_bad_request()?;
+    let query = AuditEventQuery {
+        user_id: match params.user_id {
+            Some(user_id) => Some(user_id.parse().or_bad_request()?),
+            None => None,
+        },
+        kind: match params.kind {
+            Some(kind) => Some(kind.parse().or_bad_request()?),
+            None => None,
+        },
+        client_addr: match params.client_addr {
+            Some(client_addr) => Some(client_addr.parse().or_bad_request()?),
+            None => None,
+        },
+        since: match params.since {
+            Some(since) => Some(
+                DateTime::parse_from_rfc3339(&since)
+                    .or_bad_request()?
+                    .into(),
+            ),
+            None => None,
+        },
+        until: match params.until {
+            Some(until) => Some(
+                DateTime::parse_from_rfc3339(&until)
+                    .or_bad_request()?
+                    .into(),
+            ),
+            None => None,
+        },
+        before: match params.before {
+            Some(before) => Some(before.parse().or_bad_request()?),
+            None => None,
+        },
+        limit: parse_audit_log_limit(params.limit)?,
+    };
+
+    let events = app
+        .query_audit_events(&user_id, &query)
+        .await
+        .or_server_error()?
+        .or_bad_request()?
+        .into_iter()
+        .map(AuditEventJson::from)
+        .collect();
+
+    Response::Json {
+        content: serde_json::json!(AuditEventsResponse { events }).to_string(),
+        headers: vec![],
+    }
+}
+
+fn parse_audit_log_limit(limit: Option<String>) -> HttpResponseFlowController<u32> {
+    let limit = match limit {
+        Some(limit) => limit.parse().or_bad_request()?,
+        None => DEFAULT_AUDIT_LOG_PAGE_SIZE,
+    };
+    if limit > MAX_AUDIT_LOG_PAGE_SIZE {
+        return HttpResponseFlowController::HttpResponse(Response::BadRequest);
+    }
+    HttpResponseFlowController::Value(limit)
+}
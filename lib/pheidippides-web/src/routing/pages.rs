@@ -3,10 +3,16 @@ use crate::routing;
 use crate::routing::html;
 use http_server::request::Request;
 use http_server::response::Response;
+use pheidippides_messenger::audit_log::AuditEventId;
+use pheidippides_messenger::authorization::AuthService;
 use pheidippides_messenger::data_access::DataAccess;
 use pheidippides_messenger::messenger::Messenger;
+use pheidippides_utils::serde::form_data as serde_form_data;
+use serde::Deserialize;
 use tokio::io::AsyncRead;
 
+const AUDIT_LOG_PAGE_SIZE: u32 = 50;
+
 pub fn main() -> Response {
     Response::Redirect {
         location: "/chat".into(),
@@ -14,14 +20,17 @@ pub fn main() -> Response {
     }
 }
 
-pub async fn chat<D: DataAccess, A, T: AsyncRead + Unpin>(
+pub async fn chat<D: DataAccess, A: AuthService, M, B, T: AsyncRead + Unpin>(
     request: &Request<T>,
-    app: Messenger<D, A>,
+    app: Messenger<D, A, M, B>,
     _chat_id: Option<&str>,
 ) -> Response {
     let headers = request.headers();
 
-    let user_id = match routing::get_authorization(headers).or_server_error()? {
+    let user_id = match routing::get_authorization(headers, &app, None)
+        .await
+        .or_server_error()?
+    {
         Some(user_id) => user_id,
         None => return routing::unauthorized_redirect(),
     };
@@ -37,8 +46,11 @@ pub async fn chat<D: DataAccess, A, T: AsyncRead + Unpin>(
     }
 }
 
-pub async fn authorization() -> Response {
-    let content = html::login_page().or_server_error()?;
+pub async fn authorization<M, B>(
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    let content = html::login_page(app.password_login_enabled(), app.external_login_enabled())
+        .or_server_error()?;
 
     Response::Html {
         content,
@@ -46,9 +58,141 @@ pub async fn authorization() -> Response {
     }
 }
 
-pub async fn signup() -> Response {
+pub async fn signup<M, B>(app: Messenger<impl DataAccess, impl AuthService, M, B>) -> Response {
+    if !app.password_login_enabled() {
+        return Response::Redirect {
+            location: "/login".into(),
+            headers: Vec::new(),
+        };
+    }
+
     let content = html::signup_page().or_server_error()?;
     let headers = vec![];
 
     Response::Html { content, headers }
 }
+
+pub async fn reset_password() -> Response {
+    let content = html::reset_password_page().or_server_error()?;
+
+    Response::Html {
+        content,
+        headers: Vec::new(),
+    }
+}
+
+pub async fn reset_password_confirm(params: &str) -> Response {
+    #[derive(Deserialize)]
+    struct ResetPasswordConfirmParams {
+        token: Option<String>,
+    }
+
+    let confirm_params: ResetPasswordConfirmParams =
+        serde_form_data::from_str(params).or_bad_request()?;
+    let token = confirm_params.token.unwrap_or_default();
+
+    let content = html::reset_password_confirm_page(&token, false).or_server_error()?;
+
+    Response::Html {
+        content,
+        headers: Vec::new(),
+    }
+}
+
+pub async fn account_totp<T: AsyncRead + Unpin, M, B>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    // The second factor is up to the identity provider then
+    if !app.password_login_enabled() {
+        return main();
+    }
+
+    let enabled = app
+        .second_factor_enabled(&user_id)
+        .await
+        .or_server_error()?;
+    let content = html::account_totp_page(enabled, false).or_server_error()?;
+
+    Response::Html {
+        content,
+        headers: Vec::new(),
+    }
+}
+
+pub async fn account_profile<T: AsyncRead + Unpin, M, B>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+) -> Response {
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let profile = app
+        .fetch_profile(&user_id)
+        .await
+        .or_server_error()?
+        .or_bad_request()?;
+
+    let content = html::profile_page(profile).or_server_error()?;
+    Response::Html {
+        content,
+        headers: Vec::new(),
+    }
+}
+
+pub async fn account_audit_log<T: AsyncRead + Unpin, M, B>(
+    request: &Request<T>,
+    app: Messenger<impl DataAccess, impl AuthService, M, B>,
+    params: &str,
+) -> Response {
+    #[derive(Deserialize)]
+    struct AuditLogParams {
+        before: Option<String>,
+    }
+
+    let user_id = match routing::get_authorization(request.headers(), &app, None)
+        .await
+        .or_server_error()?
+    {
+        Some(user_id) => user_id,
+        None => return routing::unauthorized_redirect(),
+    };
+
+    let audit_log_params: AuditLogParams = serde_form_data::from_str(params).or_bad_request()?;
+    let before: Option<AuditEventId> = match audit_log_params.before {
+        Some(before) => Some(before.parse().or_bad_request()?),
+        None => None,
+    };
+
+    // One extra event tells whether there is a next page
+    let mut events = app
+        .fetch_user_audit_events(&user_id, before, AUDIT_LOG_PAGE_SIZE + 1)
+        .await
+        .or_server_error()?;
+    let next_page = match events.len() > AUDIT_LOG_PAGE_SIZE as usize {
+        true => {
+            events.truncate(AUDIT_LOG_PAGE_SIZE as usize);
+            events.last().map(|event| event.id)
+        }
+        false => None,
+    };
+
+    let content = html::audit_log_page(events, next_page).or_server_error()?;
+    Response::Html {
+        content,
+        headers: Vec::new(),
+    }
+}
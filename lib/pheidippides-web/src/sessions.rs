@@ -1,20 +1,59 @@
 use std::collections::HashMap;
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
 
+use pheidippides_messenger::authorization::PendingExternalLogin;
 use pheidippides_messenger::UserId;
 
 pub type SessionId = String;
+pub type PendingLoginId = String;
 
 pub const SESSION_ID_COOKIE: &str = "_pheidippides_sid";
 pub static SESSION_INFO: Lazy<RwLock<HashMap<SessionId, SessionInfo>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
 
+/// Logins that passed the password check and wait for the second factor
+static PENDING_LOGINS: Lazy<RwLock<HashMap<PendingLoginId, PendingLogin>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+const PENDING_LOGIN_LIFETIME: Duration = Duration::from_secs(300);
+const PENDING_LOGIN_MAX_ATTEMPTS: u32 = 5;
+
+pub const EXTERNAL_LOGIN_STATE_COOKIE: &str = "_pheidippides_oidc_state";
+/// Logins redirected to the identity provider, keyed by the state parameter
+static PENDING_EXTERNAL_LOGINS: Lazy<RwLock<HashMap<String, PendingExternalLoginInfo>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+const PENDING_EXTERNAL_LOGIN_LIFETIME: Duration = Duration::from_secs(600);
+
 #[derive(Clone)]
 pub struct SessionInfo {
     pub user_id: UserId,
+    pub created_at: DateTime<Utc>,
+}
+
+impl SessionInfo {
+    pub fn new(user_id: UserId) -> Self {
+        SessionInfo {
+            user_id,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+struct PendingLogin {
+    user_id: UserId,
+    created_at: Instant,
+    attempts: u32,
+}
+
+struct PendingExternalLoginInfo {
+    pending: PendingExternalLogin,
+    /// The user that started the login to link the identity to their account
+    linking_user: Option<UserId>,
+    created_at: Instant,
 }
 
 pub fn generate_session_id() -> SessionId {
@@ -52,3 +91,125 @@ pub fn remove_session_info(session_id: &SessionId) -> Result<()> {
     }
     Ok(())
 }
+
+pub fn remove_user_sessions(user_id: &UserId) -> Result<()> {
+    match SESSION_INFO.write() {
+        Ok(mut session_info_write_lock) => {
+            session_info_write_lock.retain(|_, session_info| session_info.user_id != *user_id);
+        }
+        Err(e) => bail!("Could not lock SESSION_INFO global for write: {}", e),
+    }
+    Ok(())
+}
+
+/// Oldest first
+pub fn fetch_user_sessions(user_id: &UserId) -> Result<Vec<SessionInfo>> {
+    let mut res: Vec<SessionInfo> = match SESSION_INFO.read() {
+        Ok(session_info_read_lock) => session_info_read_lock
+            .values()
+            .filter(|session_info| session_info.user_id == *user_id)
+            .cloned()
+            .collect(),
+        Err(e) => bail!("Could not lock SESSION_INFO global for read: {}", e),
+    };
+    res.sort_by_key(|session_info| session_info.created_at);
+    Ok(res)
+}
+
+pub fn create_pending_login(user_id: UserId) -> Result<PendingLoginId> {
+    let pending_login_id: PendingLoginId = uuid::Uuid::new_v4().into();
+    let pending_login = PendingLogin {
+        user_id,
+        created_at: Instant::now(),
+        attempts: 0,
+    };
+    match PENDING_LOGINS.write() {
+        Ok(mut pending_logins_write_lock) => {
+            pending_logins_write_lock.retain(|_, pending_login| {
+                pending_login.created_at.elapsed() < PENDING_LOGIN_LIFETIME
+            });
+            pending_logins_write_lock.insert(pending_login_id.clone(), pending_login);
+        }
+        Err(e) => bail!("Could not lock PENDING_LOGINS global for write: {}", e),
+    }
+    Ok(pending_login_id)
+}
+
+/// Counts an attempt to pass the second factor check.
+/// Returns None if the pending login doesn't exist, expired or ran out of attempts
+pub fn register_pending_login_attempt(pending_login_id: &PendingLoginId) -> Result<Option<UserId>> {
+    let mut pending_logins_write_lock = match PENDING_LOGINS.write() {
+        Ok(write_lock) => write_lock,
+        Err(e) => bail!("Could not lock PENDING_LOGINS global for write: {}", e),
+    };
+
+    let pending_login = match pending_logins_write_lock.get_mut(pending_login_id) {
+        Some(pending_login) => pending_login,
+        None => return Ok(None),
+    };
+
+    pending_login.attempts += 1;
+    if pending_login.attempts > PENDING_LOGIN_MAX_ATTEMPTS
+        || pending_login.created_at.elapsed() >= PENDING_LOGIN_LIFETIME
+    {
+        pending_logins_write_lock.remove(pending_login_id);
+        return Ok(None);
+    }
+
+    Ok(Some(pending_login.user_id))
+}
+
+pub fn remove_pending_login(pending_login_id: &PendingLoginId) -> Result<()> {
+    match PENDING_LOGINS.write() {
+        Ok(mut pending_logins_write_lock) => {
+            pending_logins_write_lock.remove(pending_login_id);
+        }
+        Err(e) => bail!("Could not lock PENDING_LOGINS global for write: {}", e),
+    }
+    Ok(())
+}
+
+pub fn create_pending_external_login(
+    pending: PendingExternalLogin,
+    linking_user: Option<UserId>,
+) -> Result<()> {
+    let pending_login = PendingExternalLoginInfo {
+        pending,
+        linking_user,
+        created_at: Instant::now(),
+    };
+    match PENDING_EXTERNAL_LOGINS.write() {
+        Ok(mut pending_logins_write_lock) => {
+            pending_logins_write_lock.retain(|_, pending_login| {
+                pending_login.created_at.elapsed() < PENDING_EXTERNAL_LOGIN_LIFETIME
+            });
+            pending_logins_write_lock.insert(pending_login.pending.state.clone(), pending_login);
+        }
+        Err(e) => bail!(
+            "Could not lock PENDING_EXTERNAL_LOGINS global for write: {}",
+            e
+        ),
+    }
+    Ok(())
+}
+
+/// Removes the pending login, so that every state is used only once.
+/// Returns None if the pending login doesn't exist or expired
+pub fn take_pending_external_login(
+    state: &str,
+) -> Result<Option<(PendingExternalLogin, Option<UserId>)>> {
+    let pending_login = match PENDING_EXTERNAL_LOGINS.write() {
+        Ok(mut pending_logins_write_lock) => pending_logins_write_lock.remove(state),
+        Err(e) => bail!(
+            "Could not lock PENDING_EXTERNAL_LOGINS global for write: {}",
+            e
+        ),
+    };
+
+    let res = pending_login
+        .filter(|pending_login| {
+            pending_login.created_at.elapsed() < PENDING_EXTERNAL_LOGIN_LIFETIME
+        })
+        .map(|pending_login| (pending_login.pending, pending_login.linking_user));
+    Ok(res)
+}
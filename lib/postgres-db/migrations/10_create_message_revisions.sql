@@ -0,0 +1,19 @@
+ALTER TABLE public.messages
+    ADD COLUMN edited_at timestamp with time zone;
+
+CREATE TABLE public.message_revisions
+(
+    revision_id bigint NOT NULL GENERATED ALWAYS AS IDENTITY,
+    message_id uuid NOT NULL,
+    message text COLLATE pg_catalog."default" NOT NULL,
+    created_at timestamp with time zone NOT NULL,
+    CONSTRAINT message_revisions_pkey PRIMARY KEY (revision_id),
+    CONSTRAINT message_revisions_message_id_fkey FOREIGN KEY (message_id)
+        REFERENCES public.messages (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE INDEX message_revisions_message_id_idx
+    ON public.message_revisions USING btree
+    (message_id ASC NULLS LAST, revision_id ASC);
@@ -0,0 +1,18 @@
+-- Messages deleted for everyone are kept as tombstones so that pagination stays stable
+ALTER TABLE public.messages
+    ADD COLUMN deleted_at timestamp with time zone;
+
+CREATE TABLE public.hidden_messages
+(
+    user_id uuid NOT NULL,
+    message_id uuid NOT NULL,
+    CONSTRAINT hidden_messages_pkey PRIMARY KEY (user_id, message_id),
+    CONSTRAINT hidden_messages_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION,
+    CONSTRAINT hidden_messages_message_id_fkey FOREIGN KEY (message_id)
+        REFERENCES public.messages (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
@@ -0,0 +1,17 @@
+-- The last message a user has read in each of their conversations.
+-- conversation_id is the other user of a direct conversation or a group chat
+CREATE TABLE public.read_markers
+(
+    user_id uuid NOT NULL,
+    conversation_id uuid NOT NULL,
+    message_id uuid NOT NULL,
+    CONSTRAINT read_markers_pkey PRIMARY KEY (user_id, conversation_id),
+    CONSTRAINT read_markers_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION,
+    CONSTRAINT read_markers_message_id_fkey FOREIGN KEY (message_id)
+        REFERENCES public.messages (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
@@ -0,0 +1,25 @@
+-- Attachment contents live in blob storage, message_id stays null until the attachment is sent
+CREATE TABLE public.attachments
+(
+    id uuid NOT NULL,
+    uploader uuid NOT NULL,
+    message_id uuid,
+    file_name text COLLATE pg_catalog."default" NOT NULL,
+    content_type text COLLATE pg_catalog."default" NOT NULL,
+    size bigint NOT NULL,
+    has_thumbnail boolean NOT NULL,
+    created_at timestamp with time zone NOT NULL,
+    CONSTRAINT attachments_pkey PRIMARY KEY (id),
+    CONSTRAINT attachments_uploader_fkey FOREIGN KEY (uploader)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION,
+    CONSTRAINT attachments_message_id_fkey FOREIGN KEY (message_id)
+        REFERENCES public.messages (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE INDEX attachments_message_id_idx
+    ON public.attachments USING btree
+    (message_id ASC NULLS LAST);
@@ -0,0 +1,8 @@
+-- The 'simple' configuration only lowercases words, so search works the same for any language
+ALTER TABLE public.messages
+    ADD COLUMN search_vector tsvector
+    GENERATED ALWAYS AS (to_tsvector('simple', message)) STORED;
+
+CREATE INDEX messages_search_vector_idx
+    ON public.messages USING gin
+    (search_vector);
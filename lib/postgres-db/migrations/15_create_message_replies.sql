@@ -0,0 +1,7 @@
+-- Replies outlive purged originals, losing only the reference
+ALTER TABLE public.messages
+    ADD COLUMN reply_to uuid,
+    ADD CONSTRAINT messages_reply_to_fkey FOREIGN KEY (reply_to)
+        REFERENCES public.messages (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL;
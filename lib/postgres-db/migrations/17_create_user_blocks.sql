@@ -0,0 +1,32 @@
+CREATE TABLE public.user_blocks
+(
+    user_id uuid NOT NULL,
+    blocked_user_id uuid NOT NULL,
+    created_at timestamp with time zone NOT NULL,
+    CONSTRAINT user_blocks_pkey PRIMARY KEY (user_id, blocked_user_id),
+    CONSTRAINT user_blocks_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION,
+    CONSTRAINT user_blocks_blocked_user_id_fkey FOREIGN KEY (blocked_user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
+
+CREATE INDEX user_blocks_blocked_user_id_index
+    ON public.user_blocks USING btree
+    (blocked_user_id ASC NULLS LAST);
+
+-- Conversations a user doesn't want to be notified of.
+-- conversation_id is the other user of a direct conversation or a group chat
+CREATE TABLE public.muted_conversations
+(
+    user_id uuid NOT NULL,
+    conversation_id uuid NOT NULL,
+    CONSTRAINT muted_conversations_pkey PRIMARY KEY (user_id, conversation_id),
+    CONSTRAINT muted_conversations_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
@@ -0,0 +1,9 @@
+-- Profiles lose their avatar when its attachment is purged
+ALTER TABLE public.users
+    ADD COLUMN display_name character varying(100) COLLATE pg_catalog."default",
+    ADD COLUMN bio text COLLATE pg_catalog."default" NOT NULL DEFAULT '',
+    ADD COLUMN avatar uuid,
+    ADD CONSTRAINT users_avatar_fkey FOREIGN KEY (avatar)
+        REFERENCES public.attachments (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL;
@@ -0,0 +1,105 @@
+-- Deleted accounts are replaced by placeholder users that keep their messages for counterparties,
+-- so messages and sent attachments are handed over before the user row is deleted
+ALTER TABLE public.users
+    ADD COLUMN deleted boolean NOT NULL DEFAULT false;
+
+-- Everything else belonging to a user goes away with them
+ALTER TABLE public.auth
+    DROP CONSTRAINT auth_user_id_fkey,
+    ADD CONSTRAINT auth_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.password_reset_tokens
+    DROP CONSTRAINT password_reset_tokens_user_id_fkey,
+    ADD CONSTRAINT password_reset_tokens_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.totp
+    DROP CONSTRAINT totp_user_id_fkey,
+    ADD CONSTRAINT totp_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.recovery_codes
+    DROP CONSTRAINT recovery_codes_user_id_fkey,
+    ADD CONSTRAINT recovery_codes_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.login_events
+    DROP CONSTRAINT login_events_user_id_fkey,
+    ADD CONSTRAINT login_events_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.api_tokens
+    DROP CONSTRAINT api_tokens_user_id_fkey,
+    ADD CONSTRAINT api_tokens_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.external_identities
+    DROP CONSTRAINT external_identities_user_id_fkey,
+    ADD CONSTRAINT external_identities_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.chat_members
+    DROP CONSTRAINT chat_members_user_id_fkey,
+    ADD CONSTRAINT chat_members_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.hidden_messages
+    DROP CONSTRAINT hidden_messages_user_id_fkey,
+    ADD CONSTRAINT hidden_messages_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.read_markers
+    DROP CONSTRAINT read_markers_user_id_fkey,
+    ADD CONSTRAINT read_markers_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.message_reactions
+    DROP CONSTRAINT message_reactions_user_id_fkey,
+    ADD CONSTRAINT message_reactions_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.user_blocks
+    DROP CONSTRAINT user_blocks_user_id_fkey,
+    ADD CONSTRAINT user_blocks_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    DROP CONSTRAINT user_blocks_blocked_user_id_fkey,
+    ADD CONSTRAINT user_blocks_blocked_user_id_fkey FOREIGN KEY (blocked_user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+ALTER TABLE public.muted_conversations
+    DROP CONSTRAINT muted_conversations_user_id_fkey,
+    ADD CONSTRAINT muted_conversations_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE;
+
+CREATE INDEX attachments_uploader_idx
+    ON public.attachments USING btree
+    (uploader ASC NULLS LAST);
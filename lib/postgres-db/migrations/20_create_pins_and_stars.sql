@@ -0,0 +1,37 @@
+-- A message is pinned to its conversation once, for all of the participants
+CREATE TABLE public.pinned_messages
+(
+    message_id uuid NOT NULL,
+    pinned_by uuid NOT NULL,
+    pinned_at timestamp with time zone NOT NULL,
+    CONSTRAINT pinned_messages_pkey PRIMARY KEY (message_id),
+    CONSTRAINT pinned_messages_message_id_fkey FOREIGN KEY (message_id)
+        REFERENCES public.messages (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT pinned_messages_pinned_by_fkey FOREIGN KEY (pinned_by)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
+
+-- Stars are private to the user who starred the message
+CREATE TABLE public.starred_messages
+(
+    user_id uuid NOT NULL,
+    message_id uuid NOT NULL,
+    starred_at timestamp with time zone NOT NULL,
+    CONSTRAINT starred_messages_pkey PRIMARY KEY (user_id, message_id),
+    CONSTRAINT starred_messages_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT starred_messages_message_id_fkey FOREIGN KEY (message_id)
+        REFERENCES public.messages (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE INDEX starred_messages_user_id_starred_at_idx
+    ON public.starred_messages USING btree
+    (user_id ASC NULLS LAST, starred_at DESC, message_id DESC);
@@ -0,0 +1,30 @@
+-- Disappearing messages are purged once they expire
+ALTER TABLE public.messages
+    ADD COLUMN expires_at timestamp with time zone;
+
+CREATE INDEX messages_expires_at_idx
+    ON public.messages USING btree
+    (expires_at ASC NULLS LAST)
+    WHERE expires_at IS NOT NULL;
+
+-- Timer of disappearing messages in a group chat
+ALTER TABLE public.chats
+    ADD COLUMN message_ttl_seconds bigint;
+
+-- Timer of disappearing messages in a direct conversation, shared by both participants
+CREATE TABLE public.direct_message_timers
+(
+    user_id_1 uuid NOT NULL,
+    user_id_2 uuid NOT NULL,
+    message_ttl_seconds bigint NOT NULL,
+    CONSTRAINT direct_message_timers_pkey PRIMARY KEY (user_id_1, user_id_2),
+    CONSTRAINT direct_message_timers_user_order_check CHECK (user_id_1 < user_id_2),
+    CONSTRAINT direct_message_timers_user_id_1_fkey FOREIGN KEY (user_id_1)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT direct_message_timers_user_id_2_fkey FOREIGN KEY (user_id_2)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
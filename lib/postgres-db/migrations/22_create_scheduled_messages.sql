@@ -0,0 +1,36 @@
+-- Messages waiting to be sent on behalf of their authors.
+-- The id becomes the id of the sent message
+CREATE TABLE public.scheduled_messages
+(
+    id uuid NOT NULL,
+    sender uuid NOT NULL,
+    receiver uuid,
+    chat_id uuid,
+    message text NOT NULL,
+    attachments uuid[] NOT NULL,
+    reply_to uuid,
+    send_at timestamp with time zone NOT NULL,
+    created_at timestamp with time zone NOT NULL,
+    CONSTRAINT scheduled_messages_pkey PRIMARY KEY (id),
+    CONSTRAINT scheduled_messages_sender_fkey FOREIGN KEY (sender)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT scheduled_messages_receiver_fkey FOREIGN KEY (receiver)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT scheduled_messages_chat_id_fkey FOREIGN KEY (chat_id)
+        REFERENCES public.chats (chat_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT scheduled_messages_single_recipient_check CHECK ((receiver IS NULL) <> (chat_id IS NULL))
+);
+
+CREATE INDEX scheduled_messages_send_at_idx
+    ON public.scheduled_messages USING btree
+    (send_at ASC NULLS LAST);
+
+CREATE INDEX scheduled_messages_sender_send_at_idx
+    ON public.scheduled_messages USING btree
+    (sender ASC NULLS LAST, send_at ASC NULLS LAST);
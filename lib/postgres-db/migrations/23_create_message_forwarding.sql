@@ -0,0 +1,9 @@
+-- Author and send time of the original of a forwarded message
+ALTER TABLE public.messages
+    ADD COLUMN forwarded_from uuid,
+    ADD COLUMN forwarded_at timestamp with time zone,
+    ADD CONSTRAINT messages_forwarded_from_fkey FOREIGN KEY (forwarded_from)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION,
+    ADD CONSTRAINT messages_forwarded_check CHECK ((forwarded_from IS NULL) = (forwarded_at IS NULL));
@@ -0,0 +1,57 @@
+-- Users mentioned in a message
+CREATE TABLE public.mentions
+(
+    message_id uuid NOT NULL,
+    user_id uuid NOT NULL,
+    CONSTRAINT mentions_pkey PRIMARY KEY (message_id, user_id),
+    CONSTRAINT mentions_message_id_fkey FOREIGN KEY (message_id)
+        REFERENCES public.messages (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT mentions_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+-- Notification feed of a user. Notifications about direct conversations have no chat_id,
+-- the conversation is the one with the actor
+CREATE TABLE public.notifications
+(
+    id uuid NOT NULL,
+    user_id uuid NOT NULL,
+    actor uuid NOT NULL,
+    kind text NOT NULL,
+    message_id uuid,
+    chat_id uuid,
+    emoji text,
+    created_at timestamp with time zone NOT NULL,
+    read_at timestamp with time zone,
+    CONSTRAINT notifications_pkey PRIMARY KEY (id),
+    CONSTRAINT notifications_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT notifications_actor_fkey FOREIGN KEY (actor)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION,
+    CONSTRAINT notifications_message_id_fkey FOREIGN KEY (message_id)
+        REFERENCES public.messages (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT notifications_chat_id_fkey FOREIGN KEY (chat_id)
+        REFERENCES public.chats (chat_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT notifications_kind_check CHECK (kind IN ('mention', 'new_chat', 'reaction'))
+);
+
+CREATE INDEX notifications_user_id_created_at_idx
+    ON public.notifications USING btree
+    (user_id ASC NULLS LAST, created_at DESC, id DESC);
+
+CREATE INDEX notifications_unread_idx
+    ON public.notifications USING btree
+    (user_id ASC NULLS LAST)
+    WHERE read_at IS NULL;
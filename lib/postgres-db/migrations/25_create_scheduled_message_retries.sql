@@ -0,0 +1,10 @@
+-- Deliveries failing for other reasons than the message being rejected are retried later
+ALTER TABLE public.scheduled_messages
+    ADD COLUMN failed_attempts integer NOT NULL DEFAULT 0,
+    ADD COLUMN retry_at timestamp with time zone;
+
+DROP INDEX public.scheduled_messages_send_at_idx;
+
+CREATE INDEX scheduled_messages_due_at_idx
+    ON public.scheduled_messages USING btree
+    (COALESCE(retry_at, send_at) ASC NULLS LAST);
@@ -0,0 +1,16 @@
+ALTER TABLE public.users ADD COLUMN email character varying(250) COLLATE pg_catalog."default";
+
+CREATE TABLE public.password_reset_tokens
+(
+    token_hash character(64) NOT NULL,
+    user_id uuid NOT NULL,
+    expires_at timestamp with time zone NOT NULL,
+    used boolean NOT NULL DEFAULT false,
+    CONSTRAINT password_reset_tokens_pkey PRIMARY KEY (token_hash),
+    CONSTRAINT password_reset_tokens_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
+
+CREATE INDEX password_reset_tokens_user_id ON password_reset_tokens(user_id);
@@ -0,0 +1,24 @@
+CREATE TABLE public.totp
+(
+    user_id uuid NOT NULL,
+    encrypted_secret bytea NOT NULL,
+    confirmed boolean NOT NULL,
+    last_used_step bigint,
+    CONSTRAINT totp_pkey PRIMARY KEY (user_id),
+    CONSTRAINT totp_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
+
+CREATE TABLE public.recovery_codes
+(
+    user_id uuid NOT NULL,
+    code_hash character(64) NOT NULL,
+    used boolean NOT NULL DEFAULT false,
+    CONSTRAINT recovery_codes_pkey PRIMARY KEY (user_id, code_hash),
+    CONSTRAINT recovery_codes_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
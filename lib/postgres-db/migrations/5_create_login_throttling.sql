@@ -0,0 +1,26 @@
+CREATE TABLE public.login_failures
+(
+    throttle_key character varying(100) NOT NULL,
+    failed_attempts integer NOT NULL,
+    last_failure_at timestamp with time zone NOT NULL,
+    locked_until timestamp with time zone,
+    CONSTRAINT login_failures_pkey PRIMARY KEY (throttle_key)
+);
+
+CREATE TABLE public.login_events
+(
+    event_id bigint NOT NULL GENERATED ALWAYS AS IDENTITY,
+    user_id uuid,
+    client_addr character varying(45),
+    kind character varying(20) NOT NULL,
+    created_at timestamp with time zone NOT NULL,
+    CONSTRAINT login_events_pkey PRIMARY KEY (event_id),
+    CONSTRAINT login_events_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
+
+CREATE INDEX login_events_user_id_created_at_idx
+    ON public.login_events USING btree
+    (user_id ASC NULLS LAST, created_at DESC NULLS LAST);
@@ -0,0 +1,20 @@
+CREATE TABLE public.api_tokens
+(
+    token_id uuid NOT NULL,
+    user_id uuid NOT NULL,
+    token_hash character(64) NOT NULL,
+    name character varying(100) NOT NULL,
+    scopes character varying(50)[] NOT NULL,
+    created_at timestamp with time zone NOT NULL,
+    expires_at timestamp with time zone,
+    CONSTRAINT api_tokens_pkey PRIMARY KEY (token_id),
+    CONSTRAINT api_tokens_token_hash_key UNIQUE (token_hash),
+    CONSTRAINT api_tokens_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
+
+CREATE INDEX api_tokens_user_id_idx
+    ON public.api_tokens USING btree
+    (user_id ASC NULLS LAST);
@@ -0,0 +1,15 @@
+CREATE TABLE public.external_identities
+(
+    issuer character varying(250) NOT NULL,
+    subject character varying(250) NOT NULL,
+    user_id uuid NOT NULL,
+    CONSTRAINT external_identities_pkey PRIMARY KEY (issuer, subject),
+    CONSTRAINT external_identities_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
+
+CREATE INDEX external_identities_user_id_idx
+    ON public.external_identities USING btree
+    (user_id ASC NULLS LAST);
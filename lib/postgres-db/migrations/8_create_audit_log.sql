@@ -0,0 +1,23 @@
+CREATE TABLE public.audit_events
+(
+    event_id bigint NOT NULL GENERATED ALWAYS AS IDENTITY,
+    user_id uuid,
+    kind character varying(30) NOT NULL,
+    client_addr character varying(45),
+    created_at timestamp with time zone NOT NULL,
+    CONSTRAINT audit_events_pkey PRIMARY KEY (event_id)
+);
+
+CREATE INDEX audit_events_user_id_event_id_idx
+    ON public.audit_events USING btree
+    (user_id ASC NULLS LAST, event_id DESC);
+
+CREATE FUNCTION public.forbid_audit_event_changes() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only
+    BEFORE UPDATE OR DELETE ON public.audit_events
+    FOR EACH ROW EXECUTE FUNCTION public.forbid_audit_event_changes();
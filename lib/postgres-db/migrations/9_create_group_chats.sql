@@ -0,0 +1,42 @@
+CREATE TABLE public.chats
+(
+    chat_id uuid NOT NULL,
+    title character varying(150) COLLATE pg_catalog."default" NOT NULL,
+    created_at timestamp with time zone NOT NULL,
+    CONSTRAINT chats_pkey PRIMARY KEY (chat_id)
+);
+
+CREATE TABLE public.chat_members
+(
+    chat_id uuid NOT NULL,
+    user_id uuid NOT NULL,
+    role character varying(10) NOT NULL,
+    joined_at timestamp with time zone NOT NULL,
+    CONSTRAINT chat_members_pkey PRIMARY KEY (chat_id, user_id),
+    CONSTRAINT chat_members_chat_id_fkey FOREIGN KEY (chat_id)
+        REFERENCES public.chats (chat_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE,
+    CONSTRAINT chat_members_user_id_fkey FOREIGN KEY (user_id)
+        REFERENCES public.users (user_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION
+);
+
+CREATE INDEX chat_members_user_id_idx
+    ON public.chat_members USING btree
+    (user_id ASC NULLS LAST);
+
+-- Messages are addressed either to a user or to a group chat
+ALTER TABLE public.messages
+    ALTER COLUMN receiver DROP NOT NULL,
+    ADD COLUMN chat_id uuid,
+    ADD CONSTRAINT messages_chat_id_fkey FOREIGN KEY (chat_id)
+        REFERENCES public.chats (chat_id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE NO ACTION,
+    ADD CONSTRAINT messages_single_recipient_check CHECK ((receiver IS NULL) <> (chat_id IS NULL));
+
+CREATE INDEX messages_chat_id_timestamp_idx
+    ON public.messages USING btree
+    (chat_id ASC NULLS LAST, "timestamp" DESC);
@@ -1,21 +1,44 @@
 use std::future::Future;
+use std::net::IpAddr;
 
 use anyhow::{bail, Context, Result};
-use chrono::DateTime;
+use chrono::{DateTime, TimeDelta, Utc};
 use thiserror::Error;
 use uuid::Uuid;
 
 use sqlx::postgres::PgConnectOptions;
-use sqlx::{query, Executor, PgPool, Row};
+use sqlx::postgres::PgRow;
+use sqlx::{query, Executor, PgConnection, PgPool, Row};
 use tokio::task::JoinError;
 use tokio_util::sync::CancellationToken;
 
-use pheidippides_auth::{AuthStorage, AuthenticationInfo};
+use pheidippides_auth::{
+    AuditLogStorage, AuthStorage, AuthenticationInfo, LoginEvent, LoginFailures, LoginThrottleKey,
+    PasswordResetToken, TotpInfo,
+};
+use pheidippides_messenger::attachments::{Attachment, AttachmentId};
+use pheidippides_messenger::audit_log::{
+    AuditEvent, AuditEventId, AuditEventKind, AuditEventQuery,
+};
+use pheidippides_messenger::authorization::{ApiToken, ApiTokenId};
+use pheidippides_messenger::chats::{Chat, ChatMember, ChatRole, Conversation};
 use pheidippides_messenger::data_access::{DataAccess, MESSAGE_LOAD_BUF_SIZE};
-use pheidippides_messenger::{Message, MessageId, User, UserId};
+use pheidippides_messenger::notifications::{
+    Notification, NotificationCursor, NotificationId, NotificationKind,
+};
+use pheidippides_messenger::pins::PinnedMessage;
+use pheidippides_messenger::profiles::Profile;
+use pheidippides_messenger::reactions::group_reactions;
+use pheidippides_messenger::scheduled::{ScheduledMessage, ScheduledMessageId};
+use pheidippides_messenger::search::{search_terms, MessageSearchQuery, MessageSearchResult};
+use pheidippides_messenger::stars::{StarCursor, StarredMessage};
+use pheidippides_messenger::{
+    ChatId, ConversationId, ForwardedFrom, Message, MessageId, MessageRevision, OutgoingMessage,
+    User, UserId,
+};
 
 pub const MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!();
-const DB_VERSION: i64 = 2;
+const DB_VERSION: i64 = 25;
 
 #[derive(Clone)]
 pub struct Db {
@@ -91,6 +114,20 @@ pub enum Error {
     PgError(#[from] sqlx::Error),
     #[error("Auth info parsing error: {0}")]
     AuthInfoParsingError(#[from] pheidippides_auth::AuthenticationInfoParsingError),
+    #[error("Login event parsing error: {0}")]
+    LoginEventKindParsingError(#[from] pheidippides_auth::LoginEventKindParsingError),
+    #[error("Client address parsing error: {0}")]
+    AddrParseError(#[from] std::net::AddrParseError),
+    #[error("API scope parsing error: {0}")]
+    ApiScopeParsingError(#[from] pheidippides_messenger::authorization::ApiScopeParsingError),
+    #[error(transparent)]
+    ChatRoleParsingError(#[from] pheidippides_messenger::chats::ChatRoleParsingError),
+    #[error(transparent)]
+    AuditEventKindParsingError(
+        #[from] pheidippides_messenger::audit_log::AuditEventKindParsingError,
+    ),
+    #[error("Incorrect notification of kind {0}")]
+    IncorrectNotification(String),
 }
 
 impl DataAccess for Db {
@@ -148,7 +185,7 @@ impl DataAccess for Db {
             .fetch_all(
                 query(
                     r#"
-                select user_id, username from users where lower(username) like $1
+                select user_id, username from users where lower(username) like $1 and not deleted
             "#,
                 )
                 .bind(format!("%{}%", search_query.to_lowercase())),
@@ -172,40 +209,299 @@ impl DataAccess for Db {
             .execute("lock table users in exclusive mode;")
             .await?;
 
-        let username_exists: bool = transaction
-            .fetch_one(
+        if username_taken(&mut transaction, username, None).await? {
+            return Ok(None);
+        };
+
+        transaction
+            .execute(
                 query(
                     r#"
-                select exists(select 1 from users where lower(username) = $1)
+                insert into users(user_id, username) values ($1, $2);
             "#,
                 )
-                .bind(username.to_lowercase()),
+                .bind(user_id)
+                .bind(username),
             )
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(Some(user_id))
+    }
+
+    async fn fetch_user_email(&self, user_id: &UserId) -> Result<Option<String>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
             .await?
-            .get(0);
+            .fetch_optional(query("select email from users where user_id = $1").bind(user_id))
+            .await?
+            .and_then(|row| row.get(0));
+        Ok(res)
+    }
 
-        if username_exists {
-            return Ok(None);
+    async fn update_user_email(
+        &self,
+        user_id: &UserId,
+        email: Option<&str>,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query("update users set email = $1 where user_id = $2")
+                    .bind(email)
+                    .bind(user_id),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_profile(&self, user_id: &UserId) -> Result<Option<Profile>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(
+                query(
+                    "select username, display_name, bio, avatar, deleted from users where user_id = $1",
+                )
+                .bind(user_id),
+            )
+            .await?
+            .map(|row| Profile {
+                user_id: *user_id,
+                username: row.get(0),
+                display_name: row.get(1),
+                bio: row.get(2),
+                avatar: row.get(3),
+                deleted: row.get(4),
+            });
+        Ok(res)
+    }
+
+    async fn update_profile(
+        &self,
+        user_id: &UserId,
+        display_name: Option<&str>,
+        bio: &str,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query("update users set display_name = $1, bio = $2 where user_id = $3")
+                    .bind(display_name)
+                    .bind(bio)
+                    .bind(user_id),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_avatar(
+        &self,
+        user_id: &UserId,
+        avatar: Option<&AttachmentId>,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query("update users set avatar = $1 where user_id = $2")
+                    .bind(avatar)
+                    .bind(user_id),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_username(&self, user_id: &UserId, username: &str) -> Result<bool, Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        transaction
+            .execute("lock table users in exclusive mode;")
+            .await?;
+
+        if username_taken(&mut transaction, username, Some(user_id)).await? {
+            return Ok(false);
         };
 
+        transaction
+            .execute(
+                query("update users set username = $1 where user_id = $2")
+                    .bind(username)
+                    .bind(user_id),
+            )
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn delete_user(
+        &self,
+        user_id: &UserId,
+        replacement: &Profile,
+    ) -> Result<Vec<Attachment>, Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+
         transaction
             .execute(
                 query(
                     r#"
-                insert into users(user_id, username) values ($1, $2);
+                insert into users(user_id, username, display_name, bio, deleted)
+                values ($1, $2, $3, $4, $5)
             "#,
                 )
-                .bind(user_id)
-                .bind(username),
+                .bind(replacement.user_id)
+                .bind(&replacement.username)
+                .bind(&replacement.display_name)
+                .bind(&replacement.bio)
+                .bind(replacement.deleted),
+            )
+            .await?;
+
+        // The earliest admin, or the earliest member if there are no admins, takes over
+        transaction
+            .execute(
+                query(
+                    r#"
+                update chat_members set role = 'owner'
+                from (
+                    select distinct on (members.chat_id) members.chat_id, members.user_id
+                    from chat_members owners
+                    join chat_members members
+                        on members.chat_id = owners.chat_id and members.user_id <> owners.user_id
+                    where owners.user_id = $1 and owners.role = 'owner'
+                    order by members.chat_id, members.role = 'admin' desc, members.joined_at
+                ) successors
+                where chat_members.chat_id = successors.chat_id
+                    and chat_members.user_id = successors.user_id
+            "#,
+                )
+                .bind(user_id),
+            )
+            .await?;
+
+        for statement in [
+            "update messages set sender = $2 where sender = $1",
+            "update messages set receiver = $2 where receiver = $1",
+            "update messages set forwarded_from = $2 where forwarded_from = $1",
+            "update notifications set actor = $2 where actor = $1",
+            "update read_markers set conversation_id = $2 where conversation_id = $1",
+            "update muted_conversations set conversation_id = $2 where conversation_id = $1",
+            "update pinned_messages set pinned_by = $2 where pinned_by = $1",
+            "update attachments set uploader = $2 where uploader = $1 and message_id is not null",
+        ] {
+            transaction
+                .execute(query(statement).bind(user_id).bind(replacement.user_id))
+                .await?;
+        }
+
+        let unsent = transaction
+            .fetch_all(
+                query(
+                    r#"
+                delete from attachments
+                where uploader = $1
+                returning id, uploader, message_id, file_name, content_type, size, has_thumbnail, created_at
+            "#,
+                )
+                .bind(user_id),
             )
+            .await?
+            .iter()
+            .map(attachment_from_row)
+            .collect();
+
+        transaction
+            .execute(query("delete from users where user_id = $1").bind(user_id))
             .await?;
 
         transaction.commit().await?;
 
-        Ok(Some(user_id))
+        Ok(unsent)
+    }
+
+    async fn block_user(
+        &self,
+        user_id: &UserId,
+        blocked_user_id: &UserId,
+        created_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into user_blocks(user_id, blocked_user_id, created_at)
+                values ($1, $2, $3)
+                on conflict do nothing
+            "#,
+                )
+                .bind(user_id)
+                .bind(blocked_user_id)
+                .bind(created_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn unblock_user(
+        &self,
+        user_id: &UserId,
+        blocked_user_id: &UserId,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query("delete from user_blocks where user_id = $1 and blocked_user_id = $2")
+                    .bind(user_id)
+                    .bind(blocked_user_id),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_blocked_users(&self, user_id: &UserId) -> Result<Vec<UserId>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query(
+                    "select blocked_user_id from user_blocks where user_id = $1 order by created_at, blocked_user_id",
+                )
+                .bind(user_id),
+            )
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        Ok(res)
+    }
+
+    async fn fetch_users_blocking(&self, user_id: &UserId) -> Result<Vec<UserId>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query("select user_id from user_blocks where blocked_user_id = $1").bind(user_id),
+            )
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        Ok(res)
     }
 
-    async fn find_users_chats(&self, user_id: &UserId) -> Result<Vec<User>, Self::Error> {
+    async fn find_users_chats(&self, user_id: &UserId) -> Result<Vec<Conversation>, Self::Error> {
         let mut conn = self.pool.acquire().await?;
 
         let temp_table_chat_ids = temp_table_name("chat_ids");
@@ -217,7 +513,7 @@ impl DataAccess for Db {
                 sender as user_id,
                 MAX(timestamp) as timestamp
             from messages
-            where receiver = $1
+            where receiver = $1 and chat_id is null
             group by user_id
 
             union
@@ -226,7 +522,7 @@ impl DataAccess for Db {
                 receiver as user_id,
                 MAX(timestamp) as timestamp
             from messages
-            where sender = $1
+            where sender = $1 and chat_id is null
             group by user_id
             "#
             ))
@@ -250,23 +546,88 @@ impl DataAccess for Db {
         conn.execute(query(&format!("drop table {temp_table_chat_ids};")))
             .await?;
 
+        // Conversation id of an unread message as seen by the user is
+        // the sender of a direct message or the group chat
+        let unread_filter = r#"
+                    and unread.deleted_at is null
+                    and unread.id not in (select message_id from hidden_messages where user_id = $1)
+                    and not exists (
+                        select from read_markers
+                            inner join messages as last_read on last_read.id = read_markers.message_id
+                        where read_markers.user_id = $1
+                            and read_markers.conversation_id = coalesce(unread.chat_id, unread.sender)
+                            and (last_read.timestamp, last_read.id) >= (unread.timestamp, unread.id)
+                    )
+        "#;
         let res = conn
-            .fetch_all(query(&format!(
-                r#"
+            .fetch_all(
+                query(&format!(
+                    r#"
             select
+                false as is_group,
                 last_messages.user_id,
-                users.username
+                coalesce(users.display_name, users.username),
+                last_messages.timestamp,
+                (
+                    select count(*) from messages as unread
+                    where unread.receiver = $1 and unread.sender = last_messages.user_id
+                    {unread_filter}
+                ) as unread_count,
+                exists (
+                    select from muted_conversations
+                    where muted_conversations.user_id = $1
+                        and muted_conversations.conversation_id = last_messages.user_id
+                ) as muted,
+                users.avatar
             from
             {temp_table_chat_ids_grouped} as last_messages
                 left join users as users on last_messages.user_id = users.user_id
-            order by last_messages.timestamp desc
+
+            union all
+
+            select
+                true as is_group,
+                chats.chat_id,
+                chats.title,
+                coalesce(MAX(messages.timestamp), chat_members.joined_at),
+                (
+                    select count(*) from messages as unread
+                    where unread.chat_id = chats.chat_id and unread.sender <> $1
+                    {unread_filter}
+                ) as unread_count,
+                exists (
+                    select from muted_conversations
+                    where muted_conversations.user_id = $1
+                        and muted_conversations.conversation_id = chats.chat_id
+                ) as muted,
+                null::uuid as avatar
+            from chat_members
+                inner join chats on chats.chat_id = chat_members.chat_id
+                left join messages on messages.chat_id = chat_members.chat_id
+            where chat_members.user_id = $1
+            group by chats.chat_id, chats.title, chat_members.joined_at
+
+            order by 4 desc
             "#
-            )))
+                ))
+                .bind(user_id),
+            )
             .await?
             .iter()
-            .map(|row| User {
-                id: row.get(0),
-                username: row.get(1),
+            .map(|row| {
+                let is_group: bool = row.get(0);
+                let id: Uuid = row.get(1);
+                Conversation {
+                    id: if is_group {
+                        ConversationId::Group(id)
+                    } else {
+                        ConversationId::Direct(id)
+                    },
+                    title: row.get(2),
+                    unread_count: row.get::<i64, _>(4).try_into().unwrap_or_default(),
+                    muted: row.get(5),
+                    avatar: row.get(6),
+                }
             })
             .collect();
 
@@ -278,27 +639,36 @@ impl DataAccess for Db {
 
     async fn fetch_last_messages_in_chat(
         &self,
-        user_id_1: &UserId,
-        user_id_2: &UserId,
+        user_id: &UserId,
+        conversation: &ConversationId,
         starting_point: Option<&MessageId>,
     ) -> Result<Vec<Message>, Self::Error> {
         let mut conn = self.pool.acquire().await?;
         let mut query_builder = sqlx::QueryBuilder::new("");
-        query_builder
-            .push(
-                r#"
-            select id, sender, receiver, message, timestamp
+        query_builder.push(
+            r#"
+            select id, sender, receiver, chat_id, message, timestamp, edited_at, deleted_at, reply_to,
+                expires_at, forwarded_from, forwarded_at
             from messages
-            where ((receiver = "#,
-            )
-            .push_bind(user_id_1)
-            .push(" and sender = ")
-            .push_bind(user_id_2)
-            .push(") or (receiver = ")
-            .push_bind(user_id_2)
-            .push(" and sender = ")
-            .push_bind(user_id_1)
-            .push("))");
+            where "#,
+        );
+        match conversation {
+            ConversationId::Direct(other_user) => query_builder
+                .push("((receiver = ")
+                .push_bind(user_id)
+                .push(" and sender = ")
+                .push_bind(other_user)
+                .push(") or (receiver = ")
+                .push_bind(other_user)
+                .push(" and sender = ")
+                .push_bind(user_id)
+                .push("))"),
+            ConversationId::Group(chat_id) => query_builder.push("chat_id = ").push_bind(chat_id),
+        };
+        query_builder
+            .push(" and id not in (select message_id from hidden_messages where user_id = ")
+            .push_bind(user_id)
+            .push(")");
 
         if let Some(starting_point) = starting_point {
             let msg_timestamp = conn
@@ -324,25 +694,13 @@ impl DataAccess for Db {
             .push_bind(MESSAGE_LOAD_BUF_SIZE);
 
         let query = query_builder.build();
-        let res = conn
+        let mut res: Vec<Message> = conn
             .fetch_all(query)
             .await?
             .iter()
-            .map(|row| {
-                let id: MessageId = row.get(0);
-                let from: UserId = row.get(1);
-                let to: UserId = row.get(2);
-                let message: String = row.get(3);
-                let timestamp: DateTime<chrono::Utc> = row.get(4);
-                Message {
-                    id,
-                    from,
-                    to,
-                    message,
-                    timestamp,
-                }
-            })
+            .map(message_from_row)
             .collect();
+        complete_messages(&mut conn, &mut res).await?;
         Ok(res)
     }
 
@@ -365,13 +723,18 @@ impl DataAccess for Db {
             None => return Ok(vec![]),
         };
 
-        let res = conn
+        let mut res: Vec<Message> = conn
             .fetch_all(
                 query(
                     r#"
-            select id, sender, receiver, message, timestamp
+            select id, sender, receiver, chat_id, message, timestamp, edited_at, deleted_at, reply_to,
+                expires_at, forwarded_from, forwarded_at
             from messages
-            where ((receiver = $1) or (sender = $1)) and ((timestamp, id) > ($2, $3))
+            where (
+                (chat_id is null and ((receiver = $1) or (sender = $1)))
+                or chat_id in (select chat_id from chat_members where user_id = $1)
+            ) and ((timestamp, id) > ($2, $3))
+            and id not in (select message_id from hidden_messages where user_id = $1)
             order by timestamp
         "#,
                 )
@@ -380,75 +743,1228 @@ impl DataAccess for Db {
                 .bind(starting_point),
             )
             .await?
-            .into_iter()
-            .map(|row| {
-                let id: MessageId = row.get(0);
-                let from: UserId = row.get(1);
-                let to: UserId = row.get(2);
-                let message: String = row.get(3);
-                let timestamp: DateTime<chrono::Utc> = row.get(4);
-                Message {
-                    id,
-                    from,
-                    to,
-                    message,
-                    timestamp,
-                }
-            })
+            .iter()
+            .map(message_from_row)
             .collect();
+        complete_messages(&mut conn, &mut res).await?;
 
         Ok(res)
     }
 
     async fn create_message(&self, message: &Message) -> Result<(), Self::Error> {
-        let mut conn = self.pool.acquire().await?;
-        conn.execute(
-            query(
-                r#"
-                insert into messages(id, sender, receiver, message, timestamp)
-                values ($1, $2, $3, $4, $5)
+        let (receiver, chat_id) = match message.to {
+            ConversationId::Direct(user_id) => (Some(user_id), None),
+            ConversationId::Group(chat_id) => (None, Some(chat_id)),
+        };
+        let attachment_ids: Vec<AttachmentId> = message
+            .attachments
+            .iter()
+            .map(|attachment| attachment.id)
+            .collect();
+        let mut transaction = self.pool.begin().await?;
+        transaction
+            .execute(
+                query(
+                    r#"
+                insert into messages(id, sender, receiver, chat_id, message, timestamp, edited_at, deleted_at, reply_to,
+                    expires_at, forwarded_from, forwarded_at)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
             "#,
+                )
+                .bind(message.id)
+                .bind(message.from)
+                .bind(receiver)
+                .bind(chat_id)
+                .bind(&message.message)
+                .bind(message.timestamp)
+                .bind(message.edited_at)
+                .bind(message.deleted_at)
+                .bind(message.reply_to)
+                .bind(message.expires_at)
+                .bind(message.forwarded_from.map(|forwarded_from| forwarded_from.from))
+                .bind(message.forwarded_from.map(|forwarded_from| forwarded_from.timestamp)),
             )
-            .bind(message.id)
-            .bind(message.from)
-            .bind(message.to)
-            .bind(&message.message)
-            .bind(message.timestamp),
-        )
-        .await?;
+            .await?;
+        if !attachment_ids.is_empty() {
+            transaction
+                .execute(
+                    query("update attachments set message_id = $1 where id = any($2)")
+                        .bind(message.id)
+                        .bind(attachment_ids),
+                )
+                .await?;
+        }
+        transaction.commit().await?;
         Ok(())
     }
-}
 
-impl AuthStorage for Db {
-    type Error = Error;
-
-    async fn fetch_authentication(
-        &self,
-        user_id: &UserId,
-    ) -> Result<Option<AuthenticationInfo>, Self::Error> {
-        let res = self
-            .pool
-            .acquire()
-            .await?
+    async fn fetch_message(&self, message_id: &MessageId) -> Result<Option<Message>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let mut res: Vec<Message> = conn
             .fetch_optional(
                 query(
                     r#"
-            select phc_string from auth where user_id = $1
+            select id, sender, receiver, chat_id, message, timestamp, edited_at, deleted_at, reply_to,
+                expires_at, forwarded_from, forwarded_at
+            from messages
+            where id = $1
             "#,
                 )
-                .bind(user_id),
+                .bind(message_id),
             )
-            .await?;
-
-        match res {
-            Some(row) => {
-                let phc_string: &str = row.get(0);
-                let auth_info = phc_string.parse()?;
-                Ok(Some(auth_info))
-            }
-            None => Ok(None),
-        }
+            .await?
+            .as_ref()
+            .map(message_from_row)
+            .into_iter()
+            .collect();
+        complete_messages(&mut conn, &mut res).await?;
+        Ok(res.pop())
+    }
+
+    async fn edit_message(
+        &self,
+        message_id: &MessageId,
+        message: &str,
+        edited_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        transaction
+            .execute(
+                query(
+                    r#"
+                insert into message_revisions(message_id, message, created_at)
+                select id, message, coalesce(edited_at, timestamp)
+                from messages
+                where id = $1
+                for update
+            "#,
+                )
+                .bind(message_id),
+            )
+            .await?;
+        transaction
+            .execute(
+                query("update messages set message = $2, edited_at = $3 where id = $1")
+                    .bind(message_id)
+                    .bind(message)
+                    .bind(edited_at),
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn fetch_message_revisions(
+        &self,
+        message_id: &MessageId,
+    ) -> Result<Vec<MessageRevision>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query(
+                    r#"
+                select message, created_at from message_revisions
+                where message_id = $1
+                order by revision_id
+            "#,
+                )
+                .bind(message_id),
+            )
+            .await?
+            .iter()
+            .map(|row| MessageRevision {
+                message: row.get(0),
+                created_at: row.get(1),
+            })
+            .collect();
+        Ok(res)
+    }
+
+    async fn delete_message(
+        &self,
+        message_id: &MessageId,
+        deleted_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        transaction
+            .execute(query("delete from message_revisions where message_id = $1").bind(message_id))
+            .await?;
+        transaction
+            .execute(query("delete from attachments where message_id = $1").bind(message_id))
+            .await?;
+        transaction
+            .execute(query("delete from message_reactions where message_id = $1").bind(message_id))
+            .await?;
+        transaction
+            .execute(query("delete from pinned_messages where message_id = $1").bind(message_id))
+            .await?;
+        transaction
+            .execute(query("delete from starred_messages where message_id = $1").bind(message_id))
+            .await?;
+        transaction
+            .execute(
+                query("update messages set message = '', deleted_at = $2 where id = $1")
+                    .bind(message_id)
+                    .bind(deleted_at),
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn add_reaction(
+        &self,
+        message_id: &MessageId,
+        user_id: &UserId,
+        emoji: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into message_reactions(message_id, user_id, emoji, created_at)
+                values ($1, $2, $3, $4)
+                on conflict do nothing
+            "#,
+                )
+                .bind(message_id)
+                .bind(user_id)
+                .bind(emoji)
+                .bind(created_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_reaction(
+        &self,
+        message_id: &MessageId,
+        user_id: &UserId,
+        emoji: &str,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    "delete from message_reactions where message_id = $1 and user_id = $2 and emoji = $3",
+                )
+                .bind(message_id)
+                .bind(user_id)
+                .bind(emoji),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn pin_message(
+        &self,
+        message_id: &MessageId,
+        pinned_by: &UserId,
+        pinned_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into pinned_messages(message_id, pinned_by, pinned_at)
+                values ($1, $2, $3)
+                on conflict do nothing
+            "#,
+                )
+                .bind(message_id)
+                .bind(pinned_by)
+                .bind(pinned_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn unpin_message(&self, message_id: &MessageId) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(query("delete from pinned_messages where message_id = $1").bind(message_id))
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_pinned_messages(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<Vec<PinnedMessage>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let mut query_builder = sqlx::QueryBuilder::new("");
+        query_builder.push(
+            r#"
+            select id, sender, receiver, chat_id, message, timestamp, edited_at, deleted_at, reply_to,
+                expires_at, forwarded_from, forwarded_at, pinned_by, pinned_at
+            from messages
+            join pinned_messages on pinned_messages.message_id = messages.id
+            where "#,
+        );
+        match conversation {
+            ConversationId::Direct(other_user) => query_builder
+                .push("((receiver = ")
+                .push_bind(user_id)
+                .push(" and sender = ")
+                .push_bind(other_user)
+                .push(") or (receiver = ")
+                .push_bind(other_user)
+                .push(" and sender = ")
+                .push_bind(user_id)
+                .push("))"),
+            ConversationId::Group(chat_id) => query_builder.push("chat_id = ").push_bind(chat_id),
+        };
+        query_builder
+            .push(" and id not in (select message_id from hidden_messages where user_id = ")
+            .push_bind(user_id)
+            .push(")")
+            .push(" order by pinned_at desc, id desc");
+
+        let rows = conn.fetch_all(query_builder.build()).await?;
+        let mut messages: Vec<Message> = rows.iter().map(message_from_row).collect();
+        complete_messages(&mut conn, &mut messages).await?;
+        let res = messages
+            .into_iter()
+            .zip(rows.iter())
+            .map(|(message, row)| PinnedMessage {
+                message,
+                pinned_by: row.get("pinned_by"),
+                pinned_at: row.get("pinned_at"),
+            })
+            .collect();
+        Ok(res)
+    }
+
+    async fn star_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+        starred_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into starred_messages(user_id, message_id, starred_at)
+                values ($1, $2, $3)
+                on conflict do nothing
+            "#,
+                )
+                .bind(user_id)
+                .bind(message_id)
+                .bind(starred_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn unstar_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query("delete from starred_messages where user_id = $1 and message_id = $2")
+                    .bind(user_id)
+                    .bind(message_id),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_starred_messages(
+        &self,
+        user_id: &UserId,
+        cursor: Option<&StarCursor>,
+        limit: u32,
+    ) -> Result<Vec<StarredMessage>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let mut query_builder = sqlx::QueryBuilder::new("");
+        query_builder
+            .push(
+                r#"
+            select id, sender, receiver, chat_id, message, timestamp, edited_at, deleted_at, reply_to,
+                expires_at, forwarded_from, forwarded_at, starred_at
+            from messages
+            join starred_messages on starred_messages.message_id = messages.id
+            where starred_messages.user_id = "#,
+            )
+            .push_bind(user_id)
+            .push(" and ((chat_id is null and ((receiver = ")
+            .push_bind(user_id)
+            .push(") or (sender = ")
+            .push_bind(user_id)
+            .push("))) or chat_id in (select chat_id from chat_members where user_id = ")
+            .push_bind(user_id)
+            .push("))")
+            .push(" and id not in (select message_id from hidden_messages where user_id = ")
+            .push_bind(user_id)
+            .push(")");
+        if let Some(cursor) = cursor {
+            query_builder
+                .push(" and ((starred_at, id) < (")
+                .push_bind(cursor.starred_at)
+                .push(", ")
+                .push_bind(cursor.message_id)
+                .push("))");
+        }
+        query_builder
+            .push(" order by starred_at desc, id desc")
+            .push(" limit ")
+            .push_bind(i64::from(limit));
+
+        let rows = conn.fetch_all(query_builder.build()).await?;
+        let mut messages: Vec<Message> = rows.iter().map(message_from_row).collect();
+        complete_messages(&mut conn, &mut messages).await?;
+        let res = messages
+            .into_iter()
+            .zip(rows.iter())
+            .map(|(message, row)| StarredMessage {
+                message,
+                starred_at: row.get("starred_at"),
+            })
+            .collect();
+        Ok(res)
+    }
+
+    async fn hide_message(
+        &self,
+        user_id: &UserId,
+        message_id: &MessageId,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into hidden_messages(user_id, message_id)
+                values ($1, $2)
+                on conflict do nothing
+            "#,
+                )
+                .bind(user_id)
+                .bind(message_id),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn search_messages(
+        &self,
+        user_id: &UserId,
+        query: &MessageSearchQuery,
+    ) -> Result<Vec<MessageSearchResult>, Self::Error> {
+        let terms = search_terms(&query.text);
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        let mut query_builder = sqlx::QueryBuilder::new("");
+        query_builder
+            .push(
+                r#"
+            select id, sender, receiver, chat_id, message, timestamp, edited_at, deleted_at, reply_to,
+                expires_at, forwarded_from, forwarded_at, ts_rank(search_vector, search_query) as rank
+            from messages, plainto_tsquery('simple', "#,
+            )
+            .push_bind(terms.join(" "))
+            .push(
+                r#") search_query
+            where search_vector @@ search_query and deleted_at is null
+            and ((chat_id is null and ((receiver = "#,
+            )
+            .push_bind(user_id)
+            .push(") or (sender = ")
+            .push_bind(user_id)
+            .push("))) or chat_id in (select chat_id from chat_members where user_id = ")
+            .push_bind(user_id)
+            .push("))")
+            .push(" and id not in (select message_id from hidden_messages where user_id = ")
+            .push_bind(user_id)
+            .push(")");
+
+        match &query.conversation {
+            Some(ConversationId::Direct(other_user)) => {
+                query_builder
+                    .push(" and ((receiver = ")
+                    .push_bind(user_id)
+                    .push(" and sender = ")
+                    .push_bind(other_user)
+                    .push(") or (receiver = ")
+                    .push_bind(other_user)
+                    .push(" and sender = ")
+                    .push_bind(user_id)
+                    .push("))");
+            }
+            Some(ConversationId::Group(chat_id)) => {
+                query_builder.push(" and chat_id = ").push_bind(chat_id);
+            }
+            None => {}
+        }
+        if let Some(sender) = &query.sender {
+            query_builder.push(" and sender = ").push_bind(sender);
+        }
+        if let Some(since) = &query.since {
+            query_builder.push(" and timestamp >= ").push_bind(since);
+        }
+        if let Some(until) = &query.until {
+            query_builder.push(" and timestamp < ").push_bind(until);
+        }
+        if let Some(cursor) = &query.cursor {
+            query_builder
+                .push(" and ((ts_rank(search_vector, search_query), timestamp, id) < (")
+                .push_bind(cursor.rank)
+                .push(", ")
+                .push_bind(cursor.timestamp)
+                .push(", ")
+                .push_bind(cursor.message_id)
+                .push("))");
+        }
+
+        query_builder.push(" order by rank desc, timestamp desc, id desc");
+        query_builder
+            .push(" limit ")
+            .push_bind(i64::from(query.limit));
+
+        let rows = conn.fetch_all(query_builder.build()).await?;
+        let mut messages: Vec<Message> = rows.iter().map(message_from_row).collect();
+        complete_messages(&mut conn, &mut messages).await?;
+        let res = messages
+            .into_iter()
+            .zip(rows.iter())
+            .map(|(message, row)| MessageSearchResult {
+                message,
+                rank: row.get("rank"),
+            })
+            .collect();
+        Ok(res)
+    }
+
+    async fn fetch_read_marker(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<Option<MessageId>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(
+                query(
+                    "select message_id from read_markers where user_id = $1 and conversation_id = $2",
+                )
+                .bind(user_id)
+                .bind(conversation.uuid()),
+            )
+            .await?
+            .map(|row| row.get(0));
+        Ok(res)
+    }
+
+    async fn update_read_marker(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        message_id: &MessageId,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into read_markers(user_id, conversation_id, message_id)
+                values ($1, $2, $3)
+                on conflict (user_id, conversation_id) do update set message_id = excluded.message_id
+            "#,
+                )
+                .bind(user_id)
+                .bind(conversation.uuid())
+                .bind(message_id),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_conversation_muted(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        muted: bool,
+    ) -> Result<(), Self::Error> {
+        let statement = if muted {
+            r#"
+                insert into muted_conversations(user_id, conversation_id)
+                values ($1, $2)
+                on conflict do nothing
+            "#
+        } else {
+            "delete from muted_conversations where user_id = $1 and conversation_id = $2"
+        };
+        self.pool
+            .acquire()
+            .await?
+            .execute(query(statement).bind(user_id).bind(conversation.uuid()))
+            .await?;
+        Ok(())
+    }
+
+    async fn is_conversation_muted(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<bool, Self::Error> {
+        let row = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_one(
+                query(
+                    r#"
+                select exists (
+                    select from muted_conversations
+                    where user_id = $1 and conversation_id = $2
+                )
+            "#,
+                )
+                .bind(user_id)
+                .bind(conversation.uuid()),
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    async fn fetch_message_ttl(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<Option<TimeDelta>, Self::Error> {
+        let statement = match conversation {
+            ConversationId::Direct(other_user) => query(
+                r#"
+                select message_ttl_seconds from direct_message_timers
+                where user_id_1 = $1 and user_id_2 = $2
+            "#,
+            )
+            .bind(user_id.min(other_user))
+            .bind(user_id.max(other_user)),
+            ConversationId::Group(chat_id) => {
+                query("select message_ttl_seconds from chats where chat_id = $1").bind(chat_id)
+            }
+        };
+        let ttl_seconds: Option<i64> = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(statement)
+            .await?
+            .and_then(|row| row.get(0));
+        Ok(ttl_seconds.and_then(TimeDelta::try_seconds))
+    }
+
+    async fn update_message_ttl(
+        &self,
+        user_id: &UserId,
+        conversation: &ConversationId,
+        ttl: Option<TimeDelta>,
+    ) -> Result<(), Self::Error> {
+        let ttl_seconds = ttl.map(|ttl| ttl.num_seconds());
+        let statement = match (conversation, ttl_seconds) {
+            (ConversationId::Direct(other_user), Some(ttl_seconds)) => query(
+                r#"
+                insert into direct_message_timers(user_id_1, user_id_2, message_ttl_seconds)
+                values ($1, $2, $3)
+                on conflict (user_id_1, user_id_2) do update set message_ttl_seconds = $3
+            "#,
+            )
+            .bind(user_id.min(other_user))
+            .bind(user_id.max(other_user))
+            .bind(ttl_seconds),
+            (ConversationId::Direct(other_user), None) => {
+                query("delete from direct_message_timers where user_id_1 = $1 and user_id_2 = $2")
+                    .bind(user_id.min(other_user))
+                    .bind(user_id.max(other_user))
+            }
+            (ConversationId::Group(chat_id), ttl_seconds) => {
+                query("update chats set message_ttl_seconds = $2 where chat_id = $1")
+                    .bind(chat_id)
+                    .bind(ttl_seconds)
+            }
+        };
+        self.pool.acquire().await?.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn create_scheduled_message(
+        &self,
+        scheduled: &ScheduledMessage,
+    ) -> Result<(), Self::Error> {
+        let (receiver, chat_id) = match scheduled.to {
+            ConversationId::Direct(user_id) => (Some(user_id), None),
+            ConversationId::Group(chat_id) => (None, Some(chat_id)),
+        };
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into scheduled_messages(id, sender, receiver, chat_id, message, attachments, reply_to,
+                    send_at, created_at, failed_attempts, retry_at)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            "#,
+                )
+                .bind(scheduled.id)
+                .bind(scheduled.from)
+                .bind(receiver)
+                .bind(chat_id)
+                .bind(&scheduled.outgoing.text)
+                .bind(&scheduled.outgoing.attachments)
+                .bind(scheduled.outgoing.reply_to)
+                .bind(scheduled.send_at)
+                .bind(scheduled.created_at)
+                .bind(scheduled.failed_attempts)
+                .bind(scheduled.retry_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_scheduled_messages(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<ScheduledMessage>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query(
+                    r#"
+                select id, sender, receiver, chat_id, message, attachments, reply_to, send_at, created_at,
+                    failed_attempts, retry_at
+                from scheduled_messages
+                where sender = $1
+                order by send_at, created_at
+            "#,
+                )
+                .bind(user_id),
+            )
+            .await?
+            .iter()
+            .map(scheduled_message_from_row)
+            .collect();
+        Ok(res)
+    }
+
+    async fn fetch_due_scheduled_messages(
+        &self,
+        now: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<ScheduledMessage>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query(
+                    r#"
+                select id, sender, receiver, chat_id, message, attachments, reply_to, send_at, created_at,
+                    failed_attempts, retry_at
+                from scheduled_messages
+                where coalesce(retry_at, send_at) <= $1
+                order by coalesce(retry_at, send_at), created_at
+                limit $2
+            "#,
+                )
+                .bind(now)
+                .bind(i64::from(limit)),
+            )
+            .await?
+            .iter()
+            .map(scheduled_message_from_row)
+            .collect();
+        Ok(res)
+    }
+
+    async fn postpone_scheduled_message(
+        &self,
+        scheduled_message_id: &ScheduledMessageId,
+        retry_at: DateTime<Utc>,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                update scheduled_messages set failed_attempts = failed_attempts + 1, retry_at = $2
+                where id = $1
+            "#,
+                )
+                .bind(scheduled_message_id)
+                .bind(retry_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_scheduled_message(
+        &self,
+        user_id: &UserId,
+        scheduled_message_id: &ScheduledMessageId,
+    ) -> Result<bool, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .execute(
+                query("delete from scheduled_messages where id = $1 and sender = $2")
+                    .bind(scheduled_message_id)
+                    .bind(user_id),
+            )
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn fetch_mentions(&self, message_id: &MessageId) -> Result<Vec<UserId>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(query("select user_id from mentions where message_id = $1").bind(message_id))
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        Ok(res)
+    }
+
+    async fn create_mentions(
+        &self,
+        message_id: &MessageId,
+        user_ids: &[UserId],
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into mentions(message_id, user_id)
+                select $1, unnest($2::uuid[])
+                on conflict do nothing
+            "#,
+                )
+                .bind(message_id)
+                .bind(user_ids),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn create_notification(&self, notification: &Notification) -> Result<(), Self::Error> {
+        let (message_id, chat_id, emoji) = match &notification.kind {
+            NotificationKind::Mention {
+                message_id,
+                conversation,
+            } => (Some(message_id), group_chat_id(conversation), None),
+            NotificationKind::NewChat { chat_id } => (None, Some(chat_id), None),
+            NotificationKind::Reaction {
+                message_id,
+                conversation,
+                emoji,
+            } => (Some(message_id), group_chat_id(conversation), Some(emoji)),
+        };
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into notifications(id, user_id, actor, kind, message_id, chat_id, emoji, created_at, read_at)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+                )
+                .bind(notification.id)
+                .bind(notification.user_id)
+                .bind(notification.actor)
+                .bind(notification.kind.as_str())
+                .bind(message_id)
+                .bind(chat_id)
+                .bind(emoji)
+                .bind(notification.created_at)
+                .bind(notification.read_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_notifications(
+        &self,
+        user_id: &UserId,
+        cursor: Option<&NotificationCursor>,
+        limit: u32,
+    ) -> Result<Vec<Notification>, Self::Error> {
+        let mut query_builder = sqlx::QueryBuilder::new(
+            r#"
+            select id, user_id, actor, kind, message_id, chat_id, emoji, created_at, read_at
+            from notifications
+            where user_id = "#,
+        );
+        query_builder.push_bind(user_id);
+        if let Some(cursor) = cursor {
+            query_builder
+                .push(" and ((created_at, id) < (")
+                .push_bind(cursor.created_at)
+                .push(", ")
+                .push_bind(cursor.notification_id)
+                .push("))");
+        }
+        query_builder
+            .push(" order by created_at desc, id desc")
+            .push(" limit ")
+            .push_bind(i64::from(limit));
+
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(query_builder.build())
+            .await?
+            .iter()
+            .map(notification_from_row)
+            .collect::<Result<_, _>>()?;
+        Ok(res)
+    }
+
+    async fn count_unread_notifications(&self, user_id: &UserId) -> Result<u32, Self::Error> {
+        let count: i64 = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_one(
+                query("select count(*) from notifications where user_id = $1 and read_at is null")
+                    .bind(user_id),
+            )
+            .await?
+            .get(0);
+        Ok(count as u32)
+    }
+
+    async fn mark_notifications_read(
+        &self,
+        user_id: &UserId,
+        notification_id: Option<&NotificationId>,
+        read_at: DateTime<Utc>,
+    ) -> Result<u64, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                update notifications set read_at = $3
+                where user_id = $1 and read_at is null and ($2::uuid is null or id = $2)
+            "#,
+                )
+                .bind(user_id)
+                .bind(notification_id)
+                .bind(read_at),
+            )
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn purge_expired_messages(
+        &self,
+        now: DateTime<Utc>,
+        sent_before: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> Result<Vec<Message>, Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        // Concurrent purges skip each other's batches
+        let mut messages: Vec<Message> = transaction
+            .fetch_all(
+                query(
+                    r#"
+            select id, sender, receiver, chat_id, message, timestamp, edited_at, deleted_at, reply_to,
+                expires_at, forwarded_from, forwarded_at
+            from messages
+            where expires_at <= $1 or timestamp < $2
+            limit $3
+            for update skip locked
+        "#,
+                )
+                .bind(now)
+                .bind(sent_before)
+                .bind(i64::from(limit)),
+            )
+            .await?
+            .iter()
+            .map(message_from_row)
+            .collect();
+        complete_messages(&mut transaction, &mut messages).await?;
+
+        // Revisions, attachments, reactions, pins, stars, hidings and read markers cascade,
+        // replies lose the reference
+        let message_ids: Vec<MessageId> = messages.iter().map(|message| message.id).collect();
+        transaction
+            .execute(query("delete from messages where id = any($1)").bind(message_ids))
+            .await?;
+        transaction.commit().await?;
+        Ok(messages)
+    }
+
+    async fn create_chat(&self, chat: &Chat, owner: &UserId) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        transaction
+            .execute(
+                query("insert into chats(chat_id, title, created_at) values ($1, $2, $3)")
+                    .bind(chat.id)
+                    .bind(&chat.title)
+                    .bind(chat.created_at),
+            )
+            .await?;
+        transaction
+            .execute(
+                query(
+                    r#"
+                insert into chat_members(chat_id, user_id, role, joined_at)
+                values ($1, $2, $3, $4)
+            "#,
+                )
+                .bind(chat.id)
+                .bind(owner)
+                .bind(ChatRole::Owner.as_str())
+                .bind(chat.created_at),
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn fetch_chat(&self, chat_id: &ChatId) -> Result<Option<Chat>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(
+                query("select chat_id, title, created_at from chats where chat_id = $1")
+                    .bind(chat_id),
+            )
+            .await?
+            .map(|row| Chat {
+                id: row.get(0),
+                title: row.get(1),
+                created_at: row.get(2),
+            });
+        Ok(res)
+    }
+
+    async fn rename_chat(&self, chat_id: &ChatId, title: &str) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query("update chats set title = $2 where chat_id = $1")
+                    .bind(chat_id)
+                    .bind(title),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_chat_members(&self, chat_id: &ChatId) -> Result<Vec<ChatMember>, Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query(
+                    r#"
+                select user_id, role from chat_members
+                where chat_id = $1
+                order by joined_at, user_id
+            "#,
+                )
+                .bind(chat_id),
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                let role: &str = row.get(1);
+                Ok(ChatMember {
+                    user_id: row.get(0),
+                    role: role.parse()?,
+                })
+            })
+            .collect()
+    }
+
+    async fn update_chat_member(
+        &self,
+        chat_id: &ChatId,
+        user_id: &UserId,
+        role: ChatRole,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into chat_members(chat_id, user_id, role, joined_at)
+                values ($1, $2, $3, $4)
+                on conflict (chat_id, user_id) do update set role = excluded.role
+            "#,
+                )
+                .bind(chat_id)
+                .bind(user_id)
+                .bind(role.as_str())
+                .bind(Utc::now()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_chat_member(
+        &self,
+        chat_id: &ChatId,
+        user_id: &UserId,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query("delete from chat_members where chat_id = $1 and user_id = $2")
+                    .bind(chat_id)
+                    .bind(user_id),
+            )
+            .await?;
+        Ok(())
+    }
+    async fn create_attachment(&self, attachment: &Attachment) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into attachments(id, uploader, message_id, file_name, content_type, size, has_thumbnail, created_at)
+                values ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+                )
+                .bind(attachment.id)
+                .bind(attachment.uploader)
+                .bind(attachment.message_id)
+                .bind(&attachment.file_name)
+                .bind(&attachment.content_type)
+                .bind(attachment.size as i64)
+                .bind(attachment.has_thumbnail)
+                .bind(attachment.created_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_attachment(
+        &self,
+        attachment_id: &AttachmentId,
+    ) -> Result<Option<Attachment>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(
+                query(
+                    r#"
+                select id, uploader, message_id, file_name, content_type, size, has_thumbnail, created_at
+                from attachments
+                where id = $1
+            "#,
+                )
+                .bind(attachment_id),
+            )
+            .await?
+            .as_ref()
+            .map(attachment_from_row);
+        Ok(res)
+    }
+
+    async fn fetch_users_attachments(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Vec<Attachment>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query(
+                    r#"
+                select id, uploader, message_id, file_name, content_type, size, has_thumbnail, created_at
+                from attachments
+                where uploader = $1
+                order by created_at, id
+            "#,
+                )
+                .bind(user_id),
+            )
+            .await?
+            .iter()
+            .map(attachment_from_row)
+            .collect();
+        Ok(res)
+    }
+}
+
+impl AuthStorage for Db {
+    type Error = Error;
+
+    async fn fetch_authentication(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Option<AuthenticationInfo>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(
+                query(
+                    r#"
+            select phc_string from auth where user_id = $1
+            "#,
+                )
+                .bind(user_id),
+            )
+            .await?;
+
+        match res {
+            Some(row) => {
+                let phc_string: &str = row.get(0);
+                let auth_info = phc_string.parse()?;
+                Ok(Some(auth_info))
+            }
+            None => Ok(None),
+        }
     }
 
     async fn update_authentication(
@@ -464,39 +1980,838 @@ impl AuthStorage for Db {
             .fetch_optional(query("select phc_string from auth where user_id = $1").bind(user_id))
             .await?;
 
-        match old_auth {
-            Some(row) => {
-                let old_phc_string: &str = row.get(0);
-                let old_auth: AuthenticationInfo = old_phc_string.parse()?;
-                transaction
+        match old_auth {
+            Some(row) => {
+                let old_phc_string: &str = row.get(0);
+                let old_auth: AuthenticationInfo = old_phc_string.parse()?;
+                transaction
+                    .execute(
+                        query("update auth set phc_string = $1 where user_id = $2")
+                            .bind(auth_info.phc_string().to_string())
+                            .bind(user_id),
+                    )
+                    .await?;
+                transaction.commit().await?;
+                Ok(Some(old_auth))
+            }
+            None => {
+                transaction
+                    .execute(
+                        query(
+                            r#"
+                    insert into auth (user_id, phc_string) values ($1, $2)
+                    "#,
+                        )
+                        .bind(user_id)
+                        .bind(auth_info.phc_string().to_string()),
+                    )
+                    .await?;
+                transaction.commit().await?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn create_password_reset_token(
+        &self,
+        token: PasswordResetToken,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+                insert into password_reset_tokens(token_hash, user_id, expires_at)
+                values ($1, $2, $3)
+            "#,
+                )
+                .bind(token.token_hash)
+                .bind(token.user_id)
+                .bind(token.expires_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn spend_password_reset_token(
+        &self,
+        token_hash: &str,
+        now: DateTime<chrono::Utc>,
+    ) -> Result<Option<UserId>, Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        let token = transaction
+            .fetch_optional(
+                query(
+                    r#"
+                select user_id, expires_at, used from password_reset_tokens
+                where token_hash = $1
+                for update
+            "#,
+                )
+                .bind(token_hash),
+            )
+            .await?;
+
+        let (user_id, expires_at, used): (UserId, DateTime<chrono::Utc>, bool) = match token {
+            Some(row) => (row.get(0), row.get(1), row.get(2)),
+            None => return Ok(None),
+        };
+
+        transaction
+            .execute(
+                query("update password_reset_tokens set used = true where user_id = $1")
+                    .bind(user_id),
+            )
+            .await?;
+        transaction.commit().await?;
+
+        if used || expires_at <= now {
+            Ok(None)
+        } else {
+            Ok(Some(user_id))
+        }
+    }
+
+    async fn fetch_totp(&self, user_id: &UserId) -> Result<Option<TotpInfo>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(
+                query(
+                    r#"
+            select encrypted_secret, confirmed, last_used_step from totp where user_id = $1
+            "#,
+                )
+                .bind(user_id),
+            )
+            .await?
+            .map(|row| TotpInfo {
+                encrypted_secret: row.get(0),
+                confirmed: row.get(1),
+                last_used_step: row.get(2),
+            });
+        Ok(res)
+    }
+
+    async fn update_totp(
+        &self,
+        user_id: &UserId,
+        totp_info: Option<TotpInfo>,
+    ) -> Result<(), Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        match totp_info {
+            Some(totp_info) => {
+                conn.execute(
+                    query(
+                        r#"
+                insert into totp(user_id, encrypted_secret, confirmed, last_used_step)
+                values ($1, $2, $3, $4)
+                on conflict (user_id) do update set
+                    encrypted_secret = excluded.encrypted_secret,
+                    confirmed = excluded.confirmed,
+                    last_used_step = excluded.last_used_step
+                "#,
+                    )
+                    .bind(user_id)
+                    .bind(totp_info.encrypted_secret)
+                    .bind(totp_info.confirmed)
+                    .bind(totp_info.last_used_step),
+                )
+                .await?;
+            }
+            None => {
+                conn.execute(query("delete from totp where user_id = $1").bind(user_id))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn update_totp_last_used_step(
+        &self,
+        user_id: &UserId,
+        step: i64,
+    ) -> Result<bool, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+            update totp set last_used_step = $2
+            where user_id = $1 and (last_used_step is null or last_used_step < $2)
+            "#,
+                )
+                .bind(user_id)
+                .bind(step),
+            )
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        user_id: &UserId,
+        code_hashes: Option<Vec<String>>,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        transaction
+            .execute(query("delete from recovery_codes where user_id = $1").bind(user_id))
+            .await?;
+        for code_hash in code_hashes.unwrap_or_default() {
+            transaction
+                .execute(
+                    query("insert into recovery_codes(user_id, code_hash) values ($1, $2)")
+                        .bind(user_id)
+                        .bind(code_hash),
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn spend_recovery_code(
+        &self,
+        user_id: &UserId,
+        code_hash: &str,
+    ) -> Result<bool, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+            update recovery_codes set used = true
+            where user_id = $1 and code_hash = $2 and not used
+            "#,
+                )
+                .bind(user_id)
+                .bind(code_hash),
+            )
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn fetch_login_failures(
+        &self,
+        key: &LoginThrottleKey,
+    ) -> Result<Option<LoginFailures>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(
+                query(
+                    r#"
+            select failed_attempts, last_failure_at, locked_until
+            from login_failures where throttle_key = $1
+            "#,
+                )
+                .bind(key.to_string()),
+            )
+            .await?
+            .map(|row| LoginFailures {
+                failed_attempts: row.get(0),
+                last_failure_at: row.get(1),
+                locked_until: row.get(2),
+            });
+        Ok(res)
+    }
+
+    async fn replace_login_failures(
+        &self,
+        key: &LoginThrottleKey,
+        expected: Option<&LoginFailures>,
+        failures: Option<&LoginFailures>,
+    ) -> Result<bool, Self::Error> {
+        let mut connection = self.pool.acquire().await?;
+        let res = match (expected, failures) {
+            (None, None) => {
+                let exists: bool = connection
+                    .fetch_one(
+                        query("select exists (select from login_failures where throttle_key = $1)")
+                            .bind(key.to_string()),
+                    )
+                    .await?
+                    .get(0);
+                return Ok(!exists);
+            }
+            (None, Some(failures)) => {
+                connection
                     .execute(
-                        query("update auth set phc_string = $1 where user_id = $2")
-                            .bind(auth_info.phc_string().to_string())
-                            .bind(user_id),
+                        query(
+                            r#"
+            insert into login_failures (throttle_key, failed_attempts, last_failure_at, locked_until)
+            values ($1, $2, $3, $4)
+            on conflict (throttle_key) do nothing
+            "#,
+                        )
+                        .bind(key.to_string())
+                        .bind(failures.failed_attempts)
+                        .bind(failures.last_failure_at)
+                        .bind(failures.locked_until),
                     )
-                    .await?;
-                transaction.commit().await?;
-                Ok(Some(old_auth))
+                    .await?
             }
-            None => {
-                transaction
+            (Some(expected), Some(failures)) => {
+                connection
                     .execute(
                         query(
                             r#"
-                    insert into auth (user_id, phc_string) values ($1, $2)
-                    "#,
+            update login_failures set failed_attempts = $5, last_failure_at = $6, locked_until = $7
+            where throttle_key = $1 and failed_attempts = $2 and last_failure_at = $3
+                and locked_until is not distinct from $4
+            "#,
                         )
-                        .bind(user_id)
-                        .bind(auth_info.phc_string().to_string()),
+                        .bind(key.to_string())
+                        .bind(expected.failed_attempts)
+                        .bind(expected.last_failure_at)
+                        .bind(expected.locked_until)
+                        .bind(failures.failed_attempts)
+                        .bind(failures.last_failure_at)
+                        .bind(failures.locked_until),
                     )
-                    .await?;
-                transaction.commit().await?;
-                Ok(None)
+                    .await?
             }
+            (Some(expected), None) => {
+                connection
+                    .execute(
+                        query(
+                            r#"
+            delete from login_failures
+            where throttle_key = $1 and failed_attempts = $2 and last_failure_at = $3
+                and locked_until is not distinct from $4
+            "#,
+                        )
+                        .bind(key.to_string())
+                        .bind(expected.failed_attempts)
+                        .bind(expected.last_failure_at)
+                        .bind(expected.locked_until),
+                    )
+                    .await?
+            }
+        };
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn lock_login(
+        &self,
+        key: &LoginThrottleKey,
+        until: DateTime<chrono::Utc>,
+    ) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+            update login_failures set failed_attempts = 0, locked_until = $2
+            where throttle_key = $1
+            "#,
+                )
+                .bind(key.to_string())
+                .bind(until),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn reset_login_failures(&self, key: &LoginThrottleKey) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query("delete from login_failures where throttle_key = $1").bind(key.to_string()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn record_login_event(&self, event: LoginEvent) -> Result<(), Self::Error> {
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+            insert into login_events (user_id, client_addr, kind, created_at)
+            values ($1, $2, $3, $4)
+            "#,
+                )
+                .bind(event.user_id)
+                .bind(event.client_addr.map(|addr| addr.to_string()))
+                .bind(event.kind.as_str())
+                .bind(event.created_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_login_events(&self, user_id: &UserId) -> Result<Vec<LoginEvent>, Self::Error> {
+        let rows = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query(
+                    r#"
+            select user_id, client_addr, kind, created_at from login_events
+            where user_id = $1
+            order by created_at desc, event_id desc
+            "#,
+                )
+                .bind(user_id),
+            )
+            .await?;
+
+        let mut res = Vec::with_capacity(rows.len());
+        for row in rows {
+            let client_addr: Option<&str> = row.get(1);
+            let kind: &str = row.get(2);
+            res.push(LoginEvent {
+                user_id: row.get(0),
+                client_addr: client_addr.map(str::parse).transpose()?,
+                kind: kind.parse()?,
+                created_at: row.get(3),
+            });
+        }
+        Ok(res)
+    }
+
+    async fn create_api_token(
+        &self,
+        token: &ApiToken,
+        token_hash: &str,
+    ) -> Result<(), Self::Error> {
+        let scopes: Vec<&str> = token.scopes.iter().map(|scope| scope.as_str()).collect();
+        self.pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+            insert into api_tokens (token_id, user_id, token_hash, name, scopes, created_at, expires_at)
+            values ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+                )
+                .bind(token.id)
+                .bind(token.user_id)
+                .bind(token_hash)
+                .bind(&token.name)
+                .bind(scopes)
+                .bind(token.created_at)
+                .bind(token.expires_at),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_api_token_by_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ApiToken>, Self::Error> {
+        let row = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(
+                query(
+                    r#"
+            select token_id, user_id, name, scopes, created_at, expires_at
+            from api_tokens where token_hash = $1
+            "#,
+                )
+                .bind(token_hash),
+            )
+            .await?;
+        row.map(|row| api_token_from_row(&row)).transpose()
+    }
+
+    async fn fetch_api_tokens(&self, user_id: &UserId) -> Result<Vec<ApiToken>, Self::Error> {
+        let rows = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query(
+                    r#"
+            select token_id, user_id, name, scopes, created_at, expires_at
+            from api_tokens where user_id = $1
+            order by created_at, token_id
+            "#,
+                )
+                .bind(user_id),
+            )
+            .await?;
+        rows.iter().map(api_token_from_row).collect()
+    }
+
+    async fn delete_api_token(
+        &self,
+        user_id: &UserId,
+        token_id: &ApiTokenId,
+    ) -> Result<bool, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .execute(
+                query("delete from api_tokens where user_id = $1 and token_id = $2")
+                    .bind(user_id)
+                    .bind(token_id),
+            )
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn fetch_external_identity_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<UserId>, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_optional(
+                query("select user_id from external_identities where issuer = $1 and subject = $2")
+                    .bind(issuer)
+                    .bind(subject),
+            )
+            .await?
+            .map(|row| row.get(0));
+        Ok(res)
+    }
+
+    async fn create_external_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        user_id: &UserId,
+    ) -> Result<bool, Self::Error> {
+        let res = self
+            .pool
+            .acquire()
+            .await?
+            .execute(
+                query(
+                    r#"
+            insert into external_identities (issuer, subject, user_id) values ($1, $2, $3)
+            on conflict do nothing
+            "#,
+                )
+                .bind(issuer)
+                .bind(subject)
+                .bind(user_id),
+            )
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
+
+impl AuditLogStorage for Db {
+    type Error = Error;
+
+    async fn append_audit_event(
+        &self,
+        user_id: Option<&UserId>,
+        kind: AuditEventKind,
+        client_addr: Option<IpAddr>,
+        created_at: DateTime<Utc>,
+    ) -> Result<AuditEventId, Self::Error> {
+        let row = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_one(
+                query(
+                    r#"
+            insert into audit_events (user_id, kind, client_addr, created_at)
+            values ($1, $2, $3, $4)
+            returning event_id
+            "#,
+                )
+                .bind(user_id)
+                .bind(kind.as_str())
+                .bind(client_addr.map(|addr| addr.to_string()))
+                .bind(created_at),
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    async fn fetch_audit_events(
+        &self,
+        audit_event_query: &AuditEventQuery,
+    ) -> Result<Vec<AuditEvent>, Self::Error> {
+        let rows = self
+            .pool
+            .acquire()
+            .await?
+            .fetch_all(
+                query(
+                    r#"
+            select event_id, user_id, kind, client_addr, created_at from audit_events
+            where ($1::uuid is null or user_id = $1)
+                and ($2::varchar is null or kind = $2)
+                and ($3::varchar is null or client_addr = $3)
+                and ($4::timestamptz is null or created_at >= $4)
+                and ($5::timestamptz is null or created_at < $5)
+                and ($6::bigint is null or event_id < $6)
+            order by event_id desc
+            limit $7
+            "#,
+                )
+                .bind(audit_event_query.user_id)
+                .bind(audit_event_query.kind.map(|kind| kind.as_str()))
+                .bind(audit_event_query.client_addr.map(|addr| addr.to_string()))
+                .bind(audit_event_query.since)
+                .bind(audit_event_query.until)
+                .bind(audit_event_query.before)
+                .bind(i64::from(audit_event_query.limit)),
+            )
+            .await?;
+
+        let mut res = Vec::with_capacity(rows.len());
+        for row in rows {
+            let kind: &str = row.get(2);
+            let client_addr: Option<&str> = row.get(3);
+            res.push(AuditEvent {
+                id: row.get(0),
+                user_id: row.get(1),
+                kind: kind.parse()?,
+                client_addr: client_addr.map(str::parse).transpose()?,
+                created_at: row.get(4),
+            });
         }
+        Ok(res)
+    }
+}
+
+fn message_from_row(row: &PgRow) -> Message {
+    let receiver: Option<UserId> = row.get(2);
+    let chat_id: Option<ChatId> = row.get(3);
+    // Guaranteed by messages_single_recipient_check
+    let to = match (receiver, chat_id) {
+        (_, Some(chat_id)) => ConversationId::Group(chat_id),
+        (receiver, None) => ConversationId::Direct(receiver.unwrap_or_default()),
+    };
+    let forwarded_from: Option<UserId> = row.get(10);
+    let forwarded_at: Option<DateTime<Utc>> = row.get(11);
+    Message {
+        id: row.get(0),
+        from: row.get(1),
+        to,
+        message: row.get(4),
+        timestamp: row.get(5),
+        edited_at: row.get(6),
+        deleted_at: row.get(7),
+        expires_at: row.get(9),
+        attachments: vec![],
+        reply_to: row.get(8),
+        // Guaranteed by messages_forwarded_check
+        forwarded_from: forwarded_from
+            .zip(forwarded_at)
+            .map(|(from, timestamp)| ForwardedFrom { from, timestamp }),
+        reactions: vec![],
+    }
+}
+
+fn group_chat_id(conversation: &ConversationId) -> Option<&ChatId> {
+    match conversation {
+        ConversationId::Direct(_) => None,
+        ConversationId::Group(chat_id) => Some(chat_id),
+    }
+}
+
+fn notification_from_row(row: &PgRow) -> Result<Notification, Error> {
+    let actor: UserId = row.get(2);
+    let kind: String = row.get(3);
+    let message_id: Option<MessageId> = row.get(4);
+    let chat_id: Option<ChatId> = row.get(5);
+    // Notifications about direct conversations are about the one with the actor
+    let conversation = chat_id.map_or(ConversationId::Direct(actor), ConversationId::Group);
+    let kind = match (kind.as_str(), message_id, chat_id) {
+        ("mention", Some(message_id), _) => NotificationKind::Mention {
+            message_id,
+            conversation,
+        },
+        ("new_chat", _, Some(chat_id)) => NotificationKind::NewChat { chat_id },
+        ("reaction", Some(message_id), _) => NotificationKind::Reaction {
+            message_id,
+            conversation,
+            emoji: row.get::<Option<String>, _>(6).unwrap_or_default(),
+        },
+        _ => return Err(Error::IncorrectNotification(kind)),
+    };
+    Ok(Notification {
+        id: row.get(0),
+        user_id: row.get(1),
+        actor,
+        kind,
+        created_at: row.get(7),
+        read_at: row.get(8),
+    })
+}
+
+fn scheduled_message_from_row(row: &PgRow) -> ScheduledMessage {
+    let receiver: Option<UserId> = row.get(2);
+    let chat_id: Option<ChatId> = row.get(3);
+    // Guaranteed by scheduled_messages_single_recipient_check
+    let to = match (receiver, chat_id) {
+        (_, Some(chat_id)) => ConversationId::Group(chat_id),
+        (receiver, None) => ConversationId::Direct(receiver.unwrap_or_default()),
+    };
+    ScheduledMessage {
+        id: row.get(0),
+        from: row.get(1),
+        to,
+        outgoing: OutgoingMessage {
+            text: row.get(4),
+            attachments: row.get(5),
+            reply_to: row.get(6),
+        },
+        send_at: row.get(7),
+        created_at: row.get(8),
+        failed_attempts: row.get(9),
+        retry_at: row.get(10),
+    }
+}
+
+/// Usernames are compared case-insensitively, `except` is the user changing their own username.
+/// Callers lock the users table, so that the username can't be taken before they are done
+async fn username_taken(
+    conn: &mut PgConnection,
+    username: &str,
+    except: Option<&UserId>,
+) -> Result<bool, Error> {
+    let res = conn
+        .fetch_one(
+            query(
+                r#"
+            select exists(select 1 from users where lower(username) = $1 and user_id is distinct from $2)
+        "#,
+            )
+            .bind(username.to_lowercase())
+            .bind(except),
+        )
+        .await?
+        .get(0);
+    Ok(res)
+}
+
+/// Fills in what is stored apart from the messages: their attachments and reactions
+async fn complete_messages(conn: &mut PgConnection, messages: &mut [Message]) -> Result<(), Error> {
+    fetch_messages_attachments(conn, messages).await?;
+    fetch_messages_reactions(conn, messages).await
+}
+
+/// Fills in the reactions of the messages with a single query
+async fn fetch_messages_reactions(
+    conn: &mut PgConnection,
+    messages: &mut [Message],
+) -> Result<(), Error> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let message_ids: Vec<MessageId> = messages.iter().map(|message| message.id).collect();
+    let reactions: Vec<(MessageId, String, UserId)> = conn
+        .fetch_all(
+            query(
+                r#"
+            select message_id, emoji, user_id
+            from message_reactions
+            where message_id = any($1)
+            order by created_at, user_id, emoji
+        "#,
+            )
+            .bind(message_ids),
+        )
+        .await?
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect();
+    for message in messages.iter_mut() {
+        message.reactions = group_reactions(
+            reactions
+                .iter()
+                .filter(|(message_id, _, _)| message_id == &message.id)
+                .map(|(_, emoji, user_id)| (emoji.as_str(), *user_id)),
+        );
+    }
+    Ok(())
+}
+
+/// Fills in the attachments of the messages with a single query
+async fn fetch_messages_attachments(
+    conn: &mut PgConnection,
+    messages: &mut [Message],
+) -> Result<(), Error> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let message_ids: Vec<MessageId> = messages.iter().map(|message| message.id).collect();
+    let attachments: Vec<Attachment> = conn
+        .fetch_all(
+            query(
+                r#"
+            select id, uploader, message_id, file_name, content_type, size, has_thumbnail, created_at
+            from attachments
+            where message_id = any($1)
+            order by created_at, id
+        "#,
+            )
+            .bind(message_ids),
+        )
+        .await?
+        .iter()
+        .map(attachment_from_row)
+        .collect();
+    for message in messages.iter_mut() {
+        message.attachments = attachments
+            .iter()
+            .filter(|attachment| attachment.message_id == Some(message.id))
+            .cloned()
+            .collect();
+    }
+    Ok(())
+}
+
+fn attachment_from_row(row: &PgRow) -> Attachment {
+    let size: i64 = row.get(5);
+    Attachment {
+        id: row.get(0),
+        uploader: row.get(1),
+        message_id: row.get(2),
+        file_name: row.get(3),
+        content_type: row.get(4),
+        size: size as u64,
+        has_thumbnail: row.get(6),
+        created_at: row.get(7),
     }
 }
 
+fn api_token_from_row(row: &PgRow) -> Result<ApiToken, Error> {
+    let scopes: Vec<String> = row.get(3);
+    Ok(ApiToken {
+        id: row.get(0),
+        user_id: row.get(1),
+        name: row.get(2),
+        scopes: scopes
+            .iter()
+            .map(|scope| scope.parse())
+            .collect::<Result<_, _>>()?,
+        created_at: row.get(4),
+        expires_at: row.get(5),
+    })
+}
+
 fn temp_table_name(name: &str) -> String {
     pg_id(&format!("temp_{name}_{}", Uuid::new_v4()))
 }
@@ -1,10 +1,33 @@
-#![feature(assert_matches)]
-use std::assert_matches::assert_matches;
+use std::assert_matches;
+use std::net::IpAddr;
 
+use chrono::TimeDelta;
 use mock_db::Db;
-use pheidippides_auth::AuthServiceUsingArgon2;
-use pheidippides_messenger::messenger::Messenger;
-use pheidippides_messenger::Message;
+use pheidippides_auth::{
+    totp, AuthServiceUsingArgon2, AuthStorage, LoginEventKind, LoginThrottlingConfig,
+    PasswordHashingConfig, Pepper, SecretKey,
+};
+use pheidippides_blob::InMemoryBlobStorage;
+use pheidippides_mail::InMemoryMailer;
+use pheidippides_messenger::attachments::AttachmentLimits;
+use pheidippides_messenger::audit_log::{AuditEventKind, AuditEventQuery};
+use pheidippides_messenger::authorization::ApiScope;
+use pheidippides_messenger::chats::{ChatMember, ChatRole};
+use pheidippides_messenger::data_access::DataAccess;
+use pheidippides_messenger::events::Event;
+use pheidippides_messenger::mailer::{Mail, Mailer};
+use pheidippides_messenger::messenger::{LoginResult, Messenger};
+use pheidippides_messenger::notifications::NotificationKind;
+use pheidippides_messenger::presence::Presence;
+use pheidippides_messenger::reactions::Reaction;
+use pheidippides_messenger::scheduled::{
+    delivery_retry_delay, ScheduledMessage, DELIVERY_RETRY_DELAY, MAX_DELIVERY_ATTEMPTS,
+};
+use pheidippides_messenger::search::{MessageSearchQuery, SnippetFragment};
+use pheidippides_messenger::stars::StarCursor;
+use pheidippides_messenger::{
+    ConversationId, ForwardedFrom, Message, OutgoingMessage, ReadReceipt,
+};
 
 #[tokio::test]
 async fn subscribes_to_new_messages_without_starting_point() {
@@ -24,37 +47,61 @@ async fn subscribes_to_new_messages_without_starting_point() {
         .await
         .unwrap()
         .unwrap();
-    app.send_message("Message 1".into(), user_id_1, user_id_2)
-        .await
-        .unwrap();
-    app.send_message("Message 2".into(), user_id_2, user_id_1)
-        .await
-        .unwrap();
+    app.send_message(
+        "Message 1".into(),
+        user_id_1,
+        ConversationId::Direct(user_id_2),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 2".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
     let mut subscription = app
         .subscribe_to_new_messages(user_id_1, None)
         .await
         .unwrap();
-    app.send_message("Message 3".into(), user_id_2, user_id_1)
-        .await
-        .unwrap();
-    app.send_message("Message 4".into(), user_id_1, user_id_2)
-        .await
-        .unwrap();
-    app.send_message("Message 5".into(), user_id_2, user_id_3)
-        .await
-        .unwrap();
-    app.send_message("Message 6".into(), user_id_3, user_id_1)
-        .await
-        .unwrap();
+    app.send_message(
+        "Message 3".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 4".into(),
+        user_id_1,
+        ConversationId::Direct(user_id_2),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 5".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_3),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 6".into(),
+        user_id_3,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
 
     assert_matches!(subscription.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_2 && to == user_id_1 && &message == "Message 3");
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_2 && to == ConversationId::Direct(user_id_1) && &message == "Message 3");
 
     assert_matches!(subscription.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_1 && to == user_id_2 && &message == "Message 4");
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_1 && to == ConversationId::Direct(user_id_2) && &message == "Message 4");
 
     assert_matches!(subscription.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_3 && to == user_id_1 && &message == "Message 6");
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_3 && to == ConversationId::Direct(user_id_1) && &message == "Message 6");
 }
 
 #[tokio::test]
@@ -75,44 +122,68 @@ async fn subscribtions_to_new_messages_without_starting_point_dont_conflict() {
         .await
         .unwrap()
         .unwrap();
-    app.send_message("Message 1".into(), user_id_1, user_id_2)
-        .await
-        .unwrap();
-    app.send_message("Message 2".into(), user_id_2, user_id_1)
-        .await
-        .unwrap();
+    app.send_message(
+        "Message 1".into(),
+        user_id_1,
+        ConversationId::Direct(user_id_2),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 2".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
     let mut subscription1 = app
         .subscribe_to_new_messages(user_id_1, None)
         .await
         .unwrap();
-    app.send_message("Message 3".into(), user_id_2, user_id_1)
-        .await
-        .unwrap();
-    app.send_message("Message 4".into(), user_id_1, user_id_2)
-        .await
-        .unwrap();
-    app.send_message("Message 5".into(), user_id_2, user_id_3)
-        .await
-        .unwrap();
+    app.send_message(
+        "Message 3".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 4".into(),
+        user_id_1,
+        ConversationId::Direct(user_id_2),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 5".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_3),
+    )
+    .await
+    .unwrap();
     let mut subscription2 = app
         .subscribe_to_new_messages(user_id_1, None)
         .await
         .unwrap();
-    app.send_message("Message 6".into(), user_id_3, user_id_1)
-        .await
-        .unwrap();
+    app.send_message(
+        "Message 6".into(),
+        user_id_3,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
 
     assert_matches!(subscription1.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_2 && to == user_id_1 && &message == "Message 3");
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_2 && to == ConversationId::Direct(user_id_1) && &message == "Message 3");
 
     assert_matches!(subscription1.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_1 && to == user_id_2 && &message == "Message 4");
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_1 && to == ConversationId::Direct(user_id_2) && &message == "Message 4");
 
     assert_matches!(subscription1.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_3 && to == user_id_1 && &message == "Message 6");
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_3 && to == ConversationId::Direct(user_id_1) && &message == "Message 6");
 
     assert_matches!(subscription2.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_3 && to == user_id_1 && &message == "Message 6");
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_3 && to == ConversationId::Direct(user_id_1) && &message == "Message 6");
 }
 
 #[tokio::test]
@@ -133,167 +204,3155 @@ async fn subscribes_to_new_messages_with_starting_point() {
         .await
         .unwrap()
         .unwrap();
-    app.send_message("Message 1".into(), user_id_1, user_id_2)
+    app.send_message(
+        "Message 1".into(),
+        user_id_1,
+        ConversationId::Direct(user_id_2),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 2".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
+    let starting_point = app
+        .send_message(
+            "Message 3".into(),
+            user_id_2,
+            ConversationId::Direct(user_id_1),
+        )
         .await
         .unwrap();
-    app.send_message("Message 2".into(), user_id_2, user_id_1)
+    app.send_message(
+        "Message 4".into(),
+        user_id_1,
+        ConversationId::Direct(user_id_2),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 5".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_3),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 6".into(),
+        user_id_3,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
+    let mut subscription = app
+        .subscribe_to_new_messages(user_id_1, Some(starting_point))
         .await
         .unwrap();
-    let starting_point = app
-        .send_message("Message 3".into(), user_id_2, user_id_1)
+
+    assert_matches!(subscription.recv().await.unwrap(), 
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_1 && to == ConversationId::Direct(user_id_2) && &message == "Message 4");
+    assert_matches!(subscription.recv().await.unwrap(), 
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_3 && to == ConversationId::Direct(user_id_1) && &message == "Message 6");
+
+    app.send_message(
+        "Message 7".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 8".into(),
+        user_id_1,
+        ConversationId::Direct(user_id_2),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 9".into(),
+        user_id_2,
+        ConversationId::Direct(user_id_3),
+    )
+    .await
+    .unwrap();
+    app.send_message(
+        "Message 10".into(),
+        user_id_3,
+        ConversationId::Direct(user_id_1),
+    )
+    .await
+    .unwrap();
+
+    assert_matches!(subscription.recv().await.unwrap(), 
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_2 && to == ConversationId::Direct(user_id_1) && &message == "Message 7");
+
+    assert_matches!(subscription.recv().await.unwrap(), 
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_1 && to == ConversationId::Direct(user_id_2) && &message == "Message 8");
+
+    assert_matches!(subscription.recv().await.unwrap(), 
+        Event::NewMessage(Message{ from, to, message, ..}) if from == user_id_3 && to == ConversationId::Direct(user_id_1) && &message == "Message 10");
+}
+
+#[tokio::test]
+async fn fans_out_group_messages_to_members() {
+    let app = make_app().await;
+    let owner = app
+        .create_user("TestUser_1", "12345".into())
         .await
+        .unwrap()
         .unwrap();
-    app.send_message("Message 4".into(), user_id_1, user_id_2)
+    let member = app
+        .create_user("TestUser_2", "12345".into())
         .await
+        .unwrap()
         .unwrap();
-    app.send_message("Message 5".into(), user_id_2, user_id_3)
+    let outsider = app
+        .create_user("TestUser_3", "12345".into())
         .await
+        .unwrap()
         .unwrap();
-    app.send_message("Message 6".into(), user_id_3, user_id_1)
+    let chat_id = app.create_chat(&owner, "Group", &[member]).await.unwrap();
+    let group = ConversationId::Group(chat_id);
+
+    let mut owner_subscription = app.subscribe_to_new_messages(owner, None).await.unwrap();
+    let mut member_subscription = app.subscribe_to_new_messages(member, None).await.unwrap();
+    let mut outsider_subscription = app.subscribe_to_new_messages(outsider, None).await.unwrap();
+
+    assert!(app
+        .send_message("Intrusion".into(), outsider, group)
+        .await
+        .is_err());
+    app.send_message("Message 1".into(), member, group)
         .await
         .unwrap();
-    let mut subscription = app
-        .subscribe_to_new_messages(user_id_1, Some(starting_point))
+    app.send_message("Message 2".into(), outsider, ConversationId::Direct(owner))
         .await
         .unwrap();
 
-    assert_matches!(subscription.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_1 && to == user_id_2 && &message == "Message 4");
-    assert_matches!(subscription.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_3 && to == user_id_1 && &message == "Message 6");
+    assert_matches!(owner_subscription.recv().await.unwrap(),
+        Event::PresenceChanged{ user_id, .. } if user_id == member);
+    assert_matches!(owner_subscription.recv().await.unwrap(),
+        Event::NewMessage(Message{ from, to, message, ..}) if from == member && to == group && &message == "Message 1");
+    assert_matches!(owner_subscription.recv().await.unwrap(),
+        Event::NewMessage(Message{ from, message, ..}) if from == outsider && &message == "Message 2");
+    assert_matches!(member_subscription.recv().await.unwrap(),
+        Event::NewMessage(Message{ from, to, message, ..}) if from == member && to == group && &message == "Message 1");
+    assert_matches!(outsider_subscription.recv().await.unwrap(),
+        Event::NewMessage(Message{ message, ..}) if &message == "Message 2");
 
-    app.send_message("Message 7".into(), user_id_2, user_id_1)
+    assert!(app
+        .fetch_last_messages(&outsider, &group, None)
         .await
-        .unwrap();
-    app.send_message("Message 8".into(), user_id_1, user_id_2)
+        .is_err());
+    let chats = app.fetch_users_chats(&owner).await.unwrap();
+    assert_eq!(chats[0].id, ConversationId::Direct(outsider));
+    assert_eq!(chats[1].id, group);
+    assert_eq!(
+        app.resolve_conversation(&member, &chat_id).await.unwrap(),
+        Some(group)
+    );
+    assert_eq!(
+        app.resolve_conversation(&outsider, &chat_id).await.unwrap(),
+        None
+    );
+    assert_eq!(
+        app.resolve_conversation(&outsider, &owner).await.unwrap(),
+        Some(ConversationId::Direct(owner))
+    );
+}
+
+#[tokio::test]
+async fn manages_group_chat_members_by_role() {
+    let app = make_app().await;
+    let mut users = Vec::new();
+    for i in 1..=4 {
+        let user_id = app
+            .create_user(&format!("TestUser_{i}"), "12345".into())
+            .await
+            .unwrap()
+            .unwrap();
+        users.push(user_id);
+    }
+    let [owner, admin, member, newcomer] = users[..] else {
+        unreachable!()
+    };
+    let chat_id = app
+        .create_chat(&owner, "Group", &[admin, member])
         .await
         .unwrap();
-    app.send_message("Message 9".into(), user_id_2, user_id_3)
+    let role_of = |user_id| {
+        let app = app.clone();
+        async move {
+            app.fetch_chat_members(&owner, &chat_id)
+                .await
+                .unwrap()
+                .unwrap_or_default()
+                .into_iter()
+                .find(|m| m.user_id == user_id)
+                .map(|m| m.role)
+        }
+    };
+
+    // only the owner appoints admins
+    assert!(!app
+        .set_chat_member_role(&member, &chat_id, &admin, ChatRole::Admin)
         .await
-        .unwrap();
-    app.send_message("Message 10".into(), user_id_3, user_id_1)
+        .unwrap());
+    assert!(app
+        .set_chat_member_role(&owner, &chat_id, &admin, ChatRole::Admin)
         .await
-        .unwrap();
+        .unwrap());
+    assert!(!app
+        .set_chat_member_role(&owner, &chat_id, &member, ChatRole::Owner)
+        .await
+        .unwrap());
+    assert_eq!(role_of(admin).await, Some(ChatRole::Admin));
 
-    assert_matches!(subscription.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_2 && to == user_id_1 && &message == "Message 7");
+    // members can't rename the chat or add anyone
+    assert!(!app.rename_chat(&member, &chat_id, "Renamed").await.unwrap());
+    assert!(!app
+        .add_chat_member(&member, &chat_id, &newcomer)
+        .await
+        .unwrap());
+    assert!(app.rename_chat(&admin, &chat_id, "Renamed").await.unwrap());
+    assert!(app
+        .add_chat_member(&admin, &chat_id, &newcomer)
+        .await
+        .unwrap());
+    assert_eq!(
+        app.fetch_chat(&newcomer, &chat_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .title,
+        "Renamed"
+    );
 
-    assert_matches!(subscription.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_1 && to == user_id_2 && &message == "Message 8");
+    // admins remove members but not the owner
+    assert!(!app
+        .remove_chat_member(&member, &chat_id, &newcomer)
+        .await
+        .unwrap());
+    assert!(!app
+        .remove_chat_member(&admin, &chat_id, &owner)
+        .await
+        .unwrap());
+    assert!(app
+        .remove_chat_member(&admin, &chat_id, &newcomer)
+        .await
+        .unwrap());
+    assert_eq!(app.fetch_chat(&newcomer, &chat_id).await.unwrap(), None);
 
-    assert_matches!(subscription.recv().await.unwrap(), 
-        Message{ from, to, message, ..} if from == user_id_3 && to == user_id_1 && &message == "Message 10");
+    // the leaving owner is succeeded by an admin
+    assert!(app.leave_chat(&owner, &chat_id).await.unwrap());
+    assert!(!app.leave_chat(&owner, &chat_id).await.unwrap());
+    let members = app
+        .fetch_chat_members(&admin, &chat_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        members,
+        vec![
+            ChatMember {
+                user_id: admin,
+                role: ChatRole::Owner
+            },
+            ChatMember {
+                user_id: member,
+                role: ChatRole::Member
+            },
+        ]
+    );
 }
 
 #[tokio::test]
-async fn authorization_works_correctly() {
+async fn edits_messages_within_window() {
     let app = make_app().await;
-
-    assert!(app
-        .verify_user("User1", "User1".to_owned())
+    let author = app
+        .create_user("TestUser_1", "12345".into())
         .await
         .unwrap()
-        .is_none());
+        .unwrap();
+    let receiver = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let outsider = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let message_id = app
+        .send_message("Helo".into(), author, ConversationId::Direct(receiver))
+        .await
+        .unwrap();
+    let mut subscription = app.subscribe_to_new_messages(receiver, None).await.unwrap();
+
+    assert!(!app
+        .edit_message(&receiver, &message_id, "Hacked".into())
+        .await
+        .unwrap());
     assert!(app
-        .verify_user("User2", "12345".to_owned())
+        .edit_message(&author, &message_id, "Hello".into())
+        .await
+        .unwrap());
+
+    assert_matches!(subscription.recv().await.unwrap(),
+        Event::MessageEdited(Message{ id, message, edited_at: Some(_), ..}) if id == message_id && &message == "Hello");
+    let messages = app
+        .fetch_last_messages(&receiver, &ConversationId::Direct(author), None)
+        .await
+        .unwrap();
+    assert_eq!(messages[0].message, "Hello");
+
+    let revisions = app
+        .fetch_message_revisions(&receiver, &message_id)
         .await
         .unwrap()
-        .is_none());
+        .unwrap();
+    assert_eq!(revisions.len(), 1);
+    assert_eq!(revisions[0].message, "Helo");
     assert!(app
-        .verify_user("__invalid_user", "12345".to_owned())
+        .fetch_message_revisions(&outsider, &message_id)
         .await
         .unwrap()
         .is_none());
 
-    app.create_user("User1", "User1".to_owned()).await.unwrap();
+    let app = app.with_message_edit_window(TimeDelta::zero());
+    assert!(!app
+        .edit_message(&author, &message_id, "Too late".into())
+        .await
+        .unwrap());
+}
 
-    assert!(app
-        .verify_user("User1", "User1".to_owned())
+#[tokio::test]
+async fn replies_within_conversation() {
+    let app = make_app().await;
+    let user_id_1 = app
+        .create_user("TestUser_1", "12345".into())
         .await
         .unwrap()
-        .is_some());
-    assert!(app
-        .verify_user("User2", "12345".to_owned())
+        .unwrap();
+    let user_id_2 = app
+        .create_user("TestUser_2", "12345".into())
         .await
         .unwrap()
-        .is_none());
-    assert!(app
-        .verify_user("__invalid_user", "12345".to_owned())
+        .unwrap();
+    let user_id_3 = app
+        .create_user("TestUser_3", "12345".into())
         .await
         .unwrap()
-        .is_none());
+        .unwrap();
+    let original_id = app
+        .send_message(
+            "Question?".into(),
+            user_id_1,
+            ConversationId::Direct(user_id_2),
+        )
+        .await
+        .unwrap();
+    let other_id = app
+        .send_message(
+            "Elsewhere".into(),
+            user_id_1,
+            ConversationId::Direct(user_id_3),
+        )
+        .await
+        .unwrap();
+    let mut subscription = app
+        .subscribe_to_new_messages(user_id_1, None)
+        .await
+        .unwrap();
 
-    app.create_user("User2", "User2".to_owned()).await.unwrap();
+    let reply = |reply_to| OutgoingMessage {
+        reply_to: Some(reply_to),
+        ..OutgoingMessage::new("Answer")
+    };
+    let reply_id = app
+        .send_outgoing_message(
+            user_id_2,
+            ConversationId::Direct(user_id_1),
+            reply(original_id),
+        )
+        .await
+        .unwrap();
+    assert_matches!(subscription.recv().await.unwrap(),
+        Event::NewMessage(message) if message.id == reply_id && message.reply_to == Some(original_id));
 
+    // Only messages of the same conversation can be replied to
     assert!(app
-        .verify_user("User1", "User1".to_owned())
+        .send_outgoing_message(
+            user_id_2,
+            ConversationId::Direct(user_id_1),
+            reply(other_id)
+        )
         .await
-        .unwrap()
-        .is_some());
+        .is_err());
     assert!(app
-        .verify_user("User2", "12345".to_owned())
+        .send_outgoing_message(
+            user_id_3,
+            ConversationId::Direct(user_id_1),
+            reply(original_id)
+        )
         .await
-        .unwrap()
-        .is_none());
+        .is_err());
     assert!(app
-        .verify_user("__invalid_user", "12345".to_owned())
+        .send_outgoing_message(
+            user_id_2,
+            ConversationId::Direct(user_id_1),
+            reply(uuid::Uuid::new_v4())
+        )
         .await
-        .unwrap()
-        .is_none());
+        .is_err());
+    assert!(subscription.try_recv().is_err());
 }
 
 #[tokio::test]
-async fn authorization_ignores_case() {
+async fn reacts_to_messages() {
     let app = make_app().await;
+    let author = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let receiver = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let outsider = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let message_id = app
+        .send_message("Hi".into(), author, ConversationId::Direct(receiver))
+        .await
+        .unwrap();
+    let mut author_subscription = app.subscribe_to_new_messages(author, None).await.unwrap();
 
     assert!(app
-        .verify_user("User1", "User1".to_owned())
+        .add_reaction(&receiver, &message_id, "👋")
         .await
-        .unwrap()
-        .is_none());
+        .unwrap());
+    assert_matches!(author_subscription.recv().await.unwrap(),
+        Event::ReactionsChanged(message) if message.id == message_id
+            && message.reactions == vec![Reaction { emoji: "👋".to_owned(), users: vec![receiver] }]);
+    assert_matches!(author_subscription.recv().await.unwrap(),
+        Event::Notification(notification) if notification.actor == receiver);
+    // Repeated reactions change nothing
     assert!(app
-        .verify_user("User1", "user1".to_owned())
+        .add_reaction(&receiver, &message_id, "👋")
         .await
-        .unwrap()
-        .is_none());
+        .unwrap());
+    assert!(author_subscription.try_recv().is_err());
+
+    assert!(!app
+        .add_reaction(&outsider, &message_id, "👋")
+        .await
+        .unwrap());
+    assert!(!app
+        .remove_reaction(&outsider, &message_id, "👋")
+        .await
+        .unwrap());
+    for invalid in ["", "ok", "👍 👍", "👍👍👍👍👍👍👍👍👍👍👍"] {
+        assert!(app
+            .add_reaction(&author, &message_id, invalid)
+            .await
+            .is_err());
+    }
+
     assert!(app
-        .verify_user("user1", "User1".to_owned())
+        .remove_reaction(&receiver, &message_id, "👋")
         .await
-        .unwrap()
-        .is_none());
+        .unwrap());
+    assert_matches!(author_subscription.recv().await.unwrap(),
+        Event::ReactionsChanged(message) if message.reactions.is_empty());
+
     assert!(app
-        .verify_user("user1", "user1".to_owned())
+        .delete_message_for_everyone(&author, &message_id)
+        .await
+        .unwrap());
+    assert!(!app
+        .add_reaction(&receiver, &message_id, "👋")
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn notifies_about_mentions_chats_and_reactions() {
+    let app = make_app().await;
+    let author = app
+        .create_user("TestUser_1", "12345".into())
         .await
         .unwrap()
-        .is_none());
+        .unwrap();
+    let member = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let other_member = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let outsider = app
+        .create_user("TestUser_4", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut subscription = app.subscribe_to_new_messages(member, None).await.unwrap();
 
-    app.create_user("User1", "User1".to_owned()).await.unwrap();
+    // Members added to a chat are notified
+    let chat_id = app
+        .create_chat(&author, "Group", &[member, other_member])
+        .await
+        .unwrap();
+    let group = ConversationId::Group(chat_id);
+    let notification = loop {
+        if let Event::Notification(notification) = subscription.recv().await.unwrap() {
+            break notification;
+        }
+    };
+    assert_eq!(notification.user_id, member);
+    assert_eq!(notification.actor, author);
+    assert_eq!(notification.kind, NotificationKind::NewChat { chat_id });
 
+    // Only participants other than the author are mentioned, each once,
+    // while email-like text is not a mention
+    let message_id = app
+        .send_message(
+            "@TestUser_2, @TestUser_2 @TestUser_1 @TestUser_4 me@TestUser_3".into(),
+            author,
+            group,
+        )
+        .await
+        .unwrap();
+    let notification = loop {
+        if let Event::Notification(notification) = subscription.recv().await.unwrap() {
+            break notification;
+        }
+    };
+    assert_eq!(
+        notification.kind,
+        NotificationKind::Mention {
+            message_id,
+            conversation: group
+        }
+    );
+    let page = app.fetch_notifications(&member, None, 10).await.unwrap();
+    assert_eq!(page.notifications.len(), 2);
+    assert_eq!(page.unread_count, 2);
+    assert_eq!(
+        app.fetch_notifications(&other_member, None, 10)
+            .await
+            .unwrap()
+            .notifications
+            .len(),
+        1
+    );
+    for user_id in [author, outsider] {
+        assert!(app
+            .fetch_notifications(&user_id, None, 10)
+            .await
+            .unwrap()
+            .notifications
+            .is_empty());
+    }
+
+    // Editing notifies only the newly mentioned users
     assert!(app
-        .verify_user("User1", "User1".to_owned())
+        .edit_message(&author, &message_id, "@TestUser_2 and @TestUser_3!".into())
         .await
-        .unwrap()
-        .is_some());
+        .unwrap());
+    assert_eq!(
+        app.fetch_notifications(&member, None, 10)
+            .await
+            .unwrap()
+            .unread_count,
+        2
+    );
+    let page = app
+        .fetch_notifications(&other_member, None, 10)
+        .await
+        .unwrap();
+    assert_eq!(page.unread_count, 2);
+    assert_eq!(
+        page.notifications[0].kind,
+        NotificationKind::Mention {
+            message_id,
+            conversation: group
+        }
+    );
+
+    // Authors are notified about reactions of others, unless blocked
+    assert!(app.add_reaction(&member, &message_id, "👍").await.unwrap());
+    assert!(app.add_reaction(&author, &message_id, "👍").await.unwrap());
+    assert!(app.block_user(&author, &other_member).await.unwrap());
     assert!(app
-        .verify_user("User1", "user1".to_owned())
+        .add_reaction(&other_member, &message_id, "👍")
         .await
-        .unwrap()
-        .is_none());
+        .unwrap());
+    let page = app.fetch_notifications(&author, None, 10).await.unwrap();
+    assert_eq!(page.notifications.len(), 1);
+    assert_eq!(page.notifications[0].actor, member);
+    assert_eq!(
+        page.notifications[0].kind,
+        NotificationKind::Reaction {
+            message_id,
+            conversation: group,
+            emoji: "👍".to_owned()
+        }
+    );
+
+    // Muted conversations don't notify about mentions and reactions
     assert!(app
-        .verify_user("user1", "User1".to_owned())
+        .set_conversation_muted(&author, &group, true)
+        .await
+        .unwrap());
+    assert!(app.add_reaction(&member, &message_id, "🎉").await.unwrap());
+    assert_eq!(
+        app.fetch_notifications(&author, None, 10)
+            .await
+            .unwrap()
+            .notifications
+            .len(),
+        1
+    );
+    assert!(app
+        .set_conversation_muted(&member, &group, true)
+        .await
+        .unwrap());
+    app.send_message("@TestUser_2 again".into(), author, group)
+        .await
+        .unwrap();
+    assert_eq!(
+        app.fetch_notifications(&member, None, 10)
+            .await
+            .unwrap()
+            .unread_count,
+        2
+    );
+
+    // Notifications are read one at a time or all at once
+    let page = app.fetch_notifications(&member, None, 1).await.unwrap();
+    assert!(page.next_cursor.is_some());
+    let newest = &page.notifications[0];
+    assert!(!app
+        .mark_notifications_read(&author, Some(&newest.id))
+        .await
+        .unwrap());
+    assert!(app
+        .mark_notifications_read(&member, Some(&newest.id))
+        .await
+        .unwrap());
+    let page = app.fetch_notifications(&member, None, 10).await.unwrap();
+    assert_eq!(page.unread_count, 1);
+    assert!(page.notifications[0].read_at.is_some());
+    assert!(app.mark_notifications_read(&member, None).await.unwrap());
+    assert_eq!(
+        app.fetch_notifications(&member, None, 10)
+            .await
+            .unwrap()
+            .unread_count,
+        0
+    );
+}
+
+#[tokio::test]
+async fn pins_and_stars_messages() {
+    let app = make_app().await;
+    let author = app
+        .create_user("TestUser_1", "12345".into())
         .await
         .unwrap()
-        .is_some());
+        .unwrap();
+    let receiver = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let outsider = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let message_id = app
+        .send_message("Hi".into(), author, ConversationId::Direct(receiver))
+        .await
+        .unwrap();
+    let mut author_subscription = app.subscribe_to_new_messages(author, None).await.unwrap();
+
+    // Both participants of a direct chat pin messages
+    assert!(app.pin_message(&receiver, &message_id).await.unwrap());
+    assert_matches!(author_subscription.recv().await.unwrap(),
+        Event::MessagePinned { pinned_by, message } if pinned_by == receiver && message.id == message_id);
+    assert!(app.pin_message(&author, &message_id).await.unwrap());
+    assert!(author_subscription.try_recv().is_err());
+    assert!(!app.pin_message(&outsider, &message_id).await.unwrap());
+    let pins = app
+        .fetch_pinned_messages(&author, &ConversationId::Direct(receiver))
+        .await
+        .unwrap();
+    assert_eq!(pins.len(), 1);
+    assert_eq!(pins[0].pinned_by, receiver);
+    // The outsider's own chat with the receiver has nothing pinned
     assert!(app
-        .verify_user("user1", "user1".to_owned())
+        .fetch_pinned_messages(&outsider, &ConversationId::Direct(receiver))
         .await
         .unwrap()
-        .is_none());
+        .is_empty());
+
+    assert!(!app.unpin_message(&outsider, &message_id).await.unwrap());
+    assert!(app.unpin_message(&author, &message_id).await.unwrap());
+    assert_matches!(author_subscription.recv().await.unwrap(),
+        Event::MessageUnpinned { unpinned_by, message } if unpinned_by == author && message.id == message_id);
+
+    // Only those managing a group pin its messages
+    let chat_id = app
+        .create_chat(&author, "Group", &[receiver])
+        .await
+        .unwrap();
+    let group_message_id = app
+        .send_message("Rules".into(), author, ConversationId::Group(chat_id))
+        .await
+        .unwrap();
+    assert!(!app.pin_message(&receiver, &group_message_id).await.unwrap());
+    assert!(app
+        .set_chat_member_role(&author, &chat_id, &receiver, ChatRole::Admin)
+        .await
+        .unwrap());
+    assert!(app.pin_message(&receiver, &group_message_id).await.unwrap());
+
+    // Stars are private and span all conversations
+    let message_ids = [message_id, group_message_id];
+    for id in &message_ids {
+        assert!(app.star_message(&receiver, id).await.unwrap());
+    }
+    assert!(!app.star_message(&outsider, &message_id).await.unwrap());
+    let page = app
+        .fetch_starred_messages(&receiver, None, 1)
+        .await
+        .unwrap();
+    assert_eq!(page.messages[0].message.id, group_message_id);
+    let cursor = page.next_cursor.unwrap();
+    assert_eq!(cursor.to_string().parse::<StarCursor>().unwrap(), cursor);
+    let page = app
+        .fetch_starred_messages(&receiver, Some(&cursor), 1)
+        .await
+        .unwrap();
+    assert_eq!(page.messages[0].message.id, message_id);
+    assert!(page.next_cursor.is_none());
+    assert!(app
+        .fetch_starred_messages(&author, None, 10)
+        .await
+        .unwrap()
+        .messages
+        .is_empty());
+
+    // Deleting a message takes down its pins and stars
+    assert!(app
+        .delete_message_for_everyone(&author, &group_message_id)
+        .await
+        .unwrap());
+    assert!(app
+        .fetch_pinned_messages(&receiver, &ConversationId::Group(chat_id))
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(!app.pin_message(&receiver, &group_message_id).await.unwrap());
+    assert!(!app
+        .star_message(&receiver, &group_message_id)
+        .await
+        .unwrap());
+    app.unstar_message(&receiver, &message_id).await.unwrap();
+    assert!(app
+        .fetch_starred_messages(&receiver, None, 10)
+        .await
+        .unwrap()
+        .messages
+        .is_empty());
+}
+
+#[tokio::test]
+async fn purges_disappearing_messages() {
+    let blob_storage = InMemoryBlobStorage::new();
+    let app = make_app_with_blob_storage(blob_storage.clone()).await;
+    let author = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let receiver = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let direct = ConversationId::Direct(receiver);
+
+    for invalid in [
+        TimeDelta::zero(),
+        TimeDelta::seconds(59),
+        TimeDelta::days(366),
+    ] {
+        assert!(app
+            .set_message_ttl(&author, &direct, Some(invalid))
+            .await
+            .is_err());
+    }
+    assert!(app
+        .set_message_ttl(&author, &direct, Some(TimeDelta::days(1)))
+        .await
+        .unwrap());
+    assert_eq!(
+        app.fetch_message_ttl(&receiver, &ConversationId::Direct(author))
+            .await
+            .unwrap(),
+        Some(TimeDelta::days(1))
+    );
+    let document = app
+        .upload_attachment(&author, "notes.txt", "text/plain", b"Notes".to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+    let message_id = app
+        .send_outgoing_message(
+            author,
+            direct,
+            OutgoingMessage {
+                attachments: vec![document.id],
+                ..OutgoingMessage::new("Read and forget")
+            },
+        )
+        .await
+        .unwrap();
+    let message = app
+        .fetch_last_messages(&author, &direct, None)
+        .await
+        .unwrap()
+        .remove(0);
+    assert_eq!(
+        message.expires_at,
+        Some(message.timestamp + TimeDelta::days(1))
+    );
+
+    // Only those managing a group set its timer
+    let chat_id = app
+        .create_chat(&author, "Group", &[receiver])
+        .await
+        .unwrap();
+    let group = ConversationId::Group(chat_id);
+    assert!(!app
+        .set_message_ttl(&receiver, &group, Some(TimeDelta::hours(1)))
+        .await
+        .unwrap());
+    assert!(app
+        .set_message_ttl(&author, &group, Some(TimeDelta::hours(1)))
+        .await
+        .unwrap());
+    assert!(app.set_message_ttl(&author, &group, None).await.unwrap());
+    let group_message_id = app
+        .send_message("Forever".into(), receiver, group)
+        .await
+        .unwrap();
+    assert!(app
+        .fetch_last_messages(&receiver, &group, None)
+        .await
+        .unwrap()[0]
+        .expires_at
+        .is_none());
+
+    // Nothing has expired yet
+    assert_eq!(app.purge_expired_messages().await.unwrap(), 0);
+
+    // Retention applies to every conversation
+    let app = app.with_message_retention(Some(TimeDelta::zero()));
+    let mut receiver_subscription = app.subscribe_to_new_messages(receiver, None).await.unwrap();
+    assert_eq!(app.purge_expired_messages().await.unwrap(), 2);
+    let mut expired = vec![];
+    for _ in 0..2 {
+        match receiver_subscription.recv().await.unwrap() {
+            Event::MessageExpired(message) => expired.push(message.id),
+            event => panic!("Unexpected event {event:?}"),
+        }
+    }
+    expired.sort();
+    let mut expected = vec![message_id, group_message_id];
+    expected.sort();
+    assert_eq!(expired, expected);
+    assert!(app
+        .fetch_last_messages(&author, &direct, None)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(blob_storage.keys().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn forwards_messages() {
+    let blob_storage = InMemoryBlobStorage::new();
+    let app = make_app_with_blob_storage(blob_storage.clone()).await;
+    let author = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let forwarder = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let reader = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let document = app
+        .upload_attachment(&author, "notes.txt", "text/plain", b"Notes".to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+    let original_id = app
+        .send_outgoing_message(
+            author,
+            ConversationId::Direct(forwarder),
+            OutgoingMessage {
+                attachments: vec![document.id],
+                ..OutgoingMessage::new("News")
+            },
+        )
+        .await
+        .unwrap();
+    let original = app
+        .fetch_last_messages(&forwarder, &ConversationId::Direct(author), None)
+        .await
+        .unwrap()
+        .remove(0);
+    let chat_id = app
+        .create_chat(&forwarder, "Group", &[reader])
+        .await
+        .unwrap();
+    let group = ConversationId::Group(chat_id);
+
+    // Only participants see the original
+    assert!(app
+        .forward_message(&reader, &original_id, ConversationId::Direct(forwarder))
+        .await
+        .unwrap()
+        .is_none());
+    // and forward it only where they may send messages
+    let other_chat_id = app.create_chat(&author, "Other", &[]).await.unwrap();
+    assert!(app
+        .forward_message(
+            &forwarder,
+            &original_id,
+            ConversationId::Group(other_chat_id)
+        )
+        .await
+        .is_err());
+
+    let mut subscription = app.subscribe_to_new_messages(reader, None).await.unwrap();
+    let copy_id = app
+        .forward_message(&forwarder, &original_id, group)
+        .await
+        .unwrap()
+        .unwrap();
+    let provenance = ForwardedFrom {
+        from: author,
+        timestamp: original.timestamp,
+    };
+    let copy = match subscription.recv().await.unwrap() {
+        Event::NewMessage(copy) => copy,
+        event => panic!("Unexpected event {event:?}"),
+    };
+    assert_eq!(copy.id, copy_id);
+    assert_eq!(copy.from, forwarder);
+    assert_eq!(copy.message, "News");
+    assert_eq!(copy.forwarded_from, Some(provenance));
+    // Attachments are copied
+    assert_eq!(copy.attachments.len(), 1);
+    assert_ne!(copy.attachments[0].id, document.id);
+    let (_, content) = app
+        .fetch_attachment_content(&reader, &copy.attachments[0].id, false)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(content, b"Notes");
+    assert!(app
+        .fetch_attachment_content(&reader, &document.id, false)
+        .await
+        .unwrap()
+        .is_none());
+
+    // Forwarded copies keep the original provenance
+    let second_copy_id = app
+        .forward_message(&reader, &copy_id, ConversationId::Direct(author))
+        .await
+        .unwrap()
+        .unwrap();
+    let second_copy = app
+        .fetch_last_messages(&reader, &ConversationId::Direct(author), None)
+        .await
+        .unwrap()
+        .remove(0);
+    assert_eq!(second_copy.id, second_copy_id);
+    assert_eq!(second_copy.forwarded_from, Some(provenance));
+
+    // Deleted messages can't be forwarded
+    assert!(app
+        .delete_message_for_everyone(&author, &original_id)
+        .await
+        .unwrap());
+    assert!(app
+        .forward_message(&forwarder, &original_id, group)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn delivers_scheduled_messages() {
+    let db_access = Db::empty();
+    let auth_service = AuthServiceUsingArgon2::new(db_access.clone(), SecretKey::generate());
+    let app = Messenger::new(
+        db_access.clone(),
+        auth_service,
+        InMemoryMailer::new(),
+        InMemoryBlobStorage::new(),
+    );
+    let author = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let receiver = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let stranger = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let direct = ConversationId::Direct(receiver);
+    let now = chrono::Utc::now();
+
+    for invalid in [now - TimeDelta::minutes(1), now + TimeDelta::days(366)] {
+        assert!(app
+            .schedule_message(author, direct, OutgoingMessage::new("Hi"), invalid)
+            .await
+            .is_err());
+    }
+    let chat_id = app.create_chat(&receiver, "Group", &[]).await.unwrap();
+    assert!(app
+        .schedule_message(
+            author,
+            ConversationId::Group(chat_id),
+            OutgoingMessage::new("Hi"),
+            now + TimeDelta::hours(1),
+        )
+        .await
+        .is_err());
+
+    let scheduled_id = app
+        .schedule_message(
+            author,
+            direct,
+            OutgoingMessage::new("Later"),
+            now + TimeDelta::hours(1),
+        )
+        .await
+        .unwrap();
+    let scheduled = app.fetch_scheduled_messages(&author).await.unwrap();
+    assert_eq!(scheduled.len(), 1);
+    assert_eq!(scheduled[0].id, scheduled_id);
+    assert_eq!(scheduled[0].outgoing.text, "Later");
+    assert!(app
+        .fetch_scheduled_messages(&receiver)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(!app
+        .cancel_scheduled_message(&receiver, &scheduled_id)
+        .await
+        .unwrap());
+    assert!(app
+        .cancel_scheduled_message(&author, &scheduled_id)
+        .await
+        .unwrap());
+    assert!(app
+        .fetch_scheduled_messages(&author)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(app.deliver_scheduled_messages().await.unwrap(), 0);
+
+    // Fell due while the server was down
+    let due = ScheduledMessage {
+        id: uuid::Uuid::new_v4(),
+        from: author,
+        to: direct,
+        outgoing: OutgoingMessage::new("Good morning"),
+        send_at: now - TimeDelta::minutes(5),
+        created_at: now - TimeDelta::hours(8),
+        failed_attempts: 0,
+        retry_at: None,
+    };
+    db_access.create_scheduled_message(&due).await.unwrap();
+    // Can't be sent anymore and is dropped
+    assert!(app.block_user(&receiver, &stranger).await.unwrap());
+    db_access
+        .create_scheduled_message(&ScheduledMessage {
+            id: uuid::Uuid::new_v4(),
+            from: stranger,
+            to: direct,
+            ..due.clone()
+        })
+        .await
+        .unwrap();
+
+    let mut subscription = app.subscribe_to_new_messages(receiver, None).await.unwrap();
+    assert_eq!(app.deliver_scheduled_messages().await.unwrap(), 2);
+    assert_matches!(subscription.recv().await.unwrap(),
+        Event::NewMessage(Message { id, from, message, .. })
+        if id == due.id && from == author && message == "Good morning");
+    assert!(app
+        .fetch_scheduled_messages(&author)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(app
+        .fetch_scheduled_messages(&stranger)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Sent, but not taken off the queue before a restart
+    db_access.create_scheduled_message(&due).await.unwrap();
+    assert_eq!(app.deliver_scheduled_messages().await.unwrap(), 1);
+    let messages = app
+        .fetch_last_messages(&receiver, &ConversationId::Direct(author), None)
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].id, due.id);
+}
+
+#[tokio::test]
+async fn retries_failed_scheduled_deliveries() {
+    let db_access = Db::empty();
+    let auth_service = AuthServiceUsingArgon2::new(db_access.clone(), SecretKey::generate());
+    let app = Messenger::new(
+        db_access.clone(),
+        auth_service,
+        InMemoryMailer::new(),
+        InMemoryBlobStorage::new(),
+    );
+    let author = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let receiver = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let now = chrono::Utc::now();
+    let due = ScheduledMessage {
+        id: uuid::Uuid::new_v4(),
+        from: author,
+        to: ConversationId::Direct(receiver),
+        outgoing: OutgoingMessage::new("Good morning"),
+        send_at: now - TimeDelta::minutes(5),
+        created_at: now - TimeDelta::hours(8),
+        failed_attempts: 0,
+        retry_at: None,
+    };
+    db_access.create_scheduled_message(&due).await.unwrap();
+
+    // Kept in the queue and retried later
+    db_access.fail_message_creations(1).unwrap();
+    assert_eq!(app.deliver_scheduled_messages().await.unwrap(), 0);
+    let scheduled = app.fetch_scheduled_messages(&author).await.unwrap();
+    assert_eq!(scheduled.len(), 1);
+    assert_eq!(scheduled[0].failed_attempts, 1);
+    assert!(scheduled[0].retry_at.unwrap() > now);
+    assert_eq!(app.deliver_scheduled_messages().await.unwrap(), 0);
+
+    // The delay doubles with every failure
+    assert_eq!(delivery_retry_delay(1), DELIVERY_RETRY_DELAY);
+    assert_eq!(delivery_retry_delay(3), DELIVERY_RETRY_DELAY * 4);
+
+    db_access
+        .postpone_scheduled_message(&due.id, now)
+        .await
+        .unwrap();
+    assert_eq!(app.deliver_scheduled_messages().await.unwrap(), 1);
+    let messages = app
+        .fetch_last_messages(&receiver, &ConversationId::Direct(author), None)
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].id, due.id);
+
+    // Dropped after too many failures
+    let failing = ScheduledMessage {
+        id: uuid::Uuid::new_v4(),
+        failed_attempts: MAX_DELIVERY_ATTEMPTS - 1,
+        retry_at: Some(now),
+        ..due
+    };
+    db_access.create_scheduled_message(&failing).await.unwrap();
+    db_access.fail_message_creations(1).unwrap();
+    assert_eq!(app.deliver_scheduled_messages().await.unwrap(), 1);
+    assert!(app
+        .fetch_scheduled_messages(&author)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        app.fetch_last_messages(&receiver, &ConversationId::Direct(author), None)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn blocks_and_mutes_users() {
+    let app = make_app().await;
+    let blocker = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let blocked = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    app.send_message("Hey".into(), blocked, ConversationId::Direct(blocker))
+        .await
+        .unwrap();
+
+    assert!(!app.block_user(&blocker, &blocker).await.unwrap());
+    assert!(!app
+        .block_user(&blocker, &uuid::Uuid::new_v4())
+        .await
+        .unwrap());
+    assert!(app.block_user(&blocker, &blocked).await.unwrap());
+    assert!(app.is_blocked(&blocked, &blocker).await.unwrap());
+    assert_eq!(
+        app.fetch_blocked_users(&blocker).await.unwrap()[0].id,
+        blocked
+    );
+
+    // Neither side can write
+    assert!(app
+        .send_message("Hey?".into(), blocked, ConversationId::Direct(blocker))
+        .await
+        .is_err());
+    assert!(app
+        .send_message("Go away".into(), blocker, ConversationId::Direct(blocked))
+        .await
+        .is_err());
+    assert!(app.fetch_users_chats(&blocker).await.unwrap().is_empty());
+    assert!(app
+        .find_users_by_substring(&blocker, "TestUser_2")
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(app
+        .find_users_by_substring(&blocked, "TestUser_1")
+        .await
+        .unwrap()
+        .is_empty());
+
+    app.unblock_user(&blocker, &blocked).await.unwrap();
+    assert!(!app.is_blocked(&blocker, &blocked).await.unwrap());
+    assert_eq!(app.fetch_users_chats(&blocker).await.unwrap().len(), 1);
+
+    // Muted conversations still receive messages
+    let conversation = ConversationId::Direct(blocked);
+    assert!(app
+        .set_conversation_muted(&blocker, &conversation, true)
+        .await
+        .unwrap());
+    let mut subscription = app.subscribe_to_new_messages(blocker, None).await.unwrap();
+    app.send_message(
+        "Still there?".into(),
+        blocked,
+        ConversationId::Direct(blocker),
+    )
+    .await
+    .unwrap();
+    assert_matches!(subscription.recv().await.unwrap(),
+        Event::NewMessage(message) if message.message == "Still there?");
+    let chats = app.fetch_users_chats(&blocker).await.unwrap();
+    assert!(chats[0].muted);
+    assert_eq!(chats[0].unread_count, 2);
+
+    let outsider_chat = app
+        .create_chat(&blocked, "Закрытая группа", &[])
+        .await
+        .unwrap();
+    assert!(!app
+        .set_conversation_muted(&blocker, &ConversationId::Group(outsider_chat), true)
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn keeps_typing_and_presence_from_blocked_users() {
+    let app = make_app().await;
+    let blocker = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let blocked = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let other = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    app.send_message("Hey".into(), blocked, ConversationId::Direct(blocker))
+        .await
+        .unwrap();
+    let chat = ConversationId::Group(
+        app.create_chat(&other, "Chat", &[blocker, blocked])
+            .await
+            .unwrap(),
+    );
+    app.block_user(&blocker, &blocked).await.unwrap();
+
+    let mut subscription = app.subscribe_to_new_messages(blocker, None).await.unwrap();
+    let _blocked_subscription = app.subscribe_to_new_messages(blocked, None).await.unwrap();
+    let _other_subscription = app.subscribe_to_new_messages(other, None).await.unwrap();
+    assert_matches!(subscription.recv().await.unwrap(),
+        Event::PresenceChanged{ user_id, .. } if user_id == other);
+
+    app.notify_typing(&blocked, &chat).await.unwrap();
+    app.notify_typing(&other, &chat).await.unwrap();
+    assert_matches!(subscription.recv().await.unwrap(),
+        Event::Typing{ user_id, .. } if user_id == other);
+
+    assert_eq!(
+        app.notify_typing(&blocked, &ConversationId::Direct(blocker))
+            .await
+            .unwrap_err()
+            .to_string(),
+        format!("User {blocked} can't send messages to user {blocker}")
+    );
+}
+
+#[tokio::test]
+async fn keeps_blocked_users_out_of_each_others_chats() {
+    let app = make_app().await;
+    let blocker = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let blocked = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    app.block_user(&blocker, &blocked).await.unwrap();
+
+    assert_eq!(
+        app.create_chat(&blocked, "Chat", &[blocker])
+            .await
+            .unwrap_err()
+            .to_string(),
+        format!("User {blocked} can't send messages to user {blocker}")
+    );
+    assert!(app.create_chat(&blocker, "Chat", &[blocked]).await.is_err());
+    assert!(app.fetch_users_chats(&blocker).await.unwrap().is_empty());
+
+    let chat_id = app.create_chat(&blocked, "Chat", &[]).await.unwrap();
+    assert_eq!(
+        app.add_chat_member(&blocked, &chat_id, &blocker)
+            .await
+            .unwrap_err()
+            .to_string(),
+        format!("User {blocked} can't send messages to user {blocker}")
+    );
+    let chat_id = app.create_chat(&blocker, "Chat", &[]).await.unwrap();
+    assert!(app
+        .add_chat_member(&blocker, &chat_id, &blocked)
+        .await
+        .is_err());
+    assert_eq!(
+        app.fetch_chat_members(&blocker, &chat_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn deletes_and_hides_messages() {
+    let app = make_app().await;
+    let author = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let receiver = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let outsider = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let first_id = app
+        .send_message("First".into(), author, ConversationId::Direct(receiver))
+        .await
+        .unwrap();
+    let second_id = app
+        .send_message("Second".into(), author, ConversationId::Direct(receiver))
+        .await
+        .unwrap();
+    let mut receiver_subscription = app.subscribe_to_new_messages(receiver, None).await.unwrap();
+    let mut author_subscription = app.subscribe_to_new_messages(author, None).await.unwrap();
+
+    assert!(!app
+        .delete_message_for_everyone(&receiver, &first_id)
+        .await
+        .unwrap());
+    assert!(app
+        .delete_message_for_everyone(&author, &first_id)
+        .await
+        .unwrap());
+    assert!(!app
+        .delete_message_for_everyone(&author, &first_id)
+        .await
+        .unwrap());
+    assert!(!app
+        .edit_message(&author, &first_id, "Resurrected".into())
+        .await
+        .unwrap());
+    assert_matches!(receiver_subscription.recv().await.unwrap(),
+        Event::PresenceChanged{ user_id, .. } if user_id == author);
+    assert_matches!(receiver_subscription.recv().await.unwrap(),
+        Event::MessageDeleted(Message{ id, message, deleted_at: Some(_), ..}) if id == first_id && message.is_empty());
+    assert_matches!(author_subscription.recv().await.unwrap(),
+        Event::MessageDeleted(Message{ id, .. }) if id == first_id);
+
+    assert!(!app.hide_message(&outsider, &second_id).await.unwrap());
+    assert!(app.hide_message(&receiver, &second_id).await.unwrap());
+    assert_matches!(receiver_subscription.recv().await.unwrap(),
+        Event::MessageHidden{ user_id, message_id } if user_id == receiver && message_id == second_id);
+    assert!(author_subscription.try_recv().is_err());
+
+    let messages = app
+        .fetch_last_messages(&receiver, &ConversationId::Direct(author), None)
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].id, first_id);
+    assert!(messages[0].deleted_at.is_some());
+
+    let messages = app
+        .fetch_last_messages(&author, &ConversationId::Direct(receiver), None)
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].message, "Second");
+}
+
+const PICTURE: &[u8] = include_bytes!("fixtures/picture.png");
+
+#[tokio::test]
+async fn uploads_and_serves_attachments() {
+    let blob_storage = InMemoryBlobStorage::new();
+    let app = make_app_with_blob_storage(blob_storage.clone()).await;
+    let author = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let receiver = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let outsider = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let picture = app
+        .upload_attachment(
+            &author,
+            "../pictures/picture.png",
+            "image/png",
+            PICTURE.to_vec(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(picture.file_name, "picture.png");
+    assert_eq!(picture.size, PICTURE.len() as u64);
+    assert!(picture.has_thumbnail);
+    let document = app
+        .upload_attachment(&author, "notes.txt", "text/plain", b"Notes".to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!document.has_thumbnail);
+    let unsent = app
+        .upload_attachment(&author, "unsent.txt", "text/plain", b"Unsent".to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // The content must match the declared type, which must be allowed
+    assert!(app
+        .upload_attachment(
+            &author,
+            "picture.png",
+            "image/png",
+            b"Not a picture".to_vec()
+        )
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .upload_attachment(&author, "picture.txt", "text/plain", PICTURE.to_vec())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .upload_attachment(&author, "page.html", "text/html", b"<html>".to_vec())
+        .await
+        .unwrap()
+        .is_none());
+
+    // Attachments can only be sent by their uploader
+    assert!(app
+        .send_outgoing_message(
+            outsider,
+            ConversationId::Direct(receiver),
+            OutgoingMessage {
+                attachments: vec![picture.id],
+                ..OutgoingMessage::new("Stolen")
+            },
+        )
+        .await
+        .is_err());
+    let message_id = app
+        .send_outgoing_message(
+            author,
+            ConversationId::Direct(receiver),
+            OutgoingMessage {
+                attachments: vec![picture.id, document.id],
+                ..OutgoingMessage::new("Pictures")
+            },
+        )
+        .await
+        .unwrap();
+    // and only once
+    assert!(app
+        .send_outgoing_message(
+            author,
+            ConversationId::Direct(receiver),
+            OutgoingMessage {
+                attachments: vec![picture.id],
+                ..OutgoingMessage::new("Again")
+            },
+        )
+        .await
+        .is_err());
+
+    let messages = app
+        .fetch_last_messages(&receiver, &ConversationId::Direct(author), None)
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    let attachment_ids: Vec<_> = messages[0].attachments.iter().map(|a| a.id).collect();
+    assert_eq!(attachment_ids, vec![picture.id, document.id]);
+    assert!(messages[0]
+        .attachments
+        .iter()
+        .all(|attachment| attachment.message_id == Some(message_id)));
+
+    let (_, content) = app
+        .fetch_attachment_content(&receiver, &picture.id, false)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(content, PICTURE);
+    let (_, thumbnail) = app
+        .fetch_attachment_content(&receiver, &picture.id, true)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(thumbnail.starts_with(b"\x89PNG"));
+    assert!(app
+        .fetch_attachment_content(&receiver, &document.id, true)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .fetch_attachment_content(&outsider, &picture.id, false)
+        .await
+        .unwrap()
+        .is_none());
+    // Unsent attachments are only available to their uploader
+    assert!(app
+        .fetch_attachment_content(&author, &unsent.id, false)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(app
+        .fetch_attachment_content(&receiver, &unsent.id, false)
+        .await
+        .unwrap()
+        .is_none());
+
+    assert!(app
+        .delete_message_for_everyone(&author, &message_id)
+        .await
+        .unwrap());
+    assert!(app
+        .fetch_attachment_content(&author, &picture.id, false)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(blob_storage.keys().unwrap(), vec![unsent.content_key()]);
+}
+
+#[tokio::test]
+async fn edits_profiles() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let other = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(app
+        .update_profile(&user_id, Some("  Тестовый  "), "О себе")
+        .await
+        .unwrap());
+    let profile = app.fetch_profile(&user_id).await.unwrap().unwrap();
+    assert_eq!(profile.name(), "Тестовый");
+    assert_eq!(profile.bio, "О себе");
+    assert!(!app
+        .update_profile(&user_id, Some(&"a".repeat(101)), "")
+        .await
+        .unwrap());
+    assert!(!app
+        .update_profile(&user_id, None, &"a".repeat(1001))
+        .await
+        .unwrap());
+    // A blank display name falls back to the username
+    assert!(app.update_profile(&user_id, Some(" "), "").await.unwrap());
+    assert_eq!(
+        app.fetch_profile(&user_id).await.unwrap().unwrap().name(),
+        "TestUser_1"
+    );
+
+    let picture = app
+        .upload_attachment(&user_id, "me.png", "image/png", PICTURE.to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+    let document = app
+        .upload_attachment(&user_id, "cv.txt", "text/plain", b"CV".to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+    let others_picture = app
+        .upload_attachment(&other, "other.png", "image/png", PICTURE.to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!app.set_avatar(&user_id, Some(&document.id)).await.unwrap());
+    assert!(!app
+        .set_avatar(&user_id, Some(&others_picture.id))
+        .await
+        .unwrap());
+    assert!(app.fetch_avatar(&user_id).await.unwrap().is_none());
+    assert!(app.set_avatar(&user_id, Some(&picture.id)).await.unwrap());
+    assert!(app.fetch_avatar(&user_id).await.unwrap().is_some());
+    assert!(app.set_avatar(&user_id, None).await.unwrap());
+    assert!(app.fetch_avatar(&user_id).await.unwrap().is_none());
+
+    assert!(!app
+        .change_username(&user_id, "testuser_2", None)
+        .await
+        .unwrap());
+    assert!(app
+        .change_username(&user_id, "two words", None)
+        .await
+        .is_err());
+    assert!(app
+        .change_username(&user_id, "TestUser_Renamed", None)
+        .await
+        .unwrap());
+    let events = app
+        .fetch_user_audit_events(&user_id, None, 10)
+        .await
+        .unwrap();
+    assert_eq!(events[0].kind, AuditEventKind::UsernameChange);
+    assert!(app
+        .verify_user("TestUser_Renamed", "12345".into())
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn deletes_and_exports_accounts() {
+    let blob_storage = InMemoryBlobStorage::new();
+    let app = make_app_with_blob_storage(blob_storage.clone()).await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let other = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let blocked = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let sent = app
+        .upload_attachment(&user_id, "notes.txt", "text/plain", b"Notes".to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+    let unsent = app
+        .upload_attachment(&user_id, "me.png", "image/png", PICTURE.to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+    let outgoing = OutgoingMessage {
+        attachments: vec![sent.id],
+        ..OutgoingMessage::new("Notes for you")
+    };
+    app.send_outgoing_message(user_id, ConversationId::Direct(other), outgoing)
+        .await
+        .unwrap();
+    app.send_message("Thanks".into(), other, ConversationId::Direct(user_id))
+        .await
+        .unwrap();
+    assert!(app.block_user(&user_id, &blocked).await.unwrap());
+
+    let export = app.export_user_data(&user_id).await.unwrap().unwrap();
+    assert_eq!(export.profile.username, "TestUser_1");
+    assert_eq!(
+        export
+            .messages
+            .iter()
+            .map(|message| message.message.as_str())
+            .collect::<Vec<_>>(),
+        vec!["Notes for you", "Thanks"]
+    );
+    assert_eq!(export.conversations.len(), 1);
+    assert_eq!(export.blocked_users, vec![blocked]);
+    assert_eq!(
+        export
+            .attachments
+            .iter()
+            .map(|attachment| attachment.id)
+            .collect::<Vec<_>>(),
+        vec![sent.id, unsent.id]
+    );
+    assert_eq!(export.audit_events[0].kind, AuditEventKind::Signup);
+    assert!(app
+        .export_user_data(&uuid::Uuid::new_v4())
+        .await
+        .unwrap()
+        .is_none());
+
+    assert!(app.delete_account(&user_id, None).await.unwrap());
+    assert!(!app.delete_account(&user_id, None).await.unwrap());
+    assert!(app.fetch_profile(&user_id).await.unwrap().is_none());
+    // The audit log outlives the account
+    let events = app
+        .fetch_user_audit_events(&user_id, None, 10)
+        .await
+        .unwrap();
+    assert_eq!(events[0].kind, AuditEventKind::AccountDeletion);
+    assert!(app
+        .verify_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .is_none());
+    // The unsent picture is erased along with its thumbnail
+    let keys = blob_storage.keys().unwrap();
+    assert!(keys.contains(&sent.content_key()));
+    assert!(!keys.contains(&unsent.content_key()));
+    assert!(!keys.contains(&unsent.thumbnail_key()));
+
+    // The other user keeps the conversation with a placeholder they can't write to
+    let chats = app.fetch_users_chats(&other).await.unwrap();
+    assert_eq!(chats.len(), 1);
+    assert_eq!(chats[0].title, "Удалённый аккаунт");
+    let placeholder = chats[0].id;
+    let messages = app
+        .fetch_last_messages(&other, &placeholder, None)
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[1].attachments[0].id, sent.id);
+    assert!(app
+        .fetch_attachment_content(&other, &sent.id, false)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(app
+        .send_message("Hello?".into(), other, placeholder)
+        .await
+        .is_err());
+    assert!(app
+        .find_users_by_substring(&other, "deleted")
+        .await
+        .unwrap()
+        .is_empty());
+    // The username is free again
+    assert!(app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn rejects_attachments_over_size_limit() {
+    let app = make_app().await.with_attachment_limits(AttachmentLimits {
+        max_size: 100,
+        ..AttachmentLimits::default()
+    });
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(app
+        .upload_attachment(&user_id, "picture.png", "image/png", PICTURE.to_vec())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .upload_attachment(&user_id, "notes.txt", "text/plain", vec![b'a'; 100])
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn tracks_read_receipts() {
+    let app = make_app().await;
+    let author = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let reader = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let outsider = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let first_id = app
+        .send_message("First".into(), author, ConversationId::Direct(reader))
+        .await
+        .unwrap();
+    let second_id = app
+        .send_message("Second".into(), author, ConversationId::Direct(reader))
+        .await
+        .unwrap();
+    let mut author_subscription = app.subscribe_to_new_messages(author, None).await.unwrap();
+
+    let chats = app.fetch_users_chats(&reader).await.unwrap();
+    assert_eq!(chats[0].unread_count, 2);
+
+    assert!(!app.mark_read(&outsider, &second_id).await.unwrap());
+    assert!(app.mark_read(&reader, &second_id).await.unwrap());
+    assert_matches!(author_subscription.recv().await.unwrap(),
+        Event::MessagesRead{ reader: user_id, message } if user_id == reader && message.id == second_id);
+
+    // Markers don't move back
+    assert!(app.mark_read(&reader, &first_id).await.unwrap());
+    assert!(author_subscription.try_recv().is_err());
+
+    let chats = app.fetch_users_chats(&reader).await.unwrap();
+    assert_eq!(chats[0].unread_count, 0);
+    assert_eq!(
+        app.fetch_read_receipts(&author, &ConversationId::Direct(reader))
+            .await
+            .unwrap(),
+        vec![ReadReceipt {
+            user_id: reader,
+            message_id: second_id
+        }]
+    );
+    assert!(app
+        .fetch_read_receipts(&reader, &ConversationId::Direct(author))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn searches_messages_with_snippets() {
+    let app = make_app().await;
+    let user_id_1 = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let user_id_2 = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let long_id = app
+        .send_message(
+            "One two three four five six seven eight: the Quokka is nine ten eleven twelve \
+            thirteen fourteen fifteen sixteen seventeen eighteen nineteen"
+                .into(),
+            user_id_1,
+            ConversationId::Direct(user_id_2),
+        )
+        .await
+        .unwrap();
+    let short_id = app
+        .send_message(
+            "quokka?".into(),
+            user_id_2,
+            ConversationId::Direct(user_id_1),
+        )
+        .await
+        .unwrap();
+
+    let fragment = |text: &str, highlighted| SnippetFragment {
+        text: text.to_owned(),
+        highlighted,
+    };
+    let page = app
+        .search_messages(
+            &user_id_2,
+            &MessageSearchQuery {
+                limit: 1,
+                ..MessageSearchQuery::new("QUOKKA")
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(page.hits.len(), 1);
+    assert_eq!(page.hits[0].message.id, short_id);
+    assert_eq!(
+        page.hits[0].snippet,
+        vec![fragment("quokka", true), fragment("?", false)]
+    );
+
+    let page = app
+        .search_messages(
+            &user_id_2,
+            &MessageSearchQuery {
+                cursor: page.next_cursor,
+                limit: 1,
+                ..MessageSearchQuery::new("QUOKKA")
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(page.hits.len(), 1);
+    assert_eq!(page.hits[0].message.id, long_id);
+    assert_eq!(
+        page.hits[0].snippet,
+        vec![
+            fragment("…six seven eight: the ", false),
+            fragment("Quokka", true),
+            fragment(
+                " is nine ten eleven twelve thirteen fourteen fifteen sixteen seventeen eighteen…",
+                false
+            ),
+        ]
+    );
+    assert_eq!(page.next_cursor, None);
+
+    // Words of the query aren't matched by prefix
+    let page = app
+        .search_messages(&user_id_1, &MessageSearchQuery::new("quok"))
+        .await
+        .unwrap();
+    assert!(page.hits.is_empty());
+    let page = app
+        .search_messages(&user_id_1, &MessageSearchQuery::new(" ?! "))
+        .await
+        .unwrap();
+    assert!(page.hits.is_empty());
+}
+
+#[tokio::test]
+async fn tracks_typing_and_presence() {
+    let app = make_app().await;
+    let user_1 = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let user_2 = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    app.send_message("Hello".into(), user_1, ConversationId::Direct(user_2))
+        .await
+        .unwrap();
+    assert_eq!(
+        app.fetch_presence(&user_1, &user_2).await.unwrap(),
+        Some(Presence::default())
+    );
+
+    let mut subscription_1 = app.subscribe_to_new_messages(user_1, None).await.unwrap();
+    let subscription_2 = app.subscribe_to_new_messages(user_2, None).await.unwrap();
+    assert_matches!(subscription_1.recv().await.unwrap(),
+        Event::PresenceChanged{ user_id, presence: Presence{ online: true, .. }} if user_id == user_2);
+    assert!(
+        app.fetch_presence(&user_1, &user_2)
+            .await
+            .unwrap()
+            .unwrap()
+            .online
+    );
+
+    app.notify_typing(&user_2, &ConversationId::Direct(user_1))
+        .await
+        .unwrap();
+    assert_matches!(subscription_1.recv().await.unwrap(),
+        Event::Typing{ user_id, conversation } if user_id == user_2 && conversation == ConversationId::Direct(user_1));
+    // Throttled
+    app.notify_typing(&user_2, &ConversationId::Direct(user_1))
+        .await
+        .unwrap();
+
+    drop(subscription_2);
+    assert_matches!(subscription_1.recv().await.unwrap(),
+        Event::PresenceChanged{ user_id, presence: Presence{ online: false, last_seen: Some(_) }} if user_id == user_2);
+    assert!(
+        !app.fetch_presence(&user_1, &user_2)
+            .await
+            .unwrap()
+            .unwrap()
+            .online
+    );
+}
+
+#[tokio::test]
+async fn shows_presence_to_contacts_only() {
+    let app = make_app().await;
+    let user_1 = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let user_2 = app
+        .create_user("TestUser_2", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let stranger = app
+        .create_user("TestUser_3", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    app.send_message("Hello".into(), user_1, ConversationId::Direct(user_2))
+        .await
+        .unwrap();
+
+    assert!(app
+        .fetch_presence(&user_2, &user_1)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(app
+        .fetch_presence(&stranger, &user_1)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .fetch_presence(&user_1, &stranger)
+        .await
+        .unwrap()
+        .is_none());
+
+    app.block_user(&user_1, &user_2).await.unwrap();
+    assert!(app
+        .fetch_presence(&user_2, &user_1)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .fetch_presence(&user_1, &user_2)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn authorization_works_correctly() {
+    let app = make_app().await;
+
+    assert!(app
+        .verify_user("User1", "User1".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .verify_user("User2", "12345".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .verify_user("__invalid_user", "12345".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+
+    app.create_user("User1", "User1".to_owned()).await.unwrap();
+
+    assert!(app
+        .verify_user("User1", "User1".to_owned())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(app
+        .verify_user("User2", "12345".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .verify_user("__invalid_user", "12345".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+
+    app.create_user("User2", "User2".to_owned()).await.unwrap();
+
+    assert!(app
+        .verify_user("User1", "User1".to_owned())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(app
+        .verify_user("User2", "12345".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .verify_user("__invalid_user", "12345".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn authorization_ignores_case() {
+    let app = make_app().await;
+
+    assert!(app
+        .verify_user("User1", "User1".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .verify_user("User1", "user1".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .verify_user("user1", "User1".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .verify_user("user1", "user1".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+
+    app.create_user("User1", "User1".to_owned()).await.unwrap();
+
+    assert!(app
+        .verify_user("User1", "User1".to_owned())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(app
+        .verify_user("User1", "user1".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .verify_user("user1", "User1".to_owned())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(app
+        .verify_user("user1", "user1".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn rehashes_passwords_with_outdated_parameters() {
+    let db_access = Db::empty();
+    let secret_key = SecretKey::generate();
+    let old_config = PasswordHashingConfig {
+        memory_cost: 64,
+        time_cost: 1,
+        parallelism: 1,
+        pepper: None,
+    };
+    let new_config = PasswordHashingConfig {
+        memory_cost: 128,
+        time_cost: 2,
+        parallelism: 1,
+        pepper: Some(Pepper::new("1", b"pepper").unwrap()),
+    };
+
+    let old_app = Messenger::new(
+        db_access.clone(),
+        AuthServiceUsingArgon2::new(db_access.clone(), secret_key.clone())
+            .with_password_hashing_config(old_config),
+        InMemoryMailer::new(),
+        InMemoryBlobStorage::new(),
+    );
+    old_app
+        .create_user("user", "password".to_owned())
+        .await
+        .unwrap();
+    let user_id = old_app
+        .verify_user("user", "password".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let old_hash = db_access
+        .fetch_authentication(&user_id)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let new_app = Messenger::new(
+        db_access.clone(),
+        AuthServiceUsingArgon2::new(db_access.clone(), secret_key)
+            .with_password_hashing_config(new_config),
+        InMemoryMailer::new(),
+        InMemoryBlobStorage::new(),
+    );
+    assert!(new_app
+        .verify_user("user", "passw0rd".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        db_access
+            .fetch_authentication(&user_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .phc_string(),
+        old_hash.phc_string()
+    );
+
+    assert!(new_app
+        .verify_user("user", "password".to_owned())
+        .await
+        .unwrap()
+        .is_some());
+    let new_hash = db_access
+        .fetch_authentication(&user_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(new_hash
+        .phc_string()
+        .as_str()
+        .contains("m=128,t=2,p=1,keyid="));
+
+    assert!(new_app
+        .verify_user("user", "password".to_owned())
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(
+        db_access
+            .fetch_authentication(&user_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .phc_string(),
+        new_hash.phc_string()
+    );
+}
+
+#[tokio::test]
+async fn delays_login_after_failed_attempts() {
+    let app = make_app_with_login_throttling(LoginThrottlingConfig {
+        free_attempts: 2,
+        base_delay: TimeDelta::hours(1),
+        ..LoginThrottlingConfig::default()
+    });
+    app.create_user("user", "password".to_owned())
+        .await
+        .unwrap();
+
+    for _ in 0..3 {
+        assert_matches!(
+            app.log_in("user", "passw0rd".to_owned(), None)
+                .await
+                .unwrap(),
+            LoginResult::Failed
+        );
+    }
+
+    // the password isn't even checked during the delay
+    assert_matches!(
+        app.log_in("user", "password".to_owned(), None)
+            .await
+            .unwrap(),
+        LoginResult::Locked { .. }
+    );
+    assert!(app
+        .verify_user("user", "password".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn throttles_parallel_login_attempts() {
+    let app = make_app_with_login_throttling(LoginThrottlingConfig {
+        free_attempts: 2,
+        base_delay: TimeDelta::hours(1),
+        ..LoginThrottlingConfig::default()
+    });
+    app.create_user("user", "password".to_owned())
+        .await
+        .unwrap();
+
+    let attempts: Vec<_> = (0..10)
+        .map(|_| {
+            let app = app.clone();
+            tokio::spawn(async move { app.log_in("user", "passw0rd".to_owned(), None).await })
+        })
+        .collect();
+    let mut failed = 0;
+    for attempt in attempts {
+        match attempt.await.unwrap().unwrap() {
+            LoginResult::Failed => failed += 1,
+            LoginResult::Locked { .. } => {}
+            res => panic!("Unexpected result {res:?}"),
+        }
+    }
+    assert_eq!(failed, 3);
+}
+
+#[tokio::test]
+async fn locks_account_after_too_many_failed_attempts() {
+    let db_access = Db::empty();
+    let app = Messenger::new(
+        db_access.clone(),
+        AuthServiceUsingArgon2::new(db_access.clone(), SecretKey::generate())
+            .with_login_throttling_config(LoginThrottlingConfig {
+                free_attempts: 10,
+                user_lockout_threshold: 3,
+                ..LoginThrottlingConfig::default()
+            }),
+        InMemoryMailer::new(),
+        InMemoryBlobStorage::new(),
+    );
+    let user_id = app
+        .create_user("user", "password".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let client_addr = Some("192.0.2.1".parse().unwrap());
+
+    for _ in 0..2 {
+        assert_matches!(
+            app.log_in("user", "passw0rd".to_owned(), client_addr)
+                .await
+                .unwrap(),
+            LoginResult::Failed
+        );
+    }
+    let locked_until = match app
+        .log_in("user", "passw0rd".to_owned(), client_addr)
+        .await
+        .unwrap()
+    {
+        LoginResult::Locked { until } => until,
+        res => panic!("Account wasn't locked: {res:?}"),
+    };
+    assert!(locked_until > chrono::Utc::now() + TimeDelta::minutes(14));
+    assert_matches!(
+        app.log_in("user", "password".to_owned(), None)
+            .await
+            .unwrap(),
+        LoginResult::Locked { until } if until == locked_until
+    );
+
+    let events: Vec<_> = db_access
+        .fetch_login_events(&user_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|event| (event.kind, event.client_addr))
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            (LoginEventKind::Rejected, None),
+            (LoginEventKind::Lockout, client_addr),
+            (LoginEventKind::Failure, client_addr),
+            (LoginEventKind::Failure, client_addr),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn locks_client_address_guessing_usernames() {
+    let app = make_app_with_login_throttling(LoginThrottlingConfig {
+        client_lockout_threshold: 3,
+        ..LoginThrottlingConfig::default()
+    });
+    app.create_user("user", "password".to_owned())
+        .await
+        .unwrap();
+    let client_addr = Some("192.0.2.1".parse().unwrap());
+    let other_client_addr = Some("192.0.2.2".parse().unwrap());
+
+    for username in ["user1", "user2"] {
+        assert_matches!(
+            app.log_in(username, "password".to_owned(), client_addr)
+                .await
+                .unwrap(),
+            LoginResult::Failed
+        );
+    }
+    assert_matches!(
+        app.log_in("user3", "password".to_owned(), client_addr)
+            .await
+            .unwrap(),
+        LoginResult::Locked { .. }
+    );
+    assert_matches!(
+        app.log_in("user", "password".to_owned(), client_addr)
+            .await
+            .unwrap(),
+        LoginResult::Locked { .. }
+    );
+
+    assert_matches!(
+        app.log_in("user", "password".to_owned(), other_client_addr)
+            .await
+            .unwrap(),
+        LoginResult::Success(_)
+    );
+}
+
+#[tokio::test]
+async fn password_reset_ends_lockout() {
+    let mailer = InMemoryMailer::new();
+    let db_access = Db::empty();
+    let app = Messenger::new(
+        db_access.clone(),
+        AuthServiceUsingArgon2::new(db_access, SecretKey::generate()).with_login_throttling_config(
+            LoginThrottlingConfig {
+                user_lockout_threshold: 1,
+                ..LoginThrottlingConfig::default()
+            },
+        ),
+        mailer.clone(),
+        InMemoryBlobStorage::new(),
+    );
+    let user_id = app
+        .create_user("user", "password".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    app.update_user_email(&user_id, Some("user@example.com"))
+        .await
+        .unwrap();
+
+    assert_matches!(
+        app.log_in("user", "passw0rd".to_owned(), None)
+            .await
+            .unwrap(),
+        LoginResult::Locked { .. }
+    );
+
+    assert!(app.request_password_reset("user").await.unwrap());
+    let token = reset_token_from_mail(&mailer.sent_mail().unwrap()[0].body);
+    app.reset_password(&token, "new password".to_owned(), None)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_matches!(
+        app.log_in("user", "new password".to_owned(), None)
+            .await
+            .unwrap(),
+        LoginResult::Success(_)
+    );
+}
+
+#[tokio::test]
+async fn manages_api_tokens() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("User1", "password".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let other_user_id = app
+        .create_user("User2", "password".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let (api_token, token) = app
+        .create_api_token(
+            &user_id,
+            "bot",
+            &[ApiScope::MessagesRead, ApiScope::MessagesWrite],
+            None,
+        )
+        .await
+        .unwrap();
+    let (expired_api_token, expired_token) = app
+        .create_api_token(
+            &user_id,
+            "expired",
+            &[ApiScope::MessagesRead],
+            Some(chrono::Utc::now() - TimeDelta::seconds(1)),
+        )
+        .await
+        .unwrap();
+    assert_ne!(token, expired_token);
+
+    assert_eq!(
+        app.verify_api_token(&token, ApiScope::MessagesWrite)
+            .await
+            .unwrap(),
+        Some(user_id)
+    );
+    assert_eq!(
+        app.verify_api_token(&expired_token, ApiScope::MessagesRead)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        app.verify_api_token(&token[..token.len() - 1], ApiScope::MessagesRead)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        app.fetch_api_tokens(&user_id).await.unwrap(),
+        vec![api_token.clone(), expired_api_token]
+    );
+
+    assert!(!app
+        .revoke_api_token(&other_user_id, &api_token.id)
+        .await
+        .unwrap());
+    assert!(app.revoke_api_token(&user_id, &api_token.id).await.unwrap());
+    assert_eq!(
+        app.verify_api_token(&token, ApiScope::MessagesRead)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(app.fetch_api_tokens(&user_id).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn resets_password_with_emailed_token() {
+    let mailer = InMemoryMailer::new();
+    let app = make_app_with_mailer(mailer.clone()).await;
+
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    app.update_user_email(&user_id, Some("test_user_1@example.com"))
+        .await
+        .unwrap();
+
+    assert!(app.request_password_reset("testuser_1").await.unwrap());
+
+    let sent_mail = mailer.sent_mail().unwrap();
+    assert_eq!(sent_mail.len(), 1);
+    assert_eq!(&sent_mail[0].to, "test_user_1@example.com");
+    let token = reset_token_from_mail(&sent_mail[0].body);
+
+    assert_eq!(
+        app.reset_password(&token, "54321".into(), None)
+            .await
+            .unwrap(),
+        Some(user_id)
+    );
+
+    assert!(app
+        .verify_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        app.verify_user("TestUser_1", "54321".into()).await.unwrap(),
+        Some(user_id)
+    );
+}
+
+#[tokio::test]
+async fn password_reset_tokens_are_single_use() {
+    let mailer = InMemoryMailer::new();
+    let app = make_app_with_mailer(mailer.clone()).await;
+
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    app.update_user_email(&user_id, Some("test_user_1@example.com"))
+        .await
+        .unwrap();
+
+    app.request_password_reset("TestUser_1").await.unwrap();
+    app.request_password_reset("TestUser_1").await.unwrap();
+    let sent_mail = mailer.sent_mail().unwrap();
+    let first_token = reset_token_from_mail(&sent_mail[0].body);
+    let second_token = reset_token_from_mail(&sent_mail[1].body);
+
+    assert!(app
+        .reset_password(&second_token, "54321".into(), None)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(app
+        .reset_password(&second_token, "00000".into(), None)
+        .await
+        .unwrap()
+        .is_none());
+    // using any token invalidates all other tokens of the user
+    assert!(app
+        .reset_password(&first_token, "00000".into(), None)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(app
+        .reset_password("__invalid_token", "00000".into(), None)
+        .await
+        .unwrap()
+        .is_none());
+
+    assert!(app
+        .verify_user("TestUser_1", "54321".into())
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn doesnt_send_password_reset_without_email() {
+    let mailer = InMemoryMailer::new();
+    let app = make_app_with_mailer(mailer.clone()).await;
+
+    app.create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(!app.request_password_reset("TestUser_1").await.unwrap());
+    assert!(!app.request_password_reset("__invalid_user").await.unwrap());
+    assert!(mailer.sent_mail().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn hides_password_reset_mail_failures() {
+    let db_access = Db::empty();
+    let auth_service = AuthServiceUsingArgon2::new(db_access.clone(), SecretKey::generate());
+    let app = Messenger::new(
+        db_access,
+        auth_service,
+        FailingMailer,
+        InMemoryBlobStorage::new(),
+    );
+
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    app.update_user_email(&user_id, Some("test_user_1@example.com"))
+        .await
+        .unwrap();
+
+    // Same as for an unknown user rather than an error
+    assert!(!app.request_password_reset("TestUser_1").await.unwrap());
+    assert!(!app.request_password_reset("__invalid_user").await.unwrap());
+}
+
+#[tokio::test]
+async fn requires_confirmation_before_enabling_totp() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let enrollment = app.start_totp_enrollment(&user_id).await.unwrap().unwrap();
+    assert!(enrollment
+        .uri
+        .starts_with("otpauth://totp/Pheidippides:TestUser_1?secret="));
+    assert!(!app.second_factor_enabled(&user_id).await.unwrap());
+
+    assert!(app
+        .confirm_totp_enrollment(&user_id, "000000")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!app.second_factor_enabled(&user_id).await.unwrap());
+
+    let secret = totp::base32_decode(&enrollment.secret).unwrap();
+    let step = totp::time_step(chrono::Utc::now().timestamp());
+    let recovery_codes = app
+        .confirm_totp_enrollment(&user_id, &totp::code(&secret, step))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(recovery_codes.len(), 10);
+    assert!(app.second_factor_enabled(&user_id).await.unwrap());
+}
+
+#[tokio::test]
+async fn verifies_second_factor_codes_once() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let enrollment = app.start_totp_enrollment(&user_id).await.unwrap().unwrap();
+    let secret = totp::base32_decode(&enrollment.secret).unwrap();
+    let step = totp::time_step(chrono::Utc::now().timestamp());
+    let recovery_codes = app
+        .confirm_totp_enrollment(&user_id, &totp::code(&secret, step))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // the code used for confirmation can't be replayed
+    assert!(!app
+        .verify_second_factor(&user_id, &totp::code(&secret, step))
+        .await
+        .unwrap());
+    assert!(app
+        .verify_second_factor(&user_id, &totp::code(&secret, step + 1))
+        .await
+        .unwrap());
+    assert!(!app
+        .verify_second_factor(&user_id, &totp::code(&secret, step + 1))
+        .await
+        .unwrap());
+
+    assert!(app
+        .verify_second_factor(&user_id, &recovery_codes[0].to_uppercase())
+        .await
+        .unwrap());
+    assert!(!app
+        .verify_second_factor(&user_id, &recovery_codes[0])
+        .await
+        .unwrap());
+
+    assert!(!app.disable_totp(&user_id, "000000").await.unwrap());
+    assert!(app.second_factor_enabled(&user_id).await.unwrap());
+    assert!(app
+        .disable_totp(&user_id, &recovery_codes[1])
+        .await
+        .unwrap());
+    assert!(!app.second_factor_enabled(&user_id).await.unwrap());
+    assert!(!app
+        .verify_second_factor(&user_id, &recovery_codes[2])
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn locks_account_after_too_many_wrong_second_factor_codes() {
+    let app = make_app_with_login_throttling(LoginThrottlingConfig {
+        free_attempts: 10,
+        user_lockout_threshold: 3,
+        ..LoginThrottlingConfig::default()
+    });
+    let user_id = app
+        .create_user("user", "password".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let enrollment = app.start_totp_enrollment(&user_id).await.unwrap().unwrap();
+    let secret = totp::base32_decode(&enrollment.secret).unwrap();
+    let step = totp::time_step(chrono::Utc::now().timestamp());
+    let recovery_codes = app
+        .confirm_totp_enrollment(&user_id, &totp::code(&secret, step))
+        .await
+        .unwrap()
+        .unwrap();
+    let wrong_code = if totp::code(&secret, step) == "000000" {
+        "111111"
+    } else {
+        "000000"
+    };
+
+    // Failures are forgotten once a login is complete
+    for _ in 0..2 {
+        assert_matches!(
+            app.log_in_second_factor(&user_id, wrong_code, None)
+                .await
+                .unwrap(),
+            LoginResult::Failed
+        );
+    }
+    assert_matches!(
+        app.log_in_second_factor(&user_id, &recovery_codes[0], None)
+            .await
+            .unwrap(),
+        LoginResult::Success(_)
+    );
+
+    // Every correct password starts a new pending login,
+    // but it doesn't reset the failures of the second factor
+    for _ in 0..2 {
+        assert_matches!(
+            app.log_in("user", "password".to_owned(), None)
+                .await
+                .unwrap(),
+            LoginResult::Success(_)
+        );
+        assert_matches!(
+            app.log_in_second_factor(&user_id, wrong_code, None)
+                .await
+                .unwrap(),
+            LoginResult::Failed
+        );
+    }
+    assert_matches!(
+        app.log_in("user", "password".to_owned(), None)
+            .await
+            .unwrap(),
+        LoginResult::Success(_)
+    );
+    let locked_until = match app
+        .log_in_second_factor(&user_id, wrong_code, None)
+        .await
+        .unwrap()
+    {
+        LoginResult::Locked { until } => until,
+        res => panic!("Account wasn't locked: {res:?}"),
+    };
+
+    // Neither codes nor passwords are checked during the lockout
+    assert_matches!(
+        app.log_in_second_factor(&user_id, &totp::code(&secret, step + 1), None)
+            .await
+            .unwrap(),
+        LoginResult::Locked { until } if until == locked_until
+    );
+    assert_matches!(
+        app.log_in("user", "password".to_owned(), None)
+            .await
+            .unwrap(),
+        LoginResult::Locked { until } if until == locked_until
+    );
+}
+
+#[tokio::test]
+async fn records_authentication_events() {
+    let mailer = InMemoryMailer::new();
+    let app = make_app_with_mailer(mailer.clone()).await;
+    let client_addr: IpAddr = "192.0.2.1".parse().unwrap();
+
+    let user_id = app
+        .sign_up("TestUser_1", "12345".into(), Some(client_addr))
+        .await
+        .unwrap()
+        .unwrap();
+    app.log_in("TestUser_2", "12345".into(), Some(client_addr))
+        .await
+        .unwrap();
+    app.log_in("TestUser_1", "00000".into(), Some(client_addr))
+        .await
+        .unwrap();
+    app.log_in("TestUser_1", "12345".into(), Some(client_addr))
+        .await
+        .unwrap();
+
+    app.update_user_email(&user_id, Some("test_user_1@example.com"))
+        .await
+        .unwrap();
+    app.request_password_reset("TestUser_1").await.unwrap();
+    let token = reset_token_from_mail(&mailer.sent_mail().unwrap()[0].body);
+    app.reset_password(&token, "54321".into(), None)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let events = app
+        .fetch_user_audit_events(&user_id, None, 10)
+        .await
+        .unwrap();
+    let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            AuditEventKind::PasswordChange,
+            AuditEventKind::LoginSuccess,
+            AuditEventKind::LoginFailure,
+            AuditEventKind::Signup,
+        ]
+    );
+    assert_eq!(events[0].client_addr, None);
+    assert_eq!(events[1].client_addr, Some(client_addr));
+
+    // failures with unknown usernames are recorded without a user
+    let admin_app = app.clone().with_admins(vec![user_id]);
+    let failures = admin_app
+        .query_audit_events(
+            &user_id,
+            &AuditEventQuery {
+                kind: Some(AuditEventKind::LoginFailure),
+                client_addr: Some(client_addr),
+                ..AuditEventQuery::new(10)
+            },
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(failures.len(), 2);
+    assert_eq!(failures[0].user_id, Some(user_id));
+    assert_eq!(failures[1].user_id, None);
+}
+
+#[tokio::test]
+async fn records_login_success_after_second_factor() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let enrollment = app.start_totp_enrollment(&user_id).await.unwrap().unwrap();
+    let secret = totp::base32_decode(&enrollment.secret).unwrap();
+    let step = totp::time_step(chrono::Utc::now().timestamp());
+    let recovery_codes = app
+        .confirm_totp_enrollment(&user_id, &totp::code(&secret, step))
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_matches!(
+        app.log_in("TestUser_1", "12345".into(), None)
+            .await
+            .unwrap(),
+        LoginResult::Success(_)
+    );
+    assert_matches!(
+        app.log_in_second_factor(&user_id, "000000", None)
+            .await
+            .unwrap(),
+        LoginResult::Failed
+    );
+    assert_matches!(
+        app.log_in_second_factor(&user_id, &recovery_codes[0], None)
+            .await
+            .unwrap(),
+        LoginResult::Success(id) if id == user_id
+    );
+
+    let kinds: Vec<_> = app
+        .fetch_user_audit_events(&user_id, None, 10)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|event| event.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            AuditEventKind::LoginSuccess,
+            AuditEventKind::LoginFailure,
+            AuditEventKind::Signup,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn paginates_audit_log() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    for _ in 0..4 {
+        app.log_in("TestUser_1", "12345".into(), None)
+            .await
+            .unwrap();
+    }
+
+    let first_page = app
+        .fetch_user_audit_events(&user_id, None, 3)
+        .await
+        .unwrap();
+    assert_eq!(first_page.len(), 3);
+    let second_page = app
+        .fetch_user_audit_events(&user_id, Some(first_page[2].id), 3)
+        .await
+        .unwrap();
+    assert_eq!(second_page.len(), 2);
+    assert!(second_page[0].id < first_page[2].id);
+    assert_eq!(second_page[1].kind, AuditEventKind::Signup);
+}
+
+#[tokio::test]
+async fn only_admins_query_audit_log() {
+    let app = make_app().await;
+    let admin_id = app
+        .create_user("Admin", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let app = app.with_admins(vec![admin_id]);
+
+    let query = AuditEventQuery {
+        user_id: Some(user_id),
+        ..AuditEventQuery::new(10)
+    };
+    assert!(app
+        .query_audit_events(&user_id, &query)
+        .await
+        .unwrap()
+        .is_none());
+
+    let events = app
+        .query_audit_events(&admin_id, &query)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, AuditEventKind::Signup);
+}
+
+fn reset_token_from_mail(body: &str) -> String {
+    body.split_once("password reset page: ")
+        .unwrap()
+        .1
+        .split_whitespace()
+        .next()
+        .unwrap()
+        .to_owned()
+}
+
+#[derive(Clone)]
+struct FailingMailer;
+
+impl Mailer for FailingMailer {
+    type Error = std::io::Error;
+
+    async fn send_mail(&self, _mail: Mail) -> Result<(), Self::Error> {
+        Err(std::io::Error::other("mail server is down"))
+    }
+}
+
+async fn make_app() -> Messenger<Db, AuthServiceUsingArgon2<Db>, InMemoryMailer, InMemoryBlobStorage>
+{
+    make_app_with_mailer(InMemoryMailer::new()).await
+}
+
+fn make_app_with_login_throttling(
+    config: LoginThrottlingConfig,
+) -> Messenger<Db, AuthServiceUsingArgon2<Db>, InMemoryMailer, InMemoryBlobStorage> {
+    let db_access = Db::empty();
+    let auth_service = AuthServiceUsingArgon2::new(db_access.clone(), SecretKey::generate())
+        .with_login_throttling_config(config);
+
+    Messenger::new(
+        db_access,
+        auth_service,
+        InMemoryMailer::new(),
+        InMemoryBlobStorage::new(),
+    )
+}
+
+async fn make_app_with_blob_storage(
+    blob_storage: InMemoryBlobStorage,
+) -> Messenger<Db, AuthServiceUsingArgon2<Db>, InMemoryMailer, InMemoryBlobStorage> {
+    let db_access = Db::empty();
+    let auth_service = AuthServiceUsingArgon2::new(db_access.clone(), SecretKey::generate());
+
+    Messenger::new(db_access, auth_service, InMemoryMailer::new(), blob_storage)
 }
 
-async fn make_app() -> Messenger<Db, AuthServiceUsingArgon2<Db>> {
+async fn make_app_with_mailer(
+    mailer: InMemoryMailer,
+) -> Messenger<Db, AuthServiceUsingArgon2<Db>, InMemoryMailer, InMemoryBlobStorage> {
     let db_access = Db::empty();
-    let auth_service = AuthServiceUsingArgon2::new(db_access.clone());
+    let auth_service = AuthServiceUsingArgon2::new(db_access.clone(), SecretKey::generate());
 
-    Messenger::new(db_access, auth_service)
+    Messenger::new(db_access, auth_service, mailer, InMemoryBlobStorage::new())
 }
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+use http_server::method::Method;
+use http_server::request::Request;
+use http_server::response::Response;
+use http_server::server::RequestHandler;
+use pheidippides_blob::{FsBlobStorage, InMemoryBlobStorage, S3BlobStorage};
+use pheidippides_messenger::blob_storage::BlobStorage;
+use pheidippides_utils::utils::CaseInsensitiveString;
+
+const BUCKET: &str = "attachments";
+const ACCESS_KEY_ID: &str = "test-access-key";
+const SECRET_ACCESS_KEY: &str = "test-secret-key";
+
+#[tokio::test]
+async fn fs_blob_storage_works() {
+    let directory = tempfile::tempdir().unwrap();
+    let blob_storage = FsBlobStorage::new(directory.path());
+    stores_blobs(&blob_storage).await;
+    assert!(directory.path().join("thumbnails/1").exists());
+}
+
+#[tokio::test]
+async fn in_memory_blob_storage_works() {
+    stores_blobs(&InMemoryBlobStorage::new()).await;
+}
+
+#[tokio::test]
+async fn s3_blob_storage_works() {
+    let s3 = MockS3::start().await;
+    let blob_storage = S3BlobStorage::new(
+        &s3.endpoint,
+        BUCKET,
+        "us-east-1",
+        ACCESS_KEY_ID,
+        SECRET_ACCESS_KEY,
+    )
+    .unwrap();
+    stores_blobs(&blob_storage).await;
+    assert_eq!(
+        s3.objects.lock().unwrap().keys().collect::<Vec<_>>(),
+        vec!["thumbnails/1"]
+    );
+}
+
+#[tokio::test]
+async fn s3_blob_storage_reports_errors() {
+    let s3 = MockS3::start().await;
+    let blob_storage = S3BlobStorage::new(
+        &s3.endpoint,
+        BUCKET,
+        "us-east-1",
+        "unknown-access-key",
+        SECRET_ACCESS_KEY,
+    )
+    .unwrap();
+    assert!(blob_storage
+        .put("attachments/1", b"content".to_vec())
+        .await
+        .is_err());
+    assert!(blob_storage.get("attachments/1").await.is_err());
+}
+
+async fn stores_blobs(blob_storage: &impl BlobStorage) {
+    assert!(blob_storage.get("attachments/1").await.unwrap().is_none());
+
+    blob_storage
+        .put("attachments/1", b"content".to_vec())
+        .await
+        .unwrap();
+    blob_storage
+        .put("thumbnails/1", b"thumbnail".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(
+        blob_storage.get("attachments/1").await.unwrap(),
+        Some(b"content".to_vec())
+    );
+
+    blob_storage
+        .put("attachments/1", b"replaced".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(
+        blob_storage.get("attachments/1").await.unwrap(),
+        Some(b"replaced".to_vec())
+    );
+
+    blob_storage.delete("attachments/1").await.unwrap();
+    // Deleting a missing blob is fine
+    blob_storage.delete("attachments/1").await.unwrap();
+    assert!(blob_storage.get("attachments/1").await.unwrap().is_none());
+
+    // Keys can't escape the storage
+    assert!(blob_storage
+        .put("../attachments/1", b"content".to_vec())
+        .await
+        .is_err());
+    assert!(blob_storage
+        .put("/etc/passwd", b"content".to_vec())
+        .await
+        .is_err());
+}
+
+/// Keeps objects of a single bucket in memory. Checks the credential and the payload hash
+/// of requests, the signature itself is covered by the unit tests of the client
+#[derive(Clone)]
+struct MockS3 {
+    endpoint: String,
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MockS3 {
+    async fn start() -> Self {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{port}");
+
+        let s3 = MockS3 {
+            endpoint: format!("http://{addr}"),
+            objects: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        let server_addr = addr.clone();
+        let request_handler = s3.clone();
+        tokio::spawn(async move {
+            http_server::server::run_server(&server_addr, request_handler, CancellationToken::new())
+                .await
+                .unwrap()
+        });
+        while TcpStream::connect(&addr).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+
+        s3
+    }
+}
+
+impl RequestHandler<Request<TcpStream>> for MockS3 {
+    type Error = Infallible;
+
+    async fn handle(self, request: &mut Request<TcpStream>) -> Result<Response, Self::Error> {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(&CaseInsensitiveString::from(name))
+                .cloned()
+                .unwrap_or_default()
+        };
+        let credential = format!("AWS4-HMAC-SHA256 Credential={ACCESS_KEY_ID}/");
+        if !header("Authorization").starts_with(&credential) {
+            return Ok(Response::BadRequest);
+        }
+        let payload_hash = header("x-amz-content-sha256");
+
+        let key = match request
+            .url()
+            .strip_prefix('/')
+            .and_then(|path| path.split_once('/'))
+        {
+            Some((BUCKET, key)) => key.to_owned(),
+            _ => return Ok(Response::NotFound),
+        };
+
+        let response = match request.method() {
+            Method::Put => {
+                let content = request.content_bytes().await.unwrap();
+                if hex(&Sha256::digest(&content)) != payload_hash {
+                    return Ok(Response::BadRequest);
+                }
+                self.objects.lock().unwrap().insert(key, content);
+                Response::Empty
+            }
+            Method::Get => match self.objects.lock().unwrap().get(&key) {
+                Some(content) => Response::Bytes {
+                    content: content.clone(),
+                    content_type: "application/octet-stream".to_owned(),
+                    headers: Vec::new(),
+                },
+                None => Response::NotFound,
+            },
+            Method::Delete => match self.objects.lock().unwrap().remove(&key) {
+                Some(_) => Response::Empty,
+                None => Response::NotFound,
+            },
+            _ => Response::BadRequest,
+        };
+        Ok(response)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
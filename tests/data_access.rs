@@ -1,8 +1,26 @@
+use std::net::IpAddr;
 use std::time::Duration;
 use uuid::uuid;
 
+use chrono::{DurationRound, TimeDelta, Utc};
+use pheidippides_auth::{
+    AuditLogStorage, AuthStorage, LoginEvent, LoginEventKind, LoginFailures, LoginThrottleKey,
+};
+use pheidippides_messenger::attachments::Attachment;
+use pheidippides_messenger::audit_log::{AuditEvent, AuditEventKind, AuditEventQuery};
+use pheidippides_messenger::authorization::{ApiScope, ApiToken};
+use pheidippides_messenger::chats::{Chat, ChatMember, ChatRole, Conversation};
 use pheidippides_messenger::data_access::DataAccess;
-use pheidippides_messenger::Message;
+use pheidippides_messenger::notifications::{Notification, NotificationKind};
+use pheidippides_messenger::pins::PinnedMessage;
+use pheidippides_messenger::profiles::Profile;
+use pheidippides_messenger::reactions::Reaction;
+use pheidippides_messenger::scheduled::ScheduledMessage;
+use pheidippides_messenger::search::MessageSearchQuery;
+use pheidippides_messenger::stars::StarredMessage;
+use pheidippides_messenger::{
+    ConversationId, ForwardedFrom, Message, MessageId, MessageRevision, OutgoingMessage,
+};
 
 #[macro_export]
 macro_rules! db_access_tests {
@@ -12,6 +30,28 @@ macro_rules! db_access_tests {
         $tester! {it_creates_message}
         $tester! {fetches_last_messages}
         $tester! {fetches_users_messages_since}
+        $tester! {stores_group_chats}
+        $tester! {stores_message_revisions}
+        $tester! {deletes_and_hides_messages}
+        $tester! {stores_read_markers}
+        $tester! {stores_attachments}
+        $tester! {searches_messages}
+        $tester! {stores_replies}
+        $tester! {stores_forwarded_messages}
+        $tester! {stores_reactions}
+        $tester! {stores_pins_and_stars}
+        $tester! {purges_expired_messages}
+        $tester! {stores_scheduled_messages}
+        $tester! {stores_mentions_and_notifications}
+        $tester! {stores_blocks_and_mutes}
+        $tester! {stores_profiles}
+        $tester! {deletes_users}
+        $tester! {updates_user_email}
+        $tester! {tracks_login_failures}
+        $tester! {records_login_events}
+        $tester! {stores_api_tokens}
+        $tester! {stores_external_identities}
+        $tester! {stores_audit_events}
     };
 }
 
@@ -64,9 +104,16 @@ pub async fn it_creates_message(db_access: &impl DataAccess) {
     let message = Message {
         id: uuid!("5b438594-81bc-48ce-8694-e2c14dcd45dc"),
         from: sender_id,
-        to: receiver_id,
+        to: ConversationId::Direct(receiver_id),
         message: "Test message".to_owned(),
         timestamp: chrono::Utc::now(),
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
     };
 
     db_access.create_message(&message).await.unwrap();
@@ -140,16 +187,23 @@ pub async fn fetches_last_messages(db_access: &impl DataAccess) {
             .create_message(&Message {
                 id,
                 from,
-                to,
+                to: ConversationId::Direct(to),
                 message: msg.to_owned(),
                 timestamp,
+                edited_at: None,
+                deleted_at: None,
+                expires_at: None,
+                attachments: vec![],
+                reply_to: None,
+                forwarded_from: None,
+                reactions: vec![],
             })
             .await
             .unwrap();
     }
 
     let mut last_messages = db_access
-        .fetch_last_messages_in_chat(&user_1, &user_2, None)
+        .fetch_last_messages_in_chat(&user_1, &ConversationId::Direct(user_2), None)
         .await
         .unwrap()
         .into_iter();
@@ -161,7 +215,7 @@ pub async fn fetches_last_messages(db_access: &impl DataAccess) {
     assert_eq!(last_messages.next(), None);
 
     let mut last_messages = db_access
-        .fetch_last_messages_in_chat(&user_3, &user_2, None)
+        .fetch_last_messages_in_chat(&user_3, &ConversationId::Direct(user_2), None)
         .await
         .unwrap()
         .into_iter();
@@ -171,7 +225,7 @@ pub async fn fetches_last_messages(db_access: &impl DataAccess) {
     assert_eq!(last_messages.next(), None);
 
     let mut last_messages = db_access
-        .fetch_last_messages_in_chat(&user_1, &user_2, Some(&starting_id))
+        .fetch_last_messages_in_chat(&user_1, &ConversationId::Direct(user_2), Some(&starting_id))
         .await
         .unwrap()
         .into_iter();
@@ -247,9 +301,16 @@ pub async fn fetches_users_messages_since(db_access: &impl DataAccess) {
             .create_message(&Message {
                 id,
                 from,
-                to,
+                to: ConversationId::Direct(to),
                 message: msg.to_owned(),
                 timestamp,
+                edited_at: None,
+                deleted_at: None,
+                expires_at: None,
+                attachments: vec![],
+                reply_to: None,
+                forwarded_from: None,
+                reactions: vec![],
             })
             .await
             .unwrap();
@@ -267,6 +328,2379 @@ pub async fn fetches_users_messages_since(db_access: &impl DataAccess) {
     assert_eq!(users_messages_since.next(), None);
 }
 
+pub async fn stores_group_chats(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let user_3 = db_access.create_user("__User_3").await.unwrap().unwrap();
+    // postgres stores microseconds
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+
+    let chat = Chat {
+        id: uuid!("0b7c1d2e-5f0a-4c8e-9a51-3c6f0e2d7b14"),
+        title: "Группа".to_owned(),
+        created_at: now,
+    };
+    let group = ConversationId::Group(chat.id);
+    db_access.create_chat(&chat, &user_1).await.unwrap();
+    db_access
+        .update_chat_member(&chat.id, &user_2, ChatRole::Member)
+        .await
+        .unwrap();
+    db_access
+        .update_chat_member(&chat.id, &user_2, ChatRole::Admin)
+        .await
+        .unwrap();
+    db_access
+        .rename_chat(&chat.id, "Новая группа")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        db_access.fetch_chat(&chat.id).await.unwrap(),
+        Some(Chat {
+            title: "Новая группа".to_owned(),
+            ..chat.clone()
+        })
+    );
+    assert_eq!(
+        db_access.fetch_chat_members(&chat.id).await.unwrap(),
+        vec![
+            ChatMember {
+                user_id: user_1,
+                role: ChatRole::Owner
+            },
+            ChatMember {
+                user_id: user_2,
+                role: ChatRole::Admin
+            },
+        ]
+    );
+
+    let messages = [
+        (
+            uuid!("9d3f6a2b-7c1e-4b0d-8f5a-2e6c9b1d4a73"),
+            user_1,
+            ConversationId::Direct(user_3),
+            "Message 1",
+        ),
+        (
+            uuid!("3e8a1c5d-2b7f-4d9e-a6c0-5f1b8e3d2c94"),
+            user_2,
+            group,
+            "Message 2",
+        ), // starting point
+        (
+            uuid!("c4b9e2f1-6a3d-4e8c-b7f2-9d0a5c1e6b38"),
+            user_1,
+            group,
+            "Message 3",
+        ),
+        (
+            uuid!("e1d5c8a4-9f2b-4a6e-8c3d-7b0f2e5a9d16"),
+            user_2,
+            ConversationId::Direct(user_3),
+            "Message 4",
+        ),
+    ];
+    let mut timestamp = now;
+    for (id, from, to, msg) in messages {
+        timestamp += Duration::from_secs(1);
+        db_access
+            .create_message(&Message {
+                id,
+                from,
+                to,
+                message: msg.to_owned(),
+                timestamp,
+                edited_at: None,
+                deleted_at: None,
+                expires_at: None,
+                attachments: vec![],
+                reply_to: None,
+                forwarded_from: None,
+                reactions: vec![],
+            })
+            .await
+            .unwrap();
+    }
+
+    let last_messages: Vec<_> = db_access
+        .fetch_last_messages_in_chat(&user_2, &group, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| message.message)
+        .collect();
+    assert_eq!(last_messages, vec!["Message 3", "Message 2"]);
+
+    let starting_id = uuid!("3e8a1c5d-2b7f-4d9e-a6c0-5f1b8e3d2c94");
+    let messages_since: Vec<_> = db_access
+        .fetch_users_messages_since(&user_1, &starting_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| message.message)
+        .collect();
+    assert_eq!(messages_since, vec!["Message 3"]);
+    // Group messages are only delivered to members
+    let messages_since: Vec<_> = db_access
+        .fetch_users_messages_since(&user_3, &starting_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| message.message)
+        .collect();
+    assert_eq!(messages_since, vec!["Message 4"]);
+
+    assert_eq!(
+        db_access.find_users_chats(&user_2).await.unwrap(),
+        vec![
+            Conversation {
+                id: ConversationId::Direct(user_3),
+                title: "__User_3".to_owned(),
+                unread_count: 0,
+                muted: false,
+                avatar: None,
+            },
+            Conversation {
+                id: group,
+                title: "Новая группа".to_owned(),
+                unread_count: 1,
+                muted: false,
+                avatar: None,
+            },
+        ]
+    );
+
+    db_access
+        .delete_chat_member(&chat.id, &user_2)
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access.fetch_chat_members(&chat.id).await.unwrap().len(),
+        1
+    );
+    assert_eq!(
+        db_access.find_users_chats(&user_2).await.unwrap(),
+        vec![Conversation {
+            id: ConversationId::Direct(user_3),
+            title: "__User_3".to_owned(),
+            unread_count: 0,
+            muted: false,
+            avatar: None,
+        }]
+    );
+}
+
+pub async fn stores_message_revisions(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    // postgres stores microseconds
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let message = Message {
+        id: uuid!("7f2c9e41-3b8d-4a6f-9e05-1d4c8b2a6f37"),
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        message: "Message 1".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    db_access.create_message(&message).await.unwrap();
+    assert_eq!(
+        db_access.fetch_message(&message.id).await.unwrap(),
+        Some(message.clone())
+    );
+    assert!(db_access
+        .fetch_message(&uuid!("5d1e8f3a-9c2b-4e7d-a0f6-3b9c7e1d5a28"))
+        .await
+        .unwrap()
+        .is_none());
+
+    let first_edit = now + TimeDelta::seconds(1);
+    let second_edit = now + TimeDelta::seconds(2);
+    db_access
+        .edit_message(&message.id, "Message 2", first_edit)
+        .await
+        .unwrap();
+    db_access
+        .edit_message(&message.id, "Message 3", second_edit)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        db_access.fetch_message(&message.id).await.unwrap(),
+        Some(Message {
+            message: "Message 3".to_owned(),
+            edited_at: Some(second_edit),
+            ..message.clone()
+        })
+    );
+    assert_eq!(
+        db_access
+            .fetch_message_revisions(&message.id)
+            .await
+            .unwrap(),
+        vec![
+            MessageRevision {
+                message: "Message 1".to_owned(),
+                created_at: now,
+            },
+            MessageRevision {
+                message: "Message 2".to_owned(),
+                created_at: first_edit,
+            },
+        ]
+    );
+}
+
+pub async fn deletes_and_hides_messages(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    // postgres stores microseconds
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let messages: Vec<_> = (0..3)
+        .map(|i| Message {
+            id: uuid::Uuid::new_v4(),
+            from: user_1,
+            to: ConversationId::Direct(user_2),
+            message: format!("Message {i}"),
+            timestamp: now + TimeDelta::seconds(i),
+            edited_at: None,
+            deleted_at: None,
+            expires_at: None,
+            attachments: vec![],
+            reply_to: None,
+            forwarded_from: None,
+            reactions: vec![],
+        })
+        .collect();
+    for message in &messages {
+        db_access.create_message(message).await.unwrap();
+    }
+    db_access
+        .edit_message(&messages[1].id, "Edited", now + TimeDelta::seconds(5))
+        .await
+        .unwrap();
+
+    let deleted_at = now + TimeDelta::seconds(10);
+    db_access
+        .delete_message(&messages[1].id, deleted_at)
+        .await
+        .unwrap();
+    let tombstone = Message {
+        message: String::new(),
+        edited_at: Some(now + TimeDelta::seconds(5)),
+        deleted_at: Some(deleted_at),
+        ..messages[1].clone()
+    };
+    assert_eq!(
+        db_access.fetch_message(&messages[1].id).await.unwrap(),
+        Some(tombstone.clone())
+    );
+    assert!(db_access
+        .fetch_message_revisions(&messages[1].id)
+        .await
+        .unwrap()
+        .is_empty());
+
+    db_access
+        .hide_message(&user_2, &messages[2].id)
+        .await
+        .unwrap();
+    // Hiding twice is harmless
+    db_access
+        .hide_message(&user_2, &messages[2].id)
+        .await
+        .unwrap();
+
+    let conversation = ConversationId::Direct(user_2);
+    assert_eq!(
+        db_access
+            .fetch_last_messages_in_chat(&user_1, &conversation, None)
+            .await
+            .unwrap(),
+        vec![messages[2].clone(), tombstone.clone(), messages[0].clone()]
+    );
+    let conversation = ConversationId::Direct(user_1);
+    assert_eq!(
+        db_access
+            .fetch_last_messages_in_chat(&user_2, &conversation, None)
+            .await
+            .unwrap(),
+        vec![tombstone.clone(), messages[0].clone()]
+    );
+    // A hidden message still works as a pagination starting point
+    assert_eq!(
+        db_access
+            .fetch_last_messages_in_chat(&user_2, &conversation, Some(&messages[2].id))
+            .await
+            .unwrap(),
+        vec![tombstone.clone(), messages[0].clone()]
+    );
+    assert_eq!(
+        db_access
+            .fetch_users_messages_since(&user_2, &messages[0].id)
+            .await
+            .unwrap(),
+        vec![tombstone]
+    );
+}
+
+pub async fn stores_attachments(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    // postgres stores microseconds
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let attachments: Vec<_> = (0..3)
+        .map(|i| Attachment {
+            id: uuid::Uuid::new_v4(),
+            uploader: user_1,
+            message_id: None,
+            file_name: format!("picture {i}.png"),
+            content_type: "image/png".to_owned(),
+            size: 1000 + i,
+            has_thumbnail: i != 2,
+            created_at: now + TimeDelta::seconds(i as i64),
+        })
+        .collect();
+    for attachment in &attachments {
+        db_access.create_attachment(attachment).await.unwrap();
+    }
+    assert_eq!(
+        db_access
+            .fetch_attachment(&attachments[0].id)
+            .await
+            .unwrap(),
+        Some(attachments[0].clone())
+    );
+    assert!(db_access
+        .fetch_attachment(&uuid::Uuid::new_v4())
+        .await
+        .unwrap()
+        .is_none());
+
+    let message_id = uuid::Uuid::new_v4();
+    let linked: Vec<_> = attachments[..2]
+        .iter()
+        .map(|attachment| Attachment {
+            message_id: Some(message_id),
+            ..attachment.clone()
+        })
+        .collect();
+    let message = Message {
+        id: message_id,
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        message: "Pictures".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: linked.clone(),
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    db_access.create_message(&message).await.unwrap();
+
+    assert_eq!(
+        db_access
+            .fetch_attachment(&attachments[1].id)
+            .await
+            .unwrap(),
+        Some(linked[1].clone())
+    );
+    // Not sent with the message
+    assert_eq!(
+        db_access
+            .fetch_attachment(&attachments[2].id)
+            .await
+            .unwrap(),
+        Some(attachments[2].clone())
+    );
+    assert_eq!(
+        db_access.fetch_message(&message_id).await.unwrap(),
+        Some(message.clone())
+    );
+    assert_eq!(
+        db_access
+            .fetch_last_messages_in_chat(&user_2, &ConversationId::Direct(user_1), None)
+            .await
+            .unwrap(),
+        vec![message.clone()]
+    );
+
+    db_access
+        .delete_message(&message_id, now + TimeDelta::seconds(10))
+        .await
+        .unwrap();
+    assert!(db_access
+        .fetch_message(&message_id)
+        .await
+        .unwrap()
+        .unwrap()
+        .attachments
+        .is_empty());
+    assert!(db_access
+        .fetch_attachment(&attachments[0].id)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+pub async fn stores_read_markers(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    // postgres stores microseconds
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let senders = [user_2, user_1, user_2, user_2];
+    let messages: Vec<_> = senders
+        .into_iter()
+        .zip(0..)
+        .map(|(from, i)| Message {
+            id: uuid::Uuid::new_v4(),
+            from,
+            to: ConversationId::Direct(if from == user_1 { user_2 } else { user_1 }),
+            message: format!("Message {i}"),
+            timestamp: now + TimeDelta::seconds(i),
+            edited_at: None,
+            deleted_at: None,
+            expires_at: None,
+            attachments: vec![],
+            reply_to: None,
+            forwarded_from: None,
+            reactions: vec![],
+        })
+        .collect();
+    for message in &messages {
+        db_access.create_message(message).await.unwrap();
+    }
+
+    let unread_count = |chats: Vec<Conversation>| chats[0].unread_count;
+    let conversation = ConversationId::Direct(user_2);
+    assert_eq!(
+        db_access
+            .fetch_read_marker(&user_1, &conversation)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        unread_count(db_access.find_users_chats(&user_1).await.unwrap()),
+        3
+    );
+    assert_eq!(
+        unread_count(db_access.find_users_chats(&user_2).await.unwrap()),
+        1
+    );
+
+    db_access
+        .update_read_marker(&user_1, &conversation, &messages[0].id)
+        .await
+        .unwrap();
+    db_access
+        .update_read_marker(&user_1, &conversation, &messages[2].id)
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access
+            .fetch_read_marker(&user_1, &conversation)
+            .await
+            .unwrap(),
+        Some(messages[2].id)
+    );
+    assert_eq!(
+        db_access
+            .fetch_read_marker(&user_2, &ConversationId::Direct(user_1))
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        unread_count(db_access.find_users_chats(&user_1).await.unwrap()),
+        1
+    );
+
+    // Hidden messages aren't counted
+    db_access
+        .hide_message(&user_1, &messages[3].id)
+        .await
+        .unwrap();
+    assert_eq!(
+        unread_count(db_access.find_users_chats(&user_1).await.unwrap()),
+        0
+    );
+}
+
+pub async fn searches_messages(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let user_3 = db_access.create_user("__User_3").await.unwrap().unwrap();
+    // postgres stores microseconds
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+
+    let chat = Chat {
+        id: uuid::Uuid::new_v4(),
+        title: "Chat".to_owned(),
+        created_at: now,
+    };
+    db_access.create_chat(&chat, &user_1).await.unwrap();
+    db_access
+        .update_chat_member(&chat.id, &user_2, ChatRole::Member)
+        .await
+        .unwrap();
+
+    let messages: Vec<_> = [
+        (
+            user_1,
+            ConversationId::Direct(user_2),
+            "Let's meet at the lighthouse tomorrow",
+        ),
+        (
+            user_2,
+            ConversationId::Direct(user_1),
+            "The LIGHTHOUSE keeper likes the lighthouse",
+        ),
+        (
+            user_2,
+            ConversationId::Group(chat.id),
+            "Lighthouse photos are up",
+        ),
+        (
+            user_3,
+            ConversationId::Direct(user_2),
+            "A lighthouse secret",
+        ),
+        (
+            user_1,
+            ConversationId::Direct(user_2),
+            "A hidden lighthouse",
+        ),
+        (
+            user_1,
+            ConversationId::Direct(user_2),
+            "A deleted lighthouse",
+        ),
+        (
+            user_1,
+            ConversationId::Direct(user_2),
+            "Sunset at the beach",
+        ),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, (from, to, message))| Message {
+        id: uuid::Uuid::new_v4(),
+        from,
+        to,
+        message: message.to_owned(),
+        timestamp: now + TimeDelta::seconds(i as i64),
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    })
+    .collect();
+    for message in &messages {
+        db_access.create_message(message).await.unwrap();
+    }
+    db_access
+        .hide_message(&user_1, &messages[4].id)
+        .await
+        .unwrap();
+    db_access
+        .delete_message(&messages[5].id, now + TimeDelta::seconds(10))
+        .await
+        .unwrap();
+
+    let search = |user_id, query: MessageSearchQuery| async move {
+        db_access
+            .search_messages(&user_id, &query)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|result| result.message.id)
+            .collect::<Vec<_>>()
+    };
+
+    // More occurrences rank higher, equal ranks are ordered newest first
+    assert_eq!(
+        search(user_1, MessageSearchQuery::new("lighthouse")).await,
+        vec![messages[1].id, messages[2].id, messages[0].id]
+    );
+    assert_eq!(
+        search(user_3, MessageSearchQuery::new("Lighthouse")).await,
+        vec![messages[3].id]
+    );
+    assert_eq!(
+        search(user_1, MessageSearchQuery::new("meet, lighthouse!")).await,
+        vec![messages[0].id]
+    );
+    assert_eq!(
+        search(user_1, MessageSearchQuery::new("lighthouse beach")).await,
+        Vec::<MessageId>::new()
+    );
+
+    let in_direct_conversation = MessageSearchQuery {
+        conversation: Some(ConversationId::Direct(user_2)),
+        ..MessageSearchQuery::new("lighthouse")
+    };
+    assert_eq!(
+        search(user_1, in_direct_conversation).await,
+        vec![messages[1].id, messages[0].id]
+    );
+    let in_group = MessageSearchQuery {
+        conversation: Some(ConversationId::Group(chat.id)),
+        ..MessageSearchQuery::new("lighthouse")
+    };
+    assert_eq!(search(user_1, in_group.clone()).await, vec![messages[2].id]);
+    assert_eq!(search(user_3, in_group).await, Vec::<MessageId>::new());
+    let by_sender = MessageSearchQuery {
+        sender: Some(user_2),
+        ..MessageSearchQuery::new("lighthouse")
+    };
+    assert_eq!(
+        search(user_1, by_sender).await,
+        vec![messages[1].id, messages[2].id]
+    );
+    let by_time = MessageSearchQuery {
+        since: Some(now + TimeDelta::seconds(1)),
+        until: Some(now + TimeDelta::seconds(2)),
+        ..MessageSearchQuery::new("lighthouse")
+    };
+    assert_eq!(search(user_1, by_time).await, vec![messages[1].id]);
+
+    let mut pages = vec![];
+    let mut cursor = None;
+    loop {
+        let page = db_access
+            .search_messages(
+                &user_1,
+                &MessageSearchQuery {
+                    cursor,
+                    limit: 1,
+                    ..MessageSearchQuery::new("lighthouse")
+                },
+            )
+            .await
+            .unwrap();
+        let Some(result) = page.first() else {
+            break;
+        };
+        cursor = Some(result.cursor());
+        pages.push(result.message.id);
+    }
+    assert_eq!(pages, vec![messages[1].id, messages[2].id, messages[0].id]);
+
+    db_access
+        .edit_message(
+            &messages[0].id,
+            "Let's meet at the pier tomorrow",
+            now + TimeDelta::seconds(20),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        search(user_1, MessageSearchQuery::new("lighthouse")).await,
+        vec![messages[1].id, messages[2].id]
+    );
+    assert_eq!(
+        search(user_2, MessageSearchQuery::new("pier")).await,
+        vec![messages[0].id]
+    );
+}
+
+pub async fn stores_forwarded_messages(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let user_3 = db_access.create_user("__User_3").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let copy = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_2,
+        to: ConversationId::Direct(user_3),
+        message: "News".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: Some(ForwardedFrom {
+            from: user_1,
+            timestamp: now - TimeDelta::days(1),
+        }),
+        reactions: vec![],
+    };
+    db_access.create_message(&copy).await.unwrap();
+    assert_eq!(
+        db_access.fetch_message(&copy.id).await.unwrap(),
+        Some(copy.clone())
+    );
+    assert_eq!(
+        db_access
+            .fetch_last_messages_in_chat(&user_3, &ConversationId::Direct(user_2), None)
+            .await
+            .unwrap(),
+        vec![copy.clone()]
+    );
+
+    // Copies of a deleted author's messages refer to their replacement
+    let replacement = Profile {
+        user_id: uuid::Uuid::new_v4(),
+        username: "__Deleted_User".to_owned(),
+        display_name: None,
+        bio: String::new(),
+        avatar: None,
+        deleted: true,
+    };
+    db_access.delete_user(&user_1, &replacement).await.unwrap();
+    assert_eq!(
+        db_access
+            .fetch_message(&copy.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .forwarded_from,
+        Some(ForwardedFrom {
+            from: replacement.user_id,
+            timestamp: now - TimeDelta::days(1),
+        })
+    );
+}
+
+pub async fn stores_replies(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    // postgres stores microseconds
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let original = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        message: "Question?".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    let reply = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_2,
+        to: ConversationId::Direct(user_1),
+        message: "Answer".to_owned(),
+        timestamp: now + TimeDelta::seconds(1),
+        reply_to: Some(original.id),
+        ..original.clone()
+    };
+    db_access.create_message(&original).await.unwrap();
+    db_access.create_message(&reply).await.unwrap();
+
+    assert_eq!(
+        db_access.fetch_message(&reply.id).await.unwrap(),
+        Some(reply.clone())
+    );
+    assert_eq!(
+        db_access
+            .fetch_last_messages_in_chat(&user_1, &ConversationId::Direct(user_2), None)
+            .await
+            .unwrap(),
+        vec![reply.clone(), original.clone()]
+    );
+    assert_eq!(
+        db_access
+            .fetch_users_messages_since(&user_1, &original.id)
+            .await
+            .unwrap(),
+        vec![reply.clone()]
+    );
+
+    // Replies keep referring to deleted originals
+    db_access
+        .delete_message(&original.id, now + TimeDelta::seconds(2))
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access.fetch_message(&reply.id).await.unwrap(),
+        Some(reply)
+    );
+}
+
+pub async fn stores_reactions(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    // postgres stores microseconds
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let message = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        message: "Good news".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    db_access.create_message(&message).await.unwrap();
+
+    let reactions = [
+        (user_2, "🎉"),
+        (user_1, "👍"),
+        (user_2, "👍"),
+        (user_2, "🎉"),
+    ];
+    for (i, (user_id, emoji)) in reactions.into_iter().enumerate() {
+        db_access
+            .add_reaction(
+                &message.id,
+                &user_id,
+                emoji,
+                now + TimeDelta::seconds(i as i64),
+            )
+            .await
+            .unwrap();
+    }
+    let expected = vec![
+        Reaction {
+            emoji: "🎉".to_owned(),
+            users: vec![user_2],
+        },
+        Reaction {
+            emoji: "👍".to_owned(),
+            users: vec![user_1, user_2],
+        },
+    ];
+    assert_eq!(
+        db_access
+            .fetch_message(&message.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .reactions,
+        expected
+    );
+    assert_eq!(
+        db_access
+            .fetch_last_messages_in_chat(&user_2, &ConversationId::Direct(user_1), None)
+            .await
+            .unwrap()[0]
+            .reactions,
+        expected
+    );
+
+    db_access
+        .remove_reaction(&message.id, &user_1, "👍")
+        .await
+        .unwrap();
+    db_access
+        .remove_reaction(&message.id, &user_1, "🎉")
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access
+            .fetch_message(&message.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .reactions,
+        vec![
+            Reaction {
+                emoji: "🎉".to_owned(),
+                users: vec![user_2],
+            },
+            Reaction {
+                emoji: "👍".to_owned(),
+                users: vec![user_2],
+            },
+        ]
+    );
+
+    db_access
+        .delete_message(&message.id, now + TimeDelta::seconds(10))
+        .await
+        .unwrap();
+    assert!(db_access
+        .fetch_message(&message.id)
+        .await
+        .unwrap()
+        .unwrap()
+        .reactions
+        .is_empty());
+}
+
+pub async fn stores_pins_and_stars(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let user_3 = db_access.create_user("__User_3").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let first = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        message: "Адрес: ул. Ленина, 1".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    let second = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_2,
+        to: ConversationId::Direct(user_1),
+        message: "Встречаемся в 7".to_owned(),
+        timestamp: now + TimeDelta::seconds(1),
+        ..first.clone()
+    };
+    let other = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_3,
+        to: ConversationId::Direct(user_1),
+        message: "Пароль от wi-fi".to_owned(),
+        timestamp: now + TimeDelta::seconds(2),
+        ..first.clone()
+    };
+    for message in [&first, &second, &other] {
+        db_access.create_message(message).await.unwrap();
+    }
+
+    db_access
+        .pin_message(&first.id, &user_1, now + TimeDelta::seconds(3))
+        .await
+        .unwrap();
+    db_access
+        .pin_message(&second.id, &user_2, now + TimeDelta::seconds(4))
+        .await
+        .unwrap();
+    // already pinned
+    db_access
+        .pin_message(&first.id, &user_2, now + TimeDelta::seconds(5))
+        .await
+        .unwrap();
+    let expected = vec![
+        PinnedMessage {
+            message: second.clone(),
+            pinned_by: user_2,
+            pinned_at: now + TimeDelta::seconds(4),
+        },
+        PinnedMessage {
+            message: first.clone(),
+            pinned_by: user_1,
+            pinned_at: now + TimeDelta::seconds(3),
+        },
+    ];
+    assert_eq!(
+        db_access
+            .fetch_pinned_messages(&user_1, &ConversationId::Direct(user_2))
+            .await
+            .unwrap(),
+        expected
+    );
+    assert_eq!(
+        db_access
+            .fetch_pinned_messages(&user_2, &ConversationId::Direct(user_1))
+            .await
+            .unwrap(),
+        expected
+    );
+    assert!(db_access
+        .fetch_pinned_messages(&user_1, &ConversationId::Direct(user_3))
+        .await
+        .unwrap()
+        .is_empty());
+
+    db_access.hide_message(&user_2, &second.id).await.unwrap();
+    assert_eq!(
+        db_access
+            .fetch_pinned_messages(&user_2, &ConversationId::Direct(user_1))
+            .await
+            .unwrap(),
+        expected[1..]
+    );
+    db_access.unpin_message(&second.id).await.unwrap();
+    assert_eq!(
+        db_access
+            .fetch_pinned_messages(&user_1, &ConversationId::Direct(user_2))
+            .await
+            .unwrap(),
+        expected[1..]
+    );
+
+    for (i, message) in [&first, &other, &second].into_iter().enumerate() {
+        db_access
+            .star_message(&user_1, &message.id, now + TimeDelta::seconds(i as i64))
+            .await
+            .unwrap();
+    }
+    // already starred
+    db_access
+        .star_message(&user_1, &first.id, now + TimeDelta::seconds(10))
+        .await
+        .unwrap();
+    let starred = vec![
+        StarredMessage {
+            message: second.clone(),
+            starred_at: now + TimeDelta::seconds(2),
+        },
+        StarredMessage {
+            message: other.clone(),
+            starred_at: now + TimeDelta::seconds(1),
+        },
+        StarredMessage {
+            message: first.clone(),
+            starred_at: now,
+        },
+    ];
+    assert_eq!(
+        db_access
+            .fetch_starred_messages(&user_1, None, 10)
+            .await
+            .unwrap(),
+        starred
+    );
+    let page = db_access
+        .fetch_starred_messages(&user_1, None, 2)
+        .await
+        .unwrap();
+    assert_eq!(page, starred[..2]);
+    assert_eq!(
+        db_access
+            .fetch_starred_messages(&user_1, Some(&page[1].cursor()), 2)
+            .await
+            .unwrap(),
+        starred[2..]
+    );
+    // stars are private
+    assert!(db_access
+        .fetch_starred_messages(&user_2, None, 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    db_access.unstar_message(&user_1, &other.id).await.unwrap();
+    db_access
+        .delete_message(&first.id, now + TimeDelta::seconds(11))
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access
+            .fetch_starred_messages(&user_1, None, 10)
+            .await
+            .unwrap(),
+        starred[..1]
+    );
+    assert!(db_access
+        .fetch_pinned_messages(&user_1, &ConversationId::Direct(user_2))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+pub async fn stores_scheduled_messages(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let chat = Chat {
+        id: uuid::Uuid::new_v4(),
+        title: "Группа".to_owned(),
+        created_at: now,
+    };
+    db_access.create_chat(&chat, &user_1).await.unwrap();
+
+    let later = ScheduledMessage {
+        id: uuid::Uuid::new_v4(),
+        from: user_1,
+        to: ConversationId::Group(chat.id),
+        outgoing: OutgoingMessage {
+            text: "Позже".to_owned(),
+            attachments: vec![uuid::Uuid::new_v4()],
+            reply_to: Some(uuid::Uuid::new_v4()),
+        },
+        send_at: now + TimeDelta::hours(2),
+        created_at: now,
+        failed_attempts: 0,
+        retry_at: None,
+    };
+    let sooner = ScheduledMessage {
+        id: uuid::Uuid::new_v4(),
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        outgoing: OutgoingMessage::new("Скоро"),
+        send_at: now + TimeDelta::hours(1),
+        created_at: now,
+        failed_attempts: 0,
+        retry_at: None,
+    };
+    let other = ScheduledMessage {
+        id: uuid::Uuid::new_v4(),
+        from: user_2,
+        to: ConversationId::Direct(user_1),
+        outgoing: OutgoingMessage::new("Ответ"),
+        send_at: now + TimeDelta::hours(3),
+        created_at: now,
+        failed_attempts: 0,
+        retry_at: None,
+    };
+    for scheduled in [&later, &sooner, &other] {
+        db_access.create_scheduled_message(scheduled).await.unwrap();
+    }
+
+    assert_eq!(
+        db_access.fetch_scheduled_messages(&user_1).await.unwrap(),
+        vec![sooner.clone(), later.clone()]
+    );
+    assert_eq!(
+        db_access.fetch_scheduled_messages(&user_2).await.unwrap(),
+        vec![other.clone()]
+    );
+
+    assert!(db_access
+        .fetch_due_scheduled_messages(now, 10)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        db_access
+            .fetch_due_scheduled_messages(now + TimeDelta::hours(2), 10)
+            .await
+            .unwrap(),
+        vec![sooner.clone(), later.clone()]
+    );
+    assert_eq!(
+        db_access
+            .fetch_due_scheduled_messages(now + TimeDelta::hours(3), 1)
+            .await
+            .unwrap(),
+        vec![sooner.clone()]
+    );
+
+    // Only the author can take a message off the queue
+    assert!(!db_access
+        .delete_scheduled_message(&user_2, &sooner.id)
+        .await
+        .unwrap());
+    assert!(db_access
+        .delete_scheduled_message(&user_1, &sooner.id)
+        .await
+        .unwrap());
+    assert!(!db_access
+        .delete_scheduled_message(&user_1, &sooner.id)
+        .await
+        .unwrap());
+    assert_eq!(
+        db_access
+            .fetch_due_scheduled_messages(now + TimeDelta::hours(3), 10)
+            .await
+            .unwrap(),
+        vec![later.clone(), other.clone()]
+    );
+
+    // Failed deliveries are due when they are retried
+    db_access
+        .postpone_scheduled_message(&later.id, now + TimeDelta::hours(4))
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access
+            .fetch_due_scheduled_messages(now + TimeDelta::hours(3), 10)
+            .await
+            .unwrap(),
+        vec![other.clone()]
+    );
+    let postponed = ScheduledMessage {
+        failed_attempts: 1,
+        retry_at: Some(now + TimeDelta::hours(4)),
+        ..later
+    };
+    assert_eq!(
+        db_access
+            .fetch_due_scheduled_messages(now + TimeDelta::hours(4), 10)
+            .await
+            .unwrap(),
+        vec![other, postponed]
+    );
+}
+
+pub async fn purges_expired_messages(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let chat = Chat {
+        id: uuid::Uuid::new_v4(),
+        title: "Группа".to_owned(),
+        created_at: now,
+    };
+    db_access.create_chat(&chat, &user_1).await.unwrap();
+
+    // Both participants of a direct conversation share the timer
+    let day = TimeDelta::days(1);
+    db_access
+        .update_message_ttl(&user_2, &ConversationId::Direct(user_1), Some(day))
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access
+            .fetch_message_ttl(&user_1, &ConversationId::Direct(user_2))
+            .await
+            .unwrap(),
+        Some(day)
+    );
+    db_access
+        .update_message_ttl(&user_1, &ConversationId::Direct(user_2), Some(day * 7))
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access
+            .fetch_message_ttl(&user_2, &ConversationId::Direct(user_1))
+            .await
+            .unwrap(),
+        Some(day * 7)
+    );
+    db_access
+        .update_message_ttl(&user_1, &ConversationId::Group(chat.id), Some(day))
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access
+            .fetch_message_ttl(&user_2, &ConversationId::Group(chat.id))
+            .await
+            .unwrap(),
+        Some(day)
+    );
+    db_access
+        .update_message_ttl(&user_2, &ConversationId::Direct(user_1), None)
+        .await
+        .unwrap();
+    assert!(db_access
+        .fetch_message_ttl(&user_1, &ConversationId::Direct(user_2))
+        .await
+        .unwrap()
+        .is_none());
+
+    let attachment = Attachment {
+        id: uuid::Uuid::new_v4(),
+        uploader: user_1,
+        message_id: None,
+        file_name: "secret.txt".to_owned(),
+        content_type: "text/plain".to_owned(),
+        size: 6,
+        has_thumbnail: false,
+        created_at: now,
+    };
+    db_access.create_attachment(&attachment).await.unwrap();
+    let expiring_id = uuid::Uuid::new_v4();
+    let expiring = Message {
+        id: expiring_id,
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        message: "Секрет".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: Some(now + TimeDelta::seconds(10)),
+        attachments: vec![Attachment {
+            message_id: Some(expiring_id),
+            ..attachment
+        }],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    let old = Message {
+        id: uuid::Uuid::new_v4(),
+        to: ConversationId::Group(chat.id),
+        message: "Давно было".to_owned(),
+        timestamp: now - TimeDelta::days(30),
+        expires_at: None,
+        attachments: vec![],
+        ..expiring.clone()
+    };
+    let reply = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_2,
+        to: ConversationId::Direct(user_1),
+        message: "Понял".to_owned(),
+        timestamp: now + TimeDelta::seconds(1),
+        expires_at: Some(now + TimeDelta::seconds(11)),
+        attachments: vec![],
+        reply_to: Some(expiring.id),
+        ..expiring.clone()
+    };
+    for message in [&expiring, &old, &reply] {
+        db_access.create_message(message).await.unwrap();
+    }
+    db_access
+        .pin_message(&expiring.id, &user_2, now)
+        .await
+        .unwrap();
+    db_access
+        .star_message(&user_2, &expiring.id, now)
+        .await
+        .unwrap();
+    db_access
+        .add_reaction(&expiring.id, &user_2, "🤫", now)
+        .await
+        .unwrap();
+    db_access
+        .update_read_marker(&user_2, &ConversationId::Direct(user_1), &expiring.id)
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access.fetch_message(&reply.id).await.unwrap(),
+        Some(reply.clone())
+    );
+
+    // Nothing has expired yet
+    assert!(db_access
+        .purge_expired_messages(now, None, 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let purged = db_access
+        .purge_expired_messages(now + TimeDelta::seconds(10), None, 10)
+        .await
+        .unwrap();
+    let expected = Message {
+        reactions: vec![Reaction {
+            emoji: "🤫".to_owned(),
+            users: vec![user_2],
+        }],
+        ..expiring.clone()
+    };
+    assert_eq!(purged, vec![expected]);
+    assert!(db_access
+        .fetch_message(&expiring.id)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(db_access
+        .fetch_attachment(&expiring.attachments[0].id)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(db_access
+        .fetch_pinned_messages(&user_2, &ConversationId::Direct(user_1))
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(db_access
+        .fetch_starred_messages(&user_2, None, 10)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(db_access
+        .fetch_read_marker(&user_2, &ConversationId::Direct(user_1))
+        .await
+        .unwrap()
+        .is_none());
+    // Replies outlive the original
+    assert_eq!(
+        db_access.fetch_message(&reply.id).await.unwrap(),
+        Some(Message {
+            reply_to: None,
+            ..reply.clone()
+        })
+    );
+
+    // Messages sent before the retention period are purged in batches
+    let purged = db_access
+        .purge_expired_messages(now, Some(now - TimeDelta::days(7)), 1)
+        .await
+        .unwrap();
+    assert_eq!(purged, vec![old.clone()]);
+    assert!(db_access
+        .purge_expired_messages(now, Some(now - TimeDelta::days(7)), 1)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        db_access
+            .purge_expired_messages(now + TimeDelta::seconds(11), None, 10)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+pub async fn stores_mentions_and_notifications(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let user_3 = db_access.create_user("__User_3").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+    let chat = Chat {
+        id: uuid::Uuid::new_v4(),
+        title: "Группа".to_owned(),
+        created_at: now,
+    };
+    db_access.create_chat(&chat, &user_1).await.unwrap();
+    let message = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        message: "Привет, @__User_2".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: Some(now + TimeDelta::seconds(10)),
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    db_access.create_message(&message).await.unwrap();
+
+    // Mentioning a user again is ignored
+    db_access
+        .create_mentions(&message.id, &[user_2])
+        .await
+        .unwrap();
+    db_access
+        .create_mentions(&message.id, &[user_2, user_3])
+        .await
+        .unwrap();
+    let mut mentioned = db_access.fetch_mentions(&message.id).await.unwrap();
+    mentioned.sort();
+    let mut expected = vec![user_2, user_3];
+    expected.sort();
+    assert_eq!(mentioned, expected);
+
+    let mention = Notification {
+        id: uuid::Uuid::new_v4(),
+        user_id: user_2,
+        actor: user_1,
+        kind: NotificationKind::Mention {
+            message_id: message.id,
+            conversation: ConversationId::Direct(user_1),
+        },
+        created_at: now - TimeDelta::seconds(2),
+        read_at: None,
+    };
+    let new_chat = Notification {
+        id: uuid::Uuid::new_v4(),
+        user_id: user_2,
+        actor: user_1,
+        kind: NotificationKind::NewChat { chat_id: chat.id },
+        created_at: now - TimeDelta::seconds(1),
+        read_at: None,
+    };
+    let reaction = Notification {
+        id: uuid::Uuid::new_v4(),
+        user_id: user_2,
+        actor: user_1,
+        kind: NotificationKind::Reaction {
+            message_id: message.id,
+            conversation: ConversationId::Direct(user_1),
+            emoji: "👍".to_owned(),
+        },
+        created_at: now,
+        read_at: None,
+    };
+    for notification in [&mention, &new_chat, &reaction] {
+        db_access.create_notification(notification).await.unwrap();
+    }
+
+    // Newest first, paged by the cursor
+    let first_page = db_access
+        .fetch_notifications(&user_2, None, 2)
+        .await
+        .unwrap();
+    assert_eq!(first_page, vec![reaction.clone(), new_chat.clone()]);
+    assert_eq!(
+        db_access
+            .fetch_notifications(&user_2, Some(&first_page[1].cursor()), 2)
+            .await
+            .unwrap(),
+        vec![mention.clone()]
+    );
+    assert!(db_access
+        .fetch_notifications(&user_1, None, 10)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        db_access.count_unread_notifications(&user_2).await.unwrap(),
+        3
+    );
+
+    // Notifications are marked read one at a time or all at once
+    assert_eq!(
+        db_access
+            .mark_notifications_read(&user_1, Some(&mention.id), now)
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        db_access
+            .mark_notifications_read(&user_2, Some(&mention.id), now)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        db_access.count_unread_notifications(&user_2).await.unwrap(),
+        2
+    );
+    assert_eq!(
+        db_access
+            .mark_notifications_read(&user_2, None, now + TimeDelta::seconds(1))
+            .await
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        db_access.count_unread_notifications(&user_2).await.unwrap(),
+        0
+    );
+    assert_eq!(
+        db_access
+            .fetch_notifications(&user_2, None, 10)
+            .await
+            .unwrap(),
+        vec![
+            Notification {
+                read_at: Some(now + TimeDelta::seconds(1)),
+                ..reaction.clone()
+            },
+            Notification {
+                read_at: Some(now + TimeDelta::seconds(1)),
+                ..new_chat.clone()
+            },
+            Notification {
+                read_at: Some(now),
+                ..mention.clone()
+            },
+        ]
+    );
+
+    // Purging the message removes its mentions and notifications
+    db_access
+        .purge_expired_messages(now + TimeDelta::minutes(1), None, 100)
+        .await
+        .unwrap();
+    assert!(db_access
+        .fetch_mentions(&message.id)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        db_access
+            .fetch_notifications(&user_2, None, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|notification| notification.id)
+            .collect::<Vec<_>>(),
+        vec![new_chat.id]
+    );
+
+    // Notifications of a deleted actor refer to their replacement
+    let replacement = Profile {
+        user_id: uuid::Uuid::new_v4(),
+        username: "__Deleted_User".to_owned(),
+        display_name: None,
+        bio: String::new(),
+        avatar: None,
+        deleted: true,
+    };
+    db_access.delete_user(&user_1, &replacement).await.unwrap();
+    assert_eq!(
+        db_access
+            .fetch_notifications(&user_2, None, 10)
+            .await
+            .unwrap()[0]
+            .actor,
+        replacement.user_id
+    );
+    // and a deleted user's own notifications are gone
+    let replacement = Profile {
+        user_id: uuid::Uuid::new_v4(),
+        username: "__Deleted_User_2".to_owned(),
+        ..replacement
+    };
+    db_access.delete_user(&user_2, &replacement).await.unwrap();
+    assert!(db_access
+        .fetch_notifications(&user_2, None, 10)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+pub async fn stores_blocks_and_mutes(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let user_3 = db_access.create_user("__User_3").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+
+    db_access.block_user(&user_1, &user_3, now).await.unwrap();
+    db_access
+        .block_user(&user_1, &user_2, now + TimeDelta::seconds(1))
+        .await
+        .unwrap();
+    // Blocking again changes nothing
+    db_access
+        .block_user(&user_1, &user_3, now + TimeDelta::seconds(2))
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access.fetch_blocked_users(&user_1).await.unwrap(),
+        vec![user_3, user_2]
+    );
+    assert_eq!(
+        db_access.fetch_users_blocking(&user_2).await.unwrap(),
+        vec![user_1]
+    );
+    assert!(db_access
+        .fetch_blocked_users(&user_2)
+        .await
+        .unwrap()
+        .is_empty());
+
+    db_access.unblock_user(&user_1, &user_3).await.unwrap();
+    assert_eq!(
+        db_access.fetch_blocked_users(&user_1).await.unwrap(),
+        vec![user_2]
+    );
+    assert!(db_access
+        .fetch_users_blocking(&user_3)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let message = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_3,
+        to: ConversationId::Direct(user_1),
+        message: "Quiet please".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    db_access.create_message(&message).await.unwrap();
+    let conversation = ConversationId::Direct(user_3);
+    for muted in [true, true, false, true] {
+        db_access
+            .update_conversation_muted(&user_1, &conversation, muted)
+            .await
+            .unwrap();
+        let chats = db_access.find_users_chats(&user_1).await.unwrap();
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].muted, muted);
+        assert_eq!(
+            db_access
+                .is_conversation_muted(&user_1, &conversation)
+                .await
+                .unwrap(),
+            muted
+        );
+        // Muting is per user
+        assert!(!db_access.find_users_chats(&user_3).await.unwrap()[0].muted);
+        assert!(!db_access
+            .is_conversation_muted(&user_3, &ConversationId::Direct(user_1))
+            .await
+            .unwrap());
+    }
+}
+
+pub async fn stores_profiles(db_access: &impl DataAccess) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+
+    assert_eq!(
+        db_access.fetch_profile(&user_1).await.unwrap(),
+        Some(Profile {
+            user_id: user_1,
+            username: "__User_1".to_owned(),
+            display_name: None,
+            bio: String::new(),
+            avatar: None,
+            deleted: false,
+        })
+    );
+    assert!(db_access
+        .fetch_profile(&uuid::Uuid::new_v4())
+        .await
+        .unwrap()
+        .is_none());
+
+    let avatar = Attachment {
+        id: uuid::Uuid::new_v4(),
+        uploader: user_1,
+        message_id: None,
+        file_name: "me.png".to_owned(),
+        content_type: "image/png".to_owned(),
+        size: 1000,
+        has_thumbnail: true,
+        created_at: now,
+    };
+    db_access.create_attachment(&avatar).await.unwrap();
+    db_access
+        .update_profile(&user_1, Some("Первый"), "Пишу редко")
+        .await
+        .unwrap();
+    db_access
+        .update_avatar(&user_1, Some(&avatar.id))
+        .await
+        .unwrap();
+    let profile = db_access.fetch_profile(&user_1).await.unwrap().unwrap();
+    assert_eq!(profile.display_name.as_deref(), Some("Первый"));
+    assert_eq!(profile.bio, "Пишу редко");
+    assert_eq!(profile.avatar, Some(avatar.id));
+
+    // Chat lists show the display name and the avatar
+    let message = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        message: "Introductions".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    db_access.create_message(&message).await.unwrap();
+    let chats = db_access.find_users_chats(&user_2).await.unwrap();
+    assert_eq!(chats[0].title, "Первый");
+    assert_eq!(chats[0].avatar, Some(avatar.id));
+
+    db_access.update_avatar(&user_1, None).await.unwrap();
+    db_access.update_profile(&user_1, None, "").await.unwrap();
+    let chats = db_access.find_users_chats(&user_2).await.unwrap();
+    assert_eq!(chats[0].title, "__User_1");
+    assert_eq!(chats[0].avatar, None);
+
+    // Usernames stay unique regardless of case
+    assert!(!db_access
+        .update_username(&user_1, "__user_2")
+        .await
+        .unwrap());
+    assert!(db_access
+        .update_username(&user_1, "__USER_1")
+        .await
+        .unwrap());
+    assert!(db_access
+        .update_username(&user_1, "__Renamed")
+        .await
+        .unwrap());
+    assert_eq!(
+        db_access.find_user_by_username("__renamed").await.unwrap(),
+        Some(user_1)
+    );
+    assert!(db_access
+        .find_user_by_username("__User_1")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(db_access.create_user("__RENAMED").await.unwrap().is_none());
+}
+
+pub async fn deletes_users(db_access: &(impl DataAccess + AuthStorage)) {
+    let user_1 = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_2 = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let user_3 = db_access.create_user("__User_3").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+
+    let chat = Chat {
+        id: uuid::Uuid::new_v4(),
+        title: "Группа".to_owned(),
+        created_at: now,
+    };
+    db_access.create_chat(&chat, &user_1).await.unwrap();
+    db_access
+        .update_chat_member(&chat.id, &user_2, ChatRole::Member)
+        .await
+        .unwrap();
+    db_access
+        .update_chat_member(&chat.id, &user_3, ChatRole::Admin)
+        .await
+        .unwrap();
+
+    let sent = Attachment {
+        id: uuid::Uuid::new_v4(),
+        uploader: user_1,
+        message_id: None,
+        file_name: "sent.txt".to_owned(),
+        content_type: "text/plain".to_owned(),
+        size: 10,
+        has_thumbnail: false,
+        created_at: now,
+    };
+    let unsent = Attachment {
+        id: uuid::Uuid::new_v4(),
+        file_name: "unsent.txt".to_owned(),
+        created_at: now + TimeDelta::seconds(1),
+        ..sent.clone()
+    };
+    db_access.create_attachment(&sent).await.unwrap();
+    db_access.create_attachment(&unsent).await.unwrap();
+    let direct = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_1,
+        to: ConversationId::Direct(user_2),
+        message: "Прощай".to_owned(),
+        timestamp: now,
+        edited_at: None,
+        deleted_at: None,
+        expires_at: None,
+        attachments: vec![],
+        reply_to: None,
+        forwarded_from: None,
+        reactions: vec![],
+    };
+    let answer = Message {
+        id: uuid::Uuid::new_v4(),
+        from: user_2,
+        to: ConversationId::Direct(user_1),
+        message: "Пока".to_owned(),
+        timestamp: now + TimeDelta::seconds(1),
+        ..direct.clone()
+    };
+    let group_message_id = uuid::Uuid::new_v4();
+    let sent = Attachment {
+        message_id: Some(group_message_id),
+        ..sent
+    };
+    let group_message = Message {
+        id: group_message_id,
+        to: ConversationId::Group(chat.id),
+        message: "Всем пока".to_owned(),
+        timestamp: now + TimeDelta::seconds(2),
+        attachments: vec![sent.clone()],
+        ..direct.clone()
+    };
+    for message in [&direct, &answer, &group_message] {
+        db_access.create_message(message).await.unwrap();
+    }
+    db_access
+        .add_reaction(&answer.id, &user_1, "👍", now)
+        .await
+        .unwrap();
+    db_access
+        .update_read_marker(&user_2, &ConversationId::Direct(user_1), &direct.id)
+        .await
+        .unwrap();
+    db_access
+        .update_conversation_muted(&user_2, &ConversationId::Direct(user_1), true)
+        .await
+        .unwrap();
+    db_access.block_user(&user_3, &user_1, now).await.unwrap();
+
+    assert_eq!(
+        db_access.fetch_users_attachments(&user_1).await.unwrap(),
+        vec![sent.clone(), unsent.clone()]
+    );
+
+    let api_token = ApiToken {
+        id: uuid::Uuid::new_v4(),
+        user_id: user_1,
+        name: "Бот".to_owned(),
+        scopes: vec![ApiScope::MessagesRead],
+        created_at: now,
+        expires_at: None,
+    };
+    db_access
+        .create_api_token(&api_token, &"a".repeat(64))
+        .await
+        .unwrap();
+
+    let replacement = Profile {
+        user_id: uuid::Uuid::new_v4(),
+        username: "__Deleted".to_owned(),
+        display_name: Some("Удалённый аккаунт".to_owned()),
+        bio: String::new(),
+        avatar: None,
+        deleted: true,
+    };
+    assert_eq!(
+        db_access.delete_user(&user_1, &replacement).await.unwrap(),
+        vec![unsent]
+    );
+
+    assert!(db_access.fetch_profile(&user_1).await.unwrap().is_none());
+    assert_eq!(
+        db_access.fetch_profile(&replacement.user_id).await.unwrap(),
+        Some(replacement.clone())
+    );
+    assert!(db_access
+        .find_users_by_substring("__Deleted")
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(db_access
+        .fetch_api_tokens(&user_1)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(db_access.create_user("__User_1").await.unwrap().is_some());
+
+    // Counterparties keep the conversation with the placeholder
+    let former = ConversationId::Direct(replacement.user_id);
+    let messages = db_access
+        .fetch_last_messages_in_chat(&user_2, &former, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        messages
+            .iter()
+            .map(|message| message.id)
+            .collect::<Vec<_>>(),
+        vec![answer.id, direct.id]
+    );
+    assert_eq!(messages[1].from, replacement.user_id);
+    assert!(messages[0].reactions.is_empty());
+    assert_eq!(
+        db_access.fetch_read_marker(&user_2, &former).await.unwrap(),
+        Some(direct.id)
+    );
+    let chats = db_access.find_users_chats(&user_2).await.unwrap();
+    let direct_chat = chats.iter().find(|chat| chat.id == former).unwrap();
+    assert_eq!(direct_chat.title, "Удалённый аккаунт");
+    assert!(direct_chat.muted);
+    assert!(db_access
+        .fetch_blocked_users(&user_3)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // The admin takes over the group
+    assert_eq!(
+        db_access.fetch_chat_members(&chat.id).await.unwrap(),
+        vec![
+            ChatMember {
+                user_id: user_2,
+                role: ChatRole::Member
+            },
+            ChatMember {
+                user_id: user_3,
+                role: ChatRole::Owner
+            },
+        ]
+    );
+    let group_messages = db_access
+        .fetch_last_messages_in_chat(&user_2, &ConversationId::Group(chat.id), None)
+        .await
+        .unwrap();
+    assert_eq!(group_messages[0].from, replacement.user_id);
+    assert_eq!(
+        group_messages[0].attachments,
+        vec![Attachment {
+            uploader: replacement.user_id,
+            ..sent
+        }]
+    );
+}
+
+pub async fn updates_user_email(db_access: &impl DataAccess) {
+    let user_id = db_access.create_user("__User_1").await.unwrap().unwrap();
+    assert_eq!(db_access.fetch_user_email(&user_id).await.unwrap(), None);
+
+    db_access
+        .update_user_email(&user_id, Some("user_1@example.com"))
+        .await
+        .unwrap();
+    assert_eq!(
+        db_access
+            .fetch_user_email(&user_id)
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("user_1@example.com")
+    );
+
+    db_access.update_user_email(&user_id, None).await.unwrap();
+    assert_eq!(db_access.fetch_user_email(&user_id).await.unwrap(), None);
+}
+
+pub async fn tracks_login_failures(db_access: &(impl DataAccess + AuthStorage)) {
+    let user_id = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let user_key = LoginThrottleKey::User(user_id);
+    let client_key = LoginThrottleKey::Client("192.0.2.1".parse().unwrap());
+    // postgres stores microseconds
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+
+    assert_eq!(
+        db_access.fetch_login_failures(&user_key).await.unwrap(),
+        None
+    );
+    assert!(db_access
+        .replace_login_failures(&user_key, None, None)
+        .await
+        .unwrap());
+
+    let first = LoginFailures {
+        failed_attempts: 1,
+        last_failure_at: now,
+        locked_until: None,
+    };
+    assert!(db_access
+        .replace_login_failures(&user_key, None, Some(&first))
+        .await
+        .unwrap());
+    assert_eq!(
+        db_access.fetch_login_failures(&user_key).await.unwrap(),
+        Some(first.clone())
+    );
+    assert_eq!(
+        db_access.fetch_login_failures(&client_key).await.unwrap(),
+        None
+    );
+
+    // failures changed by someone else aren't replaced
+    let second = LoginFailures {
+        failed_attempts: 2,
+        last_failure_at: now + TimeDelta::minutes(1),
+        locked_until: None,
+    };
+    assert!(!db_access
+        .replace_login_failures(&user_key, None, Some(&second))
+        .await
+        .unwrap());
+    assert!(!db_access
+        .replace_login_failures(&user_key, None, None)
+        .await
+        .unwrap());
+    assert!(!db_access
+        .replace_login_failures(&user_key, Some(&second), None)
+        .await
+        .unwrap());
+    assert_eq!(
+        db_access.fetch_login_failures(&user_key).await.unwrap(),
+        Some(first.clone())
+    );
+
+    assert!(db_access
+        .replace_login_failures(&user_key, Some(&first), Some(&second))
+        .await
+        .unwrap());
+    assert!(!db_access
+        .replace_login_failures(&user_key, Some(&first), Some(&second))
+        .await
+        .unwrap());
+    assert_eq!(
+        db_access.fetch_login_failures(&user_key).await.unwrap(),
+        Some(second.clone())
+    );
+
+    let locked_until = now + TimeDelta::minutes(15);
+    db_access.lock_login(&user_key, locked_until).await.unwrap();
+    let locked = db_access
+        .fetch_login_failures(&user_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(locked.failed_attempts, 0);
+    assert_eq!(locked.locked_until, Some(locked_until));
+    assert!(!db_access
+        .replace_login_failures(&user_key, Some(&second), None)
+        .await
+        .unwrap());
+
+    let after_lockout = LoginFailures {
+        failed_attempts: 1,
+        ..locked.clone()
+    };
+    assert!(db_access
+        .replace_login_failures(&user_key, Some(&locked), Some(&after_lockout))
+        .await
+        .unwrap());
+    assert!(db_access
+        .replace_login_failures(&user_key, Some(&after_lockout), None)
+        .await
+        .unwrap());
+    assert_eq!(
+        db_access.fetch_login_failures(&user_key).await.unwrap(),
+        None
+    );
+
+    db_access
+        .replace_login_failures(&user_key, None, Some(&first))
+        .await
+        .unwrap();
+    db_access.reset_login_failures(&user_key).await.unwrap();
+    assert_eq!(
+        db_access.fetch_login_failures(&user_key).await.unwrap(),
+        None
+    );
+}
+
+pub async fn records_login_events(db_access: &(impl DataAccess + AuthStorage)) {
+    let user_id = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let other_user_id = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+
+    let events = [
+        LoginEvent {
+            user_id: Some(user_id),
+            client_addr: Some("192.0.2.1".parse().unwrap()),
+            kind: LoginEventKind::Failure,
+            created_at: now,
+        },
+        LoginEvent {
+            user_id: Some(other_user_id),
+            client_addr: None,
+            kind: LoginEventKind::Failure,
+            created_at: now,
+        },
+        LoginEvent {
+            user_id: None,
+            client_addr: Some("2001:db8::1".parse().unwrap()),
+            kind: LoginEventKind::Rejected,
+            created_at: now,
+        },
+        LoginEvent {
+            user_id: Some(user_id),
+            client_addr: None,
+            kind: LoginEventKind::Lockout,
+            created_at: now + TimeDelta::seconds(1),
+        },
+    ];
+    for event in events.iter().cloned() {
+        db_access.record_login_event(event).await.unwrap();
+    }
+
+    assert_eq!(
+        db_access.fetch_login_events(&user_id).await.unwrap(),
+        vec![events[3].clone(), events[0].clone()]
+    );
+}
+
+pub async fn stores_api_tokens(db_access: &(impl DataAccess + AuthStorage)) {
+    let user_id = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let other_user_id = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+
+    let api_token = ApiToken {
+        id: uuid::Uuid::new_v4(),
+        user_id,
+        name: "Бот".to_owned(),
+        scopes: vec![ApiScope::MessagesRead, ApiScope::MessagesWrite],
+        created_at: now,
+        expires_at: Some(now + TimeDelta::days(30)),
+    };
+    let other_api_token = ApiToken {
+        id: uuid::Uuid::new_v4(),
+        user_id,
+        name: "Script".to_owned(),
+        scopes: vec![ApiScope::MessagesRead],
+        created_at: now + TimeDelta::seconds(1),
+        expires_at: None,
+    };
+    db_access
+        .create_api_token(&api_token, &"a".repeat(64))
+        .await
+        .unwrap();
+    db_access
+        .create_api_token(&other_api_token, &"b".repeat(64))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        db_access
+            .fetch_api_token_by_hash(&"a".repeat(64))
+            .await
+            .unwrap(),
+        Some(api_token.clone())
+    );
+    assert_eq!(
+        db_access
+            .fetch_api_token_by_hash(&"c".repeat(64))
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        db_access.fetch_api_tokens(&user_id).await.unwrap(),
+        vec![api_token.clone(), other_api_token.clone()]
+    );
+
+    assert!(!db_access
+        .delete_api_token(&other_user_id, &api_token.id)
+        .await
+        .unwrap());
+    assert!(db_access
+        .delete_api_token(&user_id, &api_token.id)
+        .await
+        .unwrap());
+    assert_eq!(
+        db_access.fetch_api_tokens(&user_id).await.unwrap(),
+        vec![other_api_token]
+    );
+}
+
+pub async fn stores_external_identities(db_access: &(impl DataAccess + AuthStorage)) {
+    let user_id = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let other_user_id = db_access.create_user("__User_2").await.unwrap().unwrap();
+    let issuer = "https://id.example.com";
+
+    assert_eq!(
+        db_access
+            .fetch_external_identity_user(issuer, "subject-1")
+            .await
+            .unwrap(),
+        None
+    );
+
+    assert!(db_access
+        .create_external_identity(issuer, "subject-1", &user_id)
+        .await
+        .unwrap());
+    assert!(!db_access
+        .create_external_identity(issuer, "subject-1", &other_user_id)
+        .await
+        .unwrap());
+    assert!(db_access
+        .create_external_identity("https://other.example.com", "subject-1", &other_user_id)
+        .await
+        .unwrap());
+
+    assert_eq!(
+        db_access
+            .fetch_external_identity_user(issuer, "subject-1")
+            .await
+            .unwrap(),
+        Some(user_id)
+    );
+    assert_eq!(
+        db_access
+            .fetch_external_identity_user("https://other.example.com", "subject-1")
+            .await
+            .unwrap(),
+        Some(other_user_id)
+    );
+}
+
+pub async fn stores_audit_events(db_access: &(impl DataAccess + AuditLogStorage)) {
+    let user_id = db_access.create_user("__User_1").await.unwrap().unwrap();
+    let client_addr: IpAddr = "192.0.2.1".parse().unwrap();
+    let now = Utc::now().duration_trunc(TimeDelta::seconds(1)).unwrap();
+
+    let signup_id = db_access
+        .append_audit_event(Some(&user_id), AuditEventKind::Signup, None, now)
+        .await
+        .unwrap();
+    let failure_id = db_access
+        .append_audit_event(
+            None,
+            AuditEventKind::LoginFailure,
+            Some(client_addr),
+            now + TimeDelta::seconds(1),
+        )
+        .await
+        .unwrap();
+    let login_id = db_access
+        .append_audit_event(
+            Some(&user_id),
+            AuditEventKind::LoginSuccess,
+            Some(client_addr),
+            now + TimeDelta::seconds(2),
+        )
+        .await
+        .unwrap();
+    assert!(signup_id < failure_id && failure_id < login_id);
+
+    let all_events = db_access
+        .fetch_audit_events(&AuditEventQuery::new(10))
+        .await
+        .unwrap();
+    assert_eq!(
+        all_events,
+        vec![
+            AuditEvent {
+                id: login_id,
+                user_id: Some(user_id),
+                kind: AuditEventKind::LoginSuccess,
+                client_addr: Some(client_addr),
+                created_at: now + TimeDelta::seconds(2),
+            },
+            AuditEvent {
+                id: failure_id,
+                user_id: None,
+                kind: AuditEventKind::LoginFailure,
+                client_addr: Some(client_addr),
+                created_at: now + TimeDelta::seconds(1),
+            },
+            AuditEvent {
+                id: signup_id,
+                user_id: Some(user_id),
+                kind: AuditEventKind::Signup,
+                client_addr: None,
+                created_at: now,
+            },
+        ]
+    );
+
+    let ids = |events: Vec<AuditEvent>| events.iter().map(|event| event.id).collect::<Vec<_>>();
+    let user_events = AuditEventQuery {
+        user_id: Some(user_id),
+        ..AuditEventQuery::new(10)
+    };
+    assert_eq!(
+        ids(db_access.fetch_audit_events(&user_events).await.unwrap()),
+        vec![login_id, signup_id]
+    );
+    let by_address_and_kind = AuditEventQuery {
+        kind: Some(AuditEventKind::LoginFailure),
+        client_addr: Some(client_addr),
+        ..AuditEventQuery::new(10)
+    };
+    assert_eq!(
+        ids(db_access
+            .fetch_audit_events(&by_address_and_kind)
+            .await
+            .unwrap()),
+        vec![failure_id]
+    );
+    let by_time = AuditEventQuery {
+        since: Some(now + TimeDelta::seconds(1)),
+        until: Some(now + TimeDelta::seconds(2)),
+        ..AuditEventQuery::new(10)
+    };
+    assert_eq!(
+        ids(db_access.fetch_audit_events(&by_time).await.unwrap()),
+        vec![failure_id]
+    );
+    let page = AuditEventQuery {
+        before: Some(login_id),
+        ..AuditEventQuery::new(1)
+    };
+    assert_eq!(
+        ids(db_access.fetch_audit_events(&page).await.unwrap()),
+        vec![failure_id]
+    );
+}
+
 mod mock_db {
     use mock_db::Db;
 
@@ -0,0 +1,313 @@
+use pheidippides_messenger::formatting::render_html;
+
+const LINK_ATTRIBUTES: &str = r#" rel="noopener noreferrer nofollow" target="_blank""#;
+
+#[test]
+fn renders_formatting() {
+    let link = |href: &str, text: &str| format!(r#"<a href="{href}"{LINK_ATTRIBUTES}>{text}</a>"#);
+    let cases = [
+        ("Hello", "Hello".to_owned()),
+        ("**bold** text", "<strong>bold</strong> text".to_owned()),
+        (
+            "*italic* and _italic_",
+            "<em>italic</em> and <em>italic</em>".to_owned(),
+        ),
+        ("***both***", "<strong><em>both</em></strong>".to_owned()),
+        (
+            "**bold _and italic_**",
+            "<strong>bold <em>and italic</em></strong>".to_owned(),
+        ),
+        ("`x < y`", "<code>x &lt; y</code>".to_owned()),
+        ("``a ` b``", "<code>a ` b</code>".to_owned()),
+        ("`**not bold**`", "<code>**not bold**</code>".to_owned()),
+        (
+            "||spoiler||",
+            r#"<span class="spoiler">spoiler</span>"#.to_owned(),
+        ),
+        (
+            "||**bold** spoiler||",
+            r#"<span class="spoiler"><strong>bold</strong> spoiler</span>"#.to_owned(),
+        ),
+        ("line 1\nline 2", "line 1<br>line 2".to_owned()),
+        (
+            "Code:\n```rust\nfn main() {}\n```\nDone",
+            r#"Code:<pre><code class="language-rust">fn main() {}</code></pre>Done"#.to_owned(),
+        ),
+        (
+            "```\n<b>*raw*</b>\n```",
+            "<pre><code>&lt;b&gt;*raw*&lt;/b&gt;</code></pre>".to_owned(),
+        ),
+        (
+            "[the **docs**](https://example.com/docs?a=1&b=2)",
+            link(
+                "https://example.com/docs?a=1&amp;b=2",
+                "the <strong>docs</strong>",
+            ),
+        ),
+        (
+            "[mail](mailto:me@example.com)",
+            link("mailto:me@example.com", "mail"),
+        ),
+        (
+            "See https://example.com/a_b_c.",
+            format!(
+                "See {}.",
+                link("https://example.com/a_b_c", "https://example.com/a_b_c")
+            ),
+        ),
+        (
+            "(https://en.wikipedia.org/wiki/Rust_(disambiguation))",
+            format!(
+                "({})",
+                link(
+                    "https://en.wikipedia.org/wiki/Rust_(disambiguation)",
+                    "https://en.wikipedia.org/wiki/Rust_(disambiguation)"
+                )
+            ),
+        ),
+        // Markers that don't form spans stay as they are
+        ("2 * 3 * 4", "2 * 3 * 4".to_owned()),
+        ("snake_case_name", "snake_case_name".to_owned()),
+        ("**unclosed", "**unclosed".to_owned()),
+        ("a | b || c", "a | b || c".to_owned()),
+        ("`unclosed", "`unclosed".to_owned()),
+        ("```unclosed", "```unclosed".to_owned()),
+        ("**", "**".to_owned()),
+        ("\\*not italic\\*", "*not italic*".to_owned()),
+        ("[text](relative/path)", "[text](relative/path)".to_owned()),
+        // Spans don't cross each other
+        ("*a **b* c**", "<em>a **b</em> c**".to_owned()),
+    ];
+    for (source, html) in cases {
+        assert_eq!(render_html(source), html, "source {source:?}");
+    }
+}
+
+#[test]
+fn links_only_to_safe_urls() {
+    for url in [
+        "javascript:alert(1)",
+        "JavaScript:alert(1)",
+        " javascript:alert(1)",
+        "java\tscript:alert(1)",
+        "data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==",
+        "vbscript:msgbox(1)",
+        "//evil.example",
+        "https:",
+        "http://",
+        "file:///etc/passwd",
+    ] {
+        let html = render_html(&format!("[click]({url})"));
+        assert!(!html.contains("<a"), "{url:?} became {html:?}");
+        assert_safe(&html);
+    }
+}
+
+/// Well-known XSS payloads, alone and combined with the markers at random
+#[test]
+fn sanitizes_xss_corpus() {
+    let payloads = [
+        "<script>alert(1)</script>",
+        "<img src=x onerror=alert(1)>",
+        "<svg/onload=alert(1)>",
+        "<iframe src=\"javascript:alert(1)\"></iframe>",
+        "<a href=\"javascript:alert(1)\">x</a>",
+        "\"><script>alert(1)</script>",
+        "'><img src=x onerror=alert(1)>",
+        "\" onmouseover=\"alert(1)",
+        "' onfocus='alert(1)' autofocus='",
+        "&lt;script&gt;alert(1)&lt;/script&gt;",
+        "&#60;script&#62;alert(1)&#60;/script&#62;",
+        "<scr<script>ipt>alert(1)</scr</script>ipt>",
+        "<!--<script>alert(1)//-->",
+        "<style>*{background:url(javascript:alert(1))}</style>",
+        "<body onload=alert(1)>",
+        "<math><mtext><table><mglyph><style><img src=x onerror=alert(1)>",
+        "javascript:alert(1)",
+        "https://example.com/\"onmouseover=\"alert(1)",
+        "https://example.com/<script>alert(1)</script>",
+        "https://example.com/'onmouseover='alert(1)'",
+        "[x](https://example.com/\"onmouseover=\"alert(1))",
+        "[<img src=x onerror=alert(1)>](https://example.com)",
+        "[x](javascript:alert(1))",
+        "[x](https://example.com)<script>",
+        "```\"><script>alert(1)</script>\n</code></pre><script>alert(1)</script>```",
+        "```<script>\nalert(1)```",
+        "`</code><script>alert(1)</script>`",
+        "||<img src=x onerror=alert(1)>||",
+        "**<svg onload=alert(1)>**",
+        "\u{0}<script>\u{0}alert(1)",
+        "\\<script>alert(1)\\</script>",
+    ];
+    for payload in payloads {
+        let html = render_html(payload);
+        assert_safe(&html);
+        assert!(!html.contains("<script"), "{payload:?} became {html:?}");
+        assert!(!html.contains("<img"), "{payload:?} became {html:?}");
+    }
+
+    let fragments: Vec<&str> = [
+        "*",
+        "**",
+        "***",
+        "_",
+        "`",
+        "```",
+        "```js\n",
+        "||",
+        "|",
+        "[",
+        "]",
+        "(",
+        ")",
+        "](",
+        "\\",
+        "\n",
+        " ",
+        "a",
+        "word",
+        "https://",
+        "https://example.com",
+        "mailto:",
+        "<",
+        ">",
+        "&",
+        "\"",
+        "'",
+        "=",
+        "onerror=",
+        "javascript:",
+    ]
+    .into_iter()
+    .chain(payloads)
+    .collect();
+    // xorshift, so that failures are reproducible
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..20_000 {
+        let length = next() % 16 + 1;
+        let source: String = (0..length)
+            .map(|_| fragments[(next() % fragments.len() as u64) as usize])
+            .collect();
+        let html = render_html(&source);
+        assert_safe(&html);
+        assert!(!html.contains("<script"), "{source:?} became {html:?}");
+    }
+}
+
+#[test]
+fn renders_pathological_text_in_linear_time() {
+    let length = 200_000;
+    for unit in [
+        "*",
+        "_a",
+        "a_",
+        "**a",
+        "||a",
+        "*a _",
+        "`",
+        "` ``",
+        "[a](x",
+        "[",
+        "(https://a\u{1})",
+        "https://a)",
+        "```",
+    ] {
+        let source = unit.repeat(length / unit.len());
+        let html = render_html(&source);
+        assert_safe(&html);
+    }
+    let nested = format!("{}x{}", "*a ".repeat(50_000), " b*".repeat(50_000));
+    assert_safe(&render_html(&nested));
+}
+
+/// Checks that the only markup of the HTML is the one the formatter produces
+/// and that its tags are properly nested
+fn assert_safe(html: &str) {
+    const TAGS: &[(&str, Option<&str>)] = &[
+        ("<strong>", Some("strong")),
+        ("</strong>", None),
+        ("<em>", Some("em")),
+        ("</em>", None),
+        ("<code>", Some("code")),
+        ("</code>", None),
+        ("<pre>", Some("pre")),
+        ("</pre>", None),
+        (r#"<span class="spoiler">"#, Some("span")),
+        ("</span>", None),
+        ("</a>", None),
+        ("<br>", None),
+    ];
+    const ENTITIES: &[&str] = &["&amp;", "&lt;", "&gt;", "&quot;", "&#39;"];
+
+    let mut open_tags = vec![];
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '<' => {
+                let end = rest
+                    .find('>')
+                    .unwrap_or_else(|| panic!("Unclosed tag in {html:?}"))
+                    + 1;
+                let tag = &rest[..end];
+                if let Some((_, opens)) = TAGS.iter().find(|(known, _)| *known == tag) {
+                    match (opens, tag.strip_prefix("</")) {
+                        (Some(name), _) => open_tags.push(*name),
+                        (None, Some(name)) => assert_eq!(
+                            open_tags.pop(),
+                            Some(&name[..name.len() - 1]),
+                            "Misnested {tag} in {html:?}"
+                        ),
+                        (None, None) => {}
+                    }
+                } else if let Some(language) = tag
+                    .strip_prefix(r#"<code class="language-"#)
+                    .and_then(|rest| rest.strip_suffix(r#"">"#))
+                {
+                    assert!(
+                        !language.is_empty()
+                            && language
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || "+#-_.".contains(c)),
+                        "Unexpected language {language:?} in {html:?}"
+                    );
+                    open_tags.push("code");
+                } else if let Some(href) = tag
+                    .strip_prefix(r#"<a href=""#)
+                    .and_then(|rest| rest.strip_suffix(&format!("\"{LINK_ATTRIBUTES}>")))
+                {
+                    assert!(
+                        !href.contains(['"', '\'', '<', '>']),
+                        "Unescaped href {href:?} in {html:?}"
+                    );
+                    let href = href.to_ascii_lowercase();
+                    assert!(
+                        ["http://", "https://", "mailto:"]
+                            .iter()
+                            .any(|scheme| href.starts_with(scheme)),
+                        "Unsafe href {href:?} in {html:?}"
+                    );
+                    open_tags.push("a");
+                } else {
+                    panic!("Unexpected tag {tag:?} in {html:?}");
+                }
+                rest = &rest[end..];
+            }
+            '&' => {
+                let entity = ENTITIES
+                    .iter()
+                    .find(|entity| rest.starts_with(*entity))
+                    .unwrap_or_else(|| panic!("Unescaped & in {html:?}"));
+                rest = &rest[entity.len()..];
+            }
+            '>' | '"' | '\'' => panic!("Unescaped {c} in {html:?}"),
+            c => rest = &rest[c.len_utf8()..],
+        }
+    }
+    assert!(open_tags.is_empty(), "Unclosed {open_tags:?} in {html:?}");
+}
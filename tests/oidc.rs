@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+use http_server::request::Request;
+use http_server::response::Response;
+use http_server::server::RequestHandler;
+use mock_db::Db;
+use pheidippides_auth::{AuthServiceUsingArgon2, SecretKey};
+use pheidippides_blob::InMemoryBlobStorage;
+use pheidippides_mail::InMemoryMailer;
+use pheidippides_messenger::messenger::{LoginResult, Messenger};
+use pheidippides_messenger::UserId;
+use pheidippides_oidc::{OidcAuthService, OidcClient, OidcConfig};
+use pheidippides_utils::serde::form_data;
+use pheidippides_web::{routing, sessions};
+
+type App =
+    Messenger<Db, OidcAuthService<AuthServiceUsingArgon2<Db>>, InMemoryMailer, InMemoryBlobStorage>;
+
+const CLIENT_ID: &str = "pheidippides";
+const CLIENT_SECRET: &str = "client-secret";
+const REDIRECT_URL: &str = "http://localhost/login/oidc/callback";
+const SIGNING_KEY_ID: &str = "test-key";
+const SIGNING_KEY: &str = include_str!("fixtures/oidc_signing_key.pem");
+const SIGNING_KEY_MODULUS: &str = "30jxx30T2b8dcyqYkseV0JpRqMS9SvOa3UKOMYNzK_q5mBP4FI7aZI6vcD_vZpoN4ShuuAssl0n9bIRMZryAEV-NnroK-pUZdnv0h4CejDtTY2bcwf4yjdFfdzjIo869Ac9s10IYcUDbsNv9bFoQslm04usIuUkgpjMQbmEAm_KlCZa7bO4Sm11NvSHpZKK8XVO_xrc7uKPdDHzSF35J2pQ4Y3P_gnS8kgSXKJAVBKTBsLA98X9c4mz8WGfSm8QtrDMAd_jU5Tker7SCMGjtzHQQKNCqCJn_gIDsCvnpB5YUMK9q18LuuvJ6LFn2XmWmz4VH26baCD1fJYlu39OvjQ";
+const UNKNOWN_KEY: &str = include_str!("fixtures/oidc_unknown_key.pem");
+
+#[tokio::test]
+async fn creates_user_on_first_external_login() {
+    let (app, provider) = make_app().await;
+
+    let user_id = session_user(external_login(&app, &provider, "subject-1", "alice", None).await)
+        .expect("Login should succeed");
+    assert_eq!(
+        app.fetch_user(&user_id).await.unwrap().unwrap().username,
+        "alice"
+    );
+
+    let second_login = external_login(&app, &provider, "subject-1", "alice", None).await;
+    assert_eq!(session_user(second_login), Some(user_id));
+}
+
+#[tokio::test]
+async fn adds_suffix_to_taken_preferred_username() {
+    let (app, provider) = make_app().await;
+
+    let first_user =
+        session_user(external_login(&app, &provider, "subject-1", "alice", None).await).unwrap();
+    let second_user =
+        session_user(external_login(&app, &provider, "subject-2", "alice", None).await).unwrap();
+
+    assert_ne!(first_user, second_user);
+    assert_eq!(
+        app.fetch_user(&second_user)
+            .await
+            .unwrap()
+            .unwrap()
+            .username,
+        "alice_2"
+    );
+}
+
+#[tokio::test]
+async fn links_identity_to_logged_in_user() {
+    let (app, provider) = make_app().await;
+
+    let response = external_login(&app, &provider, "subject-1", "alice", None).await;
+    let session_cookie = session_cookie(&response).unwrap();
+    let user_id = session_user(response).unwrap();
+
+    let linking_login = external_login(
+        &app,
+        &provider,
+        "subject-2",
+        "alice-work",
+        Some(&session_cookie),
+    )
+    .await;
+    assert_eq!(session_user(linking_login), Some(user_id));
+
+    let login_with_linked_identity =
+        external_login(&app, &provider, "subject-2", "alice-work", None).await;
+    assert_eq!(session_user(login_with_linked_identity), Some(user_id));
+}
+
+#[tokio::test]
+async fn rejects_id_token_with_wrong_nonce() {
+    let (app, provider) = make_app().await;
+    provider.set_signing(IdTokenSigning::WrongNonce);
+
+    let response = external_login(&app, &provider, "subject-1", "alice", None).await;
+    assert!(response.is_html());
+}
+
+#[tokio::test]
+async fn rejects_id_token_signed_with_unknown_key() {
+    let (app, provider) = make_app().await;
+    provider.set_signing(IdTokenSigning::UnknownKey);
+
+    let response = external_login(&app, &provider, "subject-1", "alice", None).await;
+    assert!(response.is_html());
+}
+
+#[tokio::test]
+async fn rejects_id_token_signed_with_client_secret() {
+    let (app, provider) = make_app().await;
+    provider.set_signing(IdTokenSigning::ClientSecret);
+
+    let response = external_login(&app, &provider, "subject-1", "alice", None).await;
+    assert!(response.is_html());
+}
+
+#[tokio::test]
+async fn rejects_callback_with_foreign_state() {
+    let (app, provider) = make_app().await;
+
+    let (location, state_cookie) = start_external_login(&app, None).await;
+    let (code, state) = provider.authorize(&location, "subject-1", "alice");
+    let callback_url = format!("/login/oidc/callback?code={code}&state={state}");
+
+    let response = route(&app, "GET", &callback_url, None).await;
+    assert!(matches!(response, Response::BadRequest));
+
+    let (_, other_state_cookie) = start_external_login(&app, None).await;
+    let response = route(&app, "GET", &callback_url, Some(&other_state_cookie)).await;
+    assert!(matches!(response, Response::BadRequest));
+
+    let response = route(&app, "GET", &callback_url, Some(&state_cookie)).await;
+    assert!(session_user(response).is_some());
+
+    // every state is accepted only once
+    let response = route(&app, "GET", &callback_url, Some(&state_cookie)).await;
+    assert!(response.is_html());
+}
+
+#[tokio::test]
+async fn disables_password_login() {
+    let (app, provider) = make_app().await;
+    session_user(external_login(&app, &provider, "subject-1", "alice", None).await).unwrap();
+
+    let login_page = match route(&app, "GET", "/login", None).await {
+        Response::Html { content, .. } => content,
+        response => panic!("Expected html response, got {response:?}"),
+    };
+    assert!(!login_page.contains("/authorize"));
+    assert!(login_page.contains("/login/oidc"));
+
+    let response = route(&app, "POST", "/signup", None).await;
+    assert!(matches!(response, Response::BadRequest));
+
+    let login_result = app.log_in("alice", "12345".into(), None).await.unwrap();
+    assert!(matches!(login_result, LoginResult::Failed));
+    assert!(!app.request_password_reset("alice").await.unwrap());
+}
+
+#[derive(Clone, Copy)]
+enum IdTokenSigning {
+    Valid,
+    WrongNonce,
+    UnknownKey,
+    ClientSecret,
+}
+
+struct IssuedCode {
+    code_challenge: String,
+    nonce: String,
+    subject: String,
+    preferred_username: String,
+}
+
+#[derive(Serialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: i64,
+    iat: i64,
+    nonce: String,
+    preferred_username: String,
+}
+
+/// Identity provider that skips user interaction: codes are issued directly by `authorize`
+#[derive(Clone)]
+struct MockIdentityProvider {
+    issuer: String,
+    codes: Arc<Mutex<HashMap<String, IssuedCode>>>,
+    signing: Arc<Mutex<IdTokenSigning>>,
+}
+
+impl MockIdentityProvider {
+    async fn start() -> Self {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{port}");
+
+        let provider = MockIdentityProvider {
+            issuer: format!("http://{addr}"),
+            codes: Arc::new(Mutex::new(HashMap::new())),
+            signing: Arc::new(Mutex::new(IdTokenSigning::Valid)),
+        };
+
+        let server_addr = addr.clone();
+        let request_handler = provider.clone();
+        tokio::spawn(async move {
+            http_server::server::run_server(&server_addr, request_handler, CancellationToken::new())
+                .await
+                .unwrap()
+        });
+        while TcpStream::connect(&addr).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+
+        provider
+    }
+
+    fn set_signing(&self, signing: IdTokenSigning) {
+        *self.signing.lock().unwrap() = signing;
+    }
+
+    /// Returns the code and the state the provider would redirect back with
+    fn authorize(
+        &self,
+        authorization_url: &str,
+        subject: &str,
+        preferred_username: &str,
+    ) -> (String, String) {
+        #[derive(Deserialize)]
+        struct AuthorizationParams {
+            response_type: String,
+            client_id: String,
+            redirect_uri: String,
+            state: String,
+            nonce: String,
+            code_challenge: String,
+            code_challenge_method: String,
+        }
+
+        let (endpoint, query) = authorization_url.split_once('?').unwrap();
+        assert_eq!(endpoint, format!("{}/authorize", self.issuer));
+        let params: AuthorizationParams = form_data::from_str(query).unwrap();
+        assert_eq!(params.response_type, "code");
+        assert_eq!(params.client_id, CLIENT_ID);
+        assert_eq!(params.redirect_uri, REDIRECT_URL);
+        assert_eq!(params.code_challenge_method, "S256");
+
+        let code = uuid::Uuid::new_v4().to_string();
+        self.codes.lock().unwrap().insert(
+            code.clone(),
+            IssuedCode {
+                code_challenge: params.code_challenge,
+                nonce: params.nonce,
+                subject: subject.to_owned(),
+                preferred_username: preferred_username.to_owned(),
+            },
+        );
+
+        (code, params.state)
+    }
+
+    async fn token(&self, request: &mut Request<TcpStream>) -> Response {
+        #[derive(Deserialize)]
+        struct TokenParams {
+            grant_type: String,
+            code: String,
+            client_id: String,
+            client_secret: String,
+            code_verifier: String,
+        }
+
+        let content = match request.content().await {
+            Ok(content) => content,
+            Err(_) => return Response::BadRequest,
+        };
+        let params: TokenParams = match form_data::from_str(&content) {
+            Ok(params) => params,
+            Err(_) => return Response::BadRequest,
+        };
+        let issued_code = match self.codes.lock().unwrap().remove(&params.code) {
+            Some(issued_code) => issued_code,
+            None => return Response::BadRequest,
+        };
+        if params.grant_type != "authorization_code"
+            || params.client_id != CLIENT_ID
+            || params.client_secret != CLIENT_SECRET
+            || URL_SAFE_NO_PAD.encode(Sha256::digest(&params.code_verifier))
+                != issued_code.code_challenge
+        {
+            return Response::BadRequest;
+        }
+
+        let signing = *self.signing.lock().unwrap();
+        let now = Utc::now().timestamp();
+        let claims = IdTokenClaims {
+            iss: self.issuer.clone(),
+            sub: issued_code.subject,
+            aud: CLIENT_ID.to_owned(),
+            exp: now + 300,
+            iat: now,
+            nonce: match signing {
+                IdTokenSigning::WrongNonce => "other-nonce".to_owned(),
+                _ => issued_code.nonce,
+            },
+            preferred_username: issued_code.preferred_username,
+        };
+
+        let (algorithm, key) = match signing {
+            IdTokenSigning::Valid | IdTokenSigning::WrongNonce => (
+                Algorithm::RS256,
+                EncodingKey::from_rsa_pem(SIGNING_KEY.as_bytes()).unwrap(),
+            ),
+            IdTokenSigning::UnknownKey => (
+                Algorithm::RS256,
+                EncodingKey::from_rsa_pem(UNKNOWN_KEY.as_bytes()).unwrap(),
+            ),
+            IdTokenSigning::ClientSecret => (
+                Algorithm::HS256,
+                EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+            ),
+        };
+        let header = Header {
+            kid: Some(SIGNING_KEY_ID.to_owned()),
+            ..Header::new(algorithm)
+        };
+        let id_token = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+
+        Response::Json {
+            content: serde_json::json!({
+                "access_token": "unused",
+                "token_type": "Bearer",
+                "id_token": id_token,
+            })
+            .to_string(),
+            headers: Vec::new(),
+        }
+    }
+}
+
+impl RequestHandler<Request<TcpStream>> for MockIdentityProvider {
+    type Error = Infallible;
+
+    async fn handle(self, request: &mut Request<TcpStream>) -> Result<Response, Self::Error> {
+        let response = match request.url() {
+            "/.well-known/openid-configuration" => Response::Json {
+                content: serde_json::json!({
+                    "issuer": self.issuer,
+                    "authorization_endpoint": format!("{}/authorize", self.issuer),
+                    "token_endpoint": format!("{}/token", self.issuer),
+                    "jwks_uri": format!("{}/jwks", self.issuer),
+                })
+                .to_string(),
+                headers: Vec::new(),
+            },
+            "/jwks" => Response::Json {
+                content: serde_json::json!({
+                    "keys": [{
+                        "kty": "RSA",
+                        "use": "sig",
+                        "alg": "RS256",
+                        "kid": SIGNING_KEY_ID,
+                        "n": SIGNING_KEY_MODULUS,
+                        "e": "AQAB",
+                    }]
+                })
+                .to_string(),
+                headers: Vec::new(),
+            },
+            "/token" => self.token(request).await,
+            _ => Response::BadRequest,
+        };
+        Ok(response)
+    }
+}
+
+/// Returns the authorization url and the state cookie
+async fn start_external_login(app: &App, session_cookie: Option<&str>) -> (String, String) {
+    match route(app, "GET", "/login/oidc", session_cookie).await {
+        Response::Redirect { location, headers } => (location, headers[0].1.clone()),
+        response => panic!("Expected redirect, got {response:?}"),
+    }
+}
+
+/// Goes through the whole flow and returns the response to the callback
+async fn external_login(
+    app: &App,
+    provider: &MockIdentityProvider,
+    subject: &str,
+    preferred_username: &str,
+    session_cookie: Option<&str>,
+) -> Response {
+    let (location, state_cookie) = start_external_login(app, session_cookie).await;
+    let (code, state) = provider.authorize(&location, subject, preferred_username);
+
+    let cookies = match session_cookie {
+        Some(session_cookie) => format!("{state_cookie}; {session_cookie}"),
+        None => state_cookie,
+    };
+    route(
+        app,
+        "GET",
+        &format!("/login/oidc/callback?code={code}&state={state}"),
+        Some(&cookies),
+    )
+    .await
+}
+
+fn session_cookie(response: &Response) -> Option<String> {
+    match response {
+        Response::Redirect { headers, .. } => headers
+            .iter()
+            .map(|(_, value)| value)
+            .find(|value| value.starts_with(sessions::SESSION_ID_COOKIE))
+            .cloned(),
+        _ => None,
+    }
+}
+
+fn session_user(response: Response) -> Option<UserId> {
+    let session_cookie = session_cookie(&response)?;
+    let (_, session_id) = session_cookie.split_once('=')?;
+    sessions::get_session_info(&session_id.to_owned())
+        .unwrap()
+        .map(|session_info| session_info.user_id)
+}
+
+async fn route(app: &App, method: &str, url: &str, cookies: Option<&str>) -> Response {
+    let cookie_header = cookies
+        .map(|cookies| format!("Cookie: {cookies}\r\n"))
+        .unwrap_or_default();
+    let head = format!("{method} {url} HTTP/1.1\r\n{cookie_header}\r\n");
+    let reader = tokio_test::io::Builder::new().read(head.as_bytes()).build();
+    let mut request = http_server::request::Request::try_from_stream(reader)
+        .await
+        .unwrap();
+
+    routing::route(&mut request, app.clone()).await.unwrap()
+}
+
+async fn make_app() -> (App, MockIdentityProvider) {
+    let provider = MockIdentityProvider::start().await;
+    let oidc_client = OidcClient::discover(OidcConfig {
+        issuer_url: provider.issuer.clone(),
+        client_id: CLIENT_ID.to_owned(),
+        client_secret: Some(CLIENT_SECRET.to_owned()),
+        redirect_url: REDIRECT_URL.to_owned(),
+    })
+    .await
+    .unwrap();
+
+    let db_access = Db::empty();
+    let auth_service = OidcAuthService::new(
+        AuthServiceUsingArgon2::new(db_access.clone(), SecretKey::generate()),
+        oidc_client,
+    );
+
+    (
+        Messenger::new(
+            db_access,
+            auth_service,
+            InMemoryMailer::new(),
+            InMemoryBlobStorage::new(),
+        ),
+        provider,
+    )
+}
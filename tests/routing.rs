@@ -1,7 +1,13 @@
+use http_server::response::Response;
 use mock_db::Db;
-use pheidippides_auth::AuthServiceUsingArgon2;
+use pheidippides_auth::{AuthServiceUsingArgon2, SecretKey};
+use pheidippides_blob::InMemoryBlobStorage;
+use pheidippides_mail::InMemoryMailer;
+use pheidippides_messenger::audit_log::AuditEventKind;
+use pheidippides_messenger::authorization::ApiScope;
 use pheidippides_messenger::messenger::Messenger;
-use pheidippides_web::routing;
+use pheidippides_messenger::{ConversationId, UserId};
+use pheidippides_web::{routing, sessions};
 
 #[tokio::test]
 async fn returns_bad_request_for_wrong_url() {
@@ -19,9 +25,517 @@ async fn returns_bad_request_for_wrong_url() {
     assert!(response.is_bad_request());
 }
 
-async fn make_app() -> Messenger<Db, AuthServiceUsingArgon2<Db>> {
+#[tokio::test]
+async fn authorizes_api_tokens_by_scope() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let receiver_id = app
+        .create_user("TestUser_2", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let (_, read_token) = app
+        .create_api_token(&user_id, "reader", &[ApiScope::MessagesRead], None)
+        .await
+        .unwrap();
+    let (write_api_token, write_token) = app
+        .create_api_token(&user_id, "writer", &[ApiScope::MessagesWrite], None)
+        .await
+        .unwrap();
+
+    let messages_url = format!("/json/messages/{receiver_id}");
+    let response = route_with_token(&app, "GET", &messages_url, &read_token, "").await;
+    assert!(json_content(response).contains(r#""success":true"#));
+    let response = route_with_token(&app, "GET", &messages_url, &write_token, "").await;
+    assert!(json_content(response).contains(r#""success":false"#));
+
+    let message_url = format!("/message/{receiver_id}");
+    let body = r#"{"message":"Hello"}"#;
+    // unauthorized requests are rejected before reading the body
+    let response = route_with_token(&app, "POST", &message_url, &read_token, "").await;
+    assert!(response.is_redirect());
+    let response = route_with_token(&app, "POST", &message_url, &write_token, body).await;
+    assert!(response.is_html());
+    assert_eq!(
+        app.fetch_last_messages(&user_id, &ConversationId::Direct(receiver_id), None)
+            .await
+            .unwrap()[0]
+            .message,
+        "Hello"
+    );
+
+    // API tokens can't manage the account
+    let response = route_with_token(&app, "GET", "/json/api_tokens", &write_token, "").await;
+    assert!(response.is_redirect());
+
+    app.revoke_api_token(&user_id, &write_api_token.id)
+        .await
+        .unwrap();
+    let response = route_with_token(&app, "POST", &message_url, &write_token, "").await;
+    assert!(response.is_redirect());
+}
+
+#[tokio::test]
+async fn serves_audit_log_to_owner_and_admins() {
+    let app = make_app().await;
+    let admin_id = app
+        .create_user("Admin", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let user_id = app
+        .create_user("TestUser_1", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let app = app.with_admins(vec![admin_id]);
+    let admin_session = start_session(admin_id);
+    let user_session = start_session(user_id);
+
+    let response = route_with_session(&app, "/account/audit_log", &user_session).await;
+    match response {
+        Response::Html { content, .. } => assert!(content.contains("Регистрация")),
+        response => panic!("Expected html response, got {response:?}"),
+    }
+
+    let response = route_with_session(&app, "/json/audit_log?limit=1", &user_session).await;
+    assert!(json_content(response).contains(&format!(r#""user_id":"{user_id}""#)));
+
+    let admin_url = format!("/json/admin/audit_log?user_id={user_id}&kind=signup");
+    let response = route_with_session(&app, &admin_url, &user_session).await;
+    assert!(response.is_bad_request());
+    let response = route_with_session(&app, &admin_url, &admin_session).await;
+    let content = json_content(response);
+    assert!(content.contains(r#""kind":"signup""#));
+    assert!(!content.contains(&admin_id.to_string()));
+
+    let response = route_with_session(&app, "/logout", &user_session).await;
+    assert!(response.is_redirect());
+    let events = app
+        .fetch_user_audit_events(&user_id, None, 1)
+        .await
+        .unwrap();
+    assert_eq!(events[0].kind, AuditEventKind::Logout);
+}
+
+#[tokio::test]
+async fn serves_group_chats_to_members() {
+    let app = make_app().await;
+    let owner = app
+        .create_user("TestUser_1", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let outsider = app
+        .create_user("TestUser_2", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let scopes = [ApiScope::MessagesRead, ApiScope::MessagesWrite];
+    let (_, owner_token) = app
+        .create_api_token(&owner, "owner", &scopes, None)
+        .await
+        .unwrap();
+    let (_, outsider_token) = app
+        .create_api_token(&outsider, "outsider", &scopes, None)
+        .await
+        .unwrap();
+    let chat_id = app.create_chat(&owner, "Группа", &[]).await.unwrap();
+
+    let message_url = format!("/message/{chat_id}");
+    let body = r#"{"message":"Hello"}"#;
+    let response = route_with_token(&app, "POST", &message_url, &owner_token, body).await;
+    assert!(response.is_html());
+    // non-members are rejected before reading the body
+    let response = route_with_token(&app, "POST", &message_url, &outsider_token, "").await;
+    assert!(response.is_bad_request());
+
+    let messages_url = format!("/json/messages/{chat_id}");
+    let response = route_with_token(&app, "GET", &messages_url, &owner_token, "").await;
+    assert!(json_content(response).contains(&format!(r#""chat_id":"{chat_id}""#)));
+    let response = route_with_token(&app, "GET", &messages_url, &outsider_token, "").await;
+    assert!(response.is_bad_request());
+
+    let chat_url = format!("/json/chats/{chat_id}");
+    let response = route_with_token(&app, "GET", &chat_url, &owner_token, "").await;
+    let content = json_content(response);
+    assert!(content.contains(r#""title":"Группа""#));
+    assert!(content.contains(r#""role":"owner""#));
+    let response = route_with_token(&app, "GET", &chat_url, &outsider_token, "").await;
+    assert!(response.is_bad_request());
+}
+
+#[tokio::test]
+async fn serves_presence_to_contacts_only() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let contact_id = app
+        .create_user("TestUser_2", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let stranger_id = app
+        .create_user("TestUser_3", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    app.send_message("Hello".into(), contact_id, ConversationId::Direct(user_id))
+        .await
+        .unwrap();
+    let scopes = [ApiScope::MessagesRead];
+    let (_, contact_token) = app
+        .create_api_token(&contact_id, "client", &scopes, None)
+        .await
+        .unwrap();
+    let (_, stranger_token) = app
+        .create_api_token(&stranger_id, "client", &scopes, None)
+        .await
+        .unwrap();
+
+    let presence_url = format!("/json/presence/{user_id}");
+    let response = route_with_token(&app, "GET", &presence_url, &contact_token, "").await;
+    assert!(json_content(response).contains(r#""online":false"#));
+    let response = route_with_token(&app, "GET", &presence_url, &stranger_token, "").await;
+    assert!(response.is_not_found());
+
+    app.block_user(&user_id, &contact_id).await.unwrap();
+    let response = route_with_token(&app, "GET", &presence_url, &contact_token, "").await;
+    assert!(response.is_not_found());
+}
+
+#[tokio::test]
+async fn rejects_typing_and_chat_members_between_blocked_users() {
+    let app = make_app().await;
+    let blocker = app
+        .create_user("TestUser_1", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let blocked = app
+        .create_user("TestUser_2", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    app.block_user(&blocker, &blocked).await.unwrap();
+    let session = start_session(blocked);
+
+    let response = post_with_session(&app, &format!("/typing/{blocker}"), &session, "").await;
+    assert!(response.is_bad_request());
+
+    let body = r#"{"title":"Chat","members":["TestUser_1"]}"#;
+    let response = post_with_session(&app, "/chats", &session, body).await;
+    assert!(response.is_bad_request());
+
+    let chat_id = app.create_chat(&blocked, "Chat", &[]).await.unwrap();
+    let members_url = format!("/chats/{chat_id}/members");
+    let body = r#"{"username":"TestUser_1"}"#;
+    let response = post_with_session(&app, &members_url, &session, body).await;
+    assert!(response.is_bad_request());
+}
+
+#[tokio::test]
+async fn serves_attachments_to_participants_only() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let outsider_id = app
+        .create_user("TestUser_2", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let scopes = [ApiScope::MessagesRead, ApiScope::MessagesWrite];
+    let (_, token) = app
+        .create_api_token(&user_id, "client", &scopes, None)
+        .await
+        .unwrap();
+    let (_, outsider_token) = app
+        .create_api_token(&outsider_id, "client", &scopes, None)
+        .await
+        .unwrap();
+
+    let upload = |content_length: usize, body: &'static str| {
+        let head = format!(
+            "POST /attachments?name=my%20notes.txt HTTP/1.1\r\nAuthorization: Bearer {token}\r\n\
+            Content-Type: text/plain; charset=utf-8\r\nContent-Length: {content_length}\r\n\r\n"
+        );
+        let mut builder = tokio_test::io::Builder::new();
+        builder.read(head.as_bytes());
+        if !body.is_empty() {
+            builder.read(body.as_bytes());
+        }
+        let app = app.clone();
+        async move {
+            let mut request = http_server::request::Request::try_from_stream(builder.build())
+                .await
+                .unwrap();
+            routing::route(&mut request, app).await.unwrap()
+        }
+    };
+
+    // Oversized uploads are refused without reading the body
+    let response = upload(usize::MAX, "").await;
+    assert!(json_content(response).contains(r#""success":false"#));
+
+    let response: serde_json::Value =
+        serde_json::from_str(&json_content(upload(5, "Notes").await)).unwrap();
+    assert_eq!(response["attachment"]["file_name"], "my notes.txt");
+    let attachment_url = format!(
+        "/attachments/{}",
+        response["attachment"]["id"].as_str().unwrap()
+    );
+
+    match route_with_token(&app, "GET", &attachment_url, &token, "").await {
+        Response::Bytes {
+            content,
+            content_type,
+            headers,
+        } => {
+            assert_eq!(content, b"Notes");
+            assert_eq!(content_type, "text/plain; charset=utf-8");
+            assert!(headers.iter().any(
+                |(name, value)| name == &"X-Content-Type-Options".into() && value == "nosniff"
+            ));
+        }
+        response => panic!("Expected attachment content, got {response:?}"),
+    }
+    let response = route_with_token(&app, "GET", &attachment_url, &outsider_token, "").await;
+    assert!(response.is_not_found());
+}
+
+#[tokio::test]
+async fn serves_message_search() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let other_id = app
+        .create_user("TestUser_2", "12345".to_owned())
+        .await
+        .unwrap()
+        .unwrap();
+    let (_, token) = app
+        .create_api_token(&user_id, "client", &[ApiScope::MessagesRead], None)
+        .await
+        .unwrap();
+    let (_, write_only_token) = app
+        .create_api_token(&user_id, "client", &[ApiScope::MessagesWrite], None)
+        .await
+        .unwrap();
+    for text in ["Our <b>wombat</b>", "Another wombat", "No match"] {
+        app.send_message(text.into(), other_id, ConversationId::Direct(user_id))
+            .await
+            .unwrap();
+    }
+
+    let search = |url: String| {
+        let app = app.clone();
+        let token = token.clone();
+        async move {
+            let response = route_with_token(&app, "GET", &url, &token, "").await;
+            serde_json::from_str::<serde_json::Value>(&json_content(response)).unwrap()
+        }
+    };
+    let response = search(format!("/json/search?q=Wombat&chat={other_id}&limit=1")).await;
+    assert_eq!(response["hits"].as_array().unwrap().len(), 1);
+    assert_eq!(response["hits"][0]["message"]["message"], "Another wombat");
+    let cursor = response["next_cursor"].as_str().unwrap();
+
+    let response = search(format!(
+        "/json/search?q=Wombat&from={other_id}&cursor={cursor}"
+    ))
+    .await;
+    assert_eq!(response["next_cursor"], serde_json::Value::Null);
+    // Messages carry their source and its sanitized rendering
+    assert_eq!(
+        response["hits"][0]["message"]["message"],
+        "Our <b>wombat</b>"
+    );
+    assert_eq!(
+        response["hits"][0]["message"]["message_html"],
+        "Our &lt;b&gt;wombat&lt;/b&gt;"
+    );
+    // Snippets are plain text, highlighting is left to the client
+    assert_eq!(
+        response["hits"][0]["snippet"],
+        serde_json::json!([
+            {"text": "Our <b>", "highlighted": false},
+            {"text": "wombat", "highlighted": true},
+            {"text": "</b>", "highlighted": false},
+        ])
+    );
+
+    let response = search(format!("/json/search?q=wombat&from={user_id}")).await;
+    assert!(response["hits"].as_array().unwrap().is_empty());
+
+    let response = route_with_token(&app, "GET", "/json/search?q=a&cursor=x", &token, "").await;
+    assert!(response.is_bad_request());
+    let response = route_with_token(&app, "GET", "/json/search?q=a&limit=0", &token, "").await;
+    assert!(response.is_bad_request());
+    let response = route_with_token(&app, "GET", "/json/search?q=a", &write_only_token, "").await;
+    assert!(!matches!(response, Response::Json { .. }));
+}
+
+#[tokio::test]
+async fn exports_and_deletes_accounts() {
+    let app = make_app().await;
+    let user_id = app
+        .create_user("TestUser_1", "12345".into())
+        .await
+        .unwrap()
+        .unwrap();
+    let session_id = start_session(user_id);
+    let other_session_id = start_session(user_id);
+    app.upload_attachment(&user_id, "notes.txt", "text/plain", b"Notes".to_vec())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let response = route_with_session(&app, "/account/export?format=json", &session_id).await;
+    let data: serde_json::Value = serde_json::from_str(&json_content(response)).unwrap();
+    assert_eq!(data["profile"]["username"], "TestUser_1");
+    assert_eq!(data["sessions"].as_array().unwrap().len(), 2);
+    assert_eq!(data["attachments"][0]["file_name"], "notes.txt");
+
+    match route_with_session(&app, "/account/export", &session_id).await {
+        Response::Bytes {
+            content,
+            content_type,
+            ..
+        } => {
+            assert_eq!(content_type, "application/zip");
+            assert!(content.starts_with(b"PK"));
+            let contains = |name: &[u8]| content.windows(name.len()).any(|w| w == name);
+            assert!(contains(b"data.json"));
+            assert!(contains(b"/notes.txt"));
+        }
+        response => panic!("Expected an archive, got {response:?}"),
+    }
+    let response = route_with_session(&app, "/account/export?format=pdf", &session_id).await;
+    assert!(response.is_bad_request());
+
+    let response = post_with_session(
+        &app,
+        "/account/delete",
+        &session_id,
+        r#"{"username": "TestUser_2"}"#,
+    )
+    .await;
+    assert_eq!(json_content(response), r#"{"success":false}"#);
+    assert!(app.fetch_profile(&user_id).await.unwrap().is_some());
+
+    let response = post_with_session(
+        &app,
+        "/account/delete",
+        &session_id,
+        r#"{"username": "TestUser_1"}"#,
+    )
+    .await;
+    assert_eq!(json_content(response), r#"{"success":true}"#);
+    assert!(app.fetch_profile(&user_id).await.unwrap().is_none());
+    // Every session of the user ends
+    for session_id in [session_id, other_session_id] {
+        assert!(sessions::get_session_info(&session_id).unwrap().is_none());
+    }
+}
+
+fn start_session(user_id: UserId) -> String {
+    let session_id = sessions::generate_session_id();
+    sessions::update_session_info(session_id.clone(), sessions::SessionInfo::new(user_id)).unwrap();
+    session_id
+}
+
+async fn route_with_session(
+    app: &Messenger<Db, AuthServiceUsingArgon2<Db>, InMemoryMailer, InMemoryBlobStorage>,
+    url: &str,
+    session_id: &str,
+) -> Response {
+    let head = format!(
+        "GET {url} HTTP/1.1\r\nCookie: {}={session_id}\r\n\r\n",
+        sessions::SESSION_ID_COOKIE
+    );
+    let reader = tokio_test::io::Builder::new().read(head.as_bytes()).build();
+    let mut request = http_server::request::Request::try_from_stream(reader)
+        .await
+        .unwrap();
+
+    routing::route(&mut request, app.clone()).await.unwrap()
+}
+
+async fn post_with_session(
+    app: &Messenger<Db, AuthServiceUsingArgon2<Db>, InMemoryMailer, InMemoryBlobStorage>,
+    url: &str,
+    session_id: &str,
+    body: &str,
+) -> Response {
+    let head = format!(
+        "POST {url} HTTP/1.1\r\nCookie: {}={session_id}\r\nContent-Length: {}\r\n\r\n",
+        sessions::SESSION_ID_COOKIE,
+        body.len()
+    );
+    let reader = tokio_test::io::Builder::new()
+        .read(head.as_bytes())
+        .read(body.as_bytes())
+        .build();
+    let mut request = http_server::request::Request::try_from_stream(reader)
+        .await
+        .unwrap();
+
+    routing::route(&mut request, app.clone()).await.unwrap()
+}
+
+async fn route_with_token(
+    app: &Messenger<Db, AuthServiceUsingArgon2<Db>, InMemoryMailer, InMemoryBlobStorage>,
+    method: &str,
+    url: &str,
+    token: &str,
+    body: &str,
+) -> Response {
+    let head = format!(
+        "{method} {url} HTTP/1.1\r\nAuthorization: Bearer {token}\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    );
+    let mut builder = tokio_test::io::Builder::new();
+    builder.read(head.as_bytes());
+    if !body.is_empty() {
+        builder.read(body.as_bytes());
+    }
+    let reader = builder.build();
+    let mut request = http_server::request::Request::try_from_stream(reader)
+        .await
+        .unwrap();
+
+    routing::route(&mut request, app.clone()).await.unwrap()
+}
+
+fn json_content(response: Response) -> String {
+    match response {
+        Response::Json { content, .. } => content,
+        response => panic!("Expected json response, got {response:?}"),
+    }
+}
+
+async fn make_app() -> Messenger<Db, AuthServiceUsingArgon2<Db>, InMemoryMailer, InMemoryBlobStorage>
+{
     let db_access = mock_db::Db::new().await;
-    let auth_service = AuthServiceUsingArgon2::new(db_access.clone());
+    let auth_service = AuthServiceUsingArgon2::new(db_access.clone(), SecretKey::generate());
 
-    Messenger::new(db_access, auth_service)
+    Messenger::new(
+        db_access,
+        auth_service,
+        InMemoryMailer::new(),
+        InMemoryBlobStorage::new(),
+    )
 }